@@ -0,0 +1,29 @@
+#![no_main]
+
+use emulator::instructions::{immediate, opcode, DecodedInstruction, InstEncoding};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|inst: u32| {
+    let Ok(decoded) = DecodedInstruction::try_from(inst) else {
+        return;
+    };
+
+    // anything the decoder accepts must have a known encoding and an immediate unless it is
+    // r-type
+    let encoding = InstEncoding::try_from(opcode(inst).unwrap()).unwrap();
+    assert_eq!(
+        immediate(inst).is_err(),
+        encoding == InstEncoding::R,
+        "{decoded:?} {inst:032b}"
+    );
+
+    // and encoding its fields again must give back the same word, apart from the ordering bits
+    // of atomics
+    let encoded = decoded.encode();
+    let ordering = if opcode(inst).unwrap().0 == 0b0101111 {
+        0b11 << 25
+    } else {
+        0
+    };
+    assert_eq!(encoded, inst & !ordering, "{decoded:?}");
+});
@@ -0,0 +1,171 @@
+//! Core-local interruptor, matching `shared/peripherals/clint/hdl/registers.rdl`
+//!
+//! With several harts each has its own msip and mtimecmp, laid out as in the SiFive CLINT.
+
+use anyhow::bail;
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+pub const CLINT_BASE: u32 = 0x20000000;
+const CLINT_SIZE: u32 = 0x10000;
+
+const MSIP: u32 = 0x0;
+const MTIMECMP: u32 = 0x4000;
+const MTIME: u32 = 0xBFF8;
+const MTIMEH: u32 = 0xBFFC;
+
+/// Most harts whose mtimecmp registers fit below mtime
+pub const MAX_HARTS: usize = ((MTIME - MTIMECMP) / 8) as usize;
+
+/// Software and timer interrupt sources
+///
+/// mtime counts clock cycles, so it is owned by the cpu and passed in on each access.
+pub struct Clint {
+    base: u32,
+    /// Software interrupt line of each hart
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+}
+
+impl Clint {
+    /// Create a CLINT with registers for `harts` harts, which must be at most [`MAX_HARTS`]
+    pub fn new(base: u32, harts: usize) -> Self {
+        assert!((1..=MAX_HARTS).contains(&harts), "invalid hart count");
+        Self {
+            base,
+            msip: vec![false; harts],
+            mtimecmp: vec![0; harts],
+        }
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < CLINT_SIZE
+    }
+
+    /// Address of a register, by its name in the register map
+    ///
+    /// Names without a hart number, such as `msip`, are hart 0's, and `msip1` is hart 1's.
+    pub fn register(&self, name: &str) -> Option<u32> {
+        let (name, hart) = match name.find(|c: char| c.is_ascii_digit()) {
+            Some(index) => (&name[..index], name[index..].parse().ok()?),
+            None => (name, 0),
+        };
+        if hart >= self.msip.len() {
+            return None;
+        }
+        let offset = match name {
+            "msip" => MSIP + 4 * hart as u32,
+            "mtimecmp" => MTIMECMP + 8 * hart as u32,
+            "mtimecmph" => MTIMECMP + 8 * hart as u32 + 4,
+            "mtime" if hart == 0 => MTIME,
+            "mtimeh" if hart == 0 => MTIMEH,
+            _ => return None,
+        };
+        Some(self.base + offset)
+    }
+
+    /// Hart and register of an msip or mtimecmp address, with the offset of the word in the
+    /// register
+    fn hart_register(&self, offset: u32) -> Option<(usize, u32, u32)> {
+        let (register, index, word) = if offset < MTIMECMP {
+            (MSIP, offset / 4, 0)
+        } else if offset < MTIME {
+            (MTIMECMP, (offset - MTIMECMP) / 8, offset & 0b100)
+        } else {
+            return None;
+        };
+        let hart = index as usize;
+        (hart < self.msip.len()).then_some((hart, register, word))
+    }
+
+    pub fn read(&self, addr: u32, mtime: u64) -> Result<u32, anyhow::Error> {
+        let offset = (addr - self.base) & !0b11;
+        Ok(match (offset, self.hart_register(offset)) {
+            (MTIME, _) => mtime as u32,
+            (MTIMEH, _) => (mtime >> 32) as u32,
+            (_, Some((hart, MSIP, _))) => self.msip[hart] as u32,
+            (_, Some((hart, _, word))) => (self.mtimecmp[hart] >> (8 * word)) as u32,
+            _ => bail!("invalid clint read address: {addr:08X}"),
+        })
+    }
+
+    /// Write the bits of a register selected by `mask`, as byte enables do, where `value` is
+    /// zero outside them
+    pub fn write(&mut self, addr: u32, value: u32, mask: u32) -> Result<(), anyhow::Error> {
+        let offset = (addr - self.base) & !0b11;
+        match (offset, self.hart_register(offset)) {
+            // mtime is driven by the clock and read-only to software
+            (MTIME | MTIMEH, _) => {}
+            (_, Some((hart, MSIP, _))) => {
+                if mask & 1 != 0 {
+                    self.msip[hart] = value & 1 != 0;
+                }
+            }
+            (_, Some((hart, _, word))) => {
+                let shift = 8 * word;
+                let mtimecmp = &mut self.mtimecmp[hart];
+                *mtimecmp = (*mtimecmp & !((mask as u64) << shift)) | (value as u64) << shift;
+            }
+            _ => bail!("invalid clint write address: {addr:08X}"),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save(&self, out: &mut SnapshotWriter) -> Result<(), anyhow::Error> {
+        for (&msip, &mtimecmp) in self.msip.iter().zip(&self.mtimecmp) {
+            out.bool(msip)?;
+            out.u64(mtimecmp)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the registers of as many harts as the CLINT was created with
+    pub(crate) fn restore(&mut self, input: &mut SnapshotReader) -> Result<(), anyhow::Error> {
+        for (msip, mtimecmp) in self.msip.iter_mut().zip(&mut self.mtimecmp) {
+            *msip = input.bool()?;
+            *mtimecmp = input.u64()?;
+        }
+        Ok(())
+    }
+
+    /// Machine software interrupt line of a hart
+    pub fn software_interrupt(&self, hart: usize) -> bool {
+        self.msip[hart]
+    }
+
+    /// Machine timer interrupt line of a hart, raised while mtime >= its mtimecmp
+    pub fn timer_interrupt(&self, hart: usize, mtime: u64) -> bool {
+        mtime >= self.mtimecmp[hart]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_hart_registers() {
+        let mut clint = Clint::new(CLINT_BASE, 2);
+        assert_eq!(clint.register("msip1"), Some(CLINT_BASE + 0x4));
+        assert_eq!(clint.register("mtimecmph1"), Some(CLINT_BASE + 0x400C));
+        assert_eq!(clint.register("mtimecmp2"), None);
+        assert_eq!(clint.register("mtime1"), None);
+
+        clint.write(CLINT_BASE + 0x4, 1, u32::MAX).unwrap();
+        assert!(!clint.software_interrupt(0));
+        assert!(clint.software_interrupt(1));
+
+        clint.write(CLINT_BASE + 0x4008, 50, u32::MAX).unwrap();
+        clint.write(CLINT_BASE + 0x400C, 0, u32::MAX).unwrap();
+        assert_eq!(clint.read(CLINT_BASE + 0x4008, 0).unwrap(), 50);
+        assert!(clint.timer_interrupt(1, 50));
+        assert!(!clint.timer_interrupt(1, 49));
+        // hart 0's mtimecmp is still zero
+        assert!(clint.timer_interrupt(0, 0));
+
+        // registers of harts that don't exist
+        assert!(clint.read(CLINT_BASE + 0x8, 0).is_err());
+        assert!(clint.write(CLINT_BASE + 0x4010, 0, u32::MAX).is_err());
+        assert_eq!(clint.read(CLINT_BASE + 0xBFF8, 123).unwrap(), 123);
+    }
+}
@@ -0,0 +1,360 @@
+//! Source line coverage, from executed addresses and the ELF's DWARF line tables
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{ensure, Context};
+use elf::{endian::LittleEndian, ElfBytes};
+use gimli::{EndianSlice, RunTimeEndian, SectionId};
+
+/// Hit count of each line with code in a file
+pub type LineHits = BTreeMap<u32, u64>;
+
+/// Instructions whose code came from a single source line
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LineRange {
+    start: u32,
+    end: u32,
+    /// Index into the table's files
+    file: usize,
+    line: u32,
+}
+
+/// Map from addresses to the source lines they were compiled from
+#[derive(Debug, Default, Clone)]
+pub struct LineTable {
+    files: Vec<PathBuf>,
+    ranges: Vec<LineRange>,
+}
+
+impl LineTable {
+    /// Read the line tables of an ELF, which is loaded `offset` bytes above its addresses
+    pub fn from_elf_file(path: impl AsRef<Path>, offset: u32) -> Result<Self, anyhow::Error> {
+        let file_contents = std::fs::read(path).context("could not load elf path")?;
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(&file_contents)?;
+        Self::from_elf(&elf, offset)
+    }
+
+    pub fn from_elf(elf: &ElfBytes<LittleEndian>, offset: u32) -> Result<Self, anyhow::Error> {
+        let load_section = |id: SectionId| -> Result<_, anyhow::Error> {
+            let data = match elf.section_header_by_name(id.name())? {
+                Some(header) => {
+                    let (data, compression) = elf.section_data(&header)?;
+                    ensure!(
+                        compression.is_none(),
+                        "compressed section {} is not supported",
+                        id.name()
+                    );
+                    data
+                }
+                None => &[],
+            };
+            Ok(EndianSlice::new(data, RunTimeEndian::Little))
+        };
+        let dwarf = gimli::Dwarf::load(load_section)?;
+
+        let mut table = Self::default();
+        let mut file_indices: HashMap<PathBuf, usize> = HashMap::new();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let Some(program) = unit.line_program.clone() else {
+                continue;
+            };
+
+            // files are numbered per unit, so map them to the table's files as they are used
+            let mut unit_files: HashMap<u64, usize> = HashMap::new();
+            let mut previous: Option<(u64, usize, u32)> = None;
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                if let Some((start, file, line)) = previous.take()
+                    && row.address() > start
+                {
+                    table.ranges.push(LineRange {
+                        start: offset.wrapping_add(u32::try_from(start)?),
+                        end: offset.wrapping_add(u32::try_from(row.address())?),
+                        file,
+                        line,
+                    });
+                }
+                if row.end_sequence() {
+                    continue;
+                }
+                // line 0 is code that doesn't belong to any line
+                let (Some(line), Some(entry)) = (row.line(), row.file(header)) else {
+                    continue;
+                };
+
+                let file = match unit_files.get(&row.file_index()) {
+                    Some(file) => *file,
+                    None => {
+                        let mut path = PathBuf::new();
+                        if let Some(comp_dir) = &unit.comp_dir {
+                            path.push(comp_dir.to_string_lossy().as_ref());
+                        }
+                        if let Some(directory) = entry.directory(header) {
+                            path.push(
+                                dwarf
+                                    .attr_string(&unit, directory)?
+                                    .to_string_lossy()
+                                    .as_ref(),
+                            );
+                        }
+                        path.push(
+                            dwarf
+                                .attr_string(&unit, entry.path_name())?
+                                .to_string_lossy()
+                                .as_ref(),
+                        );
+                        let next_index = table.files.len();
+                        let file = *file_indices.entry(path.clone()).or_insert(next_index);
+                        if file == next_index {
+                            table.files.push(path);
+                        }
+                        unit_files.insert(row.file_index(), file);
+                        file
+                    }
+                };
+                previous = Some((row.address(), file, u32::try_from(line.get())?));
+            }
+        }
+        Ok(table)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+/// Number of times each address was executed
+#[derive(Debug, Default, Clone)]
+pub struct Coverage {
+    counts: HashMap<u32, u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(&mut self, pc: u32) {
+        *self.counts.entry(pc).or_default() += 1;
+    }
+
+    pub fn count(&self, pc: u32) -> u64 {
+        self.counts.get(&pc).copied().unwrap_or_default()
+    }
+
+    /// Hit count of every line with code, for each source file
+    ///
+    /// A line counts as hit as many times as its most executed instruction.
+    pub fn lines<'a>(&self, table: &'a LineTable) -> BTreeMap<&'a Path, LineHits> {
+        let mut files: BTreeMap<&Path, LineHits> = BTreeMap::new();
+        for range in &table.ranges {
+            let hits = (range.start..range.end)
+                .step_by(4)
+                .map(|pc| self.count(pc))
+                .max()
+                .unwrap_or_default();
+            let line = files
+                .entry(&table.files[range.file])
+                .or_default()
+                .entry(range.line)
+                .or_default();
+            *line = (*line).max(hits);
+        }
+        files
+    }
+
+    /// Write an lcov tracefile, as read by genhtml and most coverage services
+    pub fn write_lcov(&self, table: &LineTable, out: &mut impl io::Write) -> io::Result<()> {
+        writeln!(out, "TN:")?;
+        for (file, lines) in self.lines(table) {
+            writeln!(out, "SF:{}", file.display())?;
+            for (line, hits) in &lines {
+                writeln!(out, "DA:{line},{hits}")?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(
+                out,
+                "LH:{}",
+                lines.values().filter(|hits| **hits > 0).count()
+            )?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+
+    /// Write a Cobertura XML report, with a package for each directory
+    pub fn write_cobertura(&self, table: &LineTable, out: &mut impl io::Write) -> io::Result<()> {
+        let files = self.lines(table);
+        let mut packages: BTreeMap<&Path, Vec<(&Path, &LineHits)>> = BTreeMap::new();
+        for (file, lines) in &files {
+            packages
+                .entry(file.parent().unwrap_or(Path::new("")))
+                .or_default()
+                .push((file, lines));
+        }
+
+        let (covered, valid) = line_counts(files.values());
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        writeln!(out, r#"<?xml version="1.0" ?>"#)?;
+        writeln!(
+            out,
+            r#"<!DOCTYPE coverage SYSTEM "http://cobertura.sourceforge.net/xml/coverage-04.dtd">"#
+        )?;
+        writeln!(
+            out,
+            r#"<coverage line-rate="{:.4}" branch-rate="0" lines-covered="{covered}" lines-valid="{valid}" branches-covered="0" branches-valid="0" complexity="0" version="0" timestamp="{timestamp}">"#,
+            rate(covered, valid)
+        )?;
+        writeln!(out, "  <sources><source>/</source></sources>")?;
+        writeln!(out, "  <packages>")?;
+        for (directory, files) in packages {
+            let (covered, valid) = line_counts(files.iter().map(|(_, lines)| *lines));
+            writeln!(
+                out,
+                r#"    <package name="{}" line-rate="{:.4}" branch-rate="0" complexity="0">"#,
+                escape(&directory.to_string_lossy()),
+                rate(covered, valid)
+            )?;
+            writeln!(out, "      <classes>")?;
+            for (file, lines) in files {
+                let (covered, valid) = line_counts([lines]);
+                let name = file.to_string_lossy();
+                writeln!(
+                    out,
+                    r#"        <class name="{}" filename="{}" line-rate="{:.4}" branch-rate="0" complexity="0">"#,
+                    escape(&file.file_name().unwrap_or_default().to_string_lossy()),
+                    escape(name.trim_start_matches('/')),
+                    rate(covered, valid)
+                )?;
+                writeln!(out, "          <methods/>")?;
+                writeln!(out, "          <lines>")?;
+                for (line, hits) in lines {
+                    writeln!(out, r#"            <line number="{line}" hits="{hits}"/>"#)?;
+                }
+                writeln!(out, "          </lines>")?;
+                writeln!(out, "        </class>")?;
+            }
+            writeln!(out, "      </classes>")?;
+            writeln!(out, "    </package>")?;
+        }
+        writeln!(out, "  </packages>")?;
+        writeln!(out, "</coverage>")?;
+        Ok(())
+    }
+}
+
+/// Number of lines hit and lines with code
+fn line_counts<'a>(files: impl IntoIterator<Item = &'a LineHits>) -> (usize, usize) {
+    files.into_iter().fold((0, 0), |(covered, valid), lines| {
+        (
+            covered + lines.values().filter(|hits| **hits > 0).count(),
+            valid + lines.len(),
+        )
+    })
+}
+
+fn rate(covered: usize, valid: usize) -> f64 {
+    covered as f64 / valid.max(1) as f64
+}
+
+fn escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"']) {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> LineTable {
+        let range = |start, end, file, line| LineRange {
+            start,
+            end,
+            file,
+            line,
+        };
+        LineTable {
+            files: vec![
+                PathBuf::from("/firmware/common/src/lib.rs"),
+                PathBuf::from("/firmware/samples/src/<main>.rs"),
+            ],
+            ranges: vec![
+                range(0x1000, 0x1008, 0, 10),
+                range(0x1008, 0x100C, 0, 11),
+                range(0x100C, 0x1010, 0, 10),
+                range(0x2000, 0x2004, 1, 3),
+            ],
+        }
+    }
+
+    fn coverage() -> Coverage {
+        let mut coverage = Coverage::new();
+        for pc in [0x1000, 0x1004, 0x1004, 0x100C, 0x100C, 0x100C] {
+            coverage.record(pc);
+        }
+        coverage
+    }
+
+    #[test]
+    fn lines() {
+        let table = table();
+        let lines = coverage().lines(&table);
+        assert_eq!(
+            lines[Path::new("/firmware/common/src/lib.rs")],
+            BTreeMap::from([(10, 3), (11, 0)])
+        );
+        assert_eq!(
+            lines[Path::new("/firmware/samples/src/<main>.rs")],
+            BTreeMap::from([(3, 0)])
+        );
+    }
+
+    #[test]
+    fn lcov() {
+        let mut out = Vec::new();
+        coverage().write_lcov(&table(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "TN:\n\
+            SF:/firmware/common/src/lib.rs\nDA:10,3\nDA:11,0\nLF:2\nLH:1\nend_of_record\n\
+            SF:/firmware/samples/src/<main>.rs\nDA:3,0\nLF:1\nLH:0\nend_of_record\n"
+        );
+    }
+
+    #[test]
+    fn cobertura() {
+        let mut out = Vec::new();
+        coverage().write_cobertura(&table(), &mut out).unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(
+            xml.contains(r#"lines-covered="1" lines-valid="3""#),
+            "{xml}"
+        );
+        assert!(
+            xml.contains(r#"<package name="/firmware/common/src" line-rate="0.5000""#),
+            "{xml}"
+        );
+        assert!(
+            xml.contains(r#"filename="firmware/samples/src/&lt;main&gt;.rs""#),
+            "{xml}"
+        );
+        assert!(xml.contains(r#"<line number="10" hits="3"/>"#), "{xml}");
+    }
+}
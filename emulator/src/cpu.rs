@@ -1,62 +1,177 @@
-use std::path::Path;
+use std::{
+    io::{Read, Write},
+    ops::Range,
+    path::Path,
+    time::{Duration, Instant},
+};
 
 use anyhow::{bail, ensure, Context};
-use elf::{endian::LittleEndian, section::SectionHeader, ElfBytes};
+use elf::{endian::LittleEndian, ElfBytes};
 
-use crate::instructions::{immediate, rd, rs1, rs2, Instruction};
+#[cfg(feature = "jit")]
+use crate::jit::{self, Jit, JitMode};
+use crate::{
+    clint::{self, Clint, CLINT_BASE},
+    coverage::Coverage,
+    csr::{Csrs, Privilege, MSTATUS_TSR, MSTATUS_TVM, MSTATUS_TW, SUPERVISOR_INTERRUPTS},
+    decode_cache::DecodeCache,
+    hooks::{CsrAccess, Hook},
+    htif::Htif,
+    icache::{ICache, ICacheConfig},
+    instructions::{DecodedInstruction, Instruction},
+    isa::Isa,
+    memory::{Memory, MemoryConfig, RegionConfig},
+    mmu::{self, Fault, PAGE_SIZE},
+    plic::{Plic, PlicConfig, PLIC_BASE},
+    pmp::Access,
+    profiler::Profiler,
+    scheduler::{Interleaving, Scheduler},
+    semihosting::{self, Semihosting},
+    snapshot::{SnapshotReader, SnapshotWriter},
+    symbols::Symbols,
+    timing::{self, Stage, TimingConfig, TimingModel},
+    tlb::{Tlb, TlbAccess},
+    trace::{AccessKind, MemoryAccess, Tracer},
+    trap::{Exception, Interrupt, Trap},
+    uart::{NullBackend, Uart, UartBackend, UART_BASE},
+    watch::{WatchHit, Watchpoint},
+};
 
-const RAM_BASE: u32 = 0xE0000000;
-const RAM_SIZE: usize = 0x10000000;
+/// Size of the memory a flat binary is loaded into by default, which stops short of the
+/// debug peripheral when loaded at 0x01000000
+pub const FLAT_MEMORY_SIZE: u32 = 0x0F000000;
 
-const LOAD_OFFSET: u32 = 0xE0000000;
+/// Base address of the debug peripheral, as in the SoC
+pub const DEBUG_BASE: u32 = 0x10000000;
+
+/// Value of the debug peripheral's id register: "ORK" and 2 for the emulator, where RTL
+/// simulation reads 1
+pub const DEBUG_ID: u32 = 0x4F524B02;
+
+/// Distance ELF segments are loaded above their addresses
+pub const LOAD_OFFSET: u32 = 0xE0000000;
 
 pub struct Cpu {
-    pc: u32,
-    registers: Registers,
-    memory: Ram,
+    /// The running hart
+    hart: Hart,
+    /// Every hart by mhartid, where the running hart's slot holds a placeholder until it is
+    /// parked again
+    harts: Vec<Hart>,
+    /// mhartid of the running hart
+    current: usize,
+    scheduler: Scheduler,
+    /// Latest cycle any parked hart has reached, which mtime counts from
+    mtime: u64,
+    memory: Memory,
     debug: DebugPeripheral,
+    clint: Clint,
+    uart: Uart,
+    /// Interrupt controller between the peripherals and mip.MEIP, which the RTL lacks
+    plic: Option<Plic>,
+    isa: Isa,
+    misaligned: MisalignedMode,
+    decode_cache: DecodeCache,
+    timing: Option<TimingModel>,
+    icache: Option<ICache>,
+    tlb: Option<Tlb>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    tracer: Option<Tracer>,
+    hooks: Vec<Box<dyn Hook>>,
+    watchpoints: Vec<Watchpoint>,
+    /// First watched access made by the last step
+    watch_hit: Option<WatchHit>,
+    semihosting: Option<Semihosting>,
+    htif: Option<Htif>,
+    symbols: Symbols,
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>,
 }
 
 impl Cpu {
+    pub(crate) fn new(pc: u32, memory: Memory) -> Self {
+        Self {
+            hart: Hart::new(0, pc),
+            harts: vec![Hart::default()],
+            current: 0,
+            scheduler: Scheduler::new(Interleaving::default()),
+            mtime: 0,
+            memory,
+            decode_cache: DecodeCache::new(),
+            debug: DebugPeripheral {
+                base: DEBUG_BASE,
+                status: None,
+            },
+            clint: Clint::new(CLINT_BASE, 1),
+            uart: Uart::new(UART_BASE, Box::new(NullBackend)),
+            plic: None,
+            isa: Isa::default(),
+            misaligned: MisalignedMode::default(),
+            timing: None,
+            icache: None,
+            tlb: None,
+            profiler: None,
+            coverage: None,
+            tracer: None,
+            hooks: Vec::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            semihosting: None,
+            htif: None,
+            symbols: Symbols::default(),
+            #[cfg(feature = "jit")]
+            jit: None,
+        }
+    }
+
     pub fn from_flat_file(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
-        let file_contents = std::fs::read(path).context("could not load binary path")?;
+        let memory_config = MemoryConfig {
+            regions: vec![RegionConfig {
+                base: 0x01000000,
+                size: FLAT_MEMORY_SIZE,
+            }],
+        };
+        Self::from_flat_file_with_memory(path, 0x01000000, &memory_config)
+    }
 
-        let mut memory: Box<[u8; RAM_SIZE]> = vec![0u8; RAM_SIZE]
-            .into_boxed_slice()
-            .try_into()
-            .expect("same size");
+    /// Load a raw binary at `base`, which is also where execution starts
+    pub fn from_flat_file_with_memory(
+        path: impl AsRef<Path>,
+        base: u32,
+        memory_config: &MemoryConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let file_contents = std::fs::read(path).context("could not load binary path")?;
 
-        ensure!(
-            file_contents.len() < RAM_SIZE,
-            "file is too large for memory ({} > {RAM_SIZE})",
-            file_contents.len()
-        );
-        memory[..file_contents.len()].copy_from_slice(&file_contents);
+        let mut memory = Memory::new(memory_config)?;
+        memory
+            .load(base, &file_contents)
+            .with_context(|| format!("file does not fit in memory at {base:08X}"))?;
 
-        Ok(Self {
-            pc: 0x01000000,
-            registers: Registers::default(),
-            memory: Ram {
-                base: 0x01000000,
-                data: memory,
-            },
-            debug: DebugPeripheral {
-                base: 0x03000000,
-                status: None,
-            },
-        })
+        Ok(Self::new(base, memory))
     }
 
     pub fn from_elf(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
-        // Prepare RAM so we can load data to it
-        let memory_data: Box<[u8; RAM_SIZE]> = vec![0u8; RAM_SIZE]
-            .into_boxed_slice()
-            .try_into()
-            .expect("same size");
-        let mut memory = Ram {
-            base: RAM_BASE,
-            data: memory_data,
-        };
+        Self::from_elf_with_memory(path, &MemoryConfig::default())
+    }
+
+    pub fn from_elf_with_memory(
+        path: impl AsRef<Path>,
+        memory_config: &MemoryConfig,
+    ) -> Result<Self, anyhow::Error> {
+        Self::from_elf_with_offset(path, LOAD_OFFSET, memory_config)
+    }
+
+    /// Load an ELF `offset` bytes above its addresses, which is 0 for programs linked to run
+    /// where they are loaded
+    ///
+    /// HTIF is enabled if the ELF has a `tohost` symbol.
+    pub fn from_elf_with_offset(
+        path: impl AsRef<Path>,
+        offset: u32,
+        memory_config: &MemoryConfig,
+    ) -> Result<Self, anyhow::Error> {
+        // Prepare memory so we can load data to it
+        let mut memory = Memory::new(memory_config)?;
 
         let file_contents = std::fs::read(path).context("could not load elf path")?;
         let elf = ElfBytes::<LittleEndian>::minimal_parse(&file_contents)?;
@@ -69,189 +184,648 @@ impl Cpu {
         let arch = elf::to_str::e_machine_to_str(elf.ehdr.e_machine).unwrap();
         ensure!(arch == "EM_RISCV", "elf of arch {arch} was not RISC-V");
 
-        let mut loaded_segments = Vec::new();
-
         // Load required sections to their addresses
         for segment in elf.segments().unwrap() {
-            let flags_str = elf::to_str::p_flags_to_string(segment.p_flags);
-            let type_str = elf::to_str::p_type_to_string(segment.p_type);
-            println!(
-                "{type_str:20} 0x{:08X} 0x{:08X} 0x{:08X} 0x{:08X} 0x{:08X} {:3} 0x{:X}",
-                segment.p_offset,
-                segment.p_vaddr,
-                segment.p_paddr,
-                segment.p_filesz,
-                segment.p_memsz,
-                flags_str,
-                segment.p_align
-            );
-
-            if type_str != "PT_LOAD" {
-                dbg!(type_str);
+            if segment.p_type != elf::abi::PT_LOAD {
                 continue;
             }
 
             let segment_data = elf.segment_data(&segment)?;
 
-            let memory_slice = memory.slice_mut(
-                LOAD_OFFSET + u32::try_from(segment.p_vaddr).unwrap(),
-                segment.p_filesz.try_into().unwrap(),
-            );
+            memory
+                .load(
+                    offset.wrapping_add(u32::try_from(segment.p_vaddr).unwrap()),
+                    segment_data,
+                )
+                .context("segment does not fit in memory")?;
+        }
+
+        let entry_addr = offset.wrapping_add(u32::try_from(elf.ehdr.e_entry)?);
+        ensure!(
+            memory.contains(entry_addr),
+            "entry point {entry_addr:08X} is not in memory"
+        );
+
+        let mut cpu = Self::new(entry_addr, memory);
+        cpu.symbols = Symbols::from_elf(&elf, offset).context("could not read symbols")?;
+        cpu.htif = Htif::from_elf(&elf, offset).context("could not read htif symbols")?;
+        Ok(cpu)
+    }
+
+    /// Trace executed instructions, or stop tracing with `None`
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Tracer, which can be paused and resumed while running
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    /// Restrict the instructions that can be executed, which by default are all implemented
+    pub fn set_isa(&mut self, isa: Isa) {
+        self.isa = isa;
+    }
+
+    /// Choose whether misaligned loads and stores trap, as they do by default
+    pub fn set_misaligned_mode(&mut self, mode: MisalignedMode) {
+        self.misaligned = mode;
+    }
+
+    /// Connect the uart to the host, which by default discards its output
+    pub fn set_uart_backend(&mut self, backend: Box<dyn UartBackend>) {
+        self.uart.set_backend(backend);
+    }
+
+    /// Add a hook, called after any already added
+    ///
+    /// Hooks see every instruction, so the JIT is bypassed while any are installed.
+    pub fn add_hook(&mut self, hook: Box<dyn Hook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+
+    /// Handle HTIF commands written to `tohost`, or stop handling them with `None`
+    pub fn set_htif(&mut self, htif: Option<Htif>) {
+        self.htif = htif;
+    }
+
+    pub fn htif(&self) -> Option<&Htif> {
+        self.htif.as_ref()
+    }
+
+    /// Handle semihosting calls, instead of them raising breakpoint exceptions
+    pub fn enable_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(semihosting);
+    }
+
+    /// Stop runs after an access to a range of memory or peripheral registers
+    ///
+    /// Loads and stores are always interpreted, so unlike hooks this does not bypass the JIT.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Remove a watchpoint, returning whether it had been added
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let Some(index) = self.watchpoints.iter().position(|w| w == watchpoint) else {
+            return false;
+        };
+        self.watchpoints.remove(index);
+        true
+    }
 
-            memory_slice.copy_from_slice(segment_data);
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    /// Take the watched access made by the last step, if any
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    /// Addresses of a peripheral register named like `uart.ctrl` or `clint.mtimecmp`
+    pub fn peripheral_register(&self, name: &str) -> Option<Range<u32>> {
+        let (peripheral, register) = name.split_once('.')?;
+        let addr = match peripheral {
+            "uart" => self.uart.register(register)?,
+            "clint" => self.clint.register(register)?,
+            "debug" => self.debug.register(register)?,
+            "plic" => self.plic.as_ref()?.register(register)?,
+            _ => return None,
+        };
+        Some(addr..addr + 4)
+    }
 
-            loaded_segments.push((segment.p_offset, segment.p_filesz));
+    /// Emulate `count` harts sharing memory and peripherals, with mhartids 0 to `count - 1`
+    ///
+    /// Each hart has its own msip and mtimecmp in the CLINT, and its own PLIC context. Added
+    /// harts start at hart 0's pc, so this should be called before the program runs.
+    pub fn set_harts(&mut self, count: usize) -> Result<(), anyhow::Error> {
+        ensure!(
+            (1..=clint::MAX_HARTS).contains(&count),
+            "there must be between 1 and {} harts: {count}",
+            clint::MAX_HARTS
+        );
+        if let Some(plic) = &self.plic {
+            self.plic = Some(Plic::new(PLIC_BASE, *plic.config(), count)?);
         }
+        self.switch_hart(0);
+        let pc = self.hart.pc;
+        self.harts = (0..count).map(|id| Hart::new(id as u32, pc)).collect();
+        self.harts[0] = Hart::default();
+        self.clint = Clint::new(CLINT_BASE, count);
+        Ok(())
+    }
 
-        // Relocations
-        let (section_headers, str_tab) = elf.section_headers_with_strtab().unwrap();
-        let str_tab = str_tab.unwrap();
-        for section in section_headers.unwrap() {
-            // Find relocation sections
-            let header_type = elf::to_str::sh_type_to_string(section.sh_type);
-            assert_ne!(header_type, "SHT_REL");
-            if header_type != "SHT_RELA" {
-                continue;
+    /// Choose the order harts step in, which is round robin a step at a time by default
+    pub fn set_interleaving(&mut self, interleaving: Interleaving) {
+        self.scheduler = Scheduler::new(interleaving);
+    }
+
+    /// Number of harts
+    pub fn harts(&self) -> usize {
+        self.harts.len()
+    }
+
+    /// mhartid of the hart that steps next, which the accessors of the pc, registers and CSRs
+    /// refer to
+    pub fn hart_id(&self) -> usize {
+        self.current
+    }
+
+    /// Make hart `id` the one that steps next, as a debugger would
+    pub fn select_hart(&mut self, id: usize) -> Result<(), anyhow::Error> {
+        ensure!(id < self.harts.len(), "no such hart: {id}");
+        self.switch_hart(id);
+        Ok(())
+    }
+
+    /// Compile hot basic blocks to host code
+    ///
+    /// With the JIT enabled a single step may retire a whole block of instructions.
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self, mode: JitMode) -> Result<(), anyhow::Error> {
+        self.jit = Some(Jit::new(mode)?);
+        Ok(())
+    }
+
+    /// Step until the program reports a status, hangs, reaches a limit or hits a watchpoint
+    pub fn run(&mut self, limits: &RunLimits) -> Result<StopReason, anyhow::Error> {
+        let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+        for steps in 0u64.. {
+            if let Some(status) = self.status() {
+                return Ok(StopReason::Status(status));
+            }
+            if self.is_hung() {
+                return Ok(StopReason::Hang { pc: self.hart.pc });
             }
+            if limits
+                .max_instructions
+                .is_some_and(|max| self.total_instructions_retired() >= max)
+            {
+                return Ok(StopReason::InstructionLimit);
+            }
+            // reading the clock is slow, so only check it every so often
+            if steps % 1024 == 0 && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(StopReason::Timeout);
+            }
+            self.step()?;
+            if let Some(hit) = self.take_watch_hit() {
+                return Ok(StopReason::Watchpoint(hit));
+            }
+        }
+        unreachable!()
+    }
 
-            // Check if the relocations are for a section that is loaded
-            let associated_header = section_headers.unwrap().get(section.sh_info as usize)?;
-            let is_assoc_section_loaded =
-                is_section_in_loaded_segments(&associated_header, &loaded_segments);
-            if !is_assoc_section_loaded {
-                continue;
+    /// Whether the machine can make no further progress
+    ///
+    /// This is the case when every hart is spinning on an instruction that jumps to itself and
+    /// no interrupt can arrive, or stalled on a wfi.
+    pub fn is_hung(&self) -> bool {
+        (0..self.harts.len()).all(|id| self.is_hart_hung(id))
+    }
+
+    /// Whether a hart can make no further progress on its own
+    fn is_hart_hung(&self, id: usize) -> bool {
+        let hart = self.hart_state(id);
+        let enabled = |interrupt: Interrupt| hart.csrs.mie & (1 << interrupt.code()) != 0;
+        // the uart only reaches hart 0 without a plic
+        let external = enabled(Interrupt::MachineExternal)
+            && match &self.plic {
+                Some(plic) => plic.can_interrupt(id, |source| {
+                    source == plic.config().uart_source && self.uart.can_interrupt()
+                }),
+                None => id == 0 && self.uart.can_interrupt(),
+            };
+        // only other harts can raise it, so it is the one already raised that matters
+        let software = enabled(Interrupt::MachineSoftware) && self.clint.software_interrupt(id);
+
+        // only an external interrupt ends a wfi, regardless of mstatus.MIE, or a software
+        // interrupt from another hart
+        if hart.waiting {
+            return !(external || (software && self.harts.len() > 1));
+        }
+        // the timer and uart can raise interrupts without running any code
+        let interruptible = |interrupt: Interrupt| {
+            hart.csrs.interrupt_enabled(interrupt)
+                && match interrupt {
+                    Interrupt::MachineSoftware => software,
+                    Interrupt::MachineTimer => enabled(interrupt),
+                    Interrupt::MachineExternal => external,
+                    _ => false,
+                }
+        };
+        hart.spinning && !Interrupt::ALL.into_iter().any(interruptible)
+    }
+
+    /// Step the running hart, then let the scheduler pick the hart that steps next
+    pub fn step(&mut self) -> Result<(), anyhow::Error> {
+        self.step_hart()?;
+        if self.harts.len() > 1 {
+            let next = self.scheduler.next(self.current, self.harts.len());
+            self.switch_hart(next);
+        }
+        Ok(())
+    }
+
+    fn step_hart(&mut self) -> Result<(), anyhow::Error> {
+        self.update_interrupts();
+
+        // a stalled wfi has to be interpreted to wake up, and compiled blocks skip the pmp's
+        // checks and translation on fetches, which bind everything below machine mode even
+        // without any pmp entries
+        #[cfg(feature = "jit")]
+        if !self.observes_every_instruction()
+            && !self.hart.waiting
+            && self.hart.csrs.privilege == Privilege::Machine
+            && !self.hart.csrs.pmp.is_active()
+            && let Some(mut jit) = self.jit.take()
+        {
+            let ran_block = self.step_jit(&mut jit);
+            self.jit = Some(jit);
+            if ran_block? {
+                return self.poll_htif();
             }
+        }
 
-            println!(
-                "Section {} - {header_type}",
-                str_tab.get(section.sh_name as usize)?
-            );
-            println!(
-                "Assoc section: {} - {is_assoc_section_loaded}",
-                str_tab.get(associated_header.sh_name as usize)?
-            );
+        self.interpret()?;
+        self.poll_htif()
+    }
+
+    /// Park the running hart and run hart `id` instead
+    fn switch_hart(&mut self, id: usize) {
+        if id == self.current {
+            return;
+        }
+        self.mtime = self.mtime();
+        std::mem::swap(&mut self.hart, &mut self.harts[self.current]);
+        std::mem::swap(&mut self.hart, &mut self.harts[id]);
+        self.current = id;
+    }
+
+    /// State of hart `id`, whether it is running or parked
+    fn hart_state(&self, id: usize) -> &Hart {
+        if id == self.current {
+            &self.hart
+        } else {
+            &self.harts[id]
+        }
+    }
+
+    /// Time the CLINT presents as mtime, which is the latest cycle any hart has reached
+    ///
+    /// With a single hart this is its own cycle count, and the scheduler keeps several within
+    /// a quantum of each other.
+    fn mtime(&self) -> u64 {
+        self.mtime.max(self.hart.cycles)
+    }
+
+    fn poll_htif(&mut self) -> Result<(), anyhow::Error> {
+        let Some(mut htif) = self.htif.take() else {
+            return Ok(());
+        };
+        let exit = htif.poll(self);
+        self.htif = Some(htif);
+        if let Some(code) = exit.context("could not handle htif command")? {
+            self.debug.status = Some(Status::Exited(code));
+        }
+        Ok(())
+    }
+
+    /// Run the compiled block at the pc, compiling it first if it has become hot
+    ///
+    /// Returns false if the next instruction has to be interpreted instead.
+    #[cfg(feature = "jit")]
+    fn step_jit(&mut self, jit: &mut Jit) -> Result<bool, anyhow::Error> {
+        if !self.hart.block_start || self.pending_interrupt().is_some() {
+            return Ok(false);
+        }
+
+        let start = self.hart.pc;
+        let block = match jit.get(start) {
+            Some(block) => block,
+            None if jit.record_entry(start) => {
+                let instructions = self.fetch_block(start);
+                match jit.compile(start, &instructions)? {
+                    Some(block) => block,
+                    None => return Ok(false),
+                }
+            }
+            None => return Ok(false),
+        };
+
+        let last = start.wrapping_add(4 * (block.instructions as u32 - 1));
+        let retired = match jit.mode {
+            JitMode::Enabled => {
+                self.hart.pc = block.run(&mut self.hart.registers.registers);
+                let retired = self.block_retired(&block, last);
+                self.hart.cycles += retired as u64;
+                self.hart.instret += retired as u64;
+                retired
+            }
+            JitMode::CrossCheck => {
+                let registers = self.hart.registers.registers;
+                let jit_pc = block.run(&mut self.hart.registers.registers);
+                let jit_registers = self.hart.registers.registers;
+                self.hart.pc = jit_pc;
+                let retired = self.block_retired(&block, last);
+
+                self.hart.pc = start;
+                self.hart.registers.registers = registers;
+                for _ in 0..retired {
+                    self.interpret()?;
+                }
+                ensure!(
+                    self.hart.pc == jit_pc && self.hart.registers.registers == jit_registers,
+                    "jit block at {start:08X} diverged from the interpreter \
+                    (pc {jit_pc:08X} != {:08X}, registers {jit_registers:08X?} != {:08X?})",
+                    self.hart.pc,
+                    self.hart.registers.registers
+                );
+                retired
+            }
+        };
 
-            let mut count = 0;
-            for rela in elf.section_data_as_relas(&section)? {
-                count += 1;
+        self.hart.block_start = retired == block.instructions;
+        self.hart.spinning = retired == 1 && self.hart.pc == start;
+        Ok(true)
+    }
+
+    /// Number of instructions a block retired, with the registers and pc it left behind
+    ///
+    /// A misaligned jalr at the end of the block hands back its own address without executing,
+    /// so the interpreter can raise the exception.
+    #[cfg(feature = "jit")]
+    fn block_retired(&mut self, block: &jit::Block, last: u32) -> usize {
+        if self.hart.pc != last {
+            return block.instructions;
+        }
+        match self.fetch_at(last) {
+            Ok(inst) if inst.kind == Instruction::Jalr => {
+                let target = self
+                    .hart
+                    .registers
+                    .read(inst.rs1 as usize)
+                    .wrapping_add(inst.immediate);
+                block.instructions - (target & 0b10 != 0) as usize
             }
-            dbg!(count);
+            _ => block.instructions,
         }
+    }
 
-        let entry_addr: u32 = LOAD_OFFSET + u32::try_from(elf.ehdr.e_entry)?;
-        assert!(memory.contains(entry_addr));
+    /// Decode the instructions of the basic block starting at `addr`
+    #[cfg(feature = "jit")]
+    fn fetch_block(&mut self, addr: u32) -> Vec<DecodedInstruction> {
+        let mut instructions = Vec::new();
+        for index in 0..jit::MAX_BLOCK_INSTRUCTIONS as u32 {
+            let Ok(inst) = self.fetch_at(addr.wrapping_add(4 * index)) else {
+                break;
+            };
+            instructions.push(inst);
+            if jit::ends_block(inst.kind) || jit::is_interpreted(inst.kind) {
+                break;
+            }
+        }
+        instructions
+    }
 
-        Ok(Self {
-            pc: entry_addr,
-            registers: Registers::default(),
-            memory,
-            debug: DebugPeripheral {
-                base: 0x03000000,
-                status: None,
-            },
-        })
+    /// Whether a model or tool needs to see each instruction, so blocks can't be compiled
+    #[cfg(feature = "jit")]
+    fn observes_every_instruction(&self) -> bool {
+        self.timing.is_some()
+            || self.icache.is_some()
+            || self.profiler.is_some()
+            || self.coverage.is_some()
+            || self.tracer.as_ref().is_some_and(Tracer::is_enabled)
+            || !self.hooks.is_empty()
     }
 
-    pub fn step(&mut self) -> Result<(), anyhow::Error> {
-        let raw_inst = self.read(self.pc)?;
-        let inst = Instruction::try_from(raw_inst)?;
+    /// Run a single instruction, or take a trap
+    fn interpret(&mut self) -> Result<(), anyhow::Error> {
+        let pc = self.hart.pc;
+        let start_cycles = self.hart.cycles;
+        self.call_hooks(|hook, cpu| hook.before_instruction(cpu, pc));
+        if let Some(tracer) = &mut self.tracer {
+            tracer.begin(pc, self.hart.cycles, &self.hart.registers.registers);
+        }
+        let result = self.execute();
+        self.hart.spinning = matches!(result, Ok(Some(_))) && self.hart.pc == pc;
+        match result {
+            Ok(Some(_)) => {
+                self.hart.instret += 1;
+                if let Some(timing) = &mut self.timing {
+                    timing.retire();
+                }
+                if let Some(coverage) = &mut self.coverage {
+                    coverage.record(pc);
+                }
+            }
+            Ok(None) => {}
+            Err(trap) => self.trap(trap)?,
+        }
+
+        // without the timing model every step takes a single cycle
+        if self.timing.is_none() {
+            self.hart.cycles += 1;
+        }
 
-        let immediate = immediate(raw_inst).unwrap_or_default();
-        let rd = rd(raw_inst);
-        let rs1 = rs1(raw_inst);
-        let rs2 = rs2(raw_inst);
+        if let Some(tracer) = &mut self.tracer {
+            match result {
+                Ok(Some(inst)) => {
+                    tracer.retire(&inst, &self.hart.registers.registers, &self.symbols)
+                }
+                Ok(None) => {
+                    tracer.stall();
+                    Ok(())
+                }
+                Err(trap) => tracer.trap(trap, self.hart.pc, &self.symbols),
+            }
+            .context("could not write trace")?;
+        }
 
-        let rs1_value = self.registers.read(rs1);
-        let rs2_value = self.registers.read(rs2);
+        if self.current == 0
+            && let Some(profiler) = &mut self.profiler
+        {
+            let retired = matches!(result, Ok(Some(_)));
+            profiler.charge(retired as u64, self.hart.cycles - start_cycles);
+            match result {
+                Ok(Some(inst)) => profiler.retire(pc, &inst, self.hart.pc, &self.symbols),
+                Ok(None) => {}
+                Err(_) => profiler.trap(pc, self.hart.pc, &self.symbols),
+            }
+        }
 
-        println!(
-            "step {:08X} {inst:?} (imm = {immediate:08X}, rd = {rd}, rs1 = {rs1}, rs2 = {rs2})",
-            self.pc
-        );
+        if let Ok(Some(inst)) = result {
+            self.call_hooks(|hook, cpu| hook.after_retire(cpu, pc, &inst));
+        }
 
-        let mut advance_pc = true;
+        Ok(())
+    }
+
+    /// Take the instruction at the pc through the pipeline, returning it if it retired
+    fn execute(&mut self) -> Result<Option<DecodedInstruction>, Trap> {
+        // a stalled wfi stays in decode, so is neither fetched again nor interrupted
+        let fetched = if self.hart.waiting {
+            self.fetch_at(self.hart.pc)
+        } else {
+            let fetched = self.fetch_at(self.hart.pc);
+            self.charge_fetch(self.hart.pc, fetched.is_ok());
+            if let Err(
+                exception @ (Exception::InstructionAccessFault(_)
+                | Exception::InstructionPageFault(_)),
+            ) = fetched
+            {
+                return Err(exception.into());
+            }
+            if let Some(interrupt) = self.pending_interrupt() {
+                return Err(interrupt.into());
+            }
+            fetched
+        };
+
+        self.charge(Stage::Decode, 1);
+        let inst = fetched?;
+        if !self.isa.implements(inst.kind) {
+            return Err(Exception::IllegalInstruction(inst.raw).into());
+        }
+
+        let immediate = inst.immediate;
+        let rd = inst.rd as usize;
+        let rs1 = inst.rs1 as usize;
+        let rs2 = inst.rs2 as usize;
+
+        let rs1_value = self.hart.registers.read(rs1);
+        let rs2_value = self.hart.registers.read(rs2);
 
         use Instruction::*;
-        match inst {
+        match inst.kind {
+            Ecall => {
+                return Err(match self.hart.csrs.privilege {
+                    Privilege::User => Exception::EnvironmentCallFromU,
+                    Privilege::Supervisor => Exception::EnvironmentCallFromS,
+                    Privilege::Machine => Exception::EnvironmentCallFromM,
+                }
+                .into())
+            }
+            Mret | Sret | SfenceVma | Wfi if !self.may_execute(inst.kind) => {
+                return Err(Exception::IllegalInstruction(inst.raw).into());
+            }
+            Ebreak if self.is_semihosting_call()? => {
+                let mut semihosting = self.semihosting.take().unwrap();
+                let exited = semihosting.call(self);
+                self.semihosting = Some(semihosting);
+                if let Some(code) = exited? {
+                    self.debug.status = Some(Status::Exited(code));
+                }
+            }
+            Ebreak => return Err(Exception::Breakpoint(self.hart.pc).into()),
+            Wfi => {
+                // like the RTL, only an external interrupt ends the wait, regardless of
+                // mstatus.MIE, though other harts can also wake one with a software interrupt
+                let pending =
+                    |interrupt: Interrupt| self.hart.csrs.interrupt_pending(interrupt.code());
+                self.hart.waiting = !(pending(Interrupt::MachineExternal)
+                    || (self.harts.len() > 1 && pending(Interrupt::MachineSoftware)));
+                if self.hart.waiting {
+                    return Ok(None);
+                }
+            }
+            _ => {}
+        }
+
+        self.charge(Stage::Execute, 1);
+
+        let mut advance_pc = true;
+
+        match inst.kind {
             Lui => {
-                self.registers.write(rd, immediate);
-                //println!("writing {immediate} to register {rd}");
+                self.hart.registers.write(rd, immediate);
             }
             Auipc => {
-                let value = self.pc.wrapping_add(immediate);
-                self.registers.write(rd, value);
-                //println!("writing {value} to register {rd}");
+                let value = self.hart.pc.wrapping_add(immediate);
+                self.hart.registers.write(rd, value);
             }
             Jal => {
-                let next_inst_addr = self.pc + 4;
-                let raw_address = self.pc.wrapping_add(immediate);
-                self.pc = raw_address & 0xFFFFFFFE;
-                self.registers.write(rd, next_inst_addr);
+                let next_inst_addr = self.hart.pc.wrapping_add(4);
+                let raw_address = self.hart.pc.wrapping_add(immediate);
+                self.hart.pc = jump_target(raw_address & 0xFFFFFFFE)?;
+                self.hart.registers.write(rd, next_inst_addr);
                 advance_pc = false;
-                //println!("jumping to addr {:08X}", self.pc);
             }
             Jalr => {
-                let next_inst_addr = self.pc + 4;
+                let next_inst_addr = self.hart.pc.wrapping_add(4);
                 let raw_address = rs1_value.wrapping_add(immediate);
-                self.pc = raw_address & 0xFFFFFFFE;
-                self.registers.write(rd, next_inst_addr);
+                self.hart.pc = jump_target(raw_address & 0xFFFFFFFE)?;
+                self.hart.registers.write(rd, next_inst_addr);
                 advance_pc = false;
-                //println!("jumping to addr {:08X}", self.pc);
             }
             Add => {
                 let value = rs1_value.wrapping_add(rs2_value);
-                self.registers.write(rd, value);
-                //println!("writing {value} to register {rd}");
+                self.hart.registers.write(rd, value);
             }
             Sub => {
                 let value = rs1_value.wrapping_sub(rs2_value);
-                self.registers.write(rd, value);
-                //println!("writing {value} to register {rd}");
+                self.hart.registers.write(rd, value);
             }
             Xor => {
                 let value = rs1_value ^ rs2_value;
-                self.registers.write(rd, value);
-                //println!("writing {value} to register {rd}");
+                self.hart.registers.write(rd, value);
             }
             And => {
-                self.registers.write(rd, rs1_value & rs2_value);
+                self.hart.registers.write(rd, rs1_value & rs2_value);
             }
             Or => {
-                self.registers.write(rd, rs1_value | rs2_value);
+                self.hart.registers.write(rd, rs1_value | rs2_value);
             }
             Sll => {
-                self.registers.write(rd, rs1_value << (rs2_value & 0b11111));
+                self.hart
+                    .registers
+                    .write(rd, rs1_value << (rs2_value & 0b11111));
             }
             Srl => {
-                self.registers.write(rd, rs1_value >> (rs2_value & 0b11111));
+                self.hart
+                    .registers
+                    .write(rd, rs1_value >> (rs2_value & 0b11111));
             }
             Sra => {
-                self.registers
+                self.hart
+                    .registers
                     .write(rd, ((rs1_value as i32) >> (rs2_value & 0b11111)) as u32);
             }
             Xori => {
-                self.registers.write(rd, rs1_value ^ immediate);
+                self.hart.registers.write(rd, rs1_value ^ immediate);
+            }
+            Ori => {
+                self.hart.registers.write(rd, rs1_value | immediate);
             }
             Addi => {
                 let value = rs1_value.wrapping_add(immediate);
-                self.registers.write(rd, value);
-                //println!("writing {value} to register {rd}");
+                self.hart.registers.write(rd, value);
             }
             Andi => {
-                self.registers.write(rd, rs1_value & immediate);
+                self.hart.registers.write(rd, rs1_value & immediate);
             }
             Slli => {
-                self.registers.write(rd, rs1_value << (immediate & 0b11111));
+                self.hart
+                    .registers
+                    .write(rd, rs1_value << (immediate & 0b11111));
             }
             Srai => {
-                self.registers
+                self.hart
+                    .registers
                     .write(rd, ((rs1_value as i32) >> (immediate & 0b11111)) as u32);
             }
             Srli => {
-                self.registers.write(rd, rs1_value >> (immediate & 0b11111));
+                self.hart
+                    .registers
+                    .write(rd, rs1_value >> (immediate & 0b11111));
             }
             Slt => {
-                self.registers.write(
+                self.hart.registers.write(
                     rd,
                     if (rs1_value as i32) < (rs2_value as i32) {
                         1
@@ -261,127 +835,1100 @@ impl Cpu {
                 );
             }
             Sltu => {
-                self.registers
+                self.hart
+                    .registers
                     .write(rd, if rs1_value < rs2_value { 1 } else { 0 });
             }
+            Slti => {
+                self.hart.registers.write(
+                    rd,
+                    if (rs1_value as i32) < (immediate as i32) {
+                        1
+                    } else {
+                        0
+                    },
+                );
+            }
             Sltiu => {
-                self.registers
+                self.hart
+                    .registers
                     .write(rd, if rs1_value < immediate { 1 } else { 0 });
             }
             Bge => {
                 if (rs1_value as i32) >= (rs2_value as i32) {
-                    self.pc = self.pc.wrapping_add(immediate);
+                    self.hart.pc = jump_target(self.hart.pc.wrapping_add(immediate))?;
                     advance_pc = false;
-                    //println!("{rs1_value} >= {rs2_value}, taking branch");
-                } else {
-                    //println!("{rs1_value} < {rs2_value}, not taking branch");
                 }
             }
             Bgeu => {
                 if rs1_value >= rs2_value {
-                    self.pc = self.pc.wrapping_add(immediate);
+                    self.hart.pc = jump_target(self.hart.pc.wrapping_add(immediate))?;
                     advance_pc = false;
-                    //println!("{rs1_value} >= {rs2_value}, taking branch");
-                } else {
-                    //println!("{rs1_value} < {rs2_value}, not taking branch");
                 }
             }
             Blt => {
                 if (rs1_value as i32) < (rs2_value as i32) {
-                    self.pc = self.pc.wrapping_add(immediate);
+                    self.hart.pc = jump_target(self.hart.pc.wrapping_add(immediate))?;
                     advance_pc = false;
                 }
             }
             Bltu => {
                 if rs1_value < rs2_value {
-                    self.pc = self.pc.wrapping_add(immediate);
+                    self.hart.pc = jump_target(self.hart.pc.wrapping_add(immediate))?;
                     advance_pc = false;
                 }
             }
             Beq => {
                 if rs1_value == rs2_value {
-                    self.pc = self.pc.wrapping_add(immediate);
+                    self.hart.pc = jump_target(self.hart.pc.wrapping_add(immediate))?;
                     advance_pc = false;
                 }
             }
             Bne => {
                 if rs1_value != rs2_value {
-                    self.pc = self.pc.wrapping_add(immediate);
+                    self.hart.pc = jump_target(self.hart.pc.wrapping_add(immediate))?;
                     advance_pc = false;
                 }
             }
             Sw => {
                 let addr = rs1_value.wrapping_add(immediate);
-                self.write(addr, rs2_value)
-                    .context("could not store word")?;
-                //println!("writing {rs2_value} to {addr:08x}");
+                self.store(addr, rs2_value, 4)?;
+            }
+            Sh => {
+                let addr = rs1_value.wrapping_add(immediate);
+                self.store(addr, rs2_value, 2)?;
+            }
+            Sb => {
+                let addr = rs1_value.wrapping_add(immediate);
+                self.store(addr, rs2_value, 1)?;
             }
             Lw => {
                 let addr = rs1_value.wrapping_add(immediate);
-                let value = self.read(addr)?;
-                self.registers.write(rd, value);
-                //println!("writing {value} from addr {addr:08X} to reg {rd}");
+                let value = self.load(addr, 4)?;
+                self.hart.registers.write(rd, value);
+            }
+            Lh => {
+                let addr = rs1_value.wrapping_add(immediate);
+                let value = self.load(addr, 2)? as u16 as i16 as u32;
+                self.hart.registers.write(rd, value);
             }
             Lhu => {
                 let addr = rs1_value.wrapping_add(immediate);
-                let value = self.read(addr)? & 0x0000FFFF;
-                self.registers.write(rd, value);
-                //println!("writing {value} from addr {addr:08X} to reg {rd}");
+                let value = self.load(addr, 2)?;
+                self.hart.registers.write(rd, value);
+            }
+            Lb => {
+                let addr = rs1_value.wrapping_add(immediate);
+                let value = self.load(addr, 1)? as u8 as i8 as u32;
+                self.hart.registers.write(rd, value);
+            }
+            Lbu => {
+                let addr = rs1_value.wrapping_add(immediate);
+                let value = self.load(addr, 1)?;
+                self.hart.registers.write(rd, value);
+            }
+            LrW => {
+                let value = self.load_reserved(rs1_value)?;
+                self.hart.registers.write(rd, value);
+            }
+            ScW => {
+                let stored = self.store_conditional(rs1_value, rs2_value)?;
+                self.hart.registers.write(rd, !stored as u32);
+            }
+            AmoswapW | AmoaddW | AmoxorW | AmoandW | AmoorW | AmominW | AmomaxW | AmominuW
+            | AmomaxuW => {
+                let value = self.atomic(rs1_value, |old| match inst.kind {
+                    AmoswapW => rs2_value,
+                    AmoaddW => old.wrapping_add(rs2_value),
+                    AmoxorW => old ^ rs2_value,
+                    AmoandW => old & rs2_value,
+                    AmoorW => old | rs2_value,
+                    AmominW => (old as i32).min(rs2_value as i32) as u32,
+                    AmomaxW => (old as i32).max(rs2_value as i32) as u32,
+                    AmominuW => old.min(rs2_value),
+                    _ => old.max(rs2_value),
+                })?;
+                self.hart.registers.write(rd, value);
+            }
+            Fence => {
+                // memory accesses are performed in order, so there is nothing to wait for
+            }
+            FenceI => {
+                self.decode_cache.clear();
+                if let Some(icache) = &mut self.icache {
+                    icache.flush();
+                }
+                #[cfg(feature = "jit")]
+                if let Some(jit) = &mut self.jit {
+                    jit.clear();
+                }
+            }
+            Csrrw | Csrrs | Csrrc | Csrrwi | Csrrsi | Csrrci => {
+                let addr = (immediate & 0xFFF) as u16;
+                let operand = match inst.kind {
+                    Csrrw | Csrrs | Csrrc => rs1_value,
+                    _ => rs1 as u32,
+                };
+                let illegal = Exception::IllegalInstruction(inst.raw);
+
+                // csrrw does not read when rd is x0, and csrrs/csrrc do not write when rs1 is x0
+                let value = match inst.kind {
+                    Csrrw | Csrrwi if rd == 0 => 0,
+                    _ => self.hart.csrs.read(addr, self.mtime()).ok_or(illegal)?,
+                };
+                let new_value = match inst.kind {
+                    Csrrw | Csrrwi => Some(operand),
+                    Csrrs | Csrrsi => (rs1 != 0).then_some(value | operand),
+                    _ => (rs1 != 0).then_some(value & !operand),
+                };
+                if let Some(new_value) = new_value {
+                    self.hart.csrs.write(addr, new_value).ok_or(illegal)?;
+                }
+                self.hart.registers.write(rd, value);
+
+                let access = CsrAccess {
+                    addr,
+                    read: (!matches!(inst.kind, Csrrw | Csrrwi) || rd != 0).then_some(value),
+                    written: new_value,
+                };
+                self.call_hooks(|hook, cpu| hook.csr_access(cpu, &access));
+            }
+            Mret | Sret => {
+                let pc = self.hart.pc;
+                self.hart.pc = match inst.kind {
+                    Mret => self.hart.csrs.mret(),
+                    _ => self.hart.csrs.sret(),
+                };
+                advance_pc = false;
+                self.call_hooks(|hook, cpu| hook.trap_exit(cpu, pc, cpu.hart.pc));
+            }
+            SfenceVma => {
+                // rs1 and rs2 limit the flush to an address and an address space unless x0
+                if let Some(tlb) = &mut self.tlb {
+                    let vaddr = (rs1 != 0).then_some(rs1_value);
+                    let asid = (rs2 != 0).then_some(rs2_value & 0x1FF);
+                    tlb.flush(vaddr, asid);
+                }
             }
-            _ => bail!("unexpected opcode: {inst:?}"),
+            // these are handled in the decode stage
+            Ecall | Ebreak | Wfi => {}
         }
 
         if advance_pc {
-            self.pc += 4;
+            self.hart.pc = self.hart.pc.wrapping_add(4);
         }
 
-        Ok(())
-    }
-
-    fn read(&self, addr: u32) -> Result<u32, anyhow::Error> {
-        if self.memory.contains(addr) {
-            return self.memory.read(addr);
+        if timing::writes_back(inst.kind, inst.rd) {
+            self.charge(Stage::Writeback, 1);
         }
-        if self.debug.contains(addr) {
-            return self.debug.read(addr);
+
+        #[cfg(feature = "jit")]
+        {
+            self.hart.block_start = jit::ends_block(inst.kind) || jit::is_interpreted(inst.kind);
         }
 
-        bail!("invalid read address: {addr:08X}")
+        Ok(Some(inst))
     }
 
-    fn write(&mut self, addr: u32, value: u32) -> Result<(), anyhow::Error> {
-        if self.memory.contains(addr) {
-            self.memory.write(addr, value);
-            return Ok(());
+    /// Whether the running code may execute a privileged instruction
+    ///
+    /// Each mode may only return from traps taken into it or above, and mstatus.TSR, TVM and
+    /// TW trap sret, sfence.vma and wfi in supervisor mode. User mode may not wait, as only
+    /// an external interrupt ends a wfi.
+    fn may_execute(&self, kind: Instruction) -> bool {
+        let trapped = |bit: u32| self.hart.csrs.mstatus & bit != 0;
+        match (self.hart.csrs.privilege, kind) {
+            (Privilege::Machine, _) => true,
+            (Privilege::Supervisor, Instruction::Sret) => !trapped(MSTATUS_TSR),
+            (Privilege::Supervisor, Instruction::SfenceVma) => !trapped(MSTATUS_TVM),
+            (Privilege::Supervisor, Instruction::Wfi) => !trapped(MSTATUS_TW),
+            _ => false,
         }
-        if self.debug.contains(addr) {
-            self.debug.write(addr, value);
-            return Ok(());
+    }
+
+    /// Enter the trap handler
+    fn trap(&mut self, trap: Trap) -> Result<(), anyhow::Error> {
+        if let Some(timing) = &mut self.timing {
+            timing.trap();
         }
 
-        bail!("invalid write address: {addr:08X}");
+        // without a reachable handler this would loop forever, so report the original trap
+        ensure!(
+            !(matches!(
+                trap,
+                Trap::Exception(
+                    Exception::InstructionAccessFault(_) | Exception::InstructionPageFault(_)
+                )
+            ) && self.hart.pc == self.hart.csrs.trap_vector(trap)),
+            "could not fetch trap handler at {:08X} (previous mcause {:08X}, mepc {:08X}, \
+            mtval {:08X})",
+            self.hart.pc,
+            self.hart.csrs.mcause,
+            self.hart.csrs.mepc,
+            self.hart.csrs.mtval
+        );
+
+        let pc = self.hart.pc;
+        let vector = self.hart.csrs.trap_vector(trap);
+        let Some(handler) = self.hart.csrs.enter_trap(trap, pc) else {
+            bail!(
+                "core stopped by {trap:?} at {pc:08X}, as the trap vector mode is unsupported: \
+                {vector:08X}"
+            );
+        };
+        self.hart.pc = handler;
+
+        #[cfg(feature = "jit")]
+        {
+            self.hart.block_start = true;
+        }
+
+        self.call_hooks(|hook, cpu| hook.trap_entry(cpu, trap, pc, handler));
+        Ok(())
     }
 
-    pub fn status(&self) -> Option<Status> {
-        self.debug.status
+    /// Call each hook, which can inspect but not change the core
+    fn call_hooks(&mut self, mut call: impl FnMut(&mut dyn Hook, &Self)) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let mut hooks = std::mem::take(&mut self.hooks);
+        for hook in &mut hooks {
+            call(hook.as_mut(), self);
+        }
+        self.hooks = hooks;
     }
-}
 
-fn is_section_in_loaded_segments(
-    associated_header: &SectionHeader,
-    loaded_segments: &[(u64, u64)],
-) -> bool {
-    loaded_segments.iter().any(|(seg_p_offset, seg_p_filesz)| {
-        (associated_header.sh_offset >= *seg_p_offset)
-            && (associated_header.sh_offset + associated_header.sh_size
-                <= seg_p_offset + seg_p_filesz)
-    })
-}
+    /// Drive the running hart's interrupt lines into mip
+    fn update_interrupts(&mut self) {
+        self.uart.poll(self.mtime());
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Status {
+        // software raises the supervisor interrupts, so they are left alone
+        let mut mip = self.hart.csrs.mip & SUPERVISOR_INTERRUPTS;
+        if self.clint.software_interrupt(self.current) {
+            mip |= 1 << Interrupt::MachineSoftware.code();
+        }
+        if self.clint.timer_interrupt(self.current, self.mtime()) {
+            mip |= 1 << Interrupt::MachineTimer.code();
+        }
+        // the uart is the only source of external interrupts and goes to hart 0, unless there
+        // is a plic
+        let external = match &mut self.plic {
+            Some(plic) => {
+                plic.set_level(plic.config().uart_source, self.uart.interrupt());
+                plic.interrupt(self.current)
+            }
+            None => self.current == 0 && self.uart.interrupt(),
+        };
+        if external {
+            mip |= 1 << Interrupt::MachineExternal.code();
+        }
+        self.hart.csrs.mip = mip;
+    }
+
+    /// Whether the ebreak at the pc is surrounded by the semihosting sequence, and semihosting
+    /// is enabled
+    ///
+    /// The sequence is fetched like any other instructions, so a fault fetching it is raised.
+    fn is_semihosting_call(&mut self) -> Result<bool, Exception> {
+        if self.semihosting.is_none() {
+            return Ok(false);
+        }
+        for (addr, expected) in [
+            (self.hart.pc.wrapping_sub(4), semihosting::ENTRY),
+            (self.hart.pc.wrapping_add(4), semihosting::EXIT),
+        ] {
+            let physical = self.physical_address(addr, 4, Access::Execute)?;
+            if self.memory.read(physical, 4).ok() != Some(expected) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Highest priority interrupt that is pending, enabled and not masked by mstatus.MIE or SIE
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        Interrupt::ALL.into_iter().rev().find(|&interrupt| {
+            self.hart.csrs.interrupt_pending(interrupt.code())
+                && self.hart.csrs.interrupt_enabled(interrupt)
+        })
+    }
+
+    /// Charge cycles to a stage of the timing model, if it is enabled
+    fn charge(&mut self, stage: Stage, cycles: u64) {
+        if let Some(timing) = &mut self.timing {
+            self.hart.cycles += timing.charge(stage, cycles);
+        }
+    }
+
+    /// Latency of a bus access to `addr`
+    fn bus_latency(&self, config: &TimingConfig, addr: u32) -> u64 {
+        if self.memory.contains(addr) {
+            config.memory_latency
+        } else {
+            config.mmio_latency
+        }
+    }
+
+    /// Charge the latency of a data access to the timing model, if it is enabled
+    fn charge_access(&mut self, addr: u32) {
+        if let Some(timing) = &self.timing {
+            let latency = self.bus_latency(timing.config(), addr);
+            self.charge(Stage::Memory, latency);
+        }
+    }
+
+    /// Look up an instruction fetch in the cache model, and charge its latency to the timing
+    /// model
+    ///
+    /// Without a cache model every fetch goes to memory, like the passthrough cache in the RTL.
+    fn charge_fetch(&mut self, addr: u32, valid: bool) {
+        let (hit, refill_beats) = match &mut self.icache {
+            Some(icache) if valid => (icache.access(addr), icache.words_per_line() as u64),
+            _ => (false, 1),
+        };
+        let Some(timing) = &self.timing else {
+            return;
+        };
+        // the first word of a line arrives after the bus latency, then one per cycle
+        let miss_latency = self.bus_latency(timing.config(), addr) + refill_beats - 1;
+        if let Some(timing) = &mut self.timing {
+            self.hart.cycles += timing.fetch(hit, miss_latency);
+        }
+    }
+
+    /// Fetch the instruction at `addr`, decoding it only if it is not already cached
+    fn fetch_at(&mut self, addr: u32) -> Result<DecodedInstruction, Exception> {
+        let physical = self.physical_address(addr, 4, Access::Execute)?;
+        if let Some(inst) = self.decode_cache.get(physical) {
+            return Ok(inst);
+        }
+
+        let raw = self
+            .read(physical, 4)
+            .map_err(|_| Exception::InstructionAccessFault(addr))?;
+        let inst =
+            DecodedInstruction::try_from(raw).map_err(|_| Exception::IllegalInstruction(raw))?;
+        if self.memory.contains(physical) {
+            self.decode_cache.insert(physical, inst);
+        }
+        Ok(inst)
+    }
+
+    /// Load `width` bytes, zero-extended, in the memory stage
+    fn load(&mut self, addr: u32, width: u32) -> Result<u32, Exception> {
+        let misaligned = !addr.is_multiple_of(width);
+        if misaligned && self.misaligned == MisalignedMode::Trap {
+            return Err(Exception::LoadAddressMisaligned(addr));
+        }
+        let (physical, value) = if misaligned {
+            let bytes = self.byte_addresses(addr, width, Access::Read)?;
+            self.charge_access(bytes[0]);
+            (bytes[0], self.read_bytes(&bytes[..width as usize]))
+        } else {
+            let physical = self.physical_address(addr, width, Access::Read)?;
+            self.charge_access(physical);
+            (physical, self.read(physical, width))
+        };
+        let value = value.map_err(|_| Exception::LoadAccessFault(addr))?;
+        self.trace_access(AccessKind::Load, addr, physical, width, value);
+        Ok(value)
+    }
+
+    /// Store the lower `width` bytes of `value` in the memory stage
+    fn store(&mut self, addr: u32, value: u32, width: u32) -> Result<(), Exception> {
+        let misaligned = !addr.is_multiple_of(width);
+        if misaligned && self.misaligned == MisalignedMode::Trap {
+            return Err(Exception::StoreAddressMisaligned(addr));
+        }
+        let physical = if misaligned {
+            let bytes = self.byte_addresses(addr, width, Access::Write)?;
+            self.charge_access(bytes[0]);
+            self.write_bytes(&bytes[..width as usize], value)
+                .map(|_| bytes[0])
+        } else {
+            let physical = self.physical_address(addr, width, Access::Write)?;
+            self.charge_access(physical);
+            self.write(physical, value, width).map(|_| physical)
+        }
+        .map_err(|_| Exception::StoreAccessFault(addr))?;
+        let mask = u32::MAX >> (32 - 8 * width);
+        self.trace_access(AccessKind::Store, addr, physical, width, value & mask);
+        if let Some(htif) = &mut self.htif {
+            htif.store(physical, width);
+        }
+        Ok(())
+    }
+
+    /// Load a word and reserve it for a store-conditional
+    ///
+    /// Atomics must be aligned, even when other misaligned accesses are handled.
+    fn load_reserved(&mut self, addr: u32) -> Result<u32, Exception> {
+        if !addr.is_multiple_of(4) {
+            return Err(Exception::LoadAddressMisaligned(addr));
+        }
+        let physical = self.physical_address(addr, 4, Access::Read)?;
+        self.charge_access(physical);
+        let value = self
+            .read(physical, 4)
+            .map_err(|_| Exception::LoadAccessFault(addr))?;
+        self.hart.reservation = Some(physical);
+        self.trace_access(AccessKind::Load, addr, physical, 4, value);
+        Ok(value)
+    }
+
+    /// Store a word if the hart still holds a reservation on it, returning whether it did
+    ///
+    /// The reservation is released either way.
+    fn store_conditional(&mut self, addr: u32, value: u32) -> Result<bool, Exception> {
+        if !addr.is_multiple_of(4) {
+            return Err(Exception::StoreAddressMisaligned(addr));
+        }
+        let physical = self.physical_address(addr, 4, Access::Write)?;
+        if self.hart.reservation.take() != Some(physical) {
+            return Ok(false);
+        }
+        self.charge_access(physical);
+        self.write(physical, value, 4)
+            .map_err(|_| Exception::StoreAccessFault(addr))?;
+        self.trace_access(AccessKind::Store, addr, physical, 4, value);
+        if let Some(htif) = &mut self.htif {
+            htif.store(physical, 4);
+        }
+        Ok(true)
+    }
+
+    /// Replace a word with `op` of its value in a single step, returning the old value
+    ///
+    /// Harts take turns a step at a time, so no other hart can access the word in between.
+    fn atomic(&mut self, addr: u32, op: impl FnOnce(u32) -> u32) -> Result<u32, Exception> {
+        if !addr.is_multiple_of(4) {
+            return Err(Exception::StoreAddressMisaligned(addr));
+        }
+        let physical = self.physical_address(addr, 4, Access::Write)?;
+        self.charge_access(physical);
+        let old = self
+            .read(physical, 4)
+            .map_err(|_| Exception::StoreAccessFault(addr))?;
+        self.trace_access(AccessKind::Load, addr, physical, 4, old);
+        let value = op(old);
+        self.write(physical, value, 4)
+            .map_err(|_| Exception::StoreAccessFault(addr))?;
+        self.trace_access(AccessKind::Store, addr, physical, 4, value);
+        if let Some(htif) = &mut self.htif {
+            htif.store(physical, 4);
+        }
+        Ok(old)
+    }
+
+    /// Physical address of each byte of a misaligned access, translating each page it touches
+    /// separately
+    fn byte_addresses(
+        &mut self,
+        addr: u32,
+        width: u32,
+        access: Access,
+    ) -> Result<[u32; 4], Exception> {
+        let split = (PAGE_SIZE - addr % PAGE_SIZE).min(width);
+        let low = self.physical_address(addr, split, access)?;
+        let high = if split < width {
+            self.physical_address(addr.wrapping_add(split), width - split, access)?
+        } else {
+            low.wrapping_add(split)
+        };
+        Ok(std::array::from_fn(|offset| {
+            let offset = offset as u32;
+            if offset < split {
+                low.wrapping_add(offset)
+            } else {
+                high.wrapping_add(offset - split)
+            }
+        }))
+    }
+
+    /// Translate an access of `size` bytes at `addr` if address translation is on, and check
+    /// it against physical memory protection
+    ///
+    /// Faults report the virtual address.
+    fn physical_address(&mut self, addr: u32, size: u32, access: Access) -> Result<u32, Exception> {
+        let fault = |fault: Fault| match (access, fault) {
+            (Access::Read, Fault::Page) => Exception::LoadPageFault(addr),
+            (Access::Read, Fault::Access) => Exception::LoadAccessFault(addr),
+            (Access::Write, Fault::Page) => Exception::StorePageFault(addr),
+            (Access::Write, Fault::Access) => Exception::StoreAccessFault(addr),
+            (Access::Execute, Fault::Page) => Exception::InstructionPageFault(addr),
+            (Access::Execute, Fault::Access) => Exception::InstructionAccessFault(addr),
+        };
+
+        let physical = if self.hart.csrs.translates(access) {
+            let context = mmu::Context::new(&self.hart.csrs, access);
+            // the walk reads page tables as supervisor mode, and only from memory
+            let translation = mmu::translate(addr, access, &context, |pte_addr| {
+                let pte_addr = u32::try_from(pte_addr).ok()?;
+                let pmp = &self.hart.csrs.pmp;
+                if !self.memory.contains(pte_addr)
+                    || !pmp.allows(pte_addr, 4, Access::Read, Privilege::Supervisor)
+                {
+                    return None;
+                }
+                self.memory.read(pte_addr, 4).ok()
+            })
+            .map_err(fault)?;
+
+            let kind = match access {
+                Access::Execute => TlbAccess::Fetch,
+                _ => TlbAccess::Data,
+            };
+            let hit = self
+                .tlb
+                .as_mut()
+                .is_some_and(|tlb| tlb.access(addr, context.asid(), &translation, kind));
+            // without a tlb every access walks the page tables
+            if !hit && let Some(timing) = &self.timing {
+                let latency = timing.config().memory_latency * translation.reads as u64;
+                let stage = match access {
+                    Access::Execute => Stage::Fetch,
+                    _ => Stage::Memory,
+                };
+                self.charge(stage, latency);
+            }
+
+            // physical addresses above 4 GiB have nothing behind them
+            u32::try_from(translation.physical).map_err(|_| fault(Fault::Access))?
+        } else {
+            addr
+        };
+
+        if !self.pmp_allows(physical, size, access) {
+            return Err(fault(Fault::Access));
+        }
+        Ok(physical)
+    }
+
+    /// Whether physical memory protection lets the running code make an access
+    fn pmp_allows(&self, addr: u32, size: u32, access: Access) -> bool {
+        let privilege = self.hart.csrs.access_privilege(access);
+        self.hart.csrs.pmp.allows(addr, size, access, privilege)
+    }
+
+    /// Report a completed data access to the tracer, hooks and watchpoints, by its virtual
+    /// address
+    fn trace_access(&mut self, kind: AccessKind, addr: u32, physical: u32, size: u32, value: u32) {
+        let access = MemoryAccess {
+            kind,
+            addr,
+            size,
+            value,
+        };
+        if self.watch_hit.is_none()
+            && let Some(watchpoint) = self.watchpoints.iter().find(|w| w.matches(&access))
+        {
+            self.watch_hit = Some(watchpoint.hit(self.hart.pc, access));
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.access(access);
+        }
+        if self.memory.contains(physical) {
+            self.call_hooks(|hook, cpu| hook.memory_access(cpu, &access));
+        } else {
+            self.call_hooks(|hook, cpu| hook.mmio_access(cpu, &access));
+        }
+    }
+
+    /// Read `width` bytes, zero-extended
+    fn read(&mut self, addr: u32, width: u32) -> Result<u32, anyhow::Error> {
+        if self.memory.contains(addr) {
+            return self.memory.read(addr, width);
+        }
+        // peripherals have word registers, so narrower reads pick out the bytes addressed
+        let (shift, mask) = byte_lanes(addr, width);
+        let word = if self.debug.contains(addr) {
+            self.debug.read(addr)
+        } else if self.clint.contains(addr) {
+            self.clint.read(addr, self.mtime())
+        } else if self.uart.contains(addr) {
+            self.uart.read(addr)
+        } else if let Some(plic) = &mut self.plic
+            && plic.contains(addr)
+        {
+            plic.read(addr)
+        } else {
+            bail!("invalid read address: {addr:08X}")
+        }?;
+        Ok((word & mask) >> shift)
+    }
+
+    /// Read a misaligned access a byte at a time, which only memory supports
+    fn read_bytes(&mut self, bytes: &[u32]) -> Result<u32, anyhow::Error> {
+        let mut value = 0;
+        for (offset, &addr) in bytes.iter().enumerate() {
+            ensure!(
+                self.memory.contains(addr),
+                "misaligned read outside memory: {addr:08X}"
+            );
+            value |= self.read(addr, 1)? << (8 * offset);
+        }
+        Ok(value)
+    }
+
+    /// Write a misaligned access a byte at a time, checking that every byte is in memory first
+    fn write_bytes(&mut self, bytes: &[u32], value: u32) -> Result<(), anyhow::Error> {
+        for &addr in bytes {
+            ensure!(
+                self.memory.contains(addr),
+                "misaligned write outside memory: {addr:08X}"
+            );
+        }
+        for (offset, &addr) in bytes.iter().enumerate() {
+            self.write(addr, value >> (8 * offset), 1)?;
+        }
+        Ok(())
+    }
+
+    /// Write the lower `width` bytes of `value`
+    fn write(&mut self, addr: u32, value: u32, width: u32) -> Result<(), anyhow::Error> {
+        if self.memory.contains(addr) {
+            self.memory.write(addr, value, width)?;
+            self.decode_cache.invalidate(addr, width);
+            // other harts lose their reservations on the bytes written
+            for (id, hart) in self.harts.iter_mut().enumerate() {
+                if id != self.current
+                    && hart.reservation.is_some_and(|word| {
+                        (word..word + 4).contains(&addr) || (addr..addr + width).contains(&word)
+                    })
+                {
+                    hart.reservation = None;
+                }
+            }
+            #[cfg(feature = "jit")]
+            if let Some(jit) = &mut self.jit {
+                jit.invalidate(addr);
+                jit.invalidate(addr.wrapping_add(width - 1));
+            }
+            return Ok(());
+        }
+        // narrower writes only change the bytes addressed, like byte enables on the bus
+        let (shift, mask) = byte_lanes(addr, width);
+        let value = (value << shift) & mask;
+        if self.debug.contains(addr) {
+            return self.debug.write(addr, value, &mut self.uart);
+        }
+        if self.clint.contains(addr) {
+            return self.clint.write(addr, value, mask);
+        }
+        if self.uart.contains(addr) {
+            return self.uart.write(addr, value, mask);
+        }
+        if let Some(plic) = &mut self.plic
+            && plic.contains(addr)
+        {
+            return plic.write(addr, value, mask);
+        }
+
+        bail!("invalid write address: {addr:08X}");
+    }
+
+    /// Model the cycle timing of the RTL, which stops hot blocks being compiled
+    ///
+    /// This replaces the default of one cycle per instruction, so it also sets the rate that
+    /// mcycle and mtime advance.
+    pub fn enable_timing(&mut self, config: TimingConfig) {
+        self.timing = Some(TimingModel::new(config));
+    }
+
+    pub fn timing(&self) -> Option<&TimingModel> {
+        self.timing.as_ref()
+    }
+
+    /// Model an instruction cache, which also sets fetch latencies if timing is modelled
+    ///
+    /// Like the timing model, this stops hot blocks being compiled.
+    pub fn enable_icache(&mut self, config: ICacheConfig) -> Result<(), anyhow::Error> {
+        self.icache = Some(ICache::new(config)?);
+        Ok(())
+    }
+
+    pub fn icache(&self) -> Option<&ICache> {
+        self.icache.as_ref()
+    }
+
+    /// Count the page table walks a TLB of `entries` entries would save, which the timing
+    /// model then only charges on misses
+    pub fn enable_tlb(&mut self, entries: usize) -> Result<(), anyhow::Error> {
+        self.tlb = Some(Tlb::new(entries)?);
+        Ok(())
+    }
+
+    pub fn tlb(&self) -> Option<&Tlb> {
+        self.tlb.as_ref()
+    }
+
+    /// Add a PLIC at `PLIC_BASE` with a context for each hart, which takes over the uart's
+    /// external interrupt
+    pub fn enable_plic(&mut self, config: PlicConfig) -> Result<(), anyhow::Error> {
+        self.plic = Some(Plic::new(PLIC_BASE, config, self.harts.len())?);
+        Ok(())
+    }
+
+    pub fn plic(&self) -> Option<&Plic> {
+        self.plic.as_ref()
+    }
+
+    /// Drive the interrupt line of a PLIC source, such as a peripheral modelled by the
+    /// program embedding the emulator
+    ///
+    /// Lines stay at the level they were last driven to, and the uart's is driven every step.
+    pub fn set_interrupt_line(&mut self, source: u32, level: bool) {
+        if let Some(plic) = &mut self.plic {
+            plic.set_level(source, level);
+        }
+    }
+
+    /// Attribute instructions and cycles to functions from here on
+    ///
+    /// Only hart 0 is profiled, as the call stacks of harts can't be told apart.
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new(self.hart_state(0).pc, &self.symbols));
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Count how many times each instruction retires from here on
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Function symbols of the loaded program
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// Save the state of the core, peripherals and memory
+    pub fn save_snapshot(&self, mut out: impl Write) -> Result<(), anyhow::Error> {
+        let mut out = SnapshotWriter::new(&mut out)?;
+        out.u32(self.harts.len() as u32)?;
+        out.u32(self.current as u32)?;
+        for id in 0..self.harts.len() {
+            self.hart_state(id).save(&mut out)?;
+        }
+        self.scheduler.save(&mut out)?;
+        match self.debug.status {
+            None => out.u8(0)?,
+            Some(Status::Success) => out.u8(1)?,
+            Some(Status::Failure) => out.u8(2)?,
+            Some(Status::Exited(code)) => {
+                out.u8(3)?;
+                out.u32(code)?;
+            }
+        }
+        self.clint.save(&mut out)?;
+        self.uart.save(&mut out)?;
+        out.bool(self.plic.is_some())?;
+        if let Some(plic) = &self.plic {
+            plic.save(&mut out)?;
+        }
+        self.memory.save(&mut out)
+    }
+
+    /// Replace the state of the core, peripherals and memory with a snapshot
+    ///
+    /// The symbols are kept, so the snapshot should be of the program that was loaded. The
+    /// machine takes on the snapshot's number of harts, but keeps its interleaving. An invalid
+    /// snapshot can leave the uart restored while the rest of the machine is unchanged.
+    pub fn restore_snapshot(&mut self, mut input: impl Read) -> Result<(), anyhow::Error> {
+        let mut input = SnapshotReader::new(&mut input)?;
+        let count = input.u32()? as usize;
+        ensure!(
+            (1..=clint::MAX_HARTS).contains(&count),
+            "invalid hart count in snapshot: {count}"
+        );
+        let current = input.u32()? as usize;
+        ensure!(
+            current < count,
+            "invalid running hart in snapshot: {current}"
+        );
+        let mut harts = Vec::with_capacity(count);
+        for id in 0..count {
+            harts.push(Hart::restore(id as u32, &mut input)?);
+        }
+        self.scheduler.restore(&mut input)?;
+        let status = match input.u8()? {
+            0 => None,
+            1 => Some(Status::Success),
+            2 => Some(Status::Failure),
+            3 => Some(Status::Exited(input.u32()?)),
+            status => bail!("invalid debug status in snapshot: {status}"),
+        };
+        let mut clint = Clint::new(CLINT_BASE, count);
+        clint.restore(&mut input)?;
+        self.uart.restore(&mut input)?;
+        let plic = if input.bool()? {
+            Some(Plic::restore(PLIC_BASE, &mut input)?)
+        } else {
+            None
+        };
+        let memory = Memory::restore(&mut input)?;
+        input.finish()?;
+
+        self.hart = std::mem::take(&mut harts[current]);
+        // mtime counts on from the latest cycle the parked harts reached
+        self.mtime = harts
+            .iter()
+            .map(|hart| hart.cycles)
+            .max()
+            .unwrap_or_default();
+        self.harts = harts;
+        self.current = current;
+        self.debug.status = status;
+        self.clint = clint;
+        self.plic = plic;
+        self.memory = memory;
+        self.uart.rewind(self.mtime());
+        self.watch_hit = None;
+
+        // anything derived from the old memory or control flow is stale
+        self.decode_cache.clear();
+        #[cfg(feature = "jit")]
+        {
+            if let Some(jit) = &mut self.jit {
+                jit.clear();
+            }
+            for hart in &mut self.harts {
+                hart.block_start = true;
+            }
+            self.hart.block_start = true;
+        }
+        if self.profiler.is_some() {
+            self.enable_profiler();
+        }
+        Ok(())
+    }
+
+    /// Address of the next instruction
+    pub fn pc(&self) -> u32 {
+        self.hart.pc
+    }
+
+    /// Move execution to `pc`, as a debugger would
+    pub fn set_pc(&mut self, pc: u32) {
+        if pc != self.hart.pc {
+            self.hart.pc = pc;
+            self.hart.waiting = false;
+            self.hart.spinning = false;
+            #[cfg(feature = "jit")]
+            {
+                self.hart.block_start = true;
+            }
+        }
+    }
+
+    pub fn register(&self, index: usize) -> u32 {
+        self.hart.registers.read(index)
+    }
+
+    /// Write an integer register, where writes to x0 are ignored
+    pub fn set_register(&mut self, index: usize, value: u32) {
+        self.hart.registers.write(index, value);
+    }
+
+    /// Bytes of host memory backing guest memory and decoded instructions, which are only
+    /// allocated once they are used
+    pub fn allocated_bytes(&self) -> usize {
+        self.memory.allocated_bytes() + self.decode_cache.allocated_bytes()
+    }
+
+    /// Read `width` bytes of RAM or ROM, without reaching any peripherals
+    pub fn read_memory(&self, addr: u32, width: u32) -> Result<u32, anyhow::Error> {
+        self.memory.read(addr, width)
+    }
+
+    /// Write the lower `width` bytes of `value` to RAM or ROM, without reaching any
+    /// peripherals
+    pub fn write_memory(&mut self, addr: u32, value: u32, width: u32) -> Result<(), anyhow::Error> {
+        ensure!(
+            self.memory.contains(addr),
+            "not a memory address: {addr:08X}"
+        );
+        self.write(addr, value, width)
+    }
+
+    /// Physical address of the byte of RAM or ROM that the running hart reaches at `addr`, for
+    /// the host to access a program's buffers through its translation and pmp
+    pub(crate) fn guest_address(&mut self, addr: u32, access: Access) -> Result<u32, Exception> {
+        let physical = self.physical_address(addr, 1, access)?;
+        if self.memory.contains(physical) {
+            Ok(physical)
+        } else {
+            Err(match access {
+                Access::Read => Exception::LoadAccessFault(addr),
+                Access::Write => Exception::StoreAccessFault(addr),
+                Access::Execute => Exception::InstructionAccessFault(addr),
+            })
+        }
+    }
+
+    pub(crate) fn uart_mut(&mut self) -> &mut Uart {
+        &mut self.uart
+    }
+
+    pub fn csrs(&self) -> &Csrs {
+        &self.hart.csrs
+    }
+
+    /// Clock cycles the running hart has run for since reset
+    pub fn cycles(&self) -> u64 {
+        self.hart.cycles
+    }
+
+    /// Instructions retired by the running hart since reset
+    pub fn instructions_retired(&self) -> u64 {
+        self.hart.instret
+    }
+
+    /// Instructions retired by every hart since reset
+    pub fn total_instructions_retired(&self) -> u64 {
+        (0..self.harts.len())
+            .map(|id| self.hart_state(id).instret)
+            .sum()
+    }
+
+    /// Privilege level the running hart is at
+    pub fn privilege(&self) -> Privilege {
+        self.hart.csrs.privilege
+    }
+
+    pub fn status(&self) -> Option<Status> {
+        self.debug.status
+    }
+}
+
+/// Validate a control transfer target, as there is no compressed instruction support
+fn jump_target(addr: u32) -> Result<u32, Exception> {
+    if addr & 0b11 != 0 {
+        return Err(Exception::InstructionAddressMisaligned(addr));
+    }
+    Ok(addr)
+}
+
+/// Shift and mask of the bytes of a word register that an aligned access of `width` bytes at
+/// `addr` covers
+fn byte_lanes(addr: u32, width: u32) -> (u32, u32) {
+    let shift = 8 * (addr & 0b11);
+    (shift, (u32::MAX >> (32 - 8 * width)) << shift)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
     Success,
     Failure,
+    /// The program exited with a code, which is 0 for success
+    Exited(u32),
+}
+
+/// How loads and stores to addresses that are not a multiple of their width are handled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MisalignedMode {
+    /// Raise an address-misaligned exception with the address in mtval, as the RTL should
+    #[default]
+    Trap,
+    /// Access the bytes one at a time, which only works in memory
+    Handle,
+}
+
+/// Limits on a run, which are checked between steps
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RunLimits {
+    pub max_instructions: Option<u64>,
+    /// Wall-clock time
+    pub timeout: Option<Duration>,
+}
+
+/// Why a run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The program reported its result to the debug peripheral, or exited
+    Status(Status),
+    InstructionLimit,
+    Timeout,
+    /// The core could make no further progress, spinning or waiting at `pc`
+    Hang {
+        pc: u32,
+    },
+    /// An instruction made a watched access, which completed
+    Watchpoint(WatchHit),
+}
+
+/// Architectural state of a hart, which runs while it is the cpu's `hart` and is parked in
+/// `harts` otherwise
+#[derive(Default)]
+struct Hart {
+    pc: u32,
+    registers: Registers,
+    csrs: Csrs,
+    /// Clock cycles the hart has run for since reset
+    cycles: u64,
+    /// Instructions retired since reset
+    instret: u64,
+    /// Whether a wfi is stalled in the decode stage
+    waiting: bool,
+    /// Whether the last instruction jumped to itself
+    spinning: bool,
+    /// Physical address of the word reserved by lr.w, which a store by another hart cancels
+    reservation: Option<u32>,
+    /// Whether the pc is at the start of a basic block, and so a candidate for compilation
+    #[cfg(feature = "jit")]
+    block_start: bool,
+}
+
+impl Hart {
+    fn new(id: u32, pc: u32) -> Self {
+        Self {
+            pc,
+            csrs: Csrs {
+                hart_id: id,
+                ..Csrs::default()
+            },
+            #[cfg(feature = "jit")]
+            block_start: true,
+            ..Self::default()
+        }
+    }
+
+    fn save(&self, out: &mut SnapshotWriter) -> Result<(), anyhow::Error> {
+        out.u32(self.pc)?;
+        for index in 0..32 {
+            out.u32(self.registers.read(index))?;
+        }
+        self.csrs.save(out)?;
+        out.u64(self.cycles)?;
+        out.u64(self.instret)?;
+        out.bool(self.waiting)?;
+        out.bool(self.spinning)?;
+        out.bool(self.reservation.is_some())?;
+        out.u32(self.reservation.unwrap_or_default())
+    }
+
+    fn restore(id: u32, input: &mut SnapshotReader) -> Result<Self, anyhow::Error> {
+        let mut hart = Self::new(id, input.u32()?);
+        for index in 0..32 {
+            hart.registers.write(index, input.u32()?);
+        }
+        hart.csrs.restore(input)?;
+        hart.cycles = input.u64()?;
+        hart.instret = input.u64()?;
+        hart.waiting = input.bool()?;
+        hart.spinning = input.bool()?;
+        let reserved = input.bool()?;
+        let reservation = input.u32()?;
+        hart.reservation = reserved.then_some(reservation);
+        Ok(hart)
+    }
 }
 
 #[derive(Default)]
@@ -402,60 +1949,1868 @@ impl Registers {
     }
 }
 
-struct Ram {
+struct DebugPeripheral {
     base: u32,
-    data: Box<[u8; RAM_SIZE]>,
+    /// Result the program reported, here or by exiting through semihosting or HTIF
+    status: Option<Status>,
 }
 
-impl Ram {
+impl DebugPeripheral {
+    /// Any write reports success
+    const PASS: u32 = 0x0;
+    /// Any write reports failure
+    const FAIL: u32 = 0x4;
+    /// Writing a code exits with it, 0 being success
+    const EXIT: u32 = 0x8;
+    /// Writing a byte prints it on the host console
+    const CONSOLE: u32 = 0xC;
+    /// Reads [`DEBUG_ID`]
+    const ID: u32 = 0x10;
+
     fn contains(&self, addr: u32) -> bool {
-        (self.base..self.base + RAM_SIZE as u32).contains(&addr)
+        (self.base..self.base + 0x14).contains(&addr)
     }
 
-    fn read(&self, addr: u32) -> Result<u32, anyhow::Error> {
-        let addr = (addr - self.base) as usize;
-        Ok(u32::from_le_bytes(
-            self.data[addr..addr + 4]
-                .try_into()
-                .context("address invalid for read")?,
-        ))
+    fn register(&self, name: &str) -> Option<u32> {
+        let offset = match name {
+            "pass" => Self::PASS,
+            "fail" => Self::FAIL,
+            "exit" => Self::EXIT,
+            "console" => Self::CONSOLE,
+            "id" => Self::ID,
+            _ => return None,
+        };
+        Some(self.base + offset)
     }
 
-    fn write(&mut self, addr: u32, value: u32) {
-        let addr = (addr - self.base) as usize;
-        self.data[addr..addr + 4].copy_from_slice(&value.to_le_bytes());
+    fn read(&self, addr: u32) -> Result<u32, anyhow::Error> {
+        match (addr - self.base) & !0b11 {
+            Self::ID => Ok(DEBUG_ID),
+            _ => bail!("debug peripheral register is write-only: {addr:08X}"),
+        }
     }
 
-    fn slice_mut(&mut self, addr: u32, length: u32) -> &mut [u8] {
-        let start = addr.checked_sub(self.base).unwrap() as usize;
-        let end = start.checked_add(length as usize).unwrap();
-        assert!(start < self.data.len());
-        assert!(end <= self.data.len());
-        &mut self.data[start..end]
+    /// Handle a write, printing console bytes through the uart's backend
+    fn write(&mut self, addr: u32, value: u32, uart: &mut Uart) -> Result<(), anyhow::Error> {
+        match (addr - self.base) & !0b11 {
+            Self::PASS => self.status = Some(Status::Success),
+            Self::FAIL => self.status = Some(Status::Failure),
+            Self::EXIT => self.status = Some(Status::Exited(value)),
+            Self::CONSOLE => uart.console_write(value as u8)?,
+            _ => bail!("invalid debug peripheral address: {addr:08X}"),
+        }
+        Ok(())
     }
 }
 
-struct DebugPeripheral {
-    base: u32,
-    status: Option<Status>,
-}
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{cell::RefCell, rc::Rc};
 
-impl DebugPeripheral {
-    fn contains(&self, addr: u32) -> bool {
-        (self.base..self.base + 0x4).contains(&addr)
+    use super::*;
+    use crate::{
+        csr::{MHARTID, MSTATUS, MSTATUS_MIE, MSTATUS_MPP},
+        memory::RAM_BASE,
+        profiler::Metric,
+        symbols::Symbol,
+        trace::{tests::Output, TraceFilter, TraceFormat},
+        uart::tests::Loopback,
+        watch::WatchKind,
+    };
+
+    const INT_MIN: u32 = i32::MIN as u32;
+
+    fn r(funct7: u32, funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+        (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | 0b0110011
     }
 
-    fn read(&self, addr: u32) -> Result<u32, anyhow::Error> {
-        let addr = (addr - self.base) as usize;
-        unimplemented!()
+    fn i(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+        ((imm as u32 & 0xFFF) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
     }
 
-    fn write(&mut self, addr: u32, _value: u32) {
-        let addr = (addr - self.base) as usize;
-        match addr {
-            0x0 => self.status = Some(Status::Success),
-            0x4 => self.status = Some(Status::Failure),
-            _ => unimplemented!(),
-        }
+    fn s(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+        let imm = imm as u32;
+        ((imm >> 5 & 0x7F) << 25)
+            | (rs2 << 20)
+            | (rs1 << 15)
+            | (funct3 << 12)
+            | ((imm & 0x1F) << 7)
+            | 0b0100011
+    }
+
+    fn b(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+        let imm = imm as u32;
+        ((imm >> 12 & 0x1) << 31)
+            | ((imm >> 5 & 0x3F) << 25)
+            | (rs2 << 20)
+            | (rs1 << 15)
+            | (funct3 << 12)
+            | ((imm >> 1 & 0xF) << 8)
+            | ((imm >> 11 & 0x1) << 7)
+            | 0b1100011
+    }
+
+    fn lui(rd: u32, imm: u32) -> u32 {
+        (imm & 0xFFFFF000) | (rd << 7) | 0b0110111
+    }
+
+    fn auipc(rd: u32, imm: u32) -> u32 {
+        (imm & 0xFFFFF000) | (rd << 7) | 0b0010111
+    }
+
+    fn jal(rd: u32, imm: i32) -> u32 {
+        let imm = imm as u32;
+        ((imm >> 20 & 0x1) << 31)
+            | ((imm >> 1 & 0x3FF) << 21)
+            | ((imm >> 11 & 0x1) << 20)
+            | (imm & 0xFF000)
+            | (rd << 7)
+            | 0b1101111
+    }
+
+    fn jalr(rd: u32, rs1: u32, imm: i32) -> u32 {
+        i(0b1100111, 0b000, rd, rs1, imm)
+    }
+
+    fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+        i(0b0010011, 0b000, rd, rs1, imm)
+    }
+
+    fn csr(funct3: u32, rd: u32, rs1: u32, addr: u16) -> u32 {
+        i(0b1110011, funct3, rd, rs1, addr as i32)
+    }
+
+    fn amo(funct5: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+        r(funct5 << 2, 0b010, rd, rs1, rs2) & !0x7F | 0b0101111
+    }
+
+    const ECALL: u32 = 0x00000073;
+    const EBREAK: u32 = 0x00100073;
+    const MRET: u32 = 0x30200073;
+    const WFI: u32 = 0x10500073;
+
+    /// Trap handler installed by `cpu_with_program`, which is left empty
+    const TRAP_HANDLER: u32 = RAM_BASE + 0x1000;
+
+    /// Core running `program` from 0 in 4 KiB of memory, for other modules' tests
+    pub(crate) fn cpu_at_zero(program: &[u32]) -> Cpu {
+        let mut memory = Memory::new(&MemoryConfig {
+            regions: vec![RegionConfig {
+                base: 0,
+                size: 0x1000,
+            }],
+        })
+        .unwrap();
+        let bytes: Vec<u8> = program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        memory.load(0, &bytes).unwrap();
+        Cpu::new(0, memory)
+    }
+
+    fn cpu_with_program(program: &[u32]) -> Cpu {
+        let mut memory = Memory::new(&MemoryConfig::default()).unwrap();
+        for (index, inst) in program.iter().enumerate() {
+            memory.write(RAM_BASE + 4 * index as u32, *inst, 4).unwrap();
+        }
+
+        let mut cpu = Cpu::new(RAM_BASE, memory);
+        cpu.hart.csrs.mtvec = TRAP_HANDLER;
+        cpu
+    }
+
+    /// Run a single instruction with the given initial register values
+    fn exec(inst: u32, registers: &[(usize, u32)]) -> Cpu {
+        let mut cpu = cpu_with_program(&[inst]);
+        for (index, value) in registers {
+            cpu.hart.registers.write(*index, *value);
+        }
+        cpu.step().unwrap();
+        cpu
+    }
+
+    /// Run a single register-register instruction, returning the value written to x3
+    fn exec_r(inst: u32, rs1_value: u32, rs2_value: u32) -> u32 {
+        let cpu = exec(inst, &[(1, rs1_value), (2, rs2_value)]);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 4);
+        cpu.hart.registers.read(3)
+    }
+
+    /// Run a single register-immediate instruction, returning the value written to x3
+    fn exec_i(inst: u32, rs1_value: u32) -> u32 {
+        let cpu = exec(inst, &[(1, rs1_value)]);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 4);
+        cpu.hart.registers.read(3)
+    }
+
+    #[test]
+    fn x0_is_never_written() {
+        let cpu = exec(addi(0, 0, 5), &[]);
+        assert_eq!(cpu.hart.registers.read(0), 0);
+
+        let cpu = exec(lui(0, 0xABCDE000), &[]);
+        assert_eq!(cpu.hart.registers.read(0), 0);
+
+        let cpu = exec(r(0, 0b000, 0, 1, 2), &[(1, 1), (2, 2)]);
+        assert_eq!(cpu.hart.registers.read(0), 0);
+
+        // jal x0 is a plain jump
+        let cpu = exec(jal(0, 8), &[]);
+        assert_eq!(cpu.hart.registers.read(0), 0);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 8);
+    }
+
+    #[test]
+    fn lui_auipc() {
+        assert_eq!(
+            exec(lui(3, 0xFFFFF000), &[]).hart.registers.read(3),
+            0xFFFFF000
+        );
+        assert_eq!(
+            exec(lui(3, 0x12345000), &[]).hart.registers.read(3),
+            0x12345000
+        );
+        assert_eq!(
+            exec(auipc(3, 0x00001000), &[]).hart.registers.read(3),
+            RAM_BASE + 0x1000
+        );
+        // wraps around the top of the address space
+        assert_eq!(
+            exec(auipc(3, 0x20000000), &[]).hart.registers.read(3),
+            RAM_BASE.wrapping_add(0x20000000)
+        );
+    }
+
+    #[test]
+    fn add_sub() {
+        let add = r(0b0000000, 0b000, 3, 1, 2);
+        let sub = r(0b0100000, 0b000, 3, 1, 2);
+        assert_eq!(exec_r(add, 2, 3), 5);
+        assert_eq!(exec_r(add, 0xFFFFFFFF, 1), 0);
+        assert_eq!(exec_r(add, i32::MAX as u32, 1), INT_MIN);
+        assert_eq!(exec_r(sub, 5, 3), 2);
+        assert_eq!(exec_r(sub, 0, 1), 0xFFFFFFFF);
+        assert_eq!(exec_r(sub, INT_MIN, 1), i32::MAX as u32);
+    }
+
+    #[test]
+    fn logical() {
+        let xor = r(0, 0b100, 3, 1, 2);
+        let or = r(0, 0b110, 3, 1, 2);
+        let and = r(0, 0b111, 3, 1, 2);
+        assert_eq!(exec_r(xor, 0xFF00FF00, 0x0FF00FF0), 0xF0F0F0F0);
+        assert_eq!(exec_r(or, 0xFF00FF00, 0x0FF00FF0), 0xFFF0FFF0);
+        assert_eq!(exec_r(and, 0xFF00FF00, 0x0FF00FF0), 0x0F000F00);
+
+        let xori = |imm| i(0b0010011, 0b100, 3, 1, imm);
+        let andi = |imm| i(0b0010011, 0b111, 3, 1, imm);
+        // immediates are sign-extended before use
+        assert_eq!(exec_i(xori(-1), 0x12345678), !0x12345678);
+        assert_eq!(exec_i(xori(0x7FF), 0x12345678), 0x12345678 ^ 0x7FF);
+        assert_eq!(exec_i(andi(-16), 0x12345678), 0x12345670);
+        assert_eq!(exec_i(andi(0xF), 0x12345678), 0x8);
+    }
+
+    #[test]
+    fn add_immediate() {
+        assert_eq!(exec_i(addi(3, 1, 1), 41), 42);
+        assert_eq!(exec_i(addi(3, 1, -1), 0), 0xFFFFFFFF);
+        assert_eq!(exec_i(addi(3, 1, 1), 0xFFFFFFFF), 0);
+        assert_eq!(exec_i(addi(3, 1, -2048), 0), 0xFFFFF800);
+        assert_eq!(exec_i(addi(3, 1, 2047), 0), 0x7FF);
+    }
+
+    #[test]
+    fn register_shifts_mask_amount() {
+        let sll = r(0b0000000, 0b001, 3, 1, 2);
+        let srl = r(0b0000000, 0b101, 3, 1, 2);
+        let sra = r(0b0100000, 0b101, 3, 1, 2);
+
+        assert_eq!(exec_r(sll, 1, 31), INT_MIN);
+        assert_eq!(exec_r(sll, 1, 32), 1);
+        assert_eq!(exec_r(sll, 1, 33), 2);
+        assert_eq!(exec_r(sll, 1, 0xFFFFFFFF), INT_MIN);
+
+        assert_eq!(exec_r(srl, INT_MIN, 31), 1);
+        assert_eq!(exec_r(srl, INT_MIN, 32), INT_MIN);
+        assert_eq!(exec_r(srl, INT_MIN, 36), 0x08000000);
+
+        assert_eq!(exec_r(sra, INT_MIN, 31), 0xFFFFFFFF);
+        assert_eq!(exec_r(sra, INT_MIN, 32), INT_MIN);
+        assert_eq!(exec_r(sra, INT_MIN, 36), 0xF8000000);
+        assert_eq!(exec_r(sra, 0x40000000, 30), 1);
+    }
+
+    #[test]
+    fn immediate_shifts() {
+        let slli = |shamt| i(0b0010011, 0b001, 3, 1, shamt);
+        let srli = |shamt| i(0b0010011, 0b101, 3, 1, shamt);
+        let srai = |shamt| i(0b0010011, 0b101, 3, 1, shamt | 0x400);
+
+        assert_eq!(exec_i(slli(0), 0x12345678), 0x12345678);
+        assert_eq!(exec_i(slli(31), 1), INT_MIN);
+        assert_eq!(exec_i(srli(31), INT_MIN), 1);
+        assert_eq!(exec_i(srli(4), 0xF0000000), 0x0F000000);
+        assert_eq!(exec_i(srai(4), 0xF0000000), 0xFF000000);
+        assert_eq!(exec_i(srai(31), INT_MIN), 0xFFFFFFFF);
+        assert_eq!(exec_i(srai(31), i32::MAX as u32), 0);
+    }
+
+    #[test]
+    fn set_less_than() {
+        let slt = r(0, 0b010, 3, 1, 2);
+        let sltu = r(0, 0b011, 3, 1, 2);
+        assert_eq!(exec_r(slt, INT_MIN, 0), 1);
+        assert_eq!(exec_r(slt, 0, INT_MIN), 0);
+        assert_eq!(exec_r(slt, INT_MIN, INT_MIN), 0);
+        assert_eq!(exec_r(slt, 0xFFFFFFFF, 0), 1);
+        assert_eq!(exec_r(sltu, INT_MIN, 0), 0);
+        assert_eq!(exec_r(sltu, 0, INT_MIN), 1);
+        assert_eq!(exec_r(sltu, 0xFFFFFFFF, 0xFFFFFFFF), 0);
+
+        // the immediate is sign-extended then compared unsigned, so -1 is the largest value
+        let sltiu = |imm| i(0b0010011, 0b011, 3, 1, imm);
+        assert_eq!(exec_i(sltiu(-1), 0xFFFFFFFE), 1);
+        assert_eq!(exec_i(sltiu(-1), 0xFFFFFFFF), 0);
+        assert_eq!(exec_i(sltiu(1), 0), 1);
+        assert_eq!(exec_i(sltiu(0), 0), 0);
+    }
+
+    /// Run a branch at RAM_BASE + 0x100, returning whether it was taken
+    fn branch_taken(funct3: u32, rs1_value: u32, rs2_value: u32, offset: i32) -> bool {
+        let mut program = vec![0; 0x100 / 4];
+        program.push(b(funct3, 1, 2, offset));
+        let mut cpu = cpu_with_program(&program);
+        cpu.hart.pc = RAM_BASE + 0x100;
+        cpu.hart.registers.write(1, rs1_value);
+        cpu.hart.registers.write(2, rs2_value);
+        cpu.step().unwrap();
+
+        if cpu.hart.pc == RAM_BASE + 0x104 {
+            false
+        } else {
+            assert_eq!(cpu.hart.pc, (RAM_BASE + 0x100).wrapping_add(offset as u32));
+            true
+        }
+    }
+
+    #[test]
+    fn branches() {
+        const BEQ: u32 = 0b000;
+        const BNE: u32 = 0b001;
+        const BLT: u32 = 0b100;
+        const BGE: u32 = 0b101;
+        const BLTU: u32 = 0b110;
+        const BGEU: u32 = 0b111;
+
+        for offset in [8, -8, 0x800, -0x100] {
+            assert!(branch_taken(BEQ, 5, 5, offset));
+            assert!(!branch_taken(BEQ, 5, 6, offset));
+            assert!(branch_taken(BNE, 5, 6, offset));
+            assert!(!branch_taken(BNE, 5, 5, offset));
+
+            assert!(branch_taken(BLT, INT_MIN, 0, offset));
+            assert!(!branch_taken(BLT, 0, INT_MIN, offset));
+            assert!(!branch_taken(BLT, INT_MIN, INT_MIN, offset));
+            assert!(branch_taken(BGE, 0, INT_MIN, offset));
+            assert!(branch_taken(BGE, INT_MIN, INT_MIN, offset));
+            assert!(!branch_taken(BGE, INT_MIN, 0, offset));
+
+            assert!(!branch_taken(BLTU, INT_MIN, 0, offset));
+            assert!(branch_taken(BLTU, 0, INT_MIN, offset));
+            assert!(branch_taken(BGEU, INT_MIN, 0, offset));
+            assert!(branch_taken(BGEU, 0xFFFFFFFF, 0xFFFFFFFF, offset));
+            assert!(!branch_taken(BGEU, 0, 1, offset));
+        }
+
+        // a branch to itself is a valid infinite loop
+        assert!(branch_taken(BEQ, 0, 0, 0));
+    }
+
+    #[test]
+    fn jumps() {
+        // forwards and backwards jal, linking the successive instruction
+        let mut cpu = cpu_with_program(&[0, 0, jal(1, -8)]);
+        cpu.hart.pc = RAM_BASE + 8;
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.pc, RAM_BASE);
+        assert_eq!(cpu.hart.registers.read(1), RAM_BASE + 12);
+
+        let cpu = exec(jal(1, 0x1000), &[]);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 0x1000);
+        assert_eq!(cpu.hart.registers.read(1), RAM_BASE + 4);
+
+        // jalr clears the lowest bit of the target
+        let cpu = exec(jalr(1, 2, 0x11), &[(2, RAM_BASE + 0x100)]);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 0x110);
+        assert_eq!(cpu.hart.registers.read(1), RAM_BASE + 4);
+
+        // jalr reads rs1 before writing rd
+        let cpu = exec(jalr(1, 1, -4), &[(1, RAM_BASE + 0x100)]);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 0xFC);
+        assert_eq!(cpu.hart.registers.read(1), RAM_BASE + 4);
+    }
+
+    #[test]
+    fn pc_wraps_at_end_of_address_space() {
+        let cpu_at_last_word = |inst| {
+            let mut memory = Memory::new(&MemoryConfig {
+                regions: vec![
+                    RegionConfig {
+                        base: 0,
+                        size: 0x1000,
+                    },
+                    RegionConfig {
+                        base: 0xFFFFF000,
+                        size: 0x1000,
+                    },
+                ],
+            })
+            .unwrap();
+            memory.write(0xFFFFFFFC, inst, 4).unwrap();
+            let mut cpu = Cpu::new(0xFFFFFFFC, memory);
+            cpu.step().unwrap();
+            cpu
+        };
+
+        // the instruction after the last word is at 0
+        let cpu = cpu_at_last_word(addi(3, 3, 1));
+        assert_eq!(cpu.hart.pc, 0);
+
+        // which is what jumps from the last word link
+        let cpu = cpu_at_last_word(jal(1, 8));
+        assert_eq!(cpu.hart.pc, 4);
+        assert_eq!(cpu.hart.registers.read(1), 0);
+
+        let cpu = cpu_at_last_word(jalr(1, 0, 8));
+        assert_eq!(cpu.hart.pc, 8);
+        assert_eq!(cpu.hart.registers.read(1), 0);
+    }
+
+    /// Check that the last step trapped to the handler at `TRAP_HANDLER`
+    fn assert_trapped(cpu: &Cpu, mcause: u32, mepc: u32, mtval: u32) {
+        assert_eq!(cpu.hart.pc, TRAP_HANDLER);
+        assert_eq!(cpu.hart.csrs.mcause, mcause);
+        assert_eq!(cpu.hart.csrs.mepc, mepc);
+        assert_eq!(cpu.hart.csrs.mtval, mtval);
+    }
+
+    #[test]
+    fn misaligned_jump_targets() {
+        let mut cpu = cpu_with_program(&[jalr(1, 2, 2)]);
+        cpu.hart.registers.write(2, RAM_BASE + 0x100);
+        cpu.step().unwrap();
+        // the faulting instruction has no architectural effect
+        assert_trapped(&cpu, 0, RAM_BASE, RAM_BASE + 0x102);
+        assert_eq!(cpu.hart.registers.read(1), 0);
+
+        let mut cpu = cpu_with_program(&[jal(1, 6)]);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 0, RAM_BASE, RAM_BASE + 6);
+        assert_eq!(cpu.hart.registers.read(1), 0);
+
+        let mut cpu = cpu_with_program(&[b(0b000, 0, 0, 6)]);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 0, RAM_BASE, RAM_BASE + 6);
+
+        // an untaken branch to a misaligned target is fine
+        let cpu = exec(b(0b001, 0, 0, 6), &[]);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 4);
+    }
+
+    #[test]
+    fn loads_and_stores() {
+        let sw = s(0b010, 1, 2, -4);
+        let cpu = exec(sw, &[(1, RAM_BASE + 0x104), (2, 0xDEADBEEF)]);
+        assert_eq!(cpu.memory.read(RAM_BASE + 0x100, 4).unwrap(), 0xDEADBEEF);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 4);
+
+        let lw = i(0b0000011, 0b010, 3, 1, 0x10);
+        let mut cpu = cpu_with_program(&[lw]);
+        cpu.memory.write(RAM_BASE + 0x110, 0x80000001, 4).unwrap();
+        cpu.hart.registers.write(1, RAM_BASE + 0x100);
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(3), 0x80000001);
+
+        // lhu zero-extends the lower halfword
+        let lhu = i(0b0000011, 0b101, 3, 1, 0);
+        let mut cpu = cpu_with_program(&[lhu]);
+        cpu.memory.write(RAM_BASE + 0x100, 0x1234F00D, 4).unwrap();
+        cpu.hart.registers.write(1, RAM_BASE + 0x100);
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(3), 0x0000F00D);
+
+        // loads into x0 are discarded
+        let lw = i(0b0000011, 0b010, 0, 1, 0);
+        let mut cpu = cpu_with_program(&[lw]);
+        cpu.hart.registers.write(1, RAM_BASE);
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(0), 0);
+    }
+
+    #[test]
+    fn sub_word_loads_and_stores() {
+        let load = |funct3, offset| {
+            let mut cpu = cpu_with_program(&[i(0b0000011, funct3, 3, 1, offset)]);
+            cpu.memory.write(RAM_BASE + 0x100, 0x80F08001, 4).unwrap();
+            cpu.hart.registers.write(1, RAM_BASE + 0x100);
+            cpu.step().unwrap();
+            cpu.hart.registers.read(3)
+        };
+        const LB: u32 = 0b000;
+        const LH: u32 = 0b001;
+        const LBU: u32 = 0b100;
+        const LHU: u32 = 0b101;
+        assert_eq!(load(LB, 0), 0x00000001);
+        assert_eq!(load(LB, 1), 0xFFFFFF80);
+        assert_eq!(load(LBU, 1), 0x00000080);
+        assert_eq!(load(LB, 3), 0xFFFFFF80);
+        assert_eq!(load(LH, 0), 0xFFFF8001);
+        assert_eq!(load(LHU, 0), 0x00008001);
+        assert_eq!(load(LH, 2), 0xFFFF80F0);
+        assert_eq!(load(LHU, 2), 0x000080F0);
+
+        let store = |funct3, offset| {
+            let mut cpu = cpu_with_program(&[s(funct3, 1, 2, offset)]);
+            cpu.memory.write(RAM_BASE + 0x100, 0x11223344, 4).unwrap();
+            cpu.hart.registers.write(1, RAM_BASE + 0x100);
+            cpu.hart.registers.write(2, 0xAABBCCDD);
+            cpu.step().unwrap();
+            cpu.memory.read(RAM_BASE + 0x100, 4).unwrap()
+        };
+        const SB: u32 = 0b000;
+        const SH: u32 = 0b001;
+        assert_eq!(store(SB, 0), 0x112233DD);
+        assert_eq!(store(SB, 3), 0xDD223344);
+        assert_eq!(store(SH, 0), 0x1122CCDD);
+        assert_eq!(store(SH, 2), 0xCCDD3344);
+    }
+
+    #[test]
+    fn misaligned_accesses() {
+        let lw = i(0b0000011, 0b010, 3, 1, 0);
+        let lh = i(0b0000011, 0b001, 3, 1, 0);
+        let sw = s(0b010, 1, 2, 0);
+        let misaligned = |inst, addr, mode| {
+            let mut cpu = cpu_with_program(&[inst]);
+            cpu.set_misaligned_mode(mode);
+            cpu.memory.write(RAM_BASE + 0x100, 0x44332211, 4).unwrap();
+            cpu.memory.write(RAM_BASE + 0x104, 0x88776655, 4).unwrap();
+            cpu.hart.registers.write(1, addr);
+            cpu.hart.registers.write(2, 0xAABBCCDD);
+            cpu.hart.registers.write(3, 5);
+            cpu.step().unwrap();
+            cpu
+        };
+
+        // trapping leaves memory and the destination alone, with the address in mtval
+        let cpu = misaligned(lw, RAM_BASE + 0x102, MisalignedMode::Trap);
+        assert_trapped(&cpu, 4, RAM_BASE, RAM_BASE + 0x102);
+        assert_eq!(cpu.hart.registers.read(3), 5);
+        let cpu = misaligned(lh, RAM_BASE + 0x101, MisalignedMode::Trap);
+        assert_trapped(&cpu, 4, RAM_BASE, RAM_BASE + 0x101);
+        let cpu = misaligned(sw, RAM_BASE + 0x103, MisalignedMode::Trap);
+        assert_trapped(&cpu, 6, RAM_BASE, RAM_BASE + 0x103);
+        assert_eq!(cpu.memory.read(RAM_BASE + 0x104, 4).unwrap(), 0x88776655);
+
+        let cpu = misaligned(lw, RAM_BASE + 0x102, MisalignedMode::Handle);
+        assert_eq!(cpu.hart.registers.read(3), 0x66554433);
+        let cpu = misaligned(lh, RAM_BASE + 0x103, MisalignedMode::Handle);
+        assert_eq!(cpu.hart.registers.read(3), 0x00005544);
+        let cpu = misaligned(sw, RAM_BASE + 0x103, MisalignedMode::Handle);
+        assert_eq!(cpu.memory.read(RAM_BASE + 0x100, 4).unwrap(), 0xDD332211);
+        assert_eq!(cpu.memory.read(RAM_BASE + 0x104, 4).unwrap(), 0x88AABBCC);
+
+        // peripherals are only accessed whole, so misaligned accesses to them fault
+        let cpu = misaligned(sw, DEBUG_BASE + 2, MisalignedMode::Handle);
+        assert_trapped(&cpu, 7, RAM_BASE, DEBUG_BASE + 2);
+        assert_eq!(cpu.status(), None);
+    }
+
+    #[test]
+    fn atomics() {
+        let word = RAM_BASE + 0x100;
+        let atomic = |funct5: u32, old: u32, operand: u32| {
+            let mut cpu = cpu_with_program(&[amo(funct5, 3, 1, 2)]);
+            cpu.memory.write(word, old, 4).unwrap();
+            cpu.hart.registers.write(1, word);
+            cpu.hart.registers.write(2, operand);
+            cpu.step().unwrap();
+            assert_eq!(cpu.hart.registers.read(3), old);
+            cpu.memory.read(word, 4).unwrap()
+        };
+        assert_eq!(atomic(0b00001, 5, 7), 7);
+        assert_eq!(atomic(0b00000, 5, u32::MAX), 4);
+        assert_eq!(atomic(0b00100, 0b1100, 0b1010), 0b0110);
+        assert_eq!(atomic(0b01100, 0b1100, 0b1010), 0b1000);
+        assert_eq!(atomic(0b01000, 0b1100, 0b1010), 0b1110);
+        assert_eq!(atomic(0b10000, INT_MIN, 1), INT_MIN);
+        assert_eq!(atomic(0b10100, INT_MIN, 1), 1);
+        assert_eq!(atomic(0b11000, INT_MIN, 1), 1);
+        assert_eq!(atomic(0b11100, INT_MIN, 1), INT_MIN);
+
+        // sc.w only stores to the word reserved by lr.w, writing 0 to rd if it did
+        let lr = amo(0b00010, 3, 1, 0);
+        let sc = amo(0b00011, 4, 1, 2);
+        let mut cpu = cpu_with_program(&[sc, lr, sc, sc]);
+        cpu.memory.write(word, 5, 4).unwrap();
+        cpu.hart.registers.write(1, word);
+        cpu.hart.registers.write(2, 9);
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(4), 1);
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(3), 5);
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(4), 0);
+        assert_eq!(cpu.memory.read(word, 4).unwrap(), 9);
+        // the reservation is used up
+        cpu.hart.registers.write(2, 10);
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(4), 1);
+        assert_eq!(cpu.memory.read(word, 4).unwrap(), 9);
+
+        // atomics must be aligned even when other accesses don't have to be
+        let mut cpu = cpu_with_program(&[amo(0b00000, 3, 1, 2)]);
+        cpu.set_misaligned_mode(MisalignedMode::Handle);
+        cpu.hart.registers.write(1, word + 2);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 6, RAM_BASE, word + 2);
+        let cpu = exec(lr, &[(1, word + 1)]);
+        assert_trapped(&cpu, 4, RAM_BASE, word + 1);
+    }
+
+    #[test]
+    fn narrow_mmio_accesses() {
+        // loads pick out the bytes addressed from the word register
+        let lbu = |offset| i(0b0000011, 0b100, 3, 1, offset);
+        let cpu = exec(lbu(0x10), &[(1, DEBUG_BASE)]);
+        assert_eq!(cpu.hart.registers.read(3), DEBUG_ID & 0xFF);
+        let cpu = exec(lbu(0x11), &[(1, DEBUG_BASE)]);
+        assert_eq!(cpu.hart.registers.read(3), DEBUG_ID >> 8 & 0xFF);
+        let cpu = exec(i(0b0000011, 0b101, 3, 1, 0x12), &[(1, DEBUG_BASE)]);
+        assert_eq!(cpu.hart.registers.read(3), DEBUG_ID >> 16);
+
+        // and stores only write the bytes addressed
+        let sb = |offset| s(0b000, 1, 2, offset);
+        let cpu = exec(sb(0x8), &[(1, DEBUG_BASE), (2, 0x105)]);
+        assert_eq!(cpu.debug.status, Some(Status::Exited(5)));
+
+        let mut cpu = cpu_with_program(&[sb(1)]);
+        cpu.clint
+            .write(CLINT_BASE + 0x4000, 0x11223344, u32::MAX)
+            .unwrap();
+        cpu.hart.registers.write(1, CLINT_BASE + 0x4000);
+        cpu.hart.registers.write(2, 0xAA);
+        cpu.step().unwrap();
+        assert_eq!(cpu.clint.read(CLINT_BASE + 0x4000, 0).unwrap(), 0x1122AA44);
+    }
+
+    #[test]
+    fn slti_ori() {
+        let slti = |imm| i(0b0010011, 0b010, 3, 1, imm);
+        assert_eq!(exec_i(slti(0), INT_MIN), 1);
+        assert_eq!(exec_i(slti(-1), 0), 0);
+        assert_eq!(exec_i(slti(-1), 0xFFFFFFFE), 1);
+        assert_eq!(exec_i(slti(-2048), INT_MIN), 1);
+
+        let ori = |imm| i(0b0010011, 0b110, 3, 1, imm);
+        assert_eq!(exec_i(ori(0x0F0), 0x12345600), 0x123456F0);
+        assert_eq!(exec_i(ori(-2048), 0), 0xFFFFF800);
+    }
+
+    #[test]
+    fn stores_invalidate_decoded_instructions() {
+        let mut cpu = cpu_with_program(&[addi(3, 3, 1), s(0b010, 1, 2, 0), jal(0, -8)]);
+        cpu.hart.registers.write(1, RAM_BASE);
+        cpu.hart.registers.write(2, addi(3, 3, 16));
+
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.hart.registers.read(3), 17);
+
+        // a byte store into the middle of a cached instruction also invalidates it
+        let mut cpu = cpu_with_program(&[addi(3, 3, 1), s(0b000, 1, 2, 3), jal(0, -8)]);
+        cpu.hart.registers.write(1, RAM_BASE);
+        cpu.hart.registers.write(2, addi(3, 3, 0x100) >> 24);
+
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        // only the upper immediate bits were replaced, so the increment becomes 0x101
+        assert_eq!(cpu.hart.registers.read(3), 0x102);
+    }
+
+    #[test]
+    fn fence_i_clears_decoded_instructions() {
+        const FENCE_I: u32 = 0x0000100F;
+        let mut cpu = cpu_with_program(&[addi(3, 3, 1), FENCE_I, jal(0, -8)]);
+        cpu.step().unwrap();
+
+        // bypass the store path, so only the fence can make the change visible
+        cpu.memory.write(RAM_BASE, addi(3, 3, 16), 4).unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(3), 17);
+
+        // a plain fence does not touch the cache
+        const FENCE: u32 = 0x0FF0000F;
+        let mut cpu = cpu_with_program(&[addi(3, 3, 1), FENCE, jal(0, -8)]);
+        cpu.step().unwrap();
+        cpu.memory.write(RAM_BASE, addi(3, 3, 16), 4).unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(3), 2);
+    }
+
+    #[test]
+    fn instances_are_cheap() {
+        let mut instances: Vec<Cpu> = (0..256)
+            .map(|_| Cpu::new(RAM_BASE, Memory::new(&MemoryConfig::default()).unwrap()))
+            .collect();
+        assert!(instances.iter().all(|cpu| cpu.allocated_bytes() == 0));
+
+        // running code decodes it, and fence.i drops what was decoded
+        const FENCE_I: u32 = 0x0000100F;
+        let cpu = &mut instances[0];
+        cpu.memory.write(RAM_BASE, addi(3, 3, 1), 4).unwrap();
+        cpu.memory.write(RAM_BASE + 4, FENCE_I, 4).unwrap();
+        let memory = cpu.memory.allocated_bytes();
+        cpu.step().unwrap();
+        assert!(cpu.allocated_bytes() > memory);
+        cpu.step().unwrap();
+        assert_eq!(cpu.allocated_bytes(), memory);
+    }
+
+    /// Step until the pc reaches `addr`
+    fn run_until(cpu: &mut Cpu, addr: u32) {
+        for _ in 0..100_000 {
+            if cpu.hart.pc == addr {
+                return;
+            }
+            cpu.step().unwrap();
+        }
+        panic!("pc never reached {addr:08X}");
+    }
+
+    #[cfg(feature = "jit")]
+    fn self_modifying_loop(mode: Option<JitMode>) -> u32 {
+        let program = [
+            addi(3, 3, 1),
+            addi(4, 4, -1),
+            b(0b001, 4, 0, -8),
+            s(0b010, 1, 2, 0),
+            addi(4, 0, 20),
+            addi(5, 5, 1),
+            addi(6, 5, -2),
+            b(0b001, 6, 0, -28),
+        ];
+        let mut cpu = cpu_with_program(&program);
+        if let Some(mode) = mode {
+            cpu.enable_jit(mode).unwrap();
+        }
+        cpu.hart.registers.write(1, RAM_BASE);
+        cpu.hart.registers.write(2, addi(3, 3, 16));
+        cpu.hart.registers.write(4, 20);
+        run_until(&mut cpu, RAM_BASE + 32);
+        cpu.hart.registers.read(3)
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn jit_matches_interpreter() {
+        assert_eq!(self_modifying_loop(None), 20 + 20 * 16);
+        assert_eq!(self_modifying_loop(Some(JitMode::Enabled)), 20 + 20 * 16);
+        assert_eq!(self_modifying_loop(Some(JitMode::CrossCheck)), 20 + 20 * 16);
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn jit_leaves_misaligned_jalr_to_interpreter() {
+        for mode in [JitMode::Enabled, JitMode::CrossCheck] {
+            let program = [
+                addi(4, 4, -1),
+                i(0b0010011, 0b011, 8, 4, 1),
+                i(0b0010011, 0b001, 8, 8, 1),
+                r(0, 0b000, 9, 2, 8),
+                jalr(9, 9, 0),
+            ];
+            let mut cpu = cpu_with_program(&program);
+            cpu.enable_jit(mode).unwrap();
+            cpu.hart.registers.write(2, RAM_BASE);
+            cpu.hart.registers.write(4, 40);
+
+            run_until(&mut cpu, TRAP_HANDLER);
+            // the faulting jalr must not have linked
+            assert_trapped(&cpu, 0, RAM_BASE + 16, RAM_BASE + 2);
+            assert_eq!(cpu.hart.registers.read(9), RAM_BASE + 2);
+            assert_eq!(cpu.hart.registers.read(4), 0);
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn jit_leaves_lower_privileges_to_interpreter() {
+        let mut cpu = cpu_with_program(&[addi(3, 3, 1), jal(0, -4)]);
+        cpu.enable_jit(JitMode::Enabled).unwrap();
+        for _ in 0..1000 {
+            cpu.step().unwrap();
+        }
+
+        // without pmp entries, user mode can't fetch the loop that machine mode compiled
+        cpu.hart.csrs.privilege = Privilege::User;
+        cpu.hart.pc = RAM_BASE;
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 1, RAM_BASE, RAM_BASE);
+    }
+
+    #[test]
+    fn invalid_addresses() {
+        let lw = i(0b0000011, 0b010, 3, 1, 0);
+        let mut cpu = cpu_with_program(&[lw]);
+        cpu.hart.registers.write(1, 0x1000);
+        cpu.hart.registers.write(3, 5);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 5, RAM_BASE, 0x1000);
+        assert_eq!(cpu.hart.registers.read(3), 5);
+
+        let sw = s(0b010, 1, 2, 0);
+        let mut cpu = cpu_with_program(&[sw]);
+        cpu.hart.registers.write(1, 0x1000);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 7, RAM_BASE, 0x1000);
+
+        // only the debug peripheral's id register can be read
+        let mut cpu = cpu_with_program(&[lw]);
+        cpu.hart.registers.write(1, DEBUG_BASE);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 5, RAM_BASE, DEBUG_BASE);
+
+        let mut cpu = cpu_with_program(&[jalr(0, 1, 0)]);
+        cpu.hart.registers.write(1, 0x1000);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 1, 0x1000, 0x1000);
+    }
+
+    #[test]
+    fn unreachable_trap_handler() {
+        let mut cpu = cpu_with_program(&[0xFFFFFFFF]);
+        cpu.hart.csrs.mtvec = 0x1000;
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.pc, 0x1000);
+        let error = cpu.step().unwrap_err();
+        assert!(
+            error.to_string().contains("could not fetch trap handler"),
+            "{error}"
+        );
+
+        // vectored mode is not supported by the RTL
+        let mut cpu = cpu_with_program(&[0xFFFFFFFF]);
+        cpu.hart.csrs.mtvec = TRAP_HANDLER | 1;
+        assert!(cpu.step().is_err());
+    }
+
+    #[test]
+    fn debug_peripheral_status() {
+        let pass = s(0b010, 1, 0, 0);
+        let cpu = exec(pass, &[(1, DEBUG_BASE)]);
+        assert_eq!(cpu.status(), Some(Status::Success));
+
+        let fail = s(0b010, 1, 0, 4);
+        let cpu = exec(fail, &[(1, DEBUG_BASE)]);
+        assert_eq!(cpu.status(), Some(Status::Failure));
+
+        let exit = s(0b010, 1, 2, 8);
+        let cpu = exec(exit, &[(1, DEBUG_BASE), (2, 3)]);
+        assert_eq!(cpu.status(), Some(Status::Exited(3)));
+
+        let id = i(0b0000011, 0b010, 3, 1, 0x10);
+        let cpu = exec(id, &[(1, DEBUG_BASE)]);
+        assert_eq!(cpu.hart.registers.read(3), DEBUG_ID);
+
+        // console bytes go to the uart's backend without touching its registers
+        let transmitted = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = cpu_with_program(&[s(0b000, 1, 2, 0xC)]);
+        cpu.set_uart_backend(Box::new(Loopback {
+            received: Default::default(),
+            transmitted: transmitted.clone(),
+        }));
+        cpu.hart.registers.write(1, DEBUG_BASE);
+        cpu.hart.registers.write(2, b'!' as u32);
+        cpu.step().unwrap();
+        assert_eq!(*transmitted.borrow(), b"!");
+        assert_eq!(cpu.status(), None);
+    }
+
+    #[test]
+    fn user_mode() {
+        let csrr_mstatus = csr(0b010, 3, 0, MSTATUS);
+        let mut cpu = cpu_with_program(&[MRET, ECALL, csrr_mstatus, MRET, WFI]);
+        // user mode can only reach memory that the pmp gives it, here all of it
+        cpu.hart.csrs.pmp.write_addr(0, u32::MAX);
+        cpu.hart.csrs.pmp.write_cfg(0, 0b11 << 3 | 0b111);
+
+        // mret with mstatus.MPP clear drops to user mode
+        cpu.hart.csrs.mepc = RAM_BASE + 4;
+        cpu.step().unwrap();
+        assert_eq!(cpu.privilege(), Privilege::User);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 4);
+
+        // and traps return to machine mode, recording the mode they came from
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 8, RAM_BASE + 4, 0);
+        assert_eq!(cpu.privilege(), Privilege::Machine);
+        assert_eq!(cpu.hart.csrs.mstatus & MSTATUS_MPP, 0);
+
+        // machine-mode CSRs and mret are illegal in user mode
+        for (pc, inst) in [(8, csrr_mstatus), (12, MRET)] {
+            cpu.hart.csrs.privilege = Privilege::User;
+            cpu.hart.registers.write(3, 5);
+            cpu.hart.pc = RAM_BASE + pc;
+            cpu.step().unwrap();
+            assert_trapped(&cpu, 2, RAM_BASE + pc, inst);
+            assert_eq!(cpu.hart.registers.read(3), 5);
+        }
+
+        // as is wfi, which only an external interrupt would end
+        cpu.hart.csrs.privilege = Privilege::User;
+        cpu.hart.pc = RAM_BASE + 16;
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 2, RAM_BASE + 16, WFI);
+    }
+
+    #[test]
+    fn plic() {
+        let mut cpu = cpu_with_program(&[jal(0, 0)]);
+        cpu.enable_plic(PlicConfig::default()).unwrap();
+        cpu.hart.csrs.mstatus |= MSTATUS_MIE;
+        cpu.hart.csrs.mie |= 1 << Interrupt::MachineExternal.code();
+        let [priority1, priority5, enable, claim] = ["priority1", "priority5", "enable", "claim"]
+            .map(|name| {
+                cpu.peripheral_register(&format!("plic.{name}"))
+                    .unwrap()
+                    .start
+            });
+
+        // the transmitter is always empty, so its interrupt holds the uart's line high, but
+        // the plic only forwards enabled sources with a priority
+        cpu.write(UART_BASE + 8, 0b10, 4).unwrap();
+        cpu.step().unwrap();
+        assert!(cpu.is_hung());
+        cpu.write(priority1, 1, 4).unwrap();
+        cpu.write(enable, 0b100010, 4).unwrap();
+        assert!(!cpu.is_hung());
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 0x8000000B, RAM_BASE, 0);
+
+        // claiming a source masks it until it completes
+        assert_eq!(cpu.read(claim, 4).unwrap(), 1);
+        let external = Interrupt::MachineExternal.code();
+        cpu.update_interrupts();
+        assert!(!cpu.hart.csrs.interrupt_pending(external));
+        cpu.write(claim, 1, 4).unwrap();
+        cpu.update_interrupts();
+        assert!(cpu.hart.csrs.interrupt_pending(external));
+
+        // and sources outside the emulator are claimed in order of priority
+        cpu.write(priority5, 2, 4).unwrap();
+        cpu.set_interrupt_line(5, true);
+        assert_eq!(cpu.read(claim, 4).unwrap(), 5);
+        assert_eq!(cpu.read(claim, 4).unwrap(), 1);
+        assert_eq!(cpu.read(claim, 4).unwrap(), 0);
+    }
+
+    #[test]
+    fn harts() {
+        let counter = RAM_BASE + 0x100;
+        let program = [
+            csr(0b010, 10, 0, MHARTID),
+            amo(0b00000, 0, 11, 12),
+            // hart 1 waits for a software interrupt from hart 0
+            b(0b001, 10, 0, 20),
+            addi(0, 0, 0),
+            addi(0, 0, 0),
+            s(0b010, 13, 14, 0),
+            jal(0, 0),
+            WFI,
+            jal(0, 0),
+        ];
+        let mut cpu = cpu_with_program(&program);
+        cpu.set_harts(2).unwrap();
+        for id in 0..2 {
+            cpu.select_hart(id).unwrap();
+            cpu.set_register(11, counter);
+            cpu.set_register(12, 1 << id);
+            cpu.set_register(13, CLINT_BASE + 4);
+            cpu.set_register(14, 1);
+            cpu.hart.csrs.mie = 1 << Interrupt::MachineSoftware.code();
+        }
+        cpu.select_hart(0).unwrap();
+
+        // round robin a step at a time, with both harts adding to the counter atomically
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.read_memory(counter, 4).unwrap(), 0b11);
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.hart_id(), 0);
+        assert!(cpu.hart_state(1).waiting);
+        assert!(!cpu.is_hung());
+
+        // hart 0 raises msip1, which ends hart 1's wait
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        for id in 0..2 {
+            let hart = cpu.hart_state(id);
+            assert_eq!(hart.registers.read(10), id as u32);
+            assert_eq!(hart.pc, RAM_BASE + [24, 32][id]);
+            assert!(hart.spinning);
+        }
+        assert!(cpu.is_hung());
+        assert_eq!(cpu.total_instructions_retired(), 12);
+
+        // both harts reserve the counter, and hart 0's store conditional cancels hart 1's
+        // reservation
+        let lr = amo(0b00010, 3, 11, 0);
+        let sc = amo(0b00011, 4, 11, 12);
+        let mut cpu = cpu_with_program(&[lr, sc]);
+        cpu.set_harts(2).unwrap();
+        for id in 0..2 {
+            cpu.select_hart(id).unwrap();
+            cpu.set_register(11, counter);
+        }
+        cpu.select_hart(0).unwrap();
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.hart_state(0).registers.read(4), 0);
+        assert_eq!(cpu.hart_state(1).registers.read(4), 1);
+    }
+
+    #[test]
+    fn sv32() {
+        use crate::csr::{MSTATUS_TVM, SATP_MODE};
+
+        const SFENCE_VMA: u32 = 0x12000073;
+        let lw = i(0b0000011, 0b010, 3, 1, 0);
+        let sw = s(0b010, 1, 3, 4);
+        let mut cpu = cpu_with_program(&[lw, sw, ECALL, SFENCE_VMA]);
+        cpu.hart.csrs.pmp.write_addr(0, u32::MAX);
+        cpu.hart.csrs.pmp.write_cfg(0, 0b11 << 3 | 0b111);
+        cpu.enable_tlb(4).unwrap();
+
+        // code at 0x00400000 and data at 0x00401000, with nothing at 0x00402000
+        let root = RAM_BASE + 0x2000;
+        let table = RAM_BASE + 0x3000;
+        let pte = |addr: u32, flags: u32| (addr >> 12) << 10 | flags | 0b1;
+        cpu.memory.write(root + 4, pte(table, 0), 4).unwrap();
+        cpu.memory.write(table, pte(RAM_BASE, 0x4A), 4).unwrap();
+        cpu.memory
+            .write(table + 4, pte(RAM_BASE + 0x4000, 0xC6), 4)
+            .unwrap();
+        cpu.memory.write(RAM_BASE + 0x4000, 0x12345678, 4).unwrap();
+        cpu.hart.csrs.satp = SATP_MODE | root >> 12;
+        cpu.hart.csrs.privilege = Privilege::Supervisor;
+        cpu.hart.pc = 0x00400000;
+
+        cpu.hart.registers.write(1, 0x00401000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(3), 0x12345678);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory.read(RAM_BASE + 0x4004, 4).unwrap(), 0x12345678);
+        assert_eq!(cpu.hart.pc, 0x00400008);
+
+        // unmapped pages fault with the virtual address
+        cpu.hart.pc = 0x00400000;
+        cpu.hart.registers.write(1, 0x00402000);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 13, 0x00400000, 0x00402000);
+
+        // which supervisor mode handles itself once delegated, but not its own ecalls
+        cpu.hart.csrs.medeleg = 1 << 13;
+        cpu.hart.csrs.stvec = 0x00400008;
+        cpu.hart.csrs.privilege = Privilege::Supervisor;
+        cpu.hart.pc = 0x00400000;
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.pc, 0x00400008);
+        assert_eq!(cpu.privilege(), Privilege::Supervisor);
+        assert_eq!(
+            (
+                cpu.hart.csrs.scause,
+                cpu.hart.csrs.sepc,
+                cpu.hart.csrs.stval
+            ),
+            (13, 0x00400000, 0x00402000)
+        );
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 9, 0x00400008, 0);
+
+        let tlb = cpu.tlb().unwrap();
+        assert_eq!(tlb.fetch_counts().misses, 1);
+        assert_eq!(tlb.data_counts().misses, 1);
+
+        // sfence.vma empties the tlb, unless mstatus.TVM traps it
+        cpu.hart.csrs.privilege = Privilege::Supervisor;
+        cpu.hart.pc = 0x0040000C;
+        cpu.step().unwrap();
+        cpu.hart.pc = 0x00400000;
+        cpu.hart.registers.write(1, 0x00401000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.tlb().unwrap().fetch_counts().misses, 2);
+        assert_eq!(cpu.tlb().unwrap().data_counts().misses, 2);
+
+        cpu.hart.csrs.mstatus |= MSTATUS_TVM;
+        cpu.hart.pc = 0x0040000C;
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 2, 0x0040000C, SFENCE_VMA);
+    }
+
+    #[test]
+    fn supervisor_pmp() {
+        use crate::csr::SATP_MODE;
+
+        let lw = i(0b0000011, 0b010, 3, 1, 0);
+        let mut cpu = cpu_with_program(&[lw]);
+        // nothing is allowed at RAM_BASE + 0x3000, in front of an entry allowing everything
+        cpu.hart.csrs.pmp.write_addr(0, (RAM_BASE + 0x3000) >> 2);
+        cpu.hart.csrs.pmp.write_addr(1, u32::MAX);
+        cpu.hart
+            .csrs
+            .pmp
+            .write_cfg(0, 0b10 << 3 | (0b11 << 3 | 0b111) << 8);
+
+        // unlocked entries bind supervisor mode like user mode
+        cpu.hart.csrs.privilege = Privilege::Supervisor;
+        cpu.hart.registers.write(1, RAM_BASE + 0x3000);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 5, RAM_BASE, RAM_BASE + 0x3000);
+
+        // and so do the page table reads of a walk
+        let root = RAM_BASE + 0x2000;
+        let table = RAM_BASE + 0x3000;
+        let pte = |addr: u32, flags: u32| (addr >> 12) << 10 | flags | 0b1;
+        cpu.memory.write(root + 4, pte(table, 0), 4).unwrap();
+        cpu.memory.write(table, pte(RAM_BASE, 0x4A), 4).unwrap();
+        cpu.hart.csrs.satp = SATP_MODE | root >> 12;
+        cpu.hart.csrs.privilege = Privilege::Supervisor;
+        cpu.hart.pc = 0x00400000;
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 1, 0x00400000, 0x00400000);
+    }
+
+    #[test]
+    fn translated_semihosting() {
+        use crate::csr::SATP_MODE;
+
+        const EBREAK: u32 = 0x00100073;
+        const SYS_WRITE0: u32 = 0x04;
+        let mut cpu = cpu_with_program(&[semihosting::ENTRY, EBREAK, semihosting::EXIT]);
+        cpu.hart.csrs.pmp.write_addr(0, u32::MAX);
+        cpu.hart.csrs.pmp.write_cfg(0, 0b11 << 3 | 0b111);
+        let stdout = Output::default();
+        cpu.enable_semihosting(Semihosting::with_console(
+            Box::new(std::io::empty()),
+            Box::new(stdout.clone()),
+            Box::new(std::io::sink()),
+        ));
+
+        // the call at 0x00400004 prints a string at 0x00401000, with nothing at 0x00402000
+        let root = RAM_BASE + 0x2000;
+        let table = RAM_BASE + 0x3000;
+        let pte = |addr: u32, flags: u32| (addr >> 12) << 10 | flags | 0b1;
+        cpu.memory.write(root + 4, pte(table, 0), 4).unwrap();
+        cpu.memory.write(table, pte(RAM_BASE, 0x4A), 4).unwrap();
+        cpu.memory
+            .write(table + 4, pte(RAM_BASE + 0x4000, 0xC6), 4)
+            .unwrap();
+        cpu.memory
+            .write(RAM_BASE + 0x4000, u32::from_le_bytes(*b"hi\0\0"), 4)
+            .unwrap();
+        cpu.hart.csrs.satp = SATP_MODE | root >> 12;
+
+        cpu.hart.csrs.privilege = Privilege::Supervisor;
+        cpu.hart.pc = 0x00400004;
+        cpu.hart.registers.write(10, SYS_WRITE0);
+        cpu.hart.registers.write(11, 0x00401000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.pc, 0x00400008);
+        assert_eq!(cpu.hart.registers.read(10), 0);
+        assert_eq!(stdout.take(), "hi");
+
+        // faults reaching the string are raised instead of making the call
+        cpu.hart.pc = 0x00400004;
+        cpu.hart.registers.write(10, SYS_WRITE0);
+        cpu.hart.registers.write(11, 0x00402000);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 13, 0x00400004, 0x00402000);
+        assert_eq!(stdout.take(), "");
+    }
+
+    #[test]
+    fn pmp_faults() {
+        let lw = i(0b0000011, 0b010, 3, 1, 0);
+        let sw = s(0b010, 1, 3, 0);
+        let mut cpu = cpu_with_program(&[lw, lw, sw]);
+        // user mode can read and execute below RAM_BASE + 0x100, and nothing else
+        cpu.hart.csrs.pmp.write_addr(0, (RAM_BASE + 0x100) >> 2);
+        cpu.hart.csrs.pmp.write_cfg(0, 0b01 << 3 | 0b101);
+        cpu.hart.csrs.privilege = Privilege::User;
+
+        cpu.hart.registers.write(1, RAM_BASE + 0x80);
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.pc, RAM_BASE + 4);
+        cpu.hart.registers.write(1, RAM_BASE + 0x100);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 5, RAM_BASE + 4, RAM_BASE + 0x100);
+
+        cpu.hart.csrs.privilege = Privilege::User;
+        cpu.hart.pc = RAM_BASE + 8;
+        cpu.hart.registers.write(1, RAM_BASE + 0x80);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 7, RAM_BASE + 8, RAM_BASE + 0x80);
+
+        cpu.hart.csrs.privilege = Privilege::User;
+        cpu.hart.pc = RAM_BASE + 0x100;
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 1, RAM_BASE + 0x100, RAM_BASE + 0x100);
+
+        // the handler runs in machine mode, which the unlocked entry does not restrict
+        assert_eq!(cpu.privilege(), Privilege::Machine);
+        cpu.hart.pc = RAM_BASE + 8;
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.pc, RAM_BASE + 12);
+    }
+
+    #[test]
+    fn csr_instructions() {
+        use crate::csr::{MCAUSE, MISA, MSCRATCH};
+
+        // csrrw swaps, csrrs/csrrc set and clear bits
+        let cpu = exec(csr(0b001, 3, 1, MSCRATCH), &[(1, 0x1234)]);
+        assert_eq!(cpu.hart.csrs.mscratch, 0x1234);
+        assert_eq!(cpu.hart.registers.read(3), 0);
+
+        let mut cpu = cpu_with_program(&[
+            csr(0b010, 3, 1, MSCRATCH),
+            csr(0b011, 4, 2, MSCRATCH),
+            csr(0b101, 5, 0b10101, MCAUSE),
+            csr(0b110, 6, 0b01010, MCAUSE),
+            csr(0b111, 7, 0b00011, MCAUSE),
+            csr(0b010, 8, 0, MISA),
+        ]);
+        cpu.hart.csrs.mscratch = 0xF0;
+        cpu.hart.registers.write(1, 0x0F);
+        cpu.hart.registers.write(2, 0x3C);
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.hart.registers.read(3), 0xF0);
+        assert_eq!(cpu.hart.registers.read(4), 0xFF);
+        assert_eq!(cpu.hart.csrs.mscratch, 0xC3);
+        assert_eq!(cpu.hart.registers.read(5), 0);
+        assert_eq!(cpu.hart.registers.read(6), 0b10101);
+        assert_eq!(cpu.hart.registers.read(7), 0b11111);
+        assert_eq!(cpu.hart.csrs.mcause, 0b11100);
+        assert_eq!(cpu.hart.registers.read(8), 0x40140101);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 24);
+    }
+
+    #[test]
+    fn illegal_csr_accesses() {
+        use crate::csr::{MHARTID, MISA};
+
+        // unknown csr
+        let inst = csr(0b010, 3, 0, 0x7C0);
+        let mut cpu = cpu_with_program(&[inst]);
+        cpu.hart.registers.write(3, 5);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 2, RAM_BASE, inst);
+        assert_eq!(cpu.hart.registers.read(3), 5);
+
+        // writing a read-only csr, even with csrrw into x0
+        let inst = csr(0b001, 0, 1, MHARTID);
+        let mut cpu = cpu_with_program(&[inst]);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 2, RAM_BASE, inst);
+
+        // but reading one with csrrs from x0 is fine, as is setting no bits of a read-only field
+        let cpu = exec(csr(0b010, 3, 0, MHARTID), &[]);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 4);
+        let cpu = exec(csr(0b010, 3, 1, MISA), &[(1, 0xFF)]);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 4);
+        assert_eq!(cpu.hart.csrs.read(crate::csr::MISA, 0), Some(0x40140101));
+    }
+
+    #[test]
+    fn snapshots() {
+        // count in x1, storing each value to memory
+        let program = [
+            lui(2, RAM_BASE),
+            addi(1, 1, 1),
+            s(0b010, 2, 1, 0x100),
+            jal(0, -8),
+        ];
+        let mut cpu = cpu_with_program(&program);
+        cpu.hart.csrs.mscratch = 0x1234;
+        for _ in 0..10 {
+            cpu.step().unwrap();
+        }
+        let mut snapshot = Vec::new();
+        cpu.save_snapshot(&mut snapshot).unwrap();
+        for _ in 0..10 {
+            cpu.step().unwrap();
+        }
+
+        // restoring into an empty machine carries on from the same point
+        let mut restored = cpu_with_program(&[]);
+        restored.restore_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.hart.csrs.mscratch, 0x1234);
+        for _ in 0..10 {
+            restored.step().unwrap();
+        }
+        assert_eq!(restored.hart.pc, cpu.hart.pc);
+        assert_eq!(
+            restored.hart.registers.registers,
+            cpu.hart.registers.registers
+        );
+        assert_eq!(restored.instructions_retired(), 20);
+        assert_eq!(restored.cycles(), cpu.cycles());
+        assert_eq!(
+            restored.read_memory(RAM_BASE + 0x100, 4).unwrap(),
+            cpu.read_memory(RAM_BASE + 0x100, 4).unwrap()
+        );
+
+        // a truncated snapshot is rejected
+        let mut restored = cpu_with_program(&[]);
+        assert!(restored
+            .restore_snapshot(&snapshot[..snapshot.len() - 1])
+            .is_err());
+        assert_eq!(restored.hart.pc, RAM_BASE);
+    }
+
+    #[test]
+    fn disabled_extensions() {
+        use crate::csr::MHARTID;
+
+        let inst = csr(0b010, 3, 0, MHARTID);
+        let mut cpu = cpu_with_program(&[inst]);
+        cpu.set_isa("rv32i_zifencei".parse().unwrap());
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 2, RAM_BASE, inst);
+
+        let fence_i = 0x0000100F;
+        let mut cpu = cpu_with_program(&[fence_i]);
+        cpu.set_isa("rv32i_zicsr".parse().unwrap());
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 2, RAM_BASE, fence_i);
+    }
+
+    #[test]
+    fn exceptions_and_mret() {
+        let mut cpu = cpu_with_program(&[ECALL]);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 11, RAM_BASE, 0);
+
+        let mut cpu = cpu_with_program(&[EBREAK]);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 3, RAM_BASE, RAM_BASE);
+
+        let mut cpu = cpu_with_program(&[0x00000000]);
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 2, RAM_BASE, 0);
+        assert_eq!(cpu.instructions_retired(), 0);
+
+        // a handler that skips the faulting instruction and returns with interrupts enabled
+        let mut cpu = cpu_with_program(&[EBREAK, addi(3, 0, 1)]);
+        let handler = [
+            csr(0b010, 1, 0, crate::csr::MEPC),
+            addi(1, 1, 4),
+            csr(0b001, 0, 1, crate::csr::MEPC),
+            MRET,
+        ];
+        for (index, inst) in handler.iter().enumerate() {
+            cpu.memory
+                .write(TRAP_HANDLER + 4 * index as u32, *inst, 4)
+                .unwrap();
+        }
+        cpu.hart.csrs.mstatus = MSTATUS_MIE;
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.csrs.mstatus & MSTATUS_MIE, 0);
+        run_until(&mut cpu, RAM_BASE + 8);
+        assert_eq!(cpu.hart.registers.read(3), 1);
+        assert_eq!(cpu.hart.csrs.mstatus & MSTATUS_MIE, MSTATUS_MIE);
+        // the ebreak itself did not retire
+        assert_eq!(cpu.instructions_retired(), 5);
+    }
+
+    #[test]
+    fn timer_interrupt() {
+        // spin until interrupted by the CLINT's timer
+        let mut cpu = cpu_with_program(&[jal(0, 0)]);
+        cpu.clint.write(CLINT_BASE + 0x4000, 100, u32::MAX).unwrap();
+        cpu.hart.csrs.mie = 1 << 7;
+        for _ in 0..200 {
+            cpu.step().unwrap();
+        }
+        // masked by mstatus.MIE, but still visible in mip
+        assert_eq!(cpu.hart.pc, RAM_BASE);
+        assert_eq!(cpu.hart.csrs.mip, 1 << 7);
+
+        cpu.hart.csrs.mstatus = MSTATUS_MIE;
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 0x80000007, RAM_BASE, 0);
+        assert_eq!(cpu.hart.csrs.mstatus & MSTATUS_MIE, 0);
+
+        // software can read mtime, but not write it
+        assert_eq!(cpu.read(CLINT_BASE + 0xBFF8, 4).unwrap(), 201);
+        cpu.write(CLINT_BASE + 0xBFF8, 0, 4).unwrap();
+        assert_eq!(cpu.read(CLINT_BASE + 0xBFF8, 4).unwrap(), 201);
+    }
+
+    #[test]
+    fn software_interrupt_priority() {
+        let mut cpu = cpu_with_program(&[addi(0, 0, 0)]);
+        cpu.clint.write(CLINT_BASE, 1, u32::MAX).unwrap();
+        cpu.hart.csrs.mie = 1 << 3 | 1 << 7;
+        cpu.hart.csrs.mstatus = MSTATUS_MIE;
+        // the timer comparator is 0, so both interrupts are pending and the timer wins
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 0x80000007, RAM_BASE, 0);
+
+        let mut cpu = cpu_with_program(&[addi(0, 0, 0)]);
+        cpu.clint.write(CLINT_BASE, 1, u32::MAX).unwrap();
+        cpu.hart.csrs.mie = 1 << 3;
+        cpu.hart.csrs.mstatus = MSTATUS_MIE;
+        cpu.step().unwrap();
+        assert_trapped(&cpu, 0x80000003, RAM_BASE, 0);
+    }
+
+    #[test]
+    fn wfi_waits_for_external_interrupt() {
+        let mut cpu = cpu_with_program(&[WFI, addi(3, 0, 1)]);
+        cpu.hart.csrs.mie = 1 << 7 | 1 << 11;
+        for _ in 0..10 {
+            cpu.step().unwrap();
+        }
+        // the pending timer interrupt does not end the wait
+        assert_eq!(cpu.hart.pc, RAM_BASE);
+        assert!(cpu.hart.waiting);
+        assert_eq!(cpu.instructions_retired(), 0);
+        assert_eq!(cpu.cycles(), 10);
+
+        cpu.hart.csrs.mip |= 1 << 11;
+        cpu.execute().unwrap();
+        assert!(!cpu.hart.waiting);
+        assert_eq!(cpu.hart.pc, RAM_BASE + 4);
+    }
+
+    #[test]
+    fn uart_interrupt_ends_wfi() {
+        /// Receives a byte on the fifth poll
+        struct Delayed(u32);
+
+        impl UartBackend for Delayed {
+            fn transmit(&mut self, _byte: u8) -> Result<(), anyhow::Error> {
+                Ok(())
+            }
+
+            fn receive(&mut self, _cycle: u64) -> Option<u8> {
+                self.0 += 1;
+                (self.0 == 5).then_some(b'x')
+            }
+
+            fn can_receive(&self) -> bool {
+                self.0 < 5
+            }
+        }
+
+        let program = [
+            // enable the receive interrupt
+            addi(2, 0, 1),
+            s(0b010, 1, 2, 8),
+            WFI,
+            i(0b0000011, 0b010, 3, 1, 0),
+        ];
+        let mut cpu = cpu_with_program(&program);
+        cpu.set_uart_backend(Box::new(Delayed(0)));
+        cpu.hart.registers.write(1, UART_BASE);
+        cpu.hart.csrs.mie = 1 << Interrupt::MachineExternal.code();
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert!(cpu.hart.waiting);
+        assert!(!cpu.is_hung());
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.hart.registers.read(3), b'x' as u32);
+        assert!(!cpu.uart.can_interrupt());
+    }
+
+    #[test]
+    fn timing_model() {
+        let config = TimingConfig {
+            memory_latency: 5,
+            mmio_latency: 9,
+            ..TimingConfig::default()
+        };
+        let mut cpu = cpu_with_program(&[
+            addi(1, 0, 1),
+            s(0b010, 2, 1, 0),
+            i(0b0000011, 0b010, 3, 2, 0),
+            b(0b000, 0, 0, 8),
+            addi(0, 0, 0),
+            s(0b010, 4, 0, 0),
+            csr(0b010, 5, 0, crate::csr::MCYCLE),
+        ]);
+        cpu.enable_timing(config);
+        cpu.hart.registers.write(2, RAM_BASE + 0x100);
+        cpu.hart.registers.write(4, DEBUG_BASE);
+
+        let mut cycles = Vec::new();
+        for _ in 0..6 {
+            cpu.step().unwrap();
+            cycles.push(cpu.cycles());
+        }
+        // fetch, decode, execute, then memory and/or writeback
+        let expected = [
+            5 + 2 + 1,
+            5 + 2 + 5,
+            5 + 2 + 5 + 1,
+            5 + 2,
+            5 + 2 + 9,
+            5 + 2 + 1,
+        ];
+        let mut total = 0;
+        for (cycles, expected) in cycles.iter().zip(expected) {
+            total += expected;
+            assert_eq!(*cycles, total);
+        }
+        // mcycle is read in execute, before the writeback of the csrrs
+        assert_eq!(cpu.hart.registers.read(5) as u64, total - 1);
+        assert_eq!(cpu.status(), Some(Status::Success));
+
+        let stats = cpu.timing().unwrap().stats();
+        assert_eq!(stats.instructions, 6);
+        assert_eq!(stats.icache_misses, 6);
+        assert_eq!(stats.stage_cycles(Stage::Fetch), 30);
+        assert_eq!(stats.stage_cycles(Stage::Memory), 5 + 5 + 9);
+        assert_eq!(stats.stage_cycles(Stage::Writeback), 3);
+
+        // the core sees the same time as the CLINT
+        assert_eq!(
+            cpu.read(CLINT_BASE + 0xBFF8, 4).unwrap() as u64,
+            cpu.cycles()
+        );
+    }
+
+    #[test]
+    fn timing_of_traps() {
+        let mut cpu = cpu_with_program(&[EBREAK]);
+        cpu.enable_timing(TimingConfig {
+            memory_latency: 4,
+            ..TimingConfig::default()
+        });
+        cpu.step().unwrap();
+        assert_eq!(cpu.cycles(), 4 + 1);
+        let stats = cpu.timing().unwrap().stats();
+        assert_eq!(stats.traps, 1);
+        assert_eq!(stats.instructions, 0);
+    }
+
+    #[test]
+    fn icache_model() {
+        // a three instruction loop that runs ten times, then a fence.i
+        let program = [
+            addi(1, 1, 1),
+            addi(2, 1, -10),
+            b(0b001, 2, 0, -8),
+            i(0b0001111, 0b001, 0, 0, 0),
+        ];
+        let mut cpu = cpu_with_program(&program);
+        cpu.enable_timing(TimingConfig {
+            icache_hit_latency: 1,
+            memory_latency: 5,
+            ..TimingConfig::default()
+        });
+        cpu.enable_icache(ICacheConfig {
+            size: 256,
+            line_size: 16,
+            ways: 1,
+        })
+        .unwrap();
+        run_until(&mut cpu, RAM_BASE + 12);
+
+        // the first fetch fills the whole line, so only the first instruction misses
+        let icache = cpu.icache().unwrap();
+        assert_eq!(icache.counts().misses, 1);
+        assert_eq!(icache.counts().hits, 10 * 3 - 1);
+        // with three more cycles to refill the rest of the line
+        let stats = cpu.timing().unwrap().stats();
+        assert_eq!(stats.stage_cycles(Stage::Fetch), 5 + 3 + 10 * 3 - 1);
+
+        // fence.i flushes the cache
+        cpu.step().unwrap();
+        assert_eq!(cpu.icache().unwrap().counts().hits, 10 * 3);
+        assert!(!cpu.icache.as_mut().unwrap().access(RAM_BASE));
+    }
+
+    #[test]
+    fn profiler() {
+        // main calls leaf, which returns
+        let program = [jal(1, 8), addi(2, 2, 1), addi(3, 3, 1), jalr(0, 1, 0)];
+        let mut cpu = cpu_with_program(&program);
+        cpu.symbols = Symbols::new(vec![
+            Symbol {
+                name: "main".to_string(),
+                addr: RAM_BASE,
+                size: 8,
+            },
+            Symbol {
+                name: "leaf".to_string(),
+                addr: RAM_BASE + 8,
+                size: 8,
+            },
+        ]);
+        cpu.enable_timing(TimingConfig::default());
+        cpu.enable_profiler();
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+
+        let profiler = cpu.profiler().unwrap();
+        let mut folded = Vec::new();
+        profiler
+            .write_folded(cpu.symbols(), Metric::Instructions, &mut folded)
+            .unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "main 2\nmain;leaf 2\n");
+        assert_eq!(profiler.total().cycles, cpu.cycles());
+        assert_eq!(profiler.functions()[&RAM_BASE].total.instructions, 4);
+    }
+
+    #[test]
+    fn tracer() {
+        let program = [addi(1, 0, 0x1FF), s(0b000, 2, 1, 0), ECALL];
+        let mut cpu = cpu_with_program(&program);
+        cpu.hart.registers.write(2, RAM_BASE + 0x100);
+        let output = Output::default();
+        let tracer = Tracer::new(
+            Box::new(output.clone()),
+            TraceFormat::Text,
+            &[TraceFilter::Range(RAM_BASE + 4..RAM_BASE + 12)],
+            cpu.symbols(),
+        )
+        .unwrap();
+        cpu.set_tracer(Some(tracer));
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+
+        // the sb only stores the lowest byte
+        assert_eq!(
+            output.take(),
+            "E0000004 <unknown> sb (imm = 00000000, rd = 0, rs1 = 2, rs2 = 1); \
+            store E0000100 (1 bytes) = 000000FF\n\
+            E0000008 <unknown> trap Exception(EnvironmentCallFromM) -> E0001000 <unknown>\n"
+        );
+
+        cpu.tracer_mut().unwrap().set_enabled(false);
+        cpu.step().unwrap();
+        assert_eq!(output.take(), "");
+    }
+
+    #[test]
+    fn run_until_status() {
+        // store to the debug peripheral's failure register
+        let program = [lui(1, DEBUG_BASE), s(0b010, 1, 0, 4)];
+        let mut cpu = cpu_with_program(&program);
+        let reason = cpu.run(&RunLimits::default()).unwrap();
+        assert_eq!(reason, StopReason::Status(Status::Failure));
+        assert_eq!(cpu.instructions_retired(), 2);
+    }
+
+    #[test]
+    fn run_limits() {
+        let mut cpu = cpu_with_program(&[addi(1, 1, 1), jal(0, -4)]);
+        let limits = RunLimits {
+            max_instructions: Some(100),
+            timeout: None,
+        };
+        assert_eq!(cpu.run(&limits).unwrap(), StopReason::InstructionLimit);
+        assert_eq!(cpu.instructions_retired(), 100);
+
+        let limits = RunLimits {
+            max_instructions: None,
+            timeout: Some(Duration::ZERO),
+        };
+        assert_eq!(cpu.run(&limits).unwrap(), StopReason::Timeout);
+    }
+
+    #[test]
+    fn hangs() {
+        let mut cpu = cpu_with_program(&[addi(1, 1, 1), jal(0, 0)]);
+        let reason = cpu.run(&RunLimits::default()).unwrap();
+        assert_eq!(reason, StopReason::Hang { pc: RAM_BASE + 4 });
+
+        // nothing can end a wfi
+        let mut cpu = cpu_with_program(&[WFI]);
+        let reason = cpu.run(&RunLimits::default()).unwrap();
+        assert_eq!(reason, StopReason::Hang { pc: RAM_BASE });
+
+        // but a timer interrupt can end a self-loop
+        let mut cpu = cpu_with_program(&[jal(0, 0)]);
+        cpu.hart.csrs.mstatus |= MSTATUS_MIE;
+        cpu.hart.csrs.mie |= 1 << Interrupt::MachineTimer.code();
+        cpu.clint.write(CLINT_BASE + 0x4004, 1, u32::MAX).unwrap();
+        let limits = RunLimits {
+            max_instructions: Some(10),
+            timeout: None,
+        };
+        assert_eq!(cpu.run(&limits).unwrap(), StopReason::InstructionLimit);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let program = [
+            lui(1, UART_BASE),
+            addi(2, 0, 3),
+            i(0b0000011, 0b010, 3, 4, 0),
+            s(0b010, 1, 2, 8),
+            jal(0, 0),
+        ];
+        let mut cpu = cpu_with_program(&program);
+        cpu.hart.registers.write(4, RAM_BASE + 0x200);
+        let ctrl = cpu.peripheral_register("uart.ctrl").unwrap();
+        assert_eq!(ctrl, UART_BASE + 8..UART_BASE + 12);
+        assert_eq!(cpu.peripheral_register("uart.data"), None);
+        cpu.add_watchpoint(Watchpoint {
+            range: ctrl,
+            kind: WatchKind::Write,
+        });
+        let read = Watchpoint {
+            range: RAM_BASE + 0x202..RAM_BASE + 0x203,
+            kind: WatchKind::Read,
+        };
+        cpu.add_watchpoint(read.clone());
+
+        // the access completes before the run stops
+        let StopReason::Watchpoint(hit) = cpu.run(&RunLimits::default()).unwrap() else {
+            panic!("expected a watchpoint");
+        };
+        assert_eq!((hit.pc, hit.addr), (RAM_BASE + 8, RAM_BASE + 0x202));
+        assert_eq!(hit.access.kind, AccessKind::Load);
+        assert_eq!(cpu.pc(), RAM_BASE + 12);
+
+        let StopReason::Watchpoint(hit) = cpu.run(&RunLimits::default()).unwrap() else {
+            panic!("expected a watchpoint");
+        };
+        assert_eq!((hit.pc, hit.kind), (RAM_BASE + 12, WatchKind::Write));
+        assert_eq!(hit.access.value, 3);
+        assert_eq!(cpu.uart.read(UART_BASE + 8).unwrap(), 3);
+
+        assert!(cpu.remove_watchpoint(&read));
+        assert!(!cpu.remove_watchpoint(&read));
+        cpu.clear_watchpoints();
+        cpu.set_pc(RAM_BASE);
+        let reason = cpu.run(&RunLimits::default()).unwrap();
+        assert_eq!(reason, StopReason::Hang { pc: RAM_BASE + 16 });
+    }
+
+    #[test]
+    fn hooks() {
+        /// Records every event, with the state of the core it can see
+        struct Recorder(Rc<RefCell<Vec<String>>>);
+
+        impl Hook for Recorder {
+            fn before_instruction(&mut self, cpu: &Cpu, pc: u32) {
+                assert_eq!(cpu.pc(), pc);
+            }
+
+            fn after_retire(&mut self, cpu: &Cpu, pc: u32, inst: &DecodedInstruction) {
+                self.0.borrow_mut().push(format!(
+                    "retire {:X} {:?} -> {:X}",
+                    pc - RAM_BASE,
+                    inst.kind,
+                    cpu.pc() - RAM_BASE
+                ));
+            }
+
+            fn memory_access(&mut self, _cpu: &Cpu, access: &MemoryAccess) {
+                self.0
+                    .borrow_mut()
+                    .push(format!("memory {:X}", access.addr - RAM_BASE));
+            }
+
+            fn mmio_access(&mut self, _cpu: &Cpu, access: &MemoryAccess) {
+                self.0.borrow_mut().push(format!("mmio {:X}", access.addr));
+            }
+
+            fn csr_access(&mut self, cpu: &Cpu, access: &CsrAccess) {
+                self.0.borrow_mut().push(format!(
+                    "csr {:X} {:?} {:?} {:X}",
+                    access.addr,
+                    access.read,
+                    access.written,
+                    cpu.csrs().mscratch
+                ));
+            }
+
+            fn trap_entry(&mut self, cpu: &Cpu, trap: Trap, pc: u32, handler: u32) {
+                assert_eq!(cpu.csrs().mepc, pc);
+                self.0.borrow_mut().push(format!(
+                    "trap {trap:?} {:X} -> {:X}",
+                    pc - RAM_BASE,
+                    handler - RAM_BASE
+                ));
+            }
+
+            fn trap_exit(&mut self, _cpu: &Cpu, pc: u32, target: u32) {
+                self.0.borrow_mut().push(format!(
+                    "mret {:X} -> {:X}",
+                    pc - RAM_BASE,
+                    target - RAM_BASE
+                ));
+            }
+        }
+
+        let program = [
+            csr(0b001, 0, 1, 0x340),
+            s(0b010, 2, 1, 0),
+            s(0b010, 3, 1, 0),
+            ECALL,
+        ];
+        let mut cpu = cpu_with_program(&program);
+        cpu.memory.write(TRAP_HANDLER, MRET, 4).unwrap();
+        cpu.hart.registers.write(1, 1);
+        cpu.hart.registers.write(2, RAM_BASE + 0x100);
+        cpu.hart.registers.write(3, CLINT_BASE);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        cpu.add_hook(Box::new(Recorder(events.clone())));
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+
+        assert_eq!(
+            *events.borrow(),
+            [
+                "csr 340 None Some(1) 1",
+                "retire 0 Csrrw -> 4",
+                "memory 100",
+                "retire 4 Sw -> 8",
+                "mmio 20000000",
+                "retire 8 Sw -> C",
+                "trap Exception(EnvironmentCallFromM) C -> 1000",
+                "mret 1000 -> C",
+                "retire 1000 Mret -> C",
+            ]
+        );
+
+        cpu.clear_hooks();
+        cpu.step().unwrap();
+        assert_eq!(events.borrow().len(), 9);
     }
 }
@@ -0,0 +1,699 @@
+//! Control and status registers, matching `shared/csr/hdl/registers.rdl`
+//!
+//! The supervisor CSRs and the pmp are only modelled here, as the RTL has neither yet.
+
+use anyhow::bail;
+
+use crate::{
+    pmp::{Access, Pmp},
+    snapshot::{SnapshotReader, SnapshotWriter},
+    trap::{Interrupt, Trap},
+};
+
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+pub const SATP: u16 = 0x180;
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+pub const MCONFIGPTR: u16 = 0xF15;
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+pub const MSTATUSH: u16 = 0x310;
+pub const MEDELEGH: u16 = 0x312;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const PMPCFG0: u16 = 0x3A0;
+pub const PMPCFG3: u16 = 0x3A3;
+pub const PMPADDR0: u16 = 0x3B0;
+pub const PMPADDR15: u16 = 0x3BF;
+pub const MCYCLE: u16 = 0xB00;
+pub const MCYCLEH: u16 = 0xB80;
+pub const TIME: u16 = 0xC01;
+pub const TIMEH: u16 = 0xC81;
+
+/// Name of a CSR, for disassembly
+pub fn name(addr: u16) -> Option<&'static str> {
+    Some(match addr {
+        SSTATUS => "sstatus",
+        SIE => "sie",
+        STVEC => "stvec",
+        SCOUNTEREN => "scounteren",
+        SSCRATCH => "sscratch",
+        SEPC => "sepc",
+        SCAUSE => "scause",
+        STVAL => "stval",
+        SIP => "sip",
+        SATP => "satp",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        MCONFIGPTR => "mconfigptr",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MEDELEG => "medeleg",
+        MIDELEG => "mideleg",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MCOUNTEREN => "mcounteren",
+        MSTATUSH => "mstatush",
+        MEDELEGH => "medelegh",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        PMPCFG0..=PMPCFG3 => PMPCFG_NAMES[(addr - PMPCFG0) as usize],
+        PMPADDR0..=PMPADDR15 => PMPADDR_NAMES[(addr - PMPADDR0) as usize],
+        MCYCLE => "mcycle",
+        MCYCLEH => "mcycleh",
+        TIME => "time",
+        TIMEH => "timeh",
+        _ => return None,
+    })
+}
+
+const PMPCFG_NAMES: [&str; 4] = ["pmpcfg0", "pmpcfg1", "pmpcfg2", "pmpcfg3"];
+const PMPADDR_NAMES: [&str; 16] = [
+    "pmpaddr0",
+    "pmpaddr1",
+    "pmpaddr2",
+    "pmpaddr3",
+    "pmpaddr4",
+    "pmpaddr5",
+    "pmpaddr6",
+    "pmpaddr7",
+    "pmpaddr8",
+    "pmpaddr9",
+    "pmpaddr10",
+    "pmpaddr11",
+    "pmpaddr12",
+    "pmpaddr13",
+    "pmpaddr14",
+    "pmpaddr15",
+];
+
+/// RV32 with the I and A extensions, supervisor mode and user mode
+const MISA_VALUE: u32 = 0x40140101;
+
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TW: u32 = 1 << 21;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+
+/// Sv32 rather than bare addressing
+pub const SATP_MODE: u32 = 1 << 31;
+pub const SATP_ASID: u32 = 0x1FF << 22;
+pub const SATP_PPN: u32 = 0x3FFFFF;
+
+/// Fields of mstatus that software can write (sie, mie, spie, mpie, spp, mpp, mprv, sum, mxr,
+/// tvm, tw, tsr)
+const MSTATUS_WRITABLE: u32 = 0x7E19AA;
+/// Fields of mstatus that sstatus shows (sie, spie, spp, sum, mxr)
+const SSTATUS_FIELDS: u32 = 0xC0122;
+/// Counters that software can make readable to the mode below (tm)
+const COUNTEREN_WRITABLE: u32 = 0b10;
+/// Fields of mie that software can write (ssie, msie, stie, mtie, seie, meie)
+const MIE_WRITABLE: u32 = 0xAAA;
+/// Supervisor interrupts, which only software raises as nothing drives them
+pub const SUPERVISOR_INTERRUPTS: u32 = 0x222;
+/// Exceptions that can be handled in supervisor mode, which are all but ecalls from machine
+/// mode
+const MEDELEG_WRITABLE: u32 = 0xB3FF;
+/// Fields of mstatush that software can write (gva, mpv, mpelp, mdt)
+const MSTATUSH_WRITABLE: u32 = 0x6C0;
+
+/// Privilege level the core runs at, as encoded in mstatus.MPP
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0b00,
+    Supervisor = 0b01,
+    #[default]
+    Machine = 0b11,
+}
+
+impl Privilege {
+    fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0b00 => Some(Self::User),
+            0b01 => Some(Self::Supervisor),
+            0b11 => Some(Self::Machine),
+            _ => None,
+        }
+    }
+
+    /// Decode mstatus.MPP, returning `None` for a mode that is not implemented
+    fn from_mpp(mstatus: u32) -> Option<Self> {
+        Self::from_bits((mstatus & MSTATUS_MPP) >> 11)
+    }
+}
+
+/// Machine and supervisor CSRs, and the privilege level that guards them
+///
+/// The counters are not stored here, as the RTL drives both mcycle and time from the CLINT's
+/// mtime, so they are passed in on each read. sstatus, sie and sip are views of their machine
+/// counterparts.
+#[derive(Debug, Default, Clone)]
+pub struct Csrs {
+    /// Read-only mhartid
+    pub hart_id: u32,
+    /// Privilege level of the running code, which trap entry, mret and sret change
+    pub privilege: Privilege,
+    pub mstatus: u32,
+    pub medeleg: u32,
+    pub mideleg: u32,
+    pub mie: u32,
+    pub mtvec: u32,
+    pub mcounteren: u32,
+    pub mstatush: u32,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    /// Pending interrupts, which are driven by hardware and read-only to software apart from
+    /// the supervisor interrupts
+    pub mip: u32,
+    pub stvec: u32,
+    pub scounteren: u32,
+    pub sscratch: u32,
+    pub sepc: u32,
+    pub scause: u32,
+    pub stval: u32,
+    pub satp: u32,
+    pub pmp: Pmp,
+}
+
+impl Csrs {
+    /// Whether the running code may access the CSR at `addr`, whose bits 9:8 give the lowest
+    /// privilege level allowed
+    fn accessible(&self, addr: u16) -> bool {
+        let allowed = match addr {
+            // each mode below machine mode needs the counter enabled by every mode above it
+            TIME | TIMEH => match self.privilege {
+                Privilege::User => self.mcounteren & self.scounteren & 0b10 != 0,
+                Privilege::Supervisor => self.mcounteren & 0b10 != 0,
+                Privilege::Machine => true,
+            },
+            SATP => self.privilege != Privilege::Supervisor || self.mstatus & MSTATUS_TVM == 0,
+            _ => true,
+        };
+        (addr >> 8 & 0b11) as u8 <= self.privilege as u8 && allowed
+    }
+
+    /// Read a CSR, returning `None` if the access is illegal
+    pub fn read(&self, addr: u16, mtime: u64) -> Option<u32> {
+        if !self.accessible(addr) {
+            return None;
+        }
+        Some(match addr {
+            SSTATUS => self.mstatus & SSTATUS_FIELDS,
+            SIE => self.mie & self.mideleg,
+            STVEC => self.stvec,
+            SCOUNTEREN => self.scounteren,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => self.mip & self.mideleg,
+            SATP => self.satp,
+            MVENDORID | MARCHID | MIMPID | MCONFIGPTR => 0,
+            MHARTID => self.hart_id,
+            MSTATUS => self.mstatus,
+            MISA => MISA_VALUE,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MEDELEGH => 0,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MCOUNTEREN => self.mcounteren,
+            MSTATUSH => self.mstatush,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip,
+            PMPCFG0..=PMPCFG3 => self.pmp.read_cfg((addr - PMPCFG0) as usize),
+            PMPADDR0..=PMPADDR15 => self.pmp.read_addr((addr - PMPADDR0) as usize),
+            MCYCLE | TIME => mtime as u32,
+            MCYCLEH | TIMEH => (mtime >> 32) as u32,
+            _ => return None,
+        })
+    }
+
+    /// Write a CSR, returning `None` if the access is illegal
+    ///
+    /// Writes to read-only fields of a writable CSR are ignored.
+    pub fn write(&mut self, addr: u16, value: u32) -> Option<()> {
+        // the top two bits of the address mark a CSR as read-only
+        if addr >> 10 == 0b11 || !self.accessible(addr) {
+            return None;
+        }
+        match addr {
+            SSTATUS => {
+                self.mstatus = self.mstatus & !SSTATUS_FIELDS | value & SSTATUS_FIELDS;
+            }
+            SIE => self.mie = self.mie & !self.mideleg | value & self.mideleg,
+            STVEC => self.stvec = value,
+            SCOUNTEREN => self.scounteren = value & COUNTEREN_WRITABLE,
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = value & !0b11,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            // only the software interrupt can be raised from supervisor mode
+            SIP => {
+                let writable = self.mideleg & (1 << Interrupt::SupervisorSoftware.code());
+                self.mip = self.mip & !writable | value & writable;
+            }
+            SATP => self.satp = value,
+            MSTATUS => {
+                // mpp only holds implemented modes, so it keeps its value on other writes
+                let mpp = match Privilege::from_mpp(value) {
+                    Some(_) => value & MSTATUS_MPP,
+                    None => self.mstatus & MSTATUS_MPP,
+                };
+                self.mstatus = value & MSTATUS_WRITABLE & !MSTATUS_MPP | mpp;
+            }
+            MEDELEG => self.medeleg = value & MEDELEG_WRITABLE,
+            MIDELEG => self.mideleg = value & SUPERVISOR_INTERRUPTS,
+            MIE => self.mie = value & MIE_WRITABLE,
+            MTVEC => self.mtvec = value,
+            MCOUNTEREN => self.mcounteren = value & COUNTEREN_WRITABLE,
+            MSTATUSH => self.mstatush = value & MSTATUSH_WRITABLE,
+            MSCRATCH => self.mscratch = value,
+            // instructions are always aligned, so the low bits of mepc are fixed at zero
+            MEPC => self.mepc = value & !0b11,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            MIP => {
+                self.mip = self.mip & !SUPERVISOR_INTERRUPTS | value & SUPERVISOR_INTERRUPTS;
+            }
+            PMPCFG0..=PMPCFG3 => self.pmp.write_cfg((addr - PMPCFG0) as usize, value),
+            PMPADDR0..=PMPADDR15 => self.pmp.write_addr((addr - PMPADDR0) as usize, value),
+            MISA | MEDELEGH | MCYCLE | MCYCLEH => {}
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Whether a trap from the running code is handled in supervisor mode
+    fn delegated(&self, trap: Trap) -> bool {
+        let delegated = match trap {
+            Trap::Exception(_) => self.medeleg,
+            Trap::Interrupt(_) => self.mideleg,
+        };
+        // the code of an interrupt is its cause without the interrupt bit
+        self.privilege < Privilege::Machine && delegated >> (trap.cause() & 0x1F) & 1 != 0
+    }
+
+    /// Trap vector the trap would be taken to, which is stvec if it is delegated
+    pub fn trap_vector(&self, trap: Trap) -> u32 {
+        if self.delegated(trap) {
+            self.stvec
+        } else {
+            self.mtvec
+        }
+    }
+
+    /// Update the CSRs for entering a trap taken at `pc`, which moves to machine mode unless
+    /// the trap is delegated to supervisor mode
+    ///
+    /// Returns the address of the trap handler, or `None` if the trap vector is in a mode the
+    /// RTL does not support, in which case the core stops.
+    pub fn enter_trap(&mut self, trap: Trap, pc: u32) -> Option<u32> {
+        if self.delegated(trap) {
+            self.scause = trap.cause();
+            self.stval = trap.value();
+            self.sepc = pc;
+
+            let sie = self.mstatus & MSTATUS_SIE != 0;
+            self.mstatus &= !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+            if sie {
+                self.mstatus |= MSTATUS_SPIE;
+            }
+            if self.privilege == Privilege::Supervisor {
+                self.mstatus |= MSTATUS_SPP;
+            }
+            self.privilege = Privilege::Supervisor;
+
+            return (self.stvec & 0b11 == 0).then_some(self.stvec);
+        }
+
+        self.mcause = trap.cause();
+        self.mtval = trap.value();
+        self.mepc = pc;
+
+        let mie = self.mstatus & MSTATUS_MIE != 0;
+        self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE);
+        if mie {
+            self.mstatus |= MSTATUS_MPIE;
+        }
+        self.mstatus = self.mstatus & !MSTATUS_MPP | (self.privilege as u32) << 11;
+        self.privilege = Privilege::Machine;
+
+        (self.mtvec & 0b11 == 0).then_some(self.mtvec)
+    }
+
+    /// Update the CSRs for returning from a trap, returning the address to resume at
+    ///
+    /// The core moves to the mode in mstatus.MPP, which is then set to user mode.
+    pub fn mret(&mut self) -> u32 {
+        let mpie = self.mstatus & MSTATUS_MPIE != 0;
+        self.mstatus &= !MSTATUS_MIE;
+        if mpie {
+            self.mstatus |= MSTATUS_MIE;
+        }
+        self.privilege = Privilege::from_mpp(self.mstatus).unwrap_or_default();
+        self.mstatus = self.mstatus & !MSTATUS_MPP | MSTATUS_MPIE;
+        if self.privilege != Privilege::Machine {
+            self.mstatus &= !MSTATUS_MPRV;
+        }
+        self.mepc
+    }
+
+    /// Update the CSRs for returning from a supervisor trap, returning the address to resume at
+    ///
+    /// The core moves to the mode in mstatus.SPP, which is then set to user mode.
+    pub fn sret(&mut self) -> u32 {
+        let spie = self.mstatus & MSTATUS_SPIE != 0;
+        self.mstatus &= !(MSTATUS_SIE | MSTATUS_MPRV);
+        if spie {
+            self.mstatus |= MSTATUS_SIE;
+        }
+        self.privilege = match self.mstatus & MSTATUS_SPP {
+            0 => Privilege::User,
+            _ => Privilege::Supervisor,
+        };
+        self.mstatus = self.mstatus & !MSTATUS_SPP | MSTATUS_SPIE;
+        self.sepc
+    }
+
+    /// Whether an interrupt can be taken by the running code if it is pending
+    ///
+    /// An interrupt handled by a mode above the current one is always taken, one handled by the
+    /// current mode is taken if that mode's interrupt enable is set, and one handled below the
+    /// current mode never is.
+    pub fn interrupt_enabled(&self, interrupt: Interrupt) -> bool {
+        let (target, enable) = if self.mideleg >> interrupt.code() & 1 != 0 {
+            (Privilege::Supervisor, MSTATUS_SIE)
+        } else {
+            (Privilege::Machine, MSTATUS_MIE)
+        };
+        self.privilege < target || self.privilege == target && self.mstatus & enable != 0
+    }
+
+    /// Whether an interrupt is both pending and enabled, ignoring mstatus.MIE and SIE
+    pub fn interrupt_pending(&self, code: u32) -> bool {
+        self.mip & self.mie & (1 << code) != 0
+    }
+
+    /// Privilege level that `access` is checked at, which mstatus.MPRV changes for loads and
+    /// stores
+    pub fn access_privilege(&self, access: Access) -> Privilege {
+        match access {
+            Access::Read | Access::Write if self.mstatus & MSTATUS_MPRV != 0 => {
+                Privilege::from_mpp(self.mstatus).unwrap_or_default()
+            }
+            _ => self.privilege,
+        }
+    }
+
+    /// Whether `access` goes through Sv32 translation
+    pub fn translates(&self, access: Access) -> bool {
+        self.satp & SATP_MODE != 0 && self.access_privilege(access) < Privilege::Machine
+    }
+
+    pub(crate) fn save(&self, out: &mut SnapshotWriter) -> Result<(), anyhow::Error> {
+        out.u8(self.privilege as u8)?;
+        for value in [
+            self.mstatus,
+            self.medeleg,
+            self.mideleg,
+            self.mie,
+            self.mtvec,
+            self.mcounteren,
+            self.mstatush,
+            self.mscratch,
+            self.mepc,
+            self.mcause,
+            self.mtval,
+            self.mip,
+            self.stvec,
+            self.scounteren,
+            self.sscratch,
+            self.sepc,
+            self.scause,
+            self.stval,
+            self.satp,
+        ] {
+            out.u32(value)?;
+        }
+        self.pmp.save(out)
+    }
+
+    pub(crate) fn restore(&mut self, input: &mut SnapshotReader) -> Result<(), anyhow::Error> {
+        let privilege = input.u8()?;
+        let Some(privilege) = Privilege::from_bits(privilege as u32) else {
+            bail!("invalid privilege level in snapshot: {privilege}");
+        };
+        self.privilege = privilege;
+        for value in [
+            &mut self.mstatus,
+            &mut self.medeleg,
+            &mut self.mideleg,
+            &mut self.mie,
+            &mut self.mtvec,
+            &mut self.mcounteren,
+            &mut self.mstatush,
+            &mut self.mscratch,
+            &mut self.mepc,
+            &mut self.mcause,
+            &mut self.mtval,
+            &mut self.mip,
+            &mut self.stvec,
+            &mut self.scounteren,
+            &mut self.sscratch,
+            &mut self.sepc,
+            &mut self.scause,
+            &mut self.stval,
+            &mut self.satp,
+        ] {
+            *value = input.u32()?;
+        }
+        self.pmp.restore(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trap::{Exception, Interrupt};
+
+    #[test]
+    fn read_only_csrs() {
+        let mut csrs = Csrs::default();
+        assert_eq!(csrs.read(MISA, 0), Some(0x40140101));
+        assert_eq!(csrs.read(MHARTID, 0), Some(0));
+        // addresses 0xC00-0xFFF may not be written
+        assert_eq!(csrs.write(MHARTID, 1), None);
+        assert_eq!(csrs.write(TIME, 1), None);
+        // but writes to read-only fields elsewhere are ignored
+        assert_eq!(csrs.write(MISA, 0), Some(()));
+        assert_eq!(csrs.read(MISA, 0), Some(0x40140101));
+        assert_eq!(csrs.write(MCYCLE, 0), Some(()));
+        assert_eq!(csrs.read(MCYCLE, 1234), Some(1234));
+        // only the supervisor interrupts in mip are writable
+        assert_eq!(csrs.write(MIP, 0xFFFFFFFF), Some(()));
+        assert_eq!(csrs.read(MIP, 0), Some(0x222));
+    }
+
+    #[test]
+    fn unknown_csrs_are_illegal() {
+        let mut csrs = Csrs::default();
+        for addr in [0x000, 0x101, 0x307, 0x7B0, 0xB02, 0xC00, 0xFFF] {
+            assert_eq!(csrs.read(addr, 0), None, "{addr:03X}");
+            assert_eq!(csrs.write(addr, 0), None, "{addr:03X}");
+        }
+    }
+
+    #[test]
+    fn writable_fields() {
+        let mut csrs = Csrs::default();
+        csrs.write(MSTATUS, 0xFFFFFFFF).unwrap();
+        assert_eq!(csrs.read(MSTATUS, 0), Some(0x7E19AA));
+        assert_eq!(csrs.read(SSTATUS, 0), Some(0xC0122));
+        // mpp keeps its value when written with a mode that is not implemented
+        csrs.write(MSTATUS, 0x1000).unwrap();
+        assert_eq!(csrs.read(MSTATUS, 0), Some(0x1800));
+        csrs.write(MSTATUS, 0).unwrap();
+        assert_eq!(csrs.read(MSTATUS, 0), Some(0));
+        csrs.write(MIE, 0xFFFFFFFF).unwrap();
+        assert_eq!(csrs.read(MIE, 0), Some(0xAAA));
+        csrs.write(MEDELEG, 0xFFFFFFFF).unwrap();
+        assert_eq!(csrs.read(MEDELEG, 0), Some(0xB3FF));
+        csrs.write(MIDELEG, 0xFFFFFFFF).unwrap();
+        assert_eq!(csrs.read(MIDELEG, 0), Some(0x222));
+        csrs.write(MSCRATCH, 0x12345678).unwrap();
+        assert_eq!(csrs.read(MSCRATCH, 0), Some(0x12345678));
+    }
+
+    #[test]
+    fn user_mode_access() {
+        let mut csrs = Csrs {
+            privilege: Privilege::User,
+            ..Csrs::default()
+        };
+        for addr in [MSTATUS, MSCRATCH, MCYCLE, MHARTID, TIME] {
+            assert_eq!(csrs.read(addr, 0), None, "{addr:03X}");
+        }
+        assert_eq!(csrs.write(MSCRATCH, 1), None);
+
+        // time is readable once both mcounteren and scounteren allow it
+        csrs.mcounteren = 0b10;
+        assert_eq!(csrs.read(TIME, 5), None);
+        csrs.scounteren = 0b10;
+        assert_eq!(csrs.read(TIME, 5), Some(5));
+        assert_eq!(csrs.read(TIMEH, 5), Some(0));
+    }
+
+    #[test]
+    fn counters_follow_mtime() {
+        let csrs = Csrs::default();
+        let mtime = 0x0000_0012_3456_789A;
+        assert_eq!(csrs.read(MCYCLE, mtime), Some(0x3456789A));
+        assert_eq!(csrs.read(MCYCLEH, mtime), Some(0x12));
+        assert_eq!(csrs.read(TIME, mtime), Some(0x3456789A));
+        assert_eq!(csrs.read(TIMEH, mtime), Some(0x12));
+    }
+
+    #[test]
+    fn trap_entry_and_return() {
+        let mut csrs = Csrs {
+            mtvec: 0x1000,
+            mstatus: MSTATUS_MIE,
+            ..Csrs::default()
+        };
+
+        let handler = csrs.enter_trap(Interrupt::MachineTimer.into(), 0x2000);
+        assert_eq!(handler, Some(0x1000));
+        assert_eq!(csrs.mcause, 0x80000007);
+        assert_eq!(csrs.mepc, 0x2000);
+        assert_eq!(csrs.mstatus, MSTATUS_MPIE | MSTATUS_MPP);
+
+        assert_eq!(csrs.mret(), 0x2000);
+        assert_eq!(csrs.mstatus, MSTATUS_MIE | MSTATUS_MPIE);
+        assert_eq!(csrs.privilege, Privilege::Machine);
+
+        // returning to user mode, and trapping back from it
+        assert_eq!(csrs.mret(), 0x2000);
+        assert_eq!(csrs.privilege, Privilege::User);
+        assert!(csrs.interrupt_enabled(Interrupt::MachineTimer));
+        csrs.enter_trap(Exception::EnvironmentCallFromU.into(), 0x2004);
+        assert_eq!(csrs.privilege, Privilege::Machine);
+        assert_eq!(csrs.mstatus & MSTATUS_MPP, 0);
+        assert_eq!(csrs.mcause, 8);
+
+        // vectored mode stops the core
+        csrs.mtvec = 0x1001;
+        let trap = Exception::IllegalInstruction(0xFFFFFFFF).into();
+        assert_eq!(csrs.enter_trap(trap, 0x3000), None);
+        assert_eq!(csrs.mcause, 2);
+        assert_eq!(csrs.mtval, 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn supervisor_mode() {
+        let mut csrs = Csrs {
+            privilege: Privilege::Supervisor,
+            stvec: 0x1000,
+            mtvec: 0x4000,
+            mstatus: MSTATUS_SIE,
+            medeleg: 1 << 8,
+            mideleg: 1 << 5,
+            mie: 0x222,
+            ..Csrs::default()
+        };
+        // supervisor csrs are views of the machine ones, limited to the delegated interrupts
+        assert_eq!(csrs.read(MSTATUS, 0), None);
+        assert_eq!(csrs.read(SSTATUS, 0), Some(MSTATUS_SIE));
+        assert_eq!(csrs.read(SIE, 0), Some(0x20));
+        csrs.write(SIE, 0).unwrap();
+        assert_eq!(csrs.mie, 0x202);
+        csrs.mideleg |= 1 << 1;
+        csrs.write(SIP, 0xFFFFFFFF).unwrap();
+        assert_eq!(csrs.mip, 0x2);
+
+        // time needs mcounteren, and satp is trapped by tvm
+        assert_eq!(csrs.read(TIME, 0), None);
+        csrs.mcounteren = 0b10;
+        assert_eq!(csrs.read(TIME, 7), Some(7));
+        assert_eq!(csrs.read(SATP, 0), Some(0));
+        csrs.mstatus |= MSTATUS_TVM;
+        assert_eq!(csrs.read(SATP, 0), None);
+        csrs.mstatus &= !MSTATUS_TVM;
+
+        // delegated interrupts are taken when sie is set, machine ones always
+        assert!(csrs.interrupt_enabled(Interrupt::SupervisorTimer));
+        assert!(csrs.interrupt_enabled(Interrupt::MachineTimer));
+
+        // a delegated ecall from user mode is handled in supervisor mode
+        csrs.privilege = Privilege::User;
+        let handler = csrs.enter_trap(Exception::EnvironmentCallFromU.into(), 0x2000);
+        assert_eq!(handler, Some(0x1000));
+        assert_eq!((csrs.scause, csrs.sepc), (8, 0x2000));
+        assert_eq!(csrs.privilege, Privilege::Supervisor);
+        assert_eq!(csrs.mstatus & SSTATUS_FIELDS, MSTATUS_SPIE);
+        assert!(!csrs.interrupt_enabled(Interrupt::SupervisorTimer));
+
+        // but one that is not delegated goes to machine mode, saving supervisor mode in mpp
+        let trap = Exception::IllegalInstruction(0).into();
+        assert_eq!(csrs.enter_trap(trap, 0x1000), Some(0x4000));
+        assert_eq!(csrs.mstatus & MSTATUS_MPP, 0b01 << 11);
+        assert_eq!(csrs.mret(), 0x1000);
+        assert_eq!(csrs.privilege, Privilege::Supervisor);
+
+        assert_eq!(csrs.sret(), 0x2000);
+        assert_eq!(csrs.privilege, Privilege::User);
+        assert_eq!(csrs.mstatus & SSTATUS_FIELDS, MSTATUS_SIE | MSTATUS_SPIE);
+    }
+
+    #[test]
+    fn translation() {
+        let mut csrs = Csrs {
+            satp: SATP_MODE,
+            ..Csrs::default()
+        };
+        // machine mode is never translated, unless mprv makes loads and stores use mpp
+        assert!(!csrs.translates(Access::Read));
+        csrs.mstatus = MSTATUS_MPRV | 0b01 << 11;
+        assert!(csrs.translates(Access::Read));
+        assert!(csrs.translates(Access::Write));
+        assert!(!csrs.translates(Access::Execute));
+        csrs.privilege = Privilege::Supervisor;
+        assert!(csrs.translates(Access::Execute));
+    }
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use crate::instructions::DecodedInstruction;
+
+/// Number of instructions covered by a single lazily allocated page of the cache
+const PAGE_INSTRUCTIONS: usize = 1024;
+
+type Page = [Option<DecodedInstruction>; PAGE_INSTRUCTIONS];
+
+/// Cache of decoded instructions, indexed by address
+///
+/// Pages are only allocated once code inside them is executed, so the cache only pays for
+/// the pages a program runs from.
+pub struct DecodeCache {
+    pages: HashMap<usize, Box<Page>>,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {
+            pages: HashMap::new(),
+        }
+    }
+
+    fn index(addr: u32) -> (usize, usize) {
+        let word = addr as usize / 4;
+        (word / PAGE_INSTRUCTIONS, word % PAGE_INSTRUCTIONS)
+    }
+
+    pub fn get(&self, addr: u32) -> Option<DecodedInstruction> {
+        let (page, offset) = Self::index(addr);
+        self.pages.get(&page)?[offset]
+    }
+
+    pub fn insert(&mut self, addr: u32, inst: DecodedInstruction) {
+        let (page, offset) = Self::index(addr);
+        self.pages
+            .entry(page)
+            .or_insert_with(|| Box::new([None; PAGE_INSTRUCTIONS]))[offset] = Some(inst);
+    }
+
+    /// Drop any cached instructions overlapping a store of `width` bytes to `addr`
+    pub fn invalidate(&mut self, addr: u32, width: u32) {
+        for word_addr in [addr & !0b11, addr.wrapping_add(width - 1) & !0b11] {
+            let (page, offset) = Self::index(word_addr);
+            if let Some(page) = self.pages.get_mut(&page) {
+                page[offset] = None;
+            }
+        }
+    }
+
+    /// Drop every cached instruction, as required by `fence.i`
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+
+    /// Bytes allocated for the pages that have been filled
+    pub fn allocated_bytes(&self) -> usize {
+        self.pages.len() * size_of::<Page>()
+    }
+}
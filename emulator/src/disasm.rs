@@ -0,0 +1,145 @@
+//! Disassembly into the assembler syntax used by objdump, without pseudo-instructions
+
+use crate::{
+    csr,
+    instructions::{DecodedInstruction, Instruction},
+    symbols::Symbols,
+};
+
+/// ABI names of the integer registers
+pub const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Disassemble the instruction at `pc`, naming the functions that jumps and branches go to
+pub fn disassemble(inst: &DecodedInstruction, pc: u32, symbols: &Symbols) -> String {
+    use Instruction::*;
+
+    let mnemonic = format!("{:?}", inst.kind).to_lowercase();
+    let rd = REGISTER_NAMES[inst.rd as usize];
+    let rs1 = REGISTER_NAMES[inst.rs1 as usize];
+    let rs2 = REGISTER_NAMES[inst.rs2 as usize];
+    let imm = inst.immediate as i32;
+    let target = |offset: u32| {
+        let target = pc.wrapping_add(offset);
+        match symbols.lookup(target) {
+            Some(symbol) => format!("{target:08X} <{}+{:#x}>", symbol.name, target - symbol.addr),
+            None => format!("{target:08X}"),
+        }
+    };
+    let csr = || {
+        let addr = (inst.immediate & 0xFFF) as u16;
+        csr::name(addr).map_or_else(|| format!("{addr:#x}"), str::to_string)
+    };
+
+    match inst.kind {
+        Lui | Auipc => format!("{mnemonic} {rd}, {:#x}", inst.immediate >> 12),
+        Jal => format!("jal {rd}, {}", target(inst.immediate)),
+        Jalr => format!("jalr {rd}, {imm}({rs1})"),
+        Beq | Bne | Blt | Bge | Bltu | Bgeu => {
+            format!("{mnemonic} {rs1}, {rs2}, {}", target(inst.immediate))
+        }
+        Lb | Lh | Lw | Lbu | Lhu => format!("{mnemonic} {rd}, {imm}({rs1})"),
+        Sb | Sh | Sw => format!("{mnemonic} {rs2}, {imm}({rs1})"),
+        Addi | Slti | Sltiu | Xori | Ori | Andi => format!("{mnemonic} {rd}, {rs1}, {imm}"),
+        Slli | Srli | Srai => format!("{mnemonic} {rd}, {rs1}, {}", inst.immediate & 0x1F),
+        Add | Sub | Sll | Slt | Sltu | Xor | Srl | Sra | Or | And => {
+            format!("{mnemonic} {rd}, {rs1}, {rs2}")
+        }
+        Fence => {
+            let set = |bits: u32| {
+                let set: String = "iorw"
+                    .chars()
+                    .enumerate()
+                    .filter(|(index, _)| bits & (0b1000 >> index) != 0)
+                    .map(|(_, c)| c)
+                    .collect();
+                if set.is_empty() {
+                    "0".to_string()
+                } else {
+                    set
+                }
+            };
+            format!(
+                "fence {}, {}",
+                set(inst.raw >> 24 & 0xF),
+                set(inst.raw >> 20 & 0xF)
+            )
+        }
+        FenceI => "fence.i".to_string(),
+        Ecall | Ebreak | Mret | Sret | Wfi => mnemonic,
+        SfenceVma => format!("sfence.vma {rs1}, {rs2}"),
+        Csrrw | Csrrs | Csrrc => format!("{mnemonic} {rd}, {}, {rs1}", csr()),
+        Csrrwi | Csrrsi | Csrrci => format!("{mnemonic} {rd}, {}, {}", csr(), inst.rs1),
+        LrW | ScW | AmoswapW | AmoaddW | AmoxorW | AmoandW | AmoorW | AmominW | AmomaxW
+        | AmominuW | AmomaxuW => {
+            let ordering = match inst.raw >> 25 & 0b11 {
+                0b00 => "",
+                0b01 => ".rl",
+                0b10 => ".aq",
+                _ => ".aqrl",
+            };
+            let mnemonic = format!("{}.w{ordering}", &mnemonic[..mnemonic.len() - 1]);
+            match inst.kind {
+                LrW => format!("{mnemonic} {rd}, ({rs1})"),
+                _ => format!("{mnemonic} {rd}, {rs2}, ({rs1})"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::Symbol;
+
+    fn disasm(raw: u32, pc: u32) -> String {
+        let symbols = Symbols::new(vec![Symbol {
+            name: "main".to_string(),
+            addr: 0x1000,
+            size: 0x100,
+        }]);
+        disassemble(&DecodedInstruction::try_from(raw).unwrap(), pc, &symbols)
+    }
+
+    #[test]
+    fn instructions() {
+        let cases = [
+            (0x12345537, "lui a0, 0x12345"),
+            (0xFFF00513, "addi a0, zero, -1"),
+            (0x00812503, "lw a0, 8(sp)"),
+            (0xFEA12E23, "sw a0, -4(sp)"),
+            (0x40A5D593, "srai a1, a1, 10"),
+            (0x40B50533, "sub a0, a0, a1"),
+            (0x000080E7, "jalr ra, 0(ra)"),
+            (0x0FF0000F, "fence iorw, iorw"),
+            (0x0000100F, "fence.i"),
+            (0x30200073, "mret"),
+            (0x10200073, "sret"),
+            (0x12B50073, "sfence.vma a0, a1"),
+            (0x18051073, "csrrw zero, satp, a0"),
+            (0x30529073, "csrrw zero, mtvec, t0"),
+            (0x7C0025F3, "csrrs a1, 0x7c0, zero"),
+            (0x3002E073, "csrrsi zero, mstatus, 5"),
+            (0x1005A52F, "lr.w a0, (a1)"),
+            (0x06C5A52F, "amoadd.w.aqrl a0, a2, (a1)"),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(disasm(raw, 0), expected, "{raw:08X}");
+        }
+    }
+
+    #[test]
+    fn targets() {
+        // jal ra, +0x10
+        assert_eq!(disasm(0x010000EF, 0x1000), "jal ra, 00001010 <main+0x10>");
+        // bne a0, zero, -4
+        assert_eq!(
+            disasm(0xFE051EE3, 0x1004),
+            "bne a0, zero, 00001000 <main+0x0>"
+        );
+        assert_eq!(disasm(0x010000EF, 0x2000), "jal ra, 00002010");
+    }
+}
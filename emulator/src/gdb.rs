@@ -0,0 +1,659 @@
+//! GDB remote serial protocol server
+//!
+//! The debugger can read and write registers and memory, single step, continue, and set
+//! software breakpoints. Breakpoints are kept here rather than written into memory as
+//! ebreaks, so the program never sees them.
+//!
+//! Hardware watchpoints stop the program after an access to memory. Peripheral registers,
+//! which GDB cannot read to watch itself, can be watched by name with
+//! `monitor watch uart.ctrl [r|w|rw]` and `monitor unwatch`, and stop it with a SIGTRAP.
+//!
+//! Stepping and continuing can also run in reverse. Snapshots are taken every so often while
+//! running forwards, and going backwards restores the one before the target and runs forwards
+//! again from there, which relies on the uart backend replaying its input after a rewind.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::TcpStream,
+};
+
+use anyhow::anyhow;
+use gdbstub::{
+    common::Signal,
+    conn::{Connection, ConnectionExt},
+    stub::{
+        run_blocking::{self, BlockingEventLoop},
+        DisconnectReason, GdbStub, SingleThreadStopReason,
+    },
+    target::{
+        ext::{
+            base::{
+                reverse_exec::{
+                    ReplayLogPosition, ReverseCont, ReverseContOps, ReverseStep, ReverseStepOps,
+                },
+                singlethread::{
+                    SingleThreadBase, SingleThreadResume, SingleThreadResumeOps,
+                    SingleThreadSingleStep, SingleThreadSingleStepOps,
+                },
+                BaseOps,
+            },
+            breakpoints::{
+                self, Breakpoints, BreakpointsOps, HwWatchpoint, HwWatchpointOps, SwBreakpoint,
+                SwBreakpointOps,
+            },
+            monitor_cmd::{outputln, ConsoleOutput, MonitorCmd, MonitorCmdOps},
+        },
+        Target, TargetError, TargetResult,
+    },
+};
+use gdbstub_arch::riscv::{reg::RiscvCoreRegs, Riscv32};
+
+use crate::{
+    cpu::{Cpu, Status},
+    watch::{WatchHit, WatchKind, Watchpoint},
+};
+
+/// Instructions run between checks for the debugger interrupting
+const POLL_INTERVAL: u32 = 1024;
+
+/// Steps between the snapshots taken for reverse execution
+const CHECKPOINT_INTERVAL: u64 = 100_000;
+
+/// How the program should run when the debugger resumes it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExecMode {
+    Step,
+    Continue,
+    ReverseStep,
+    ReverseContinue,
+}
+
+/// Program being debugged
+pub struct GdbTarget {
+    cpu: Cpu,
+    breakpoints: HashSet<u32>,
+    /// Watchpoints added by the debugger, as opposed to with the monitor command
+    watchpoints: Vec<Watchpoint>,
+    mode: ExecMode,
+    /// Steps taken since the debugger attached, which is the position in the execution
+    steps: u64,
+    /// Snapshots to rewind to, by the step they were taken at
+    checkpoints: BTreeMap<u64, Vec<u8>>,
+}
+
+impl GdbTarget {
+    /// Debug `cpu`, whose uart backend should be a `RecordingBackend` or `ReplayBackend` for
+    /// reverse execution to see the same input again
+    pub fn new(cpu: Cpu) -> Self {
+        Self {
+            cpu,
+            breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+            mode: ExecMode::Continue,
+            steps: 0,
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    /// Debug the program over a connection to GDB, until the debugger disconnects or the
+    /// program exits
+    pub fn serve(&mut self, conn: TcpStream) -> Result<DisconnectReason, anyhow::Error> {
+        GdbStub::new(conn)
+            .run_blocking::<EventLoop>(self)
+            .map_err(|error| anyhow!("gdb session failed: {error}"))
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    /// Run in the current mode until the program stops, or `interrupted` returns true
+    fn resume(
+        &mut self,
+        interrupted: impl FnMut() -> bool,
+    ) -> Result<Option<SingleThreadStopReason<u32>>, anyhow::Error> {
+        match self.mode {
+            ExecMode::Step | ExecMode::Continue => self.run_forwards(interrupted),
+            ExecMode::ReverseStep => {
+                if self.steps == 0 {
+                    return Ok(Some(beginning()));
+                }
+                self.rewind_to(self.steps - 1)?;
+                Ok(Some(SingleThreadStopReason::DoneStep))
+            }
+            ExecMode::ReverseContinue => self.run_backwards().map(Some),
+        }
+    }
+
+    fn run_forwards(
+        &mut self,
+        mut interrupted: impl FnMut() -> bool,
+    ) -> Result<Option<SingleThreadStopReason<u32>>, anyhow::Error> {
+        for steps in 1u32.. {
+            if let Some(status) = self.cpu.status() {
+                let code = match status {
+                    Status::Success => 0,
+                    Status::Failure => 1,
+                    Status::Exited(code) => code as u8,
+                };
+                return Ok(Some(SingleThreadStopReason::Exited(code)));
+            }
+            if self.cpu.is_hung() {
+                return Ok(Some(SingleThreadStopReason::Signal(Signal::SIGSTOP)));
+            }
+
+            if let Some(hit) = self.step()? {
+                return Ok(Some(self.watch_stop(hit)));
+            }
+            if self.mode == ExecMode::Step {
+                return Ok(Some(SingleThreadStopReason::DoneStep));
+            }
+            if self.breakpoints.contains(&self.cpu.pc()) {
+                return Ok(Some(SingleThreadStopReason::SwBreak(())));
+            }
+            if steps % POLL_INTERVAL == 0 && interrupted() {
+                return Ok(None);
+            }
+        }
+        unreachable!()
+    }
+
+    /// Run back to the last breakpoint that was hit or watched access, or else the beginning
+    ///
+    /// Running backwards stops before the instruction that made a watched access, rather than
+    /// after it.
+    fn run_backwards(&mut self) -> Result<SingleThreadStopReason<u32>, anyhow::Error> {
+        let current = self.steps;
+        // search one checkpoint interval at a time, starting with the latest
+        let starts: Vec<u64> = self
+            .checkpoints
+            .range(..current)
+            .map(|(&start, _)| start)
+            .collect();
+        let mut end = current;
+        for &start in starts.iter().rev() {
+            self.rewind_to(start)?;
+            let mut stop = None;
+            while self.steps < end {
+                let before = self.steps;
+                if let Some(hit) = self.step()? {
+                    stop = Some((before, self.watch_stop(hit)));
+                } else if self.steps < current && self.breakpoints.contains(&self.cpu.pc()) {
+                    stop = Some((self.steps, SingleThreadStopReason::SwBreak(())));
+                }
+            }
+            if let Some((position, reason)) = stop {
+                self.rewind_to(position)?;
+                return Ok(reason);
+            }
+            end = start;
+        }
+        self.rewind_to(0)?;
+        Ok(beginning())
+    }
+
+    /// Take a single step forwards, first taking a snapshot if one is due, returning any
+    /// watched access it made
+    fn step(&mut self) -> Result<Option<WatchHit>, anyhow::Error> {
+        if self.steps.is_multiple_of(CHECKPOINT_INTERVAL)
+            && !self.checkpoints.contains_key(&self.steps)
+        {
+            let mut snapshot = Vec::new();
+            self.cpu.save_snapshot(&mut snapshot)?;
+            self.checkpoints.insert(self.steps, snapshot);
+        }
+        self.cpu.step()?;
+        self.steps += 1;
+        Ok(self.cpu.take_watch_hit())
+    }
+
+    /// Stop reason for a watched access, which GDB only recognizes for its own watchpoints
+    fn watch_stop(&self, hit: WatchHit) -> SingleThreadStopReason<u32> {
+        let kind = gdb_watch_kind(hit.kind);
+        let ours = self.watchpoints.iter().any(|watchpoint| {
+            gdb_watch_kind(watchpoint.kind) == kind && watchpoint.range.contains(&hit.addr)
+        });
+        if !ours {
+            return SingleThreadStopReason::Signal(Signal::SIGTRAP);
+        }
+        SingleThreadStopReason::Watch {
+            tid: (),
+            kind,
+            addr: hit.addr,
+        }
+    }
+
+    /// Run a `monitor` command, returning the output for the debugger
+    fn monitor(&mut self, cmd: &str) -> Result<String, anyhow::Error> {
+        let words: Vec<&str> = cmd.split_whitespace().collect();
+        let (command, name, kind) = match words[..] {
+            [command, name] => (command, name, WatchKind::Write),
+            [command, name, kind] => (command, name, kind.parse()?),
+            _ => return Ok(MONITOR_HELP.to_string()),
+        };
+        let range = self
+            .cpu
+            .peripheral_register(name)
+            .ok_or_else(|| anyhow!("no peripheral register named {name}"))?;
+        let addr = range.start;
+        let watchpoint = Watchpoint { range, kind };
+        match command {
+            "watch" => {
+                self.cpu.add_watchpoint(watchpoint);
+                Ok(format!("watching {name} at {addr:08X}"))
+            }
+            "unwatch" if self.cpu.remove_watchpoint(&watchpoint) => {
+                Ok(format!("stopped watching {name}"))
+            }
+            "unwatch" => Ok(format!("{name} is not being watched")),
+            _ => Ok(MONITOR_HELP.to_string()),
+        }
+    }
+
+    /// Restore the machine to how it was after `target` steps, which must have been taken
+    fn rewind_to(&mut self, target: u64) -> Result<(), anyhow::Error> {
+        let (&start, snapshot) = self
+            .checkpoints
+            .range(..=target)
+            .next_back()
+            .ok_or_else(|| anyhow!("no snapshot to rewind to step {target}"))?;
+        self.cpu.restore_snapshot(snapshot.as_slice())?;
+        self.steps = start;
+        while self.steps < target {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Forget the snapshots after the current step, as the debugger has changed what happens
+    /// from here on
+    fn diverge(&mut self) {
+        self.checkpoints.split_off(&(self.steps + 1));
+    }
+}
+
+const MONITOR_HELP: &str = "commands:
+  watch REGISTER [r|w|rw]    stop on accesses to a peripheral register such as uart.ctrl
+  unwatch REGISTER [r|w|rw]  stop watching a peripheral register";
+
+fn gdb_watch_kind(kind: WatchKind) -> breakpoints::WatchKind {
+    match kind {
+        WatchKind::Read => breakpoints::WatchKind::Read,
+        WatchKind::Write => breakpoints::WatchKind::Write,
+        WatchKind::Access => breakpoints::WatchKind::ReadWrite,
+    }
+}
+
+/// Stop reason for reaching the start of the execution while running in reverse
+fn beginning() -> SingleThreadStopReason<u32> {
+    SingleThreadStopReason::ReplayLog {
+        tid: None,
+        pos: ReplayLogPosition::Begin,
+    }
+}
+
+impl Target for GdbTarget {
+    type Arch = Riscv32;
+    type Error = anyhow::Error;
+
+    #[inline(always)]
+    fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
+        BaseOps::SingleThread(self)
+    }
+
+    #[inline(always)]
+    fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_monitor_cmd(&mut self) -> Option<MonitorCmdOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadBase for GdbTarget {
+    fn read_registers(&mut self, regs: &mut RiscvCoreRegs<u32>) -> TargetResult<(), Self> {
+        for (index, reg) in regs.x.iter_mut().enumerate() {
+            *reg = self.cpu.register(index);
+        }
+        regs.pc = self.cpu.pc();
+        Ok(())
+    }
+
+    fn write_registers(&mut self, regs: &RiscvCoreRegs<u32>) -> TargetResult<(), Self> {
+        for (index, reg) in regs.x.iter().enumerate() {
+            self.cpu.set_register(index, *reg);
+        }
+        self.cpu.set_pc(regs.pc);
+        self.diverge();
+        Ok(())
+    }
+
+    fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<usize, Self> {
+        // peripherals are not read, as reading them can have side effects
+        for (index, byte) in data.iter_mut().enumerate() {
+            match self
+                .cpu
+                .read_memory(start_addr.wrapping_add(index as u32), 1)
+            {
+                Ok(value) => *byte = value as u8,
+                Err(_) if index > 0 => return Ok(index),
+                Err(_) => return Err(TargetError::NonFatal),
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
+        for (addr, byte) in (start_addr..).zip(data) {
+            self.cpu
+                .write_memory(addr, *byte as u32, 1)
+                .map_err(|_| TargetError::NonFatal)?;
+        }
+        self.diverge();
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadResume for GdbTarget {
+    fn resume(&mut self, signal: Option<Signal>) -> Result<(), Self::Error> {
+        if signal.is_some() {
+            return Err(anyhow!("continuing with a signal is not supported"));
+        }
+        self.mode = ExecMode::Continue;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_reverse_step(&mut self) -> Option<ReverseStepOps<'_, (), Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_reverse_cont(&mut self) -> Option<ReverseContOps<'_, (), Self>> {
+        Some(self)
+    }
+}
+
+impl ReverseStep<()> for GdbTarget {
+    fn reverse_step(&mut self, _tid: ()) -> Result<(), Self::Error> {
+        self.mode = ExecMode::ReverseStep;
+        Ok(())
+    }
+}
+
+impl ReverseCont<()> for GdbTarget {
+    fn reverse_cont(&mut self) -> Result<(), Self::Error> {
+        self.mode = ExecMode::ReverseContinue;
+        Ok(())
+    }
+}
+
+impl SingleThreadSingleStep for GdbTarget {
+    fn step(&mut self, signal: Option<Signal>) -> Result<(), Self::Error> {
+        if signal.is_some() {
+            return Err(anyhow!("stepping with a signal is not supported"));
+        }
+        self.mode = ExecMode::Step;
+        Ok(())
+    }
+}
+
+impl Breakpoints for GdbTarget {
+    #[inline(always)]
+    fn support_sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_hw_watchpoint(&mut self) -> Option<HwWatchpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SwBreakpoint for GdbTarget {
+    fn add_sw_breakpoint(&mut self, addr: u32, _kind: usize) -> TargetResult<bool, Self> {
+        self.breakpoints.insert(addr);
+        Ok(true)
+    }
+
+    fn remove_sw_breakpoint(&mut self, addr: u32, _kind: usize) -> TargetResult<bool, Self> {
+        Ok(self.breakpoints.remove(&addr))
+    }
+}
+
+impl HwWatchpoint for GdbTarget {
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: u32,
+        len: u32,
+        kind: breakpoints::WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let watchpoint = watchpoint(addr, len, kind);
+        self.cpu.add_watchpoint(watchpoint.clone());
+        self.watchpoints.push(watchpoint);
+        Ok(true)
+    }
+
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: u32,
+        len: u32,
+        kind: breakpoints::WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let watchpoint = watchpoint(addr, len, kind);
+        let Some(index) = self.watchpoints.iter().position(|w| *w == watchpoint) else {
+            return Ok(false);
+        };
+        self.watchpoints.remove(index);
+        Ok(self.cpu.remove_watchpoint(&watchpoint))
+    }
+}
+
+fn watchpoint(addr: u32, len: u32, kind: breakpoints::WatchKind) -> Watchpoint {
+    let kind = match kind {
+        breakpoints::WatchKind::Read => WatchKind::Read,
+        breakpoints::WatchKind::Write => WatchKind::Write,
+        breakpoints::WatchKind::ReadWrite => WatchKind::Access,
+    };
+    Watchpoint {
+        range: addr..addr.saturating_add(len),
+        kind,
+    }
+}
+
+impl MonitorCmd for GdbTarget {
+    fn handle_monitor_cmd(
+        &mut self,
+        cmd: &[u8],
+        mut out: ConsoleOutput<'_>,
+    ) -> Result<(), Self::Error> {
+        match self.monitor(&String::from_utf8_lossy(cmd)) {
+            Ok(output) => outputln!(out, "{output}"),
+            Err(error) => outputln!(out, "error: {error}"),
+        }
+        Ok(())
+    }
+}
+
+enum EventLoop {}
+
+impl BlockingEventLoop for EventLoop {
+    type Target = GdbTarget;
+    type Connection = TcpStream;
+    type StopReason = SingleThreadStopReason<u32>;
+
+    #[allow(clippy::type_complexity)]
+    fn wait_for_stop_reason(
+        target: &mut Self::Target,
+        conn: &mut Self::Connection,
+    ) -> Result<
+        run_blocking::Event<Self::StopReason>,
+        run_blocking::WaitForStopReasonError<
+            <Self::Target as Target>::Error,
+            <Self::Connection as Connection>::Error,
+        >,
+    > {
+        let interrupted = || conn.peek().map(|byte| byte.is_some()).unwrap_or(true);
+        match target
+            .resume(interrupted)
+            .map_err(run_blocking::WaitForStopReasonError::Target)?
+        {
+            Some(reason) => Ok(run_blocking::Event::TargetStopped(reason)),
+            None => {
+                let byte = conn
+                    .read()
+                    .map_err(run_blocking::WaitForStopReasonError::Connection)?;
+                Ok(run_blocking::Event::IncomingData(byte))
+            }
+        }
+    }
+
+    fn on_interrupt(
+        _target: &mut Self::Target,
+    ) -> Result<Option<Self::StopReason>, <Self::Target as Target>::Error> {
+        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::tests::cpu_at_zero,
+        trace::{AccessKind, MemoryAccess},
+    };
+
+    /// Target for a loop that counts in x1 and stores each value to 0x100
+    fn counting_target() -> GdbTarget {
+        GdbTarget::new(cpu_at_zero(&[0x00108093, 0x10102023, 0xFF9FF06F]))
+    }
+
+    fn resume(target: &mut GdbTarget, mode: ExecMode) -> SingleThreadStopReason<u32> {
+        target.mode = mode;
+        target.resume(|| false).unwrap().unwrap()
+    }
+
+    #[test]
+    fn reverse_execution() {
+        let mut target = counting_target();
+        target.breakpoints.insert(0x4);
+        for _ in 0..3 {
+            assert_eq!(
+                resume(&mut target, ExecMode::Continue),
+                SingleThreadStopReason::SwBreak(())
+            );
+        }
+        assert_eq!(target.steps, 7);
+        assert_eq!(target.cpu.register(1), 3);
+
+        assert_eq!(
+            resume(&mut target, ExecMode::ReverseStep),
+            SingleThreadStopReason::DoneStep
+        );
+        assert_eq!((target.steps, target.cpu.pc()), (6, 0x0));
+        assert_eq!(target.cpu.register(1), 2);
+
+        assert_eq!(
+            resume(&mut target, ExecMode::ReverseContinue),
+            SingleThreadStopReason::SwBreak(())
+        );
+        assert_eq!((target.steps, target.cpu.pc()), (4, 0x4));
+        assert_eq!(target.cpu.read_memory(0x100, 4).unwrap(), 1);
+
+        assert_eq!(
+            resume(&mut target, ExecMode::ReverseContinue),
+            SingleThreadStopReason::SwBreak(())
+        );
+        assert_eq!(target.steps, 1);
+        assert_eq!(resume(&mut target, ExecMode::ReverseContinue), beginning());
+        assert_eq!((target.steps, target.cpu.pc()), (0, 0x0));
+        assert_eq!(target.cpu.register(1), 0);
+        assert_eq!(target.cpu.read_memory(0x100, 4).unwrap(), 0);
+        assert_eq!(resume(&mut target, ExecMode::ReverseStep), beginning());
+
+        // running forwards again goes through the same states
+        resume(&mut target, ExecMode::Continue);
+        resume(&mut target, ExecMode::Continue);
+        assert_eq!(target.steps, 4);
+        assert_eq!(target.cpu.register(1), 2);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut target = counting_target();
+        assert!(matches!(
+            target.add_hw_watchpoint(0x100, 4, breakpoints::WatchKind::Write),
+            Ok(true)
+        ));
+        let watch = SingleThreadStopReason::Watch {
+            tid: (),
+            kind: breakpoints::WatchKind::Write,
+            addr: 0x100,
+        };
+        assert_eq!(resume(&mut target, ExecMode::Continue), watch);
+        assert_eq!(resume(&mut target, ExecMode::Continue), watch);
+        assert_eq!((target.steps, target.cpu.pc()), (5, 0x8));
+        assert_eq!(target.cpu.read_memory(0x100, 4).unwrap(), 2);
+
+        // running backwards stops before the store
+        assert_eq!(resume(&mut target, ExecMode::ReverseContinue), watch);
+        assert_eq!((target.steps, target.cpu.pc()), (4, 0x4));
+        assert_eq!(target.cpu.read_memory(0x100, 4).unwrap(), 1);
+        assert_eq!(resume(&mut target, ExecMode::ReverseContinue), watch);
+        assert_eq!(target.steps, 1);
+        assert_eq!(resume(&mut target, ExecMode::ReverseContinue), beginning());
+
+        assert!(matches!(
+            target.remove_hw_watchpoint(0x100, 4, breakpoints::WatchKind::Write),
+            Ok(true)
+        ));
+        target.breakpoints.insert(0x8);
+        assert_eq!(
+            resume(&mut target, ExecMode::Continue),
+            SingleThreadStopReason::SwBreak(())
+        );
+
+        // watchpoints from the monitor command are not known to GDB
+        assert!(target.monitor("watch uart.data").is_err());
+        target.monitor("watch uart.ctrl rw").unwrap();
+        let ctrl = target.cpu.peripheral_register("uart.ctrl").unwrap();
+        let hit = Watchpoint {
+            range: ctrl.clone(),
+            kind: WatchKind::Access,
+        }
+        .hit(
+            0x4,
+            MemoryAccess {
+                kind: AccessKind::Store,
+                addr: ctrl.start,
+                size: 4,
+                value: 1,
+            },
+        );
+        assert_eq!(
+            target.watch_stop(hit),
+            SingleThreadStopReason::Signal(Signal::SIGTRAP)
+        );
+        target.monitor("unwatch uart.ctrl rw").unwrap();
+        assert!(!target.cpu.remove_watchpoint(&Watchpoint {
+            range: ctrl,
+            kind: WatchKind::Access,
+        }));
+    }
+}
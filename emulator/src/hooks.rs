@@ -0,0 +1,39 @@
+//! Callbacks for analysing execution without changing the core
+//!
+//! Hooks are given the [`Cpu`] to inspect, and are called in the order they were added.
+
+use crate::{cpu::Cpu, instructions::DecodedInstruction, trace::MemoryAccess, trap::Trap};
+
+/// CSR instruction that completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsrAccess {
+    pub addr: u16,
+    /// Value read, unless the instruction skipped the read
+    pub read: Option<u32>,
+    /// Value written, unless the instruction skipped the write
+    pub written: Option<u32>,
+}
+
+/// Analysis run alongside the core, with every method doing nothing by default
+#[allow(unused_variables)]
+pub trait Hook {
+    /// Called before each instruction at `pc`, even if it then traps or stalls
+    fn before_instruction(&mut self, cpu: &Cpu, pc: u32) {}
+
+    /// Called once the instruction at `pc` has retired, with the pc at its next instruction
+    fn after_retire(&mut self, cpu: &Cpu, pc: u32, inst: &DecodedInstruction) {}
+
+    /// Called for each successful load or store to RAM or ROM
+    fn memory_access(&mut self, cpu: &Cpu, access: &MemoryAccess) {}
+
+    /// Called for each successful load or store to a peripheral
+    fn mmio_access(&mut self, cpu: &Cpu, access: &MemoryAccess) {}
+
+    fn csr_access(&mut self, cpu: &Cpu, access: &CsrAccess) {}
+
+    /// Called after entering the trap handler at `handler`, for a trap taken at `pc`
+    fn trap_entry(&mut self, cpu: &Cpu, trap: Trap, pc: u32, handler: u32) {}
+
+    /// Called after an mret at `pc` returns to `target`
+    fn trap_exit(&mut self, cpu: &Cpu, pc: u32, target: u32) {}
+}
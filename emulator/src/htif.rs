@@ -0,0 +1,290 @@
+//! Host-target interface, which riscv-tests and the architecture tests use to exit and print
+//!
+//! The program writes a command to the 64-bit `tohost` variable, which names a device, a
+//! command for it and a payload in bits 63:56, 55:48 and 47:0, and polls `fromhost` for the
+//! response. Both are found by their symbols in the ELF. A command is handled once an
+//! instruction runs that does not store to `tohost`, so that both halves of a 64-bit write
+//! land first.
+
+use anyhow::{bail, Context};
+use elf::{endian::LittleEndian, ElfBytes};
+
+use crate::cpu::Cpu;
+
+/// Device for exiting and proxied syscalls
+const DEVICE_SYSCALL: u64 = 0;
+/// Device for the console, which shares the uart's backend
+const DEVICE_CONSOLE: u64 = 1;
+
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const ENOSYS: u64 = 38;
+
+/// Addresses of `tohost` and `fromhost`, and the progress of a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Htif {
+    tohost: u32,
+    fromhost: Option<u32>,
+    /// Whether the last step stored to `tohost`
+    written: bool,
+    /// Whether `tohost` has been written since the last command was handled
+    pending: bool,
+}
+
+impl Htif {
+    pub fn new(tohost: u32, fromhost: Option<u32>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            written: false,
+            pending: false,
+        }
+    }
+
+    /// Find `tohost` and `fromhost` in an ELF loaded `offset` bytes above its addresses,
+    /// returning `None` if it has no `tohost`
+    pub fn from_elf(
+        elf: &ElfBytes<LittleEndian>,
+        offset: u32,
+    ) -> Result<Option<Self>, anyhow::Error> {
+        let Some((symbol_table, string_table)) = elf.symbol_table()? else {
+            return Ok(None);
+        };
+        let (mut tohost, mut fromhost) = (None, None);
+        for symbol in symbol_table {
+            if symbol.is_undefined() {
+                continue;
+            }
+            let addr = offset.wrapping_add(u32::try_from(symbol.st_value)?);
+            match string_table.get(symbol.st_name as usize)? {
+                "tohost" => tohost = Some(addr),
+                "fromhost" => fromhost = Some(addr),
+                _ => {}
+            }
+        }
+        Ok(tohost.map(|tohost| Self::new(tohost, fromhost)))
+    }
+
+    pub fn tohost(&self) -> u32 {
+        self.tohost
+    }
+
+    pub fn fromhost(&self) -> Option<u32> {
+        self.fromhost
+    }
+
+    /// Note a store the program made
+    pub(crate) fn store(&mut self, addr: u32, width: u32) {
+        let offset = addr.wrapping_sub(self.tohost);
+        if offset < 8 || self.tohost.wrapping_sub(addr) < width {
+            self.written = true;
+        }
+    }
+
+    /// Handle a command once the program has finished writing it, returning the exit code if
+    /// the program exited
+    pub(crate) fn poll(&mut self, cpu: &mut Cpu) -> Result<Option<u32>, anyhow::Error> {
+        if self.written {
+            self.written = false;
+            self.pending = true;
+            return Ok(None);
+        }
+        if !self.pending {
+            return Ok(None);
+        }
+
+        let command = read_u64(cpu, self.tohost)?;
+        let device = command >> 56;
+        let cmd = (command >> 48) & 0xFF;
+        let payload = command & 0xFFFF_FFFF_FFFF;
+        let response = match (device, cmd) {
+            // anything else in tohost is cleared without being acted on
+            _ if command == 0 => None,
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => {
+                self.pending = false;
+                return Ok(Some((payload >> 1) as u32));
+            }
+            (DEVICE_SYSCALL, 0) => {
+                let addr = u32::try_from(payload).context("htif syscall block is out of range")?;
+                let args: Vec<u64> = (0..4)
+                    .map(|index| read_u64(cpu, addr.wrapping_add(8 * index)))
+                    .collect::<Result<_, _>>()?;
+                let result = match args[0] {
+                    SYS_WRITE => {
+                        let (addr, len) = (args[2] as u32, args[3] as u32);
+                        for offset in 0..len {
+                            let byte = cpu.read_memory(addr.wrapping_add(offset), 1)?;
+                            cpu.uart_mut().console_write(byte as u8)?;
+                        }
+                        len as u64
+                    }
+                    SYS_EXIT => {
+                        self.pending = false;
+                        return Ok(Some(args[1] as u32));
+                    }
+                    _ => ENOSYS.wrapping_neg(),
+                };
+                write_u64(cpu, addr, result)?;
+                Some(1)
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                cpu.uart_mut().console_write(payload as u8)?;
+                Some(command & !0xFFFF_FFFF_FFFF)
+            }
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => {
+                // the command stays pending until there is a byte to respond with
+                let cycles = cpu.cycles();
+                let Some(byte) = cpu.uart_mut().console_read(cycles) else {
+                    return Ok(None);
+                };
+                Some(command & !0xFFFF_FFFF_FFFF | byte as u64)
+            }
+            _ => bail!("unsupported htif command: {command:016X}"),
+        };
+
+        self.pending = false;
+        write_u64(cpu, self.tohost, 0)?;
+        if let (Some(response), Some(fromhost)) = (response, self.fromhost) {
+            write_u64(cpu, fromhost, response)?;
+        }
+        Ok(None)
+    }
+}
+
+fn read_u64(cpu: &Cpu, addr: u32) -> Result<u64, anyhow::Error> {
+    let low = cpu.read_memory(addr, 4)?;
+    let high = cpu.read_memory(addr.wrapping_add(4), 4)?;
+    Ok((high as u64) << 32 | low as u64)
+}
+
+fn write_u64(cpu: &mut Cpu, addr: u32, value: u64) -> Result<(), anyhow::Error> {
+    cpu.write_memory(addr, value as u32, 4)?;
+    cpu.write_memory(addr.wrapping_add(4), (value >> 32) as u32, 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    use super::*;
+    use crate::{
+        cpu::{tests::cpu_at_zero, RunLimits, Status, StopReason},
+        memory::{Memory, MemoryConfig, RegionConfig},
+        uart::tests::Loopback,
+    };
+
+    const TOHOST: u32 = 0x400;
+    const FROMHOST: u32 = 0x408;
+
+    fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+        ((imm as u32 & 0xFFF) << 20) | (rs1 << 15) | (rd << 7) | 0b0010011
+    }
+
+    fn lui(rd: u32, imm: u32) -> u32 {
+        (imm & 0xFFFFF000) | (rd << 7) | 0b0110111
+    }
+
+    fn sw(rs2: u32, imm: i32) -> u32 {
+        let imm = imm as u32;
+        ((imm >> 5) << 25) | (rs2 << 20) | (0b010 << 12) | ((imm & 0x1F) << 7) | 0b0100011
+    }
+
+    /// Core running `program` from 0, with HTIF and a console of `received` bytes
+    fn htif_cpu(program: &[u32], received: &[u8]) -> (Cpu, Rc<RefCell<Vec<u8>>>) {
+        let mut cpu = cpu_at_zero(program);
+        let transmitted = Rc::new(RefCell::new(Vec::new()));
+        cpu.set_uart_backend(Box::new(Loopback {
+            received: VecDeque::from(received.to_vec()),
+            transmitted: transmitted.clone(),
+        }));
+        cpu.set_htif(Some(Htif::new(TOHOST, Some(FROMHOST))));
+        (cpu, transmitted)
+    }
+
+    fn run(cpu: &mut Cpu) -> StopReason {
+        let limits = RunLimits {
+            max_instructions: Some(100),
+            timeout: None,
+        };
+        cpu.run(&limits).unwrap()
+    }
+
+    #[test]
+    fn console() {
+        // putchar as a 64-bit write, then getchar, then exit with code 3
+        let program = [
+            addi(1, 0, b'A' as i32),
+            lui(2, 0x01010000),
+            sw(1, TOHOST as i32),
+            sw(2, TOHOST as i32 + 4),
+            lui(2, 0x01000000),
+            sw(0, TOHOST as i32),
+            sw(2, TOHOST as i32 + 4),
+            addi(3, 0, 7),
+            addi(0, 0, 0),
+            sw(3, TOHOST as i32),
+            addi(0, 0, 0),
+        ];
+        let (mut cpu, transmitted) = htif_cpu(&program, b"z");
+        assert_eq!(run(&mut cpu), StopReason::Status(Status::Exited(3)));
+        assert_eq!(*transmitted.borrow(), b"A");
+        assert_eq!(cpu.read_memory(FROMHOST, 4).unwrap(), b'z' as u32);
+        assert_eq!(cpu.read_memory(FROMHOST + 4, 4).unwrap(), 0x01000000);
+    }
+
+    #[test]
+    fn syscalls() {
+        // write(1, 0xA00, 2) through the block at 0x600
+        let program = [addi(1, 0, 0x600), sw(1, TOHOST as i32), addi(0, 0, 0)];
+        let (mut cpu, transmitted) = htif_cpu(&program, b"");
+        for (index, arg) in [SYS_WRITE, 1, 0xA00, 2].into_iter().enumerate() {
+            write_u64(&mut cpu, 0x600 + 8 * index as u32, arg).unwrap();
+        }
+        cpu.write_memory(0xA00, u32::from_le_bytes(*b"hi!\0"), 4)
+            .unwrap();
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(*transmitted.borrow(), b"hi");
+        assert_eq!(read_u64(&cpu, 0x600).unwrap(), 2);
+        assert_eq!(read_u64(&cpu, TOHOST).unwrap(), 0);
+        assert_eq!(read_u64(&cpu, FROMHOST).unwrap(), 1);
+
+        write_u64(&mut cpu, 0x600, SYS_EXIT).unwrap();
+        write_u64(&mut cpu, 0x608, 5).unwrap();
+        cpu.set_pc(0);
+        assert_eq!(run(&mut cpu), StopReason::Status(Status::Exited(5)));
+    }
+
+    #[test]
+    fn syscall_block_wraps_at_end_of_address_space() {
+        // exit(7) through a block whose last two arguments wrap around to address 0
+        let mut memory = Memory::new(&MemoryConfig {
+            regions: vec![
+                RegionConfig {
+                    base: 0,
+                    size: 0x1000,
+                },
+                RegionConfig {
+                    base: 0xFFFFF000,
+                    size: 0x1000,
+                },
+            ],
+        })
+        .unwrap();
+        for (index, inst) in [addi(1, 0, -16), sw(1, TOHOST as i32), addi(0, 0, 0)]
+            .into_iter()
+            .enumerate()
+        {
+            memory.write(4 * index as u32, inst, 4).unwrap();
+        }
+        let mut cpu = Cpu::new(0, memory);
+        cpu.set_htif(Some(Htif::new(TOHOST, Some(FROMHOST))));
+        write_u64(&mut cpu, 0xFFFFFFF0, SYS_EXIT).unwrap();
+        write_u64(&mut cpu, 0xFFFFFFF8, 7).unwrap();
+        assert_eq!(run(&mut cpu), StopReason::Status(Status::Exited(7)));
+    }
+}
@@ -0,0 +1,319 @@
+//! Set-associative instruction cache model, for sizing `InstCache.vhd`
+
+use std::{collections::HashMap, fmt};
+
+use anyhow::ensure;
+
+use crate::symbols::Symbols;
+
+/// Geometry of the instruction cache, with all sizes in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ICacheConfig {
+    pub size: u32,
+    pub line_size: u32,
+    pub ways: u32,
+}
+
+impl Default for ICacheConfig {
+    fn default() -> Self {
+        Self {
+            size: 4096,
+            line_size: 16,
+            ways: 2,
+        }
+    }
+}
+
+/// Number of instruction fetches that hit and missed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AccessCounts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl AccessCounts {
+    pub fn accesses(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        self.hits as f64 / self.accesses().max(1) as f64
+    }
+
+    pub(crate) fn record(&mut self, hit: bool) {
+        if hit {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    tag: Option<u32>,
+    /// Access time, for least-recently-used replacement
+    last_used: u64,
+}
+
+pub struct ICache {
+    config: ICacheConfig,
+    sets: u32,
+    /// `ways` lines for each set
+    lines: Vec<Line>,
+    time: u64,
+    counts: AccessCounts,
+    /// Counts for each fetched address
+    counts_by_address: HashMap<u32, AccessCounts>,
+}
+
+impl ICache {
+    pub fn new(config: ICacheConfig) -> Result<Self, anyhow::Error> {
+        ensure!(
+            config.line_size.is_power_of_two() && config.line_size >= 4,
+            "cache line size must be a power of two of at least 4 bytes: {}",
+            config.line_size
+        );
+        ensure!(config.ways > 0, "cache must have at least one way");
+        ensure!(
+            config.size.is_multiple_of(config.line_size * config.ways),
+            "cache size {} is not a multiple of the line size times the number of ways",
+            config.size
+        );
+        let sets = config.size / (config.line_size * config.ways);
+        ensure!(
+            sets.is_power_of_two(),
+            "cache must have a power of two number of sets: {sets}"
+        );
+
+        Ok(Self {
+            config,
+            sets,
+            lines: vec![Line::default(); config.size as usize / config.line_size as usize],
+            time: 0,
+            counts: AccessCounts::default(),
+            counts_by_address: HashMap::new(),
+        })
+    }
+
+    pub fn config(&self) -> &ICacheConfig {
+        &self.config
+    }
+
+    /// Number of bus beats to refill a line
+    pub fn words_per_line(&self) -> u32 {
+        self.config.line_size / 4
+    }
+
+    /// Fetch from `addr`, filling its line on a miss, and return whether it hit
+    pub fn access(&mut self, addr: u32) -> bool {
+        self.time += 1;
+        let line_addr = addr / self.config.line_size;
+        let set = (line_addr % self.sets) as usize;
+        let tag = line_addr / self.sets;
+        let ways = self.config.ways as usize;
+        let lines = &mut self.lines[set * ways..(set + 1) * ways];
+
+        let hit = match lines.iter_mut().find(|line| line.tag == Some(tag)) {
+            Some(line) => {
+                line.last_used = self.time;
+                true
+            }
+            None => {
+                // empty lines have never been used, so are replaced first
+                let victim = lines.iter_mut().min_by_key(|line| line.last_used).unwrap();
+                *victim = Line {
+                    tag: Some(tag),
+                    last_used: self.time,
+                };
+                false
+            }
+        };
+
+        self.counts.record(hit);
+        self.counts_by_address.entry(addr).or_default().record(hit);
+        hit
+    }
+
+    /// Invalidate every line, as done for fence.i
+    pub fn flush(&mut self) {
+        self.lines.fill(Line::default());
+    }
+
+    pub fn counts(&self) -> AccessCounts {
+        self.counts
+    }
+
+    pub fn counts_by_address(&self) -> &HashMap<u32, AccessCounts> {
+        &self.counts_by_address
+    }
+
+    /// Counts for each function, sorted by most misses first
+    pub fn counts_by_function<'a>(&self, symbols: &'a Symbols) -> Vec<(&'a str, AccessCounts)> {
+        let mut functions: HashMap<&str, AccessCounts> = HashMap::new();
+        for (addr, counts) in &self.counts_by_address {
+            let function = functions.entry(symbols.name(*addr)).or_default();
+            function.hits += counts.hits;
+            function.misses += counts.misses;
+        }
+
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|(a_name, a), (b_name, b)| {
+            b.misses.cmp(&a.misses).then_with(|| a_name.cmp(b_name))
+        });
+        functions
+    }
+
+    /// Write the overall hit rate and a breakdown by function
+    pub fn report(&self, symbols: &Symbols, out: &mut impl fmt::Write) -> fmt::Result {
+        let config = &self.config;
+        writeln!(
+            out,
+            "icache ({} bytes, {} byte lines, {} ways): {} hits, {} misses, {:.2}% hit rate",
+            config.size,
+            config.line_size,
+            config.ways,
+            self.counts.hits,
+            self.counts.misses,
+            100.0 * self.counts.hit_rate()
+        )?;
+        writeln!(
+            out,
+            "  {:>12} {:>12} {:>8}  function",
+            "fetches", "misses", "hit rate"
+        )?;
+        for (name, counts) in self.counts_by_function(symbols) {
+            writeln!(
+                out,
+                "  {:>12} {:>12} {:>7.2}%  {name}",
+                counts.accesses(),
+                counts.misses,
+                100.0 * counts.hit_rate()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::Symbol;
+
+    fn new_cache(size: u32, line_size: u32, ways: u32) -> ICache {
+        ICache::new(ICacheConfig {
+            size,
+            line_size,
+            ways,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn lines_are_filled_on_miss() {
+        let mut cache = new_cache(256, 16, 1);
+        assert!(!cache.access(0x1000));
+        assert!(cache.access(0x1004));
+        assert!(cache.access(0x100C));
+        assert!(!cache.access(0x1010));
+        assert_eq!(cache.counts(), AccessCounts { hits: 2, misses: 2 });
+        assert_eq!(cache.counts_by_address()[&0x1000].misses, 1);
+    }
+
+    #[test]
+    fn direct_mapped_conflicts() {
+        // 0x1000 and 0x1100 map to the same set
+        let mut cache = new_cache(256, 16, 1);
+        assert!(!cache.access(0x1000));
+        assert!(!cache.access(0x1100));
+        assert!(!cache.access(0x1000));
+
+        // but fit side by side with two ways
+        let mut cache = new_cache(256, 16, 2);
+        assert!(!cache.access(0x1000));
+        assert!(!cache.access(0x1080));
+        assert!(cache.access(0x1000));
+        assert!(cache.access(0x1080));
+    }
+
+    #[test]
+    fn least_recently_used_is_replaced() {
+        let mut cache = new_cache(64, 16, 2);
+        cache.access(0x000);
+        cache.access(0x020);
+        cache.access(0x000);
+        // evicts 0x020, which was used longest ago
+        assert!(!cache.access(0x040));
+        assert!(cache.access(0x000));
+        assert!(!cache.access(0x020));
+    }
+
+    #[test]
+    fn flush() {
+        let mut cache = new_cache(256, 16, 2);
+        cache.access(0x1000);
+        cache.flush();
+        assert!(!cache.access(0x1000));
+    }
+
+    #[test]
+    fn invalid_configs() {
+        for (size, line_size, ways) in [(256, 6, 1), (256, 2, 1), (256, 16, 0), (100, 16, 1)] {
+            let config = ICacheConfig {
+                size,
+                line_size,
+                ways,
+            };
+            assert!(ICache::new(config).is_err(), "{config:?}");
+        }
+        // three sets
+        assert!(ICache::new(ICacheConfig {
+            size: 96,
+            line_size: 16,
+            ways: 2
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn counts_by_function() {
+        let symbols = Symbols::new(vec![
+            Symbol {
+                name: "hot".to_string(),
+                addr: 0x1000,
+                size: 0x40,
+            },
+            Symbol {
+                name: "cold".to_string(),
+                addr: 0x2000,
+                size: 0x40,
+            },
+        ]);
+        let mut cache = new_cache(256, 16, 1);
+        for _ in 0..3 {
+            for addr in (0x1000..0x1040).step_by(4) {
+                cache.access(addr);
+            }
+        }
+        cache.access(0x2000);
+        cache.access(0x3000);
+
+        let functions = cache.counts_by_function(&symbols);
+        assert_eq!(
+            functions[0],
+            (
+                "hot",
+                AccessCounts {
+                    hits: 44,
+                    misses: 4
+                }
+            )
+        );
+        assert_eq!(functions[1].1.misses, 1);
+        assert_eq!(functions.len(), 3);
+
+        let mut report = String::new();
+        cache.report(&symbols, &mut report).unwrap();
+        assert!(report.contains("<unknown>"), "{report}");
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::{bail, ensure, Context};
+use anyhow::{bail, ensure};
 
 /// 7-bit opcode (includes length bits)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -54,8 +54,30 @@ pub enum Instruction {
     Or,
     And,
     Fence,
+    FenceI,
     Ecall,
     Ebreak,
+    Mret,
+    Sret,
+    Wfi,
+    SfenceVma,
+    Csrrw,
+    Csrrs,
+    Csrrc,
+    Csrrwi,
+    Csrrsi,
+    Csrrci,
+    LrW,
+    ScW,
+    AmoswapW,
+    AmoaddW,
+    AmoxorW,
+    AmoandW,
+    AmoorW,
+    AmominW,
+    AmomaxW,
+    AmominuW,
+    AmomaxuW,
 }
 
 impl TryFrom<u32> for Instruction {
@@ -70,7 +92,7 @@ impl TryFrom<u32> for Instruction {
             (0b0110111, _, _) => Lui,
             (0b0010111, _, _) => Auipc,
             (0b1101111, _, _) => Jal,
-            (0b1100111, _, _) => Jalr,
+            (0b1100111, 0b000, _) => Jalr,
             (0b1100011, 0b000, _) => Beq,
             (0b1100011, 0b001, _) => Bne,
             (0b1100011, 0b100, _) => Blt,
@@ -104,29 +126,196 @@ impl TryFrom<u32> for Instruction {
             (0b0110011, 0b101, 0b0100000) => Sra,
             (0b0110011, 0b110, 0b0000000) => Or,
             (0b0110011, 0b111, 0b0000000) => And,
+            (0b0001111, 0b000, _) => Fence,
+            (0b0001111, 0b001, _) => FenceI,
+            (0b1110011, 0b000, _) if inst >> 7 == 0 => Ecall,
+            (0b1110011, 0b000, _) if inst >> 7 == 1 << 13 => Ebreak,
+            (0b1110011, 0b000, _) if inst == 0x30200073 => Mret,
+            (0b1110011, 0b000, _) if inst == 0x10200073 => Sret,
+            (0b1110011, 0b000, _) if inst == 0x10500073 => Wfi,
+            (0b1110011, 0b000, 0b0001001) if rd(inst) == 0 => SfenceVma,
+            (0b1110011, 0b001, _) => Csrrw,
+            (0b1110011, 0b010, _) => Csrrs,
+            (0b1110011, 0b011, _) => Csrrc,
+            (0b1110011, 0b101, _) => Csrrwi,
+            (0b1110011, 0b110, _) => Csrrsi,
+            (0b1110011, 0b111, _) => Csrrci,
+            // the low two bits of funct7 are the aq and rl ordering bits, which don't matter
+            // as memory accesses are performed in order
+            (0b0101111, 0b010, funct7) => match funct7 >> 2 {
+                0b00010 if rs2(inst) == 0 => LrW,
+                0b00011 => ScW,
+                0b00001 => AmoswapW,
+                0b00000 => AmoaddW,
+                0b00100 => AmoxorW,
+                0b01100 => AmoandW,
+                0b01000 => AmoorW,
+                0b10000 => AmominW,
+                0b10100 => AmomaxW,
+                0b11000 => AmominuW,
+                0b11100 => AmomaxuW,
+                _ => bail!("could not decode instruction: {inst:032b}"),
+            },
             _ => bail!("could not decode instruction: {inst:032b}"),
         })
     }
 }
 
+/// An instruction with its operand fields already extracted, so it can be cached and executed
+/// without decoding it again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    pub raw: u32,
+    pub kind: Instruction,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    /// Sign-extended immediate, or zero for instructions without one
+    pub immediate: u32,
+}
+
+impl TryFrom<u32> for DecodedInstruction {
+    type Error = anyhow::Error;
+
+    fn try_from(inst: u32) -> Result<Self, Self::Error> {
+        Ok(Self {
+            raw: inst,
+            kind: Instruction::try_from(inst)?,
+            rd: rd(inst) as u8,
+            rs1: rs1(inst) as u8,
+            rs2: rs2(inst) as u8,
+            immediate: immediate(inst).unwrap_or_default(),
+        })
+    }
+}
+
+impl DecodedInstruction {
+    /// Encode the instruction from its fields, giving back the word it was decoded from apart
+    /// from the ordering bits of atomics, which the decoder ignores
+    pub fn encode(&self) -> u32 {
+        use Instruction::*;
+        let (rd, rs1, rs2, imm) =
+            (self.rd as u32, self.rs1 as u32, self.rs2 as u32, self.immediate);
+        let r = |opcode: u32, funct3: u32, funct7: u32| {
+            opcode | (rd << 7) | (funct3 << 12) | (rs1 << 15) | (rs2 << 20) | (funct7 << 25)
+        };
+        let i = |opcode: u32, funct3: u32| {
+            opcode | (rd << 7) | (funct3 << 12) | (rs1 << 15) | ((imm & 0xFFF) << 20)
+        };
+        let s = |opcode: u32, funct3: u32| {
+            opcode
+                | ((imm & 0x1F) << 7)
+                | (funct3 << 12)
+                | (rs1 << 15)
+                | (rs2 << 20)
+                | ((imm >> 5 & 0x7F) << 25)
+        };
+        let b = |funct3: u32| {
+            0b1100011
+                | ((imm >> 11 & 0x1) << 7)
+                | ((imm >> 1 & 0xF) << 8)
+                | (funct3 << 12)
+                | (rs1 << 15)
+                | (rs2 << 20)
+                | ((imm >> 5 & 0x3F) << 25)
+                | ((imm >> 12 & 0x1) << 31)
+        };
+        let u = |opcode: u32| opcode | (rd << 7) | (imm & 0xFFFFF000);
+        // atomics keep their operation in the upper five bits of funct7
+        let amo = |funct5: u32| r(0b0101111, 0b010, funct5 << 2);
+
+        match self.kind {
+            Lui => u(0b0110111),
+            Auipc => u(0b0010111),
+            Jal => {
+                0b1101111
+                    | (rd << 7)
+                    | (imm & 0xFF000)
+                    | ((imm >> 11 & 0x1) << 20)
+                    | ((imm >> 1 & 0x3FF) << 21)
+                    | ((imm >> 20 & 0x1) << 31)
+            }
+            Jalr => i(0b1100111, 0b000),
+            Beq => b(0b000),
+            Bne => b(0b001),
+            Blt => b(0b100),
+            Bge => b(0b101),
+            Bltu => b(0b110),
+            Bgeu => b(0b111),
+            Lb => i(0b0000011, 0b000),
+            Lh => i(0b0000011, 0b001),
+            Lw => i(0b0000011, 0b010),
+            Lbu => i(0b0000011, 0b100),
+            Lhu => i(0b0000011, 0b101),
+            Sb => s(0b0100011, 0b000),
+            Sh => s(0b0100011, 0b001),
+            Sw => s(0b0100011, 0b010),
+            Addi => i(0b0010011, 0b000),
+            Slti => i(0b0010011, 0b010),
+            Sltiu => i(0b0010011, 0b011),
+            Xori => i(0b0010011, 0b100),
+            Ori => i(0b0010011, 0b110),
+            Andi => i(0b0010011, 0b111),
+            // the funct7 of the shifts is part of their immediate
+            Slli => i(0b0010011, 0b001),
+            Srli | Srai => i(0b0010011, 0b101),
+            Add => r(0b0110011, 0b000, 0b0000000),
+            Sub => r(0b0110011, 0b000, 0b0100000),
+            Sll => r(0b0110011, 0b001, 0b0000000),
+            Slt => r(0b0110011, 0b010, 0b0000000),
+            Sltu => r(0b0110011, 0b011, 0b0000000),
+            Xor => r(0b0110011, 0b100, 0b0000000),
+            Srl => r(0b0110011, 0b101, 0b0000000),
+            Sra => r(0b0110011, 0b101, 0b0100000),
+            Or => r(0b0110011, 0b110, 0b0000000),
+            And => r(0b0110011, 0b111, 0b0000000),
+            Fence => i(0b0001111, 0b000),
+            FenceI => i(0b0001111, 0b001),
+            Ecall => 0x00000073,
+            Ebreak => 0x00100073,
+            Mret => 0x30200073,
+            Sret => 0x10200073,
+            Wfi => 0x10500073,
+            SfenceVma => r(0b1110011, 0b000, 0b0001001),
+            Csrrw => i(0b1110011, 0b001),
+            Csrrs => i(0b1110011, 0b010),
+            Csrrc => i(0b1110011, 0b011),
+            Csrrwi => i(0b1110011, 0b101),
+            Csrrsi => i(0b1110011, 0b110),
+            Csrrci => i(0b1110011, 0b111),
+            LrW => amo(0b00010),
+            ScW => amo(0b00011),
+            AmoswapW => amo(0b00001),
+            AmoaddW => amo(0b00000),
+            AmoxorW => amo(0b00100),
+            AmoandW => amo(0b01100),
+            AmoorW => amo(0b01000),
+            AmominW => amo(0b10000),
+            AmomaxW => amo(0b10100),
+            AmominuW => amo(0b11000),
+            AmomaxuW => amo(0b11100),
+        }
+    }
+}
+
 impl TryFrom<Opcode> for InstEncoding {
     type Error = anyhow::Error;
 
     fn try_from(value: Opcode) -> Result<Self, Self::Error> {
         Ok(match value.0 {
-            0b1100111 | 0b0000011 | 0b0010011 | 0b1110011 => InstEncoding::I,
+            0b1100111 | 0b0000011 | 0b0010011 | 0b0001111 | 0b1110011 => InstEncoding::I,
             0b0100011 => InstEncoding::S,
             0b1100011 => InstEncoding::B,
             0b0110111 | 0b0010111 => InstEncoding::U,
             0b1101111 => InstEncoding::J,
-            0b0110011 => InstEncoding::R,
+            0b0110011 | 0b0101111 => InstEncoding::R,
             opcode => bail!("could not determine inst encoding for opcode: {opcode:07b}"),
         })
     }
 }
 
 pub fn opcode(inst: u32) -> Result<Opcode, anyhow::Error> {
-    ensure!(inst & 0b11 == 0b11, "instruction is not compressed");
+    ensure!(inst & 0b11 == 0b11, "compressed instructions are not supported");
     Ok(Opcode(inst as u8 & 0b1111111))
 }
 
@@ -184,3 +373,284 @@ pub fn immediate(inst: u32) -> Result<u32, anyhow::Error> {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Fixed bits of an instruction form: (instruction, encoding, opcode, funct3, funct7).
+    ///
+    /// For the shift-immediate forms the funct7 lives in the upper immediate bits.
+    type Form = (Instruction, InstEncoding, u32, Option<u32>, Option<u32>);
+
+    const FORMS: &[Form] = &[
+        (Instruction::Lui, InstEncoding::U, 0b0110111, None, None),
+        (Instruction::Auipc, InstEncoding::U, 0b0010111, None, None),
+        (Instruction::Jal, InstEncoding::J, 0b1101111, None, None),
+        (Instruction::Jalr, InstEncoding::I, 0b1100111, Some(0b000), None),
+        (Instruction::Beq, InstEncoding::B, 0b1100011, Some(0b000), None),
+        (Instruction::Bne, InstEncoding::B, 0b1100011, Some(0b001), None),
+        (Instruction::Blt, InstEncoding::B, 0b1100011, Some(0b100), None),
+        (Instruction::Bge, InstEncoding::B, 0b1100011, Some(0b101), None),
+        (Instruction::Bltu, InstEncoding::B, 0b1100011, Some(0b110), None),
+        (Instruction::Bgeu, InstEncoding::B, 0b1100011, Some(0b111), None),
+        (Instruction::Lb, InstEncoding::I, 0b0000011, Some(0b000), None),
+        (Instruction::Lh, InstEncoding::I, 0b0000011, Some(0b001), None),
+        (Instruction::Lw, InstEncoding::I, 0b0000011, Some(0b010), None),
+        (Instruction::Lbu, InstEncoding::I, 0b0000011, Some(0b100), None),
+        (Instruction::Lhu, InstEncoding::I, 0b0000011, Some(0b101), None),
+        (Instruction::Sb, InstEncoding::S, 0b0100011, Some(0b000), None),
+        (Instruction::Sh, InstEncoding::S, 0b0100011, Some(0b001), None),
+        (Instruction::Sw, InstEncoding::S, 0b0100011, Some(0b010), None),
+        (Instruction::Addi, InstEncoding::I, 0b0010011, Some(0b000), None),
+        (Instruction::Slti, InstEncoding::I, 0b0010011, Some(0b010), None),
+        (Instruction::Sltiu, InstEncoding::I, 0b0010011, Some(0b011), None),
+        (Instruction::Xori, InstEncoding::I, 0b0010011, Some(0b100), None),
+        (Instruction::Ori, InstEncoding::I, 0b0010011, Some(0b110), None),
+        (Instruction::Andi, InstEncoding::I, 0b0010011, Some(0b111), None),
+        (Instruction::Slli, InstEncoding::I, 0b0010011, Some(0b001), Some(0b0000000)),
+        (Instruction::Srli, InstEncoding::I, 0b0010011, Some(0b101), Some(0b0000000)),
+        (Instruction::Srai, InstEncoding::I, 0b0010011, Some(0b101), Some(0b0100000)),
+        (Instruction::Add, InstEncoding::R, 0b0110011, Some(0b000), Some(0b0000000)),
+        (Instruction::Sub, InstEncoding::R, 0b0110011, Some(0b000), Some(0b0100000)),
+        (Instruction::Sll, InstEncoding::R, 0b0110011, Some(0b001), Some(0b0000000)),
+        (Instruction::Slt, InstEncoding::R, 0b0110011, Some(0b010), Some(0b0000000)),
+        (Instruction::Sltu, InstEncoding::R, 0b0110011, Some(0b011), Some(0b0000000)),
+        (Instruction::Xor, InstEncoding::R, 0b0110011, Some(0b100), Some(0b0000000)),
+        (Instruction::Srl, InstEncoding::R, 0b0110011, Some(0b101), Some(0b0000000)),
+        (Instruction::Sra, InstEncoding::R, 0b0110011, Some(0b101), Some(0b0100000)),
+        (Instruction::Or, InstEncoding::R, 0b0110011, Some(0b110), Some(0b0000000)),
+        (Instruction::And, InstEncoding::R, 0b0110011, Some(0b111), Some(0b0000000)),
+        (Instruction::Fence, InstEncoding::I, 0b0001111, Some(0b000), None),
+        (Instruction::FenceI, InstEncoding::I, 0b0001111, Some(0b001), None),
+        (Instruction::Csrrw, InstEncoding::I, 0b1110011, Some(0b001), None),
+        (Instruction::Csrrs, InstEncoding::I, 0b1110011, Some(0b010), None),
+        (Instruction::Csrrc, InstEncoding::I, 0b1110011, Some(0b011), None),
+        (Instruction::Csrrwi, InstEncoding::I, 0b1110011, Some(0b101), None),
+        (Instruction::Csrrsi, InstEncoding::I, 0b1110011, Some(0b110), None),
+        (Instruction::Csrrci, InstEncoding::I, 0b1110011, Some(0b111), None),
+        (Instruction::LrW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b0001000)),
+        (Instruction::ScW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b0001100)),
+        (Instruction::AmoswapW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b0000100)),
+        (Instruction::AmoaddW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b0000000)),
+        (Instruction::AmoxorW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b0010000)),
+        (Instruction::AmoandW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b0110000)),
+        (Instruction::AmoorW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b0100000)),
+        (Instruction::AmominW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b1000000)),
+        (Instruction::AmomaxW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b1010000)),
+        (Instruction::AmominuW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b1100000)),
+        (Instruction::AmomaxuW, InstEncoding::R, 0b0101111, Some(0b010), Some(0b1110000)),
+    ];
+
+    const SYSTEM: u32 = 0b1110011;
+    const AMO: u32 = 0b0101111;
+
+    /// Operand fields of a single instruction, with the immediate already in its final
+    /// (sign-extended, unscaled) form.
+    #[derive(Debug, Clone, Copy)]
+    struct Operands {
+        rd: u32,
+        rs1: u32,
+        rs2: u32,
+        imm: i32,
+    }
+
+    fn encode(form: usize, ops: Operands) -> u32 {
+        let (_, encoding, opcode, funct3, funct7) = FORMS[form];
+        let imm = ops.imm as u32;
+        let mut inst = opcode | (funct3.unwrap_or(0) << 12) | (funct7.unwrap_or(0) << 25);
+        inst |= match encoding {
+            InstEncoding::R => (ops.rd << 7) | (ops.rs1 << 15) | (ops.rs2 << 20),
+            InstEncoding::I => (ops.rd << 7) | (ops.rs1 << 15) | ((imm & 0xFFF) << 20),
+            InstEncoding::S => {
+                ((imm & 0x1F) << 7) | (ops.rs1 << 15) | (ops.rs2 << 20) | ((imm >> 5 & 0x7F) << 25)
+            }
+            InstEncoding::B => {
+                ((imm >> 11 & 0x1) << 7)
+                    | ((imm >> 1 & 0xF) << 8)
+                    | (ops.rs1 << 15)
+                    | (ops.rs2 << 20)
+                    | ((imm >> 5 & 0x3F) << 25)
+                    | ((imm >> 12 & 0x1) << 31)
+            }
+            InstEncoding::U => (ops.rd << 7) | (imm & 0xFFFFF000),
+            InstEncoding::J => {
+                (ops.rd << 7)
+                    | (imm & 0xFF000)
+                    | ((imm >> 11 & 0x1) << 20)
+                    | ((imm >> 1 & 0x3FF) << 21)
+                    | ((imm >> 20 & 0x1) << 31)
+            }
+        };
+        inst
+    }
+
+    /// Strategy for a legal immediate of the given form.
+    fn immediate_strategy(form: usize) -> BoxedStrategy<i32> {
+        let (inst, encoding, ..) = FORMS[form];
+        match (inst, encoding) {
+            (Instruction::Slli | Instruction::Srli | Instruction::Srai, _) => (0..32).boxed(),
+            (_, InstEncoding::R) => Just(0).boxed(),
+            (_, InstEncoding::I | InstEncoding::S) => (-2048..2048).boxed(),
+            (_, InstEncoding::B) => (-2048..2048).prop_map(|imm| imm * 2).boxed(),
+            (_, InstEncoding::U) => any::<i32>().prop_map(|imm| imm & !0xFFF).boxed(),
+            (_, InstEncoding::J) => (-(1 << 19)..(1 << 19)).prop_map(|imm| imm * 2).boxed(),
+        }
+    }
+
+    fn form_and_operands() -> impl Strategy<Value = (usize, Operands)> {
+        (0..FORMS.len()).prop_flat_map(|form| {
+            (
+                Just(form),
+                (0..32u32, 0..32u32, 0..32u32, immediate_strategy(form)).prop_map(
+                    move |(rd, rs1, rs2, imm)| {
+                        // lr.w has no source value, so rs2 must be zero
+                        let rs2 = if FORMS[form].0 == Instruction::LrW { 0 } else { rs2 };
+                        Operands { rd, rs1, rs2, imm }
+                    },
+                ),
+            )
+        })
+    }
+
+    /// The instruction a selector (opcode, funct3, funct7) should decode to, derived from
+    /// [`FORMS`] rather than the decoder itself.
+    fn expected_instruction(opcode: u32, funct3: u32, funct7: u32) -> Option<Instruction> {
+        // atomics ignore their aq and rl bits
+        let funct7 = if opcode == AMO { funct7 & !0b11 } else { funct7 };
+        FORMS
+            .iter()
+            .find(|(_, _, form_opcode, form_funct3, form_funct7)| {
+                *form_opcode == opcode
+                    && form_funct3.is_none_or(|f| f == funct3)
+                    && form_funct7.is_none_or(|f| f == funct7)
+            })
+            .map(|(inst, ..)| *inst)
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(
+            (form, ops) in form_and_operands(),
+        ) {
+            let (expected, encoding, ..) = FORMS[form];
+            let raw = encode(form, ops);
+
+            prop_assert_eq!(Instruction::try_from(raw).unwrap(), expected);
+            let decoded = DecodedInstruction::try_from(raw).unwrap();
+            prop_assert_eq!(decoded.kind, expected);
+            prop_assert_eq!(decoded.immediate, immediate(raw).unwrap_or_default());
+            prop_assert_eq!(decoded.encode(), raw);
+            if matches!(encoding, InstEncoding::R | InstEncoding::I | InstEncoding::U | InstEncoding::J) {
+                prop_assert_eq!(rd(raw), ops.rd as usize);
+            }
+            if !matches!(encoding, InstEncoding::U | InstEncoding::J) {
+                prop_assert_eq!(rs1(raw), ops.rs1 as usize);
+            }
+            if matches!(encoding, InstEncoding::R | InstEncoding::S | InstEncoding::B) {
+                prop_assert_eq!(rs2(raw), ops.rs2 as usize);
+            }
+
+            match encoding {
+                InstEncoding::R => prop_assert!(immediate(raw).is_err()),
+                // shift amounts are masked by the cpu, so only the low bits need to match
+                _ if matches!(expected, Instruction::Slli | Instruction::Srli | Instruction::Srai) => {
+                    prop_assert_eq!(immediate(raw).unwrap() & 0b11111, ops.imm as u32);
+                }
+                _ => prop_assert_eq!(immediate(raw).unwrap() as i32, ops.imm),
+            }
+        }
+
+        #[test]
+        fn decoder_never_panics(inst in any::<u32>()) {
+            if let Ok(decoded) = DecodedInstruction::try_from(inst) {
+                let encoding = InstEncoding::try_from(opcode(inst).unwrap()).unwrap();
+                prop_assert_eq!(immediate(inst).is_err(), encoding == InstEncoding::R, "{:?}", decoded);
+                // atomics lose their ordering bits
+                let ordering = if opcode(inst).unwrap().0 == AMO as u8 { 0b11 << 25 } else { 0 };
+                prop_assert_eq!(decoded.encode(), inst & !ordering);
+            }
+        }
+    }
+
+    #[test]
+    fn exhaustive_selectors() {
+        // every opcode/funct3/funct7 combination, with the remaining bits both clear and set
+        for fill in [0, 0x01FF8F80] {
+            for opcode in 0..(1 << 7) {
+                for funct3 in 0..(1 << 3) {
+                    // ecall/ebreak/mret/wfi are selected by more than the funct fields
+                    if opcode == SYSTEM && funct3 == 0 {
+                        continue;
+                    }
+                    for funct7 in 0..(1 << 7) {
+                        let inst = fill | opcode | (funct3 << 12) | (funct7 << 25);
+                        let decoded = Instruction::try_from(inst).ok();
+                        let expected = if opcode & 0b11 == 0b11 {
+                            expected_instruction(opcode, funct3, funct7)
+                        } else {
+                            None
+                        };
+                        // lr.w is also selected by rs2, which the fill sets
+                        let expected =
+                            expected.filter(|&kind| kind != Instruction::LrW || fill == 0);
+                        assert_eq!(decoded, expected, "{inst:032b}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn system_instructions() {
+        assert_eq!(Instruction::try_from(0x00000073).unwrap(), Instruction::Ecall);
+        assert_eq!(Instruction::try_from(0x00100073).unwrap(), Instruction::Ebreak);
+        // any other register or immediate bits are not ecall/ebreak
+        assert!(Instruction::try_from(0x000000F3).is_err());
+        assert!(Instruction::try_from(0x00108073).is_err());
+        assert!(Instruction::try_from(0x00200073).is_err());
+        assert_eq!(Instruction::try_from(0x30200073).unwrap(), Instruction::Mret);
+        assert_eq!(Instruction::try_from(0x10500073).unwrap(), Instruction::Wfi);
+        assert!(Instruction::try_from(0x30200173).is_err());
+        assert_eq!(Instruction::try_from(0x10200073).unwrap(), Instruction::Sret);
+        // sfence.vma a0, a1, but not with a destination register
+        assert_eq!(
+            Instruction::try_from(0x12B50073).unwrap(),
+            Instruction::SfenceVma
+        );
+        assert!(Instruction::try_from(0x12B500F3).is_err());
+    }
+
+    #[test]
+    fn atomic_instructions() {
+        // amoadd.w.aqrl a0, a2, (a1)
+        assert_eq!(Instruction::try_from(0x06C5A52F).unwrap(), Instruction::AmoaddW);
+        assert_eq!(Instruction::try_from(0x1005A52F).unwrap(), Instruction::LrW);
+        // lr.w with a source register, and doubleword atomics
+        assert!(Instruction::try_from(0x1015A52F).is_err());
+        assert!(Instruction::try_from(0x1005B52F).is_err());
+    }
+
+    #[test]
+    fn immediate_sign_extension() {
+        // I-type: addi x1, x0, -1 / 2047
+        assert_eq!(immediate(0xFFF00093).unwrap(), 0xFFFFFFFF);
+        assert_eq!(immediate(0x7FF00093).unwrap(), 0x000007FF);
+        assert_eq!(immediate(0x80000093).unwrap(), 0xFFFFF800);
+        // S-type: sw x0, -2048(x0) / 2047(x0)
+        assert_eq!(immediate(0x80002023).unwrap(), 0xFFFFF800);
+        assert_eq!(immediate(0x7E002FA3).unwrap(), 0x000007FF);
+        // B-type: beq x0, x0, -4096 / 4094
+        assert_eq!(immediate(0x80000063).unwrap(), 0xFFFFF000);
+        assert_eq!(immediate(0x7E000FE3).unwrap(), 0x00000FFE);
+        // U-type: lui x1, 0xFFFFF
+        assert_eq!(immediate(0xFFFFF0B7).unwrap(), 0xFFFFF000);
+        // J-type: jal x0, -1MiB / 1MiB - 2
+        assert_eq!(immediate(0x8000006F).unwrap(), 0xFFF00000);
+        assert_eq!(immediate(0x7FFFF06F).unwrap(), 0x000FFFFE);
+        // R-type has no immediate
+        assert!(immediate(0x00000033).is_err());
+    }
+}
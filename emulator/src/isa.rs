@@ -0,0 +1,95 @@
+//! Extensions of the base integer ISA that the core implements
+
+use std::str::FromStr;
+
+use anyhow::{bail, ensure};
+
+use crate::instructions::Instruction;
+
+/// Extensions enabled on top of RV32I, all of which are by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Isa {
+    /// Atomic memory operations
+    pub a: bool,
+    pub zicsr: bool,
+    pub zifencei: bool,
+}
+
+impl Default for Isa {
+    fn default() -> Self {
+        Self {
+            a: true,
+            zicsr: true,
+            zifencei: true,
+        }
+    }
+}
+
+impl Isa {
+    /// Whether `kind` is in the base ISA or an enabled extension
+    pub fn implements(&self, kind: Instruction) -> bool {
+        use Instruction::*;
+        match kind {
+            Csrrw | Csrrs | Csrrc | Csrrwi | Csrrsi | Csrrci => self.zicsr,
+            FenceI => self.zifencei,
+            LrW | ScW | AmoswapW | AmoaddW | AmoxorW | AmoandW | AmoorW | AmominW | AmomaxW
+            | AmominuW | AmomaxuW => self.a,
+            _ => true,
+        }
+    }
+}
+
+impl FromStr for Isa {
+    type Err = anyhow::Error;
+
+    /// Parse an ISA string such as `rv32ia_zicsr_zifencei`
+    fn from_str(isa: &str) -> Result<Self, Self::Err> {
+        let isa = isa.to_lowercase();
+        let Some(extensions) = isa.strip_prefix("rv32i") else {
+            bail!("only rv32i is supported as the base ISA: {isa}");
+        };
+
+        let mut enabled = Self {
+            a: false,
+            zicsr: false,
+            zifencei: false,
+        };
+        for extension in extensions
+            .split('_')
+            .filter(|extension| !extension.is_empty())
+        {
+            let flag = match extension {
+                "a" => &mut enabled.a,
+                "zicsr" => &mut enabled.zicsr,
+                "zifencei" => &mut enabled.zifencei,
+                _ => bail!("unsupported extension: {extension}"),
+            };
+            ensure!(!*flag, "extension given twice: {extension}");
+            *flag = true;
+        }
+        Ok(enabled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "RV32IA_Zicsr_Zifencei".parse::<Isa>().unwrap(),
+            Isa::default()
+        );
+        let base: Isa = "rv32i".parse().unwrap();
+        assert!(!base.implements(Instruction::LrW));
+        assert!(!base.implements(Instruction::Csrrw));
+        assert!(!base.implements(Instruction::FenceI));
+        assert!(base.implements(Instruction::Fence));
+        assert!("rv32i_zicsr".parse::<Isa>().unwrap().zicsr);
+
+        assert!("rv64i".parse::<Isa>().is_err());
+        assert!("rv32im".parse::<Isa>().is_err());
+        assert!("rv32i_zicsr_zicsr".parse::<Isa>().is_err());
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::anyhow;
+use cranelift_codegen::{
+    ir::{condcodes::IntCC, types, AbiParam, InstBuilder, MemFlags, Value},
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use crate::instructions::{DecodedInstruction, Instruction};
+
+/// Number of times a block has to be entered by the interpreter before it is compiled
+const HOT_THRESHOLD: u32 = 16;
+
+/// Maximum number of instructions in a single compiled block
+pub const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+
+/// Granularity at which stores invalidate compiled blocks
+const PAGE_SHIFT: u32 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JitMode {
+    /// Run compiled blocks in place of the interpreter
+    Enabled,
+    /// Run every compiled block, then re-run it with the interpreter and compare the results
+    CrossCheck,
+}
+
+/// Compiled block, taking a pointer to the 32 guest registers and returning the next pc
+type BlockFn = unsafe extern "C" fn(*mut u32) -> u32;
+
+#[derive(Clone, Copy)]
+pub struct Block {
+    func: BlockFn,
+    /// Number of guest instructions retired by running the block to completion
+    pub instructions: usize,
+}
+
+impl Block {
+    /// Run the block against the guest registers, returning the next pc
+    pub fn run(&self, registers: &mut [u32; 32]) -> u32 {
+        // SAFETY: the function was generated by `Jit::compile` for exactly this signature, and
+        // only accesses the 32 registers behind the pointer
+        unsafe { (self.func)(registers.as_mut_ptr()) }
+    }
+}
+
+/// Translates hot basic blocks of register-only instructions to host code
+///
+/// Anything that touches memory, CSRs or can trap is left for the interpreter, so a block
+/// ends either at its terminating branch/jump or just before the first instruction it
+/// cannot translate.
+pub struct Jit {
+    pub mode: JitMode,
+    module: JITModule,
+    builder_context: FunctionBuilderContext,
+    blocks: HashMap<u32, Block>,
+    /// Blocks that could not be compiled, so they are not attempted again
+    rejected: HashSet<u32>,
+    /// Start addresses of compiled blocks in each page of guest memory
+    pages: HashMap<u32, Vec<u32>>,
+    entry_counts: HashMap<u32, u32>,
+}
+
+impl Jit {
+    pub fn new(mode: JitMode) -> Result<Self, anyhow::Error> {
+        let mut flag_builder = settings::builder();
+        flag_builder.set("use_colocated_libcalls", "false")?;
+        flag_builder.set("is_pic", "false")?;
+        flag_builder.set("opt_level", "speed")?;
+        let isa = cranelift_native::builder()
+            .map_err(|msg| anyhow!("host is not supported by cranelift: {msg}"))?
+            .finish(settings::Flags::new(flag_builder))?;
+
+        Ok(Self {
+            mode,
+            module: JITModule::new(JITBuilder::with_isa(isa, default_libcall_names())),
+            builder_context: FunctionBuilderContext::new(),
+            blocks: HashMap::new(),
+            rejected: HashSet::new(),
+            pages: HashMap::new(),
+            entry_counts: HashMap::new(),
+        })
+    }
+
+    pub fn get(&self, pc: u32) -> Option<Block> {
+        self.blocks.get(&pc).copied()
+    }
+
+    /// Record an interpreter entry into the block at `pc`, returning true once it is hot
+    /// enough to be compiled
+    pub fn record_entry(&mut self, pc: u32) -> bool {
+        if self.rejected.contains(&pc) {
+            return false;
+        }
+        let count = self.entry_counts.entry(pc).or_default();
+        *count += 1;
+        *count == HOT_THRESHOLD
+    }
+
+    /// Compile the instructions starting at `pc` into a block
+    ///
+    /// Returns `None` if not even the first instruction can be translated.
+    pub fn compile(
+        &mut self,
+        pc: u32,
+        instructions: &[DecodedInstruction],
+    ) -> Result<Option<Block>, anyhow::Error> {
+        let length = block_length(pc, instructions);
+        if length == 0 {
+            self.rejected.insert(pc);
+            return Ok(None);
+        }
+        let instructions = &instructions[..length];
+
+        let mut context = self.module.make_context();
+        let pointer_type = self.module.target_config().pointer_type();
+        context
+            .func
+            .signature
+            .params
+            .push(AbiParam::new(pointer_type));
+        context
+            .func
+            .signature
+            .returns
+            .push(AbiParam::new(types::I32));
+
+        let mut builder = FunctionBuilder::new(&mut context.func, &mut self.builder_context);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let registers_ptr = builder.block_params(entry)[0];
+
+        let mut translator = Translator {
+            builder,
+            registers_ptr,
+            values: [None; 32],
+            dirty: [false; 32],
+        };
+        let mut next_pc = None;
+        for (index, inst) in instructions.iter().enumerate() {
+            let inst_pc = pc.wrapping_add(4 * index as u32);
+            next_pc = translator.translate(inst_pc, inst);
+        }
+        let next_pc = match next_pc {
+            Some(next_pc) => next_pc,
+            None => translator.constant(pc.wrapping_add(4 * length as u32)),
+        };
+        translator.flush();
+        translator.builder.ins().return_(&[next_pc]);
+        translator.builder.finalize();
+
+        let id = self
+            .module
+            .declare_anonymous_function(&context.func.signature)?;
+        self.module.define_function(id, &mut context)?;
+        self.module.clear_context(&mut context);
+        self.module.finalize_definitions()?;
+
+        // SAFETY: the function was just defined with the `BlockFn` signature
+        let func = unsafe {
+            std::mem::transmute::<*const u8, BlockFn>(self.module.get_finalized_function(id))
+        };
+        let block = Block {
+            func,
+            instructions: length,
+        };
+        self.blocks.insert(pc, block);
+        self.pages.entry(pc >> PAGE_SHIFT).or_default().push(pc);
+        let last_page = pc.wrapping_add(4 * (length as u32 - 1)) >> PAGE_SHIFT;
+        if last_page != pc >> PAGE_SHIFT {
+            self.pages.entry(last_page).or_default().push(pc);
+        }
+        Ok(Some(block))
+    }
+
+    /// Drop any compiled blocks that could contain `addr`
+    pub fn invalidate(&mut self, addr: u32) {
+        if let Some(starts) = self.pages.remove(&(addr >> PAGE_SHIFT)) {
+            for start in starts {
+                self.blocks.remove(&start);
+                self.entry_counts.remove(&start);
+            }
+        }
+    }
+
+    /// Drop every compiled block, as required by `fence.i`
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.rejected.clear();
+        self.pages.clear();
+        self.entry_counts.clear();
+    }
+}
+
+/// Whether an instruction ends a basic block
+pub fn ends_block(kind: Instruction) -> bool {
+    use Instruction::*;
+    matches!(
+        kind,
+        Jal | Jalr
+            | Beq
+            | Bne
+            | Blt
+            | Bge
+            | Bltu
+            | Bgeu
+            | FenceI
+            | Ecall
+            | Ebreak
+            | Mret
+            | Sret
+            | Wfi
+    )
+}
+
+/// Whether an instruction is always left to the interpreter
+pub fn is_interpreted(kind: Instruction) -> bool {
+    use Instruction::*;
+    matches!(
+        kind,
+        Lb | Lh
+            | Lw
+            | Lbu
+            | Lhu
+            | Sb
+            | Sh
+            | Sw
+            | Fence
+            | FenceI
+            | Ecall
+            | Ebreak
+            | Mret
+            | Sret
+            | Wfi
+            | SfenceVma
+            | Csrrw
+            | Csrrs
+            | Csrrc
+            | Csrrwi
+            | Csrrsi
+            | Csrrci
+            | LrW
+            | ScW
+            | AmoswapW
+            | AmoaddW
+            | AmoxorW
+            | AmoandW
+            | AmoorW
+            | AmominW
+            | AmomaxW
+            | AmominuW
+            | AmomaxuW
+    )
+}
+
+/// Number of leading instructions that can be translated into a single block
+fn block_length(pc: u32, instructions: &[DecodedInstruction]) -> usize {
+    use Instruction::*;
+    for (index, inst) in instructions.iter().enumerate() {
+        let inst_pc = pc.wrapping_add(4 * index as u32);
+        if is_interpreted(inst.kind) {
+            return index;
+        }
+        // leave misaligned static targets to the interpreter so it can report them
+        if matches!(inst.kind, Jal | Beq | Bne | Blt | Bge | Bltu | Bgeu)
+            && inst_pc.wrapping_add(inst.immediate) & 0b11 != 0
+        {
+            return index;
+        }
+        if ends_block(inst.kind) {
+            return index + 1;
+        }
+    }
+    instructions.len()
+}
+
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    registers_ptr: Value,
+    /// Current value of each guest register, loaded on first use
+    values: [Option<Value>; 32],
+    dirty: [bool; 32],
+}
+
+impl Translator<'_> {
+    fn constant(&mut self, value: u32) -> Value {
+        self.builder.ins().iconst(types::I32, value as i32 as i64)
+    }
+
+    fn read(&mut self, index: u8) -> Value {
+        let index = index as usize;
+        if index == 0 {
+            return self.constant(0);
+        }
+        if let Some(value) = self.values[index] {
+            return value;
+        }
+        let value = self.builder.ins().load(
+            types::I32,
+            MemFlags::trusted(),
+            self.registers_ptr,
+            4 * index as i32,
+        );
+        self.values[index] = Some(value);
+        value
+    }
+
+    fn write(&mut self, index: u8, value: Value) {
+        let index = index as usize;
+        if index == 0 {
+            return;
+        }
+        self.values[index] = Some(value);
+        self.dirty[index] = true;
+    }
+
+    /// Store every modified register back to the guest register file
+    fn flush(&mut self) {
+        for index in 1..32 {
+            if let (true, Some(value)) = (self.dirty[index], self.values[index]) {
+                self.builder.ins().store(
+                    MemFlags::trusted(),
+                    value,
+                    self.registers_ptr,
+                    4 * index as i32,
+                );
+            }
+        }
+    }
+
+    fn compare(&mut self, cc: IntCC, lhs: Value, rhs: Value) -> Value {
+        let flag = self.builder.ins().icmp(cc, lhs, rhs);
+        self.builder.ins().uextend(types::I32, flag)
+    }
+
+    /// Translate a single instruction, returning the next pc if it is a control transfer
+    fn translate(&mut self, pc: u32, inst: &DecodedInstruction) -> Option<Value> {
+        use Instruction::*;
+
+        let immediate = inst.immediate;
+        let rs1 = self.read(inst.rs1);
+        let rs2 = self.read(inst.rs2);
+        let imm = self.constant(immediate);
+
+        let result = match inst.kind {
+            Lui => imm,
+            Auipc => self.constant(pc.wrapping_add(immediate)),
+            Addi => self.builder.ins().iadd(rs1, imm),
+            Slti => self.compare(IntCC::SignedLessThan, rs1, imm),
+            Sltiu => self.compare(IntCC::UnsignedLessThan, rs1, imm),
+            Xori => self.builder.ins().bxor(rs1, imm),
+            Ori => self.builder.ins().bor(rs1, imm),
+            Andi => self.builder.ins().band(rs1, imm),
+            // cranelift masks shift amounts to the width of the type, matching RV32
+            Slli => self.builder.ins().ishl(rs1, imm),
+            Srli => self.builder.ins().ushr(rs1, imm),
+            Srai => self.builder.ins().sshr(rs1, imm),
+            Add => self.builder.ins().iadd(rs1, rs2),
+            Sub => self.builder.ins().isub(rs1, rs2),
+            Sll => self.builder.ins().ishl(rs1, rs2),
+            Slt => self.compare(IntCC::SignedLessThan, rs1, rs2),
+            Sltu => self.compare(IntCC::UnsignedLessThan, rs1, rs2),
+            Xor => self.builder.ins().bxor(rs1, rs2),
+            Srl => self.builder.ins().ushr(rs1, rs2),
+            Sra => self.builder.ins().sshr(rs1, rs2),
+            Or => self.builder.ins().bor(rs1, rs2),
+            And => self.builder.ins().band(rs1, rs2),
+            Jal => {
+                let link = self.constant(pc.wrapping_add(4));
+                self.write(inst.rd, link);
+                return Some(self.constant(pc.wrapping_add(immediate)));
+            }
+            Jalr => {
+                let target = self.builder.ins().iadd(rs1, imm);
+                let target = self.builder.ins().band_imm(target, !1i64);
+                // a misaligned target returns to the jalr itself without side effects, so the
+                // interpreter can report it
+                let misaligned = self.builder.ins().band_imm(target, 0b11);
+                let old_rd = self.read(inst.rd);
+                let link = self.constant(pc.wrapping_add(4));
+                let rd_value = self.builder.ins().select(misaligned, old_rd, link);
+                self.write(inst.rd, rd_value);
+                let jalr_pc = self.constant(pc);
+                return Some(self.builder.ins().select(misaligned, jalr_pc, target));
+            }
+            Beq | Bne | Blt | Bge | Bltu | Bgeu => {
+                let cc = match inst.kind {
+                    Beq => IntCC::Equal,
+                    Bne => IntCC::NotEqual,
+                    Blt => IntCC::SignedLessThan,
+                    Bge => IntCC::SignedGreaterThanOrEqual,
+                    Bltu => IntCC::UnsignedLessThan,
+                    _ => IntCC::UnsignedGreaterThanOrEqual,
+                };
+                let taken = self.builder.ins().icmp(cc, rs1, rs2);
+                let target = self.constant(pc.wrapping_add(immediate));
+                let next = self.constant(pc.wrapping_add(4));
+                return Some(self.builder.ins().select(taken, target, next));
+            }
+            Lb | Lh | Lw | Lbu | Lhu | Sb | Sh | Sw | Fence | FenceI | Ecall | Ebreak | Mret
+            | Sret | Wfi | SfenceVma | Csrrw | Csrrs | Csrrc | Csrrwi | Csrrsi | Csrrci | LrW
+            | ScW | AmoswapW | AmoaddW | AmoxorW | AmoandW | AmoorW | AmominW | AmomaxW
+            | AmominuW | AmomaxuW => {
+                unreachable!("{:?} is never part of a block", inst.kind)
+            }
+        };
+        self.write(inst.rd, result);
+        None
+    }
+}
@@ -0,0 +1,30 @@
+pub mod clint;
+pub mod coverage;
+pub mod cpu;
+pub mod csr;
+mod decode_cache;
+pub mod disasm;
+pub mod gdb;
+pub mod hooks;
+pub mod htif;
+pub mod icache;
+pub mod instructions;
+pub mod isa;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod memory;
+pub mod mmu;
+pub mod plic;
+pub mod pmp;
+pub mod profiler;
+pub mod replay;
+pub mod scheduler;
+pub mod semihosting;
+mod snapshot;
+pub mod symbols;
+pub mod timing;
+pub mod tlb;
+pub mod trace;
+pub mod trap;
+pub mod uart;
+pub mod watch;
@@ -1,45 +1,666 @@
-use std::{path::PathBuf, process::Command};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    net::TcpListener,
+    ops::Range,
+    path::PathBuf,
+    process::ExitCode,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use gdbstub::stub::DisconnectReason;
 
-use crate::cpu::Cpu;
+#[cfg(feature = "jit")]
+use emulator::jit::JitMode;
+use emulator::{
+    coverage::LineTable,
+    cpu::{Cpu, MisalignedMode, RunLimits, Status, StopReason, FLAT_MEMORY_SIZE},
+    disasm::disassemble,
+    gdb::GdbTarget,
+    icache::ICacheConfig,
+    instructions::DecodedInstruction,
+    isa::Isa,
+    memory::{MemoryConfig, RegionConfig},
+    plic::PlicConfig,
+    profiler::Metric,
+    replay::{read_input_log, RecordingBackend, ReplayBackend},
+    scheduler::Interleaving,
+    semihosting::Semihosting,
+    timing::TimingConfig,
+    trace::{AccessKind, TraceFilter, TraceFormat, Tracer},
+    uart::{FileBackend, NullBackend, StdioBackend, UartBackend},
+    watch::{WatchKind, Watchpoint},
+};
 
-mod cpu;
-mod instructions;
+/// Exit code for errors in the emulator itself, as opposed to a failure reported by the program
+const EXIT_ERROR: u8 = 2;
+/// Exit code for a program exiting with code 1, above the emulator's own codes, where higher
+/// codes follow on up to 255
+const EXIT_PROGRAM: u8 = 16;
 
-fn main() -> Result<(), anyhow::Error> {
-    let elf_path: PathBuf = std::env::args_os()
-        .nth(1)
-        .ok_or_else(|| anyhow!("binary path required"))?
-        .into();
-    // Build binary
-    // Command::new("cargo")
-    //     .args(["build", "--example", &example])
-    //     .current_dir("../rust")
-    //     .status()
-    //     .context("failed to build the binary")?;
-    // let bin_path = elf_path.with_extension("bin");
+/// Emulator for the OrkaRV core and its peripherals
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a program until it reports a status, hangs, reaches a limit or hits a watchpoint
+    Run {
+        #[command(flatten)]
+        machine: MachineArgs,
+        #[command(flatten)]
+        run: RunArgs,
+    },
+    /// Run a program, printing each instruction it executes
+    Trace {
+        #[command(flatten)]
+        machine: MachineArgs,
+        #[command(flatten)]
+        run: RunArgs,
+        /// Write the trace to a file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// `text`, or `json` for a JSON object per line
+        #[arg(long, default_value = "text", value_parser = parse_trace_format)]
+        format: TraceFormat,
+        /// Only trace an address range `START..END` or a function, which can be repeated
+        #[arg(long = "filter", value_parser = parse_trace_filter)]
+        filters: Vec<TraceFilter>,
+    },
+    /// Run a program, attributing instructions and cycles to the functions they ran in
+    Profile {
+        #[command(flatten)]
+        machine: MachineArgs,
+        #[command(flatten)]
+        run: RunArgs,
+        /// File to write call stacks to, in the folded format used by flamegraph tools
+        #[arg(long, short, default_value = "profile.folded")]
+        output: PathBuf,
+        /// `cycles` or `instructions`, for the folded stacks
+        #[arg(long, default_value = "cycles", value_parser = parse_metric)]
+        metric: Metric,
+    },
+    /// Disassemble the functions of a program, or the whole of a flat binary
+    Disasm {
+        #[command(flatten)]
+        machine: MachineArgs,
+        /// Only disassemble this function
+        #[arg(long)]
+        function: Option<String>,
+    },
+    /// Wait for GDB to connect over TCP and debug a program
+    Gdb {
+        #[command(flatten)]
+        machine: MachineArgs,
+        #[arg(long, default_value_t = 1234)]
+        port: u16,
+    },
+}
+
+/// How the program is loaded and the machine it runs on
+#[derive(Args)]
+struct MachineArgs {
+    /// ELF or flat binary to load
+    program: PathBuf,
+    #[arg(long, value_enum, default_value_t = Loader::Auto)]
+    loader: Loader,
+    /// Address a flat binary is loaded at and starts running from
+    #[arg(long, default_value = "0x01000000", value_parser = parse_address)]
+    load_address: u32,
+    /// Distance an ELF is loaded above its addresses, which is 0 for one linked to run in place
+    #[arg(long, default_value = "0xE0000000", value_parser = parse_address)]
+    load_offset: u32,
+    /// Memory region `BASE:SIZE` in hex, which can be repeated to replace the default map
+    #[arg(long = "memory", value_name = "BASE:SIZE", value_parser = parse_region)]
+    regions: Vec<RegionConfig>,
+    /// Extensions of RV32I that can be executed
+    #[arg(long, default_value = "rv32ia_zicsr_zifencei", value_parser = clap::value_parser!(Isa))]
+    isa: Isa,
+    /// Handle misaligned loads and stores in memory, instead of trapping like the RTL
+    #[arg(long)]
+    misaligned: bool,
+    /// Harts sharing memory and peripherals, which all start at the program's entry point
+    #[arg(long, default_value_t = 1)]
+    harts: usize,
+    /// `round-robin[:QUANTUM]` or `random:SEED[:MAX_QUANTUM]` order that harts step in
+    #[arg(long, default_value = "round-robin", value_parser = clap::value_parser!(Interleaving))]
+    interleave: Interleaving,
+    /// Add a PLIC in front of the external interrupt, optionally as `SOURCES,UART_SOURCE`
+    #[arg(long, num_args = 0..=1, default_missing_value = "31,1", value_parser = parse_plic_config)]
+    plic: Option<PlicConfig>,
+    /// `none`, `stdio`, or `file:PATH` to write the uart's output to a file
+    #[arg(long, default_value = "stdio", value_parser = parse_uart)]
+    uart: UartArg,
+    /// Resume from a snapshot of the program, instead of starting from reset
+    #[arg(long)]
+    restore: Option<PathBuf>,
+    /// Log the input received by the uart, to replay with `--replay`
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Deliver the uart input from a log written by `--record`, instead of from the host
+    #[arg(long)]
+    replay: Option<PathBuf>,
+    /// Handle semihosting calls, giving the program the host's console and files
+    #[arg(long)]
+    semihosting: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Loader {
+    /// ELF if the file starts with the ELF magic number, otherwise flat
+    Auto,
+    Elf,
+    Flat,
+}
+
+#[derive(Debug, Clone)]
+struct WatchArg {
+    location: WatchLocation,
+    kind: WatchKind,
+}
+
+#[derive(Debug, Clone)]
+enum WatchLocation {
+    Range(Range<u32>),
+    /// Peripheral register, which is resolved once the machine is loaded
+    Register(String),
+}
+
+#[derive(Debug, Clone)]
+enum UartArg {
+    None,
+    Stdio,
+    File(PathBuf),
+}
+
+/// Limits and models for a run
+#[derive(Args)]
+struct RunArgs {
+    /// Stop after this many instructions
+    #[arg(long)]
+    max_instructions: Option<u64>,
+    /// Stop after this many seconds
+    #[arg(long, value_parser = parse_timeout)]
+    timeout: Option<Duration>,
+    /// Model the cycle timing of `Cpu.vhd`
+    #[arg(long)]
+    timing: bool,
+    /// Model an instruction cache, optionally as `SIZE,LINE_SIZE,WAYS`
+    #[arg(long, num_args = 0..=1, default_missing_value = "4096,16,2", value_parser = parse_icache_config)]
+    icache: Option<ICacheConfig>,
+    /// Model a TLB for Sv32 translation, optionally with the number of entries
+    #[arg(long, num_args = 0..=1, default_missing_value = "32", value_name = "ENTRIES")]
+    tlb: Option<usize>,
+    /// Write line coverage to a Cobertura report if it ends in `.xml`, otherwise lcov
+    #[arg(long)]
+    coverage: Option<PathBuf>,
+    /// Save a snapshot once the program stops, to resume from with `--restore`
+    #[arg(long)]
+    save_snapshot: Option<PathBuf>,
+    /// Stop on an access to `ADDR`, `START..END` or a register such as `uart.ctrl`, with a
+    /// suffix of `:r`, `:w` (the default) or `:rw` for the accesses to stop on
+    #[arg(long = "watch", value_name = "LOCATION[:r|w|rw]", value_parser = parse_watch)]
+    watches: Vec<WatchArg>,
+    /// Compile hot code with the JIT
+    #[cfg(feature = "jit")]
+    #[arg(long)]
+    jit: bool,
+    /// Compare every compiled block against the interpreter
+    #[cfg(feature = "jit")]
+    #[arg(long, conflicts_with = "jit")]
+    jit_check: bool,
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: {error:?}");
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
 
-    // // Make flat file
-    // Command::new("riscv64-unknown-elf-objcopy")
-    //     .args(["-O", "binary"])
-    //     .arg(elf_path)
-    //     .arg(&bin_path)
-    //     .status()
-    //     .context("could not create flat file")?;
+fn run(cli: Cli) -> Result<ExitCode, anyhow::Error> {
+    match cli.command {
+        Command::Run { machine, run } => {
+            let mut cpu = machine.load(false)?;
+            execute(&mut cpu, &machine, &run)
+        }
+        Command::Trace {
+            machine,
+            run,
+            output,
+            format,
+            filters,
+        } => {
+            let mut cpu = machine.load(false)?;
+            let out: Box<dyn Write + Send> = match output {
+                Some(path) => Box::new(BufWriter::new(
+                    File::create(path).context("could not create trace")?,
+                )),
+                None => Box::new(std::io::stdout()),
+            };
+            cpu.set_tracer(Some(Tracer::new(out, format, &filters, cpu.symbols())?));
+            execute(&mut cpu, &machine, &run)
+        }
+        Command::Profile {
+            machine,
+            run,
+            output,
+            metric,
+        } => {
+            let mut cpu = machine.load(false)?;
+            cpu.enable_profiler();
+            let code = execute(&mut cpu, &machine, &run)?;
 
-    // // Create CPU
-    // let mut cpu = Cpu::from_flat_file(&bin_path).context("could not load cpu")?;
+            let profiler = cpu.profiler().unwrap();
+            let mut report = String::new();
+            profiler.report(cpu.symbols(), &mut report)?;
+            print!("{report}");
 
-    let mut cpu = Cpu::from_elf(&elf_path).context("could not load cpu")?;
+            let mut file =
+                BufWriter::new(File::create(&output).context("could not create profile")?);
+            profiler.write_folded(cpu.symbols(), metric, &mut file)?;
+            println!("folded stacks written to {}", output.display());
+            Ok(code)
+        }
+        Command::Disasm { machine, function } => {
+            let cpu = machine.load(false)?;
+            if let Some(name) = function {
+                let symbol = cpu
+                    .symbols()
+                    .by_name(&name)
+                    .ok_or_else(|| anyhow!("no function named {name}"))?;
+                print_disassembly(&cpu, symbol.addr..symbol.addr + symbol.size)?;
+            } else if cpu.symbols().is_empty() {
+                if machine.loader()? == Loader::Elf {
+                    bail!("the elf has no function symbols to disassemble");
+                }
+                let size = std::fs::metadata(&machine.program)?.len() as u32;
+                print_disassembly(&cpu, machine.load_address..machine.load_address + size)?;
+            } else {
+                for symbol in cpu.symbols().iter() {
+                    print_disassembly(&cpu, symbol.addr..symbol.addr + symbol.size)?;
+                }
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Gdb { machine, port } => {
+            if machine.harts > 1 {
+                bail!("the gdb stub only supports a single hart");
+            }
+            let cpu = machine.load(true)?;
+            let listener = TcpListener::bind(("127.0.0.1", port))
+                .with_context(|| format!("could not listen on port {port}"))?;
+            println!("waiting for gdb to connect on port {port}");
+            let (stream, addr) = listener.accept()?;
+            println!("gdb connected from {addr}");
 
-    // Run
-    while cpu.status().is_none() {
-        cpu.step().context("could not step cpu")?;
+            let code = match GdbTarget::new(cpu).serve(stream)? {
+                DisconnectReason::TargetExited(code) => {
+                    println!("program exited with {code}");
+                    code
+                }
+                DisconnectReason::Disconnect => {
+                    println!("gdb disconnected");
+                    0
+                }
+                DisconnectReason::Kill => {
+                    println!("gdb killed the program");
+                    0
+                }
+                DisconnectReason::TargetTerminated(signal) => {
+                    println!("program terminated with {signal}");
+                    EXIT_ERROR
+                }
+            };
+            Ok(ExitCode::from(code))
+        }
     }
+}
 
-    let status = cpu.status().unwrap();
-    println!("cpu stopped with status: {status:?}");
+impl MachineArgs {
+    /// Loader to use, looking at the file if it is to be detected
+    fn loader(&self) -> Result<Loader, anyhow::Error> {
+        if self.loader != Loader::Auto {
+            return Ok(self.loader);
+        }
+        let mut magic = [0; 4];
+        let is_elf = File::open(&self.program)
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .is_ok()
+            && magic == *b"\x7FELF";
+        Ok(if is_elf { Loader::Elf } else { Loader::Flat })
+    }
 
+    /// Load the program, keeping the uart's input to receive again after a rewind if
+    /// `rewindable`, as any recording or replay also does
+    fn load(&self, rewindable: bool) -> Result<Cpu, anyhow::Error> {
+        let mut cpu = match self.loader()? {
+            Loader::Elf => {
+                let memory = if self.regions.is_empty() {
+                    MemoryConfig::default()
+                } else {
+                    MemoryConfig {
+                        regions: self.regions.clone(),
+                    }
+                };
+                Cpu::from_elf_with_offset(&self.program, self.load_offset, &memory)
+            }
+            Loader::Flat => {
+                let regions = if self.regions.is_empty() {
+                    vec![RegionConfig {
+                        base: self.load_address,
+                        size: FLAT_MEMORY_SIZE,
+                    }]
+                } else {
+                    self.regions.clone()
+                };
+                Cpu::from_flat_file_with_memory(
+                    &self.program,
+                    self.load_address,
+                    &MemoryConfig { regions },
+                )
+            }
+            Loader::Auto => unreachable!(),
+        }
+        .context("could not load cpu")?;
+
+        cpu.set_isa(self.isa);
+        if self.misaligned {
+            cpu.set_misaligned_mode(MisalignedMode::Handle);
+        }
+        cpu.set_harts(self.harts)?;
+        cpu.set_interleaving(self.interleave);
+        if let Some(config) = self.plic {
+            cpu.enable_plic(config)?;
+        }
+        let mut backend: Box<dyn UartBackend> = match &self.uart {
+            UartArg::None => Box::new(NullBackend),
+            UartArg::Stdio => Box::new(StdioBackend::new()),
+            UartArg::File(path) => Box::new(FileBackend::create(path)?),
+        };
+        if let Some(path) = &self.replay {
+            backend = Box::new(ReplayBackend::new(read_input_log(path)?, backend));
+        } else if let Some(path) = &self.record {
+            backend = Box::new(RecordingBackend::new(backend).with_log(path)?);
+        } else if rewindable {
+            backend = Box::new(RecordingBackend::new(backend));
+        }
+        cpu.set_uart_backend(backend);
+        if self.semihosting {
+            cpu.enable_semihosting(Semihosting::new());
+        }
+        if let Some(path) = &self.restore {
+            let file = File::open(path).context("could not open snapshot")?;
+            cpu.restore_snapshot(BufReader::new(file))
+                .context("could not restore snapshot")?;
+        }
+        Ok(cpu)
+    }
+}
+
+/// Run to completion with the models and limits requested, printing their reports
+fn execute(cpu: &mut Cpu, machine: &MachineArgs, run: &RunArgs) -> Result<ExitCode, anyhow::Error> {
+    if run.timing {
+        cpu.enable_timing(TimingConfig::default());
+    }
+    if let Some(config) = run.icache {
+        cpu.enable_icache(config)?;
+    }
+    if let Some(entries) = run.tlb {
+        cpu.enable_tlb(entries)?;
+    }
+    if run.coverage.is_some() {
+        cpu.enable_coverage();
+    }
+    #[cfg(feature = "jit")]
+    if run.jit_check {
+        cpu.enable_jit(JitMode::CrossCheck)?;
+    } else if run.jit {
+        cpu.enable_jit(JitMode::Enabled)?;
+    }
+    for watch in &run.watches {
+        let range = match &watch.location {
+            WatchLocation::Range(range) => range.clone(),
+            WatchLocation::Register(name) => cpu
+                .peripheral_register(name)
+                .ok_or_else(|| anyhow!("no peripheral register named {name}"))?,
+        };
+        cpu.add_watchpoint(Watchpoint {
+            range,
+            kind: watch.kind,
+        });
+    }
+
+    let limits = RunLimits {
+        max_instructions: run.max_instructions,
+        timeout: run.timeout,
+    };
+    let reason = cpu.run(&limits).context("could not run cpu")?;
+    let code = match reason {
+        StopReason::Status(status) => {
+            println!("cpu stopped with status: {status:?}");
+            match status {
+                Status::Success | Status::Exited(0) => 0,
+                Status::Failure => 1,
+                // offset so they aren't mistaken for the emulator's own
+                Status::Exited(code) => {
+                    (code - 1).min((u8::MAX - EXIT_PROGRAM) as u32) as u8 + EXIT_PROGRAM
+                }
+            }
+        }
+        StopReason::InstructionLimit => {
+            println!(
+                "cpu stopped after reaching the limit of {} instructions",
+                cpu.total_instructions_retired()
+            );
+            3
+        }
+        StopReason::Timeout => {
+            println!("cpu stopped after timing out");
+            4
+        }
+        StopReason::Hang { pc } => {
+            println!("cpu stopped as it hung at {pc:08X}");
+            5
+        }
+        StopReason::Watchpoint(hit) => {
+            let access = hit.access;
+            let (kind, direction) = match access.kind {
+                AccessKind::Load => ("load", "from"),
+                AccessKind::Store => ("store", "to"),
+            };
+            println!(
+                "cpu stopped at a watchpoint: {kind} of {:08X} {direction} {:08X} at {:08X}",
+                access.value, access.addr, hit.pc
+            );
+            6
+        }
+    };
+
+    if let Some(timing) = cpu.timing() {
+        let mut report = String::new();
+        timing.report(&mut report)?;
+        print!("{report}");
+    }
+    if let Some(icache) = cpu.icache() {
+        let mut report = String::new();
+        icache.report(cpu.symbols(), &mut report)?;
+        print!("{report}");
+    }
+    if let Some(tlb) = cpu.tlb() {
+        let mut report = String::new();
+        tlb.report(&mut report)?;
+        print!("{report}");
+    }
+    if let (Some(coverage), Some(path)) = (cpu.coverage(), &run.coverage) {
+        if machine.loader()? != Loader::Elf {
+            bail!("coverage needs line tables from an elf");
+        }
+        let lines = LineTable::from_elf_file(&machine.program, machine.load_offset)
+            .context("could not read line tables")?;
+        if lines.is_empty() {
+            eprintln!("no line tables found, so the elf needs to be built with debug info");
+        }
+
+        let mut file =
+            BufWriter::new(File::create(path).context("could not create coverage report")?);
+        if path.extension().is_some_and(|extension| extension == "xml") {
+            coverage.write_cobertura(&lines, &mut file)?;
+        } else {
+            coverage.write_lcov(&lines, &mut file)?;
+        }
+        println!("coverage written to {}", path.display());
+    }
+    if let Some(tracer) = cpu.tracer_mut() {
+        tracer.flush().context("could not write trace")?;
+    }
+    if let Some(path) = &run.save_snapshot {
+        let mut file = BufWriter::new(File::create(path).context("could not create snapshot")?);
+        cpu.save_snapshot(&mut file)?;
+        file.flush().context("could not write snapshot")?;
+        println!("snapshot written to {}", path.display());
+    }
+
+    Ok(ExitCode::from(code))
+}
+
+/// Print each instruction in `range` in the style of objdump, labelled with its function
+fn print_disassembly(cpu: &Cpu, range: Range<u32>) -> Result<(), anyhow::Error> {
+    let mut out = std::io::stdout().lock();
+    if let Some(symbol) = cpu.symbols().lookup(range.start) {
+        writeln!(out, "\n{:08X} <{}>:", symbol.addr, symbol.name)?;
+    }
+    for pc in range.step_by(4) {
+        let raw = cpu
+            .read_memory(pc, 4)
+            .with_context(|| format!("could not read instruction at {pc:08X}"))?;
+        let text = match DecodedInstruction::try_from(raw) {
+            Ok(inst) => disassemble(&inst, pc, cpu.symbols()),
+            Err(_) => "<unknown>".to_string(),
+        };
+        writeln!(out, "{pc:8X}:\t{raw:08X}\t{text}")?;
+    }
     Ok(())
 }
+
+/// Parse a hex address, with or without a `0x` prefix
+fn parse_address(addr: &str) -> Result<u32, anyhow::Error> {
+    u32::from_str_radix(addr.trim_start_matches("0x"), 16)
+        .with_context(|| format!("invalid address: {addr}"))
+}
+
+/// Parse a memory region such as `0xE0000000:0x10000000`
+fn parse_region(region: &str) -> Result<RegionConfig, anyhow::Error> {
+    let (base, size) = region
+        .split_once(':')
+        .ok_or_else(|| anyhow!("memory region must be BASE:SIZE: {region}"))?;
+    Ok(RegionConfig {
+        base: parse_address(base)?,
+        size: parse_address(size)?,
+    })
+}
+
+fn parse_uart(uart: &str) -> Result<UartArg, anyhow::Error> {
+    match uart {
+        "none" => Ok(UartArg::None),
+        "stdio" => Ok(UartArg::Stdio),
+        _ => match uart.strip_prefix("file:") {
+            Some(path) => Ok(UartArg::File(path.into())),
+            None => bail!("uart must be none, stdio or file:PATH: {uart}"),
+        },
+    }
+}
+
+fn parse_timeout(seconds: &str) -> Result<Duration, anyhow::Error> {
+    seconds
+        .parse()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .with_context(|| format!("invalid timeout: {seconds}"))
+}
+
+fn parse_trace_format(format: &str) -> Result<TraceFormat, anyhow::Error> {
+    match format {
+        "text" => Ok(TraceFormat::Text),
+        "json" => Ok(TraceFormat::JsonLines),
+        _ => bail!("unknown trace format: {format}"),
+    }
+}
+
+/// Parse an address range such as `0xE0000000..0xE0000100`, or else a function name
+fn parse_trace_filter(filter: &str) -> Result<TraceFilter, anyhow::Error> {
+    let Some((start, end)) = filter.split_once("..") else {
+        return Ok(TraceFilter::Function(filter.to_string()));
+    };
+    Ok(TraceFilter::Range(
+        parse_address(start)?..parse_address(end)?,
+    ))
+}
+
+/// Parse a watchpoint such as `0xE0000000..0xE0000010:rw` or `uart.ctrl`
+fn parse_watch(watch: &str) -> Result<WatchArg, anyhow::Error> {
+    let (location, kind) = match watch.rsplit_once(':') {
+        Some((location, kind)) => (location, kind.parse()?),
+        None => (watch, WatchKind::Write),
+    };
+    let location = if let Some((start, end)) = location.split_once("..") {
+        WatchLocation::Range(parse_address(start)?..parse_address(end)?)
+    } else if location.contains('.') {
+        WatchLocation::Register(location.to_string())
+    } else {
+        // a single address watches the word there
+        let addr = parse_address(location)?;
+        WatchLocation::Range(addr..addr.saturating_add(4))
+    };
+    Ok(WatchArg { location, kind })
+}
+
+fn parse_metric(metric: &str) -> Result<Metric, anyhow::Error> {
+    match metric {
+        "cycles" => Ok(Metric::Cycles),
+        "instructions" => Ok(Metric::Instructions),
+        _ => bail!("unknown metric: {metric}"),
+    }
+}
+
+/// Parse a PLIC configuration `SOURCES,UART_SOURCE`
+fn parse_plic_config(config: &str) -> Result<PlicConfig, anyhow::Error> {
+    let values = config
+        .split(',')
+        .map(|value| value.parse())
+        .collect::<Result<Vec<u32>, _>>()
+        .with_context(|| format!("invalid plic configuration: {config}"))?;
+    let [sources, uart_source] = values[..] else {
+        bail!("plic configuration must be SOURCES,UART_SOURCE: {config}");
+    };
+    Ok(PlicConfig {
+        sources,
+        uart_source,
+    })
+}
+
+/// Parse an icache geometry `SIZE,LINE_SIZE,WAYS`
+fn parse_icache_config(geometry: &str) -> Result<ICacheConfig, anyhow::Error> {
+    let values = geometry
+        .split(',')
+        .map(|value| value.parse())
+        .collect::<Result<Vec<u32>, _>>()
+        .with_context(|| format!("invalid icache geometry: {geometry}"))?;
+    let [size, line_size, ways] = values[..] else {
+        bail!("icache geometry must be SIZE,LINE_SIZE,WAYS: {geometry}");
+    };
+    Ok(ICacheConfig {
+        size,
+        line_size,
+        ways,
+    })
+}
@@ -0,0 +1,355 @@
+use anyhow::{bail, ensure};
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+pub(crate) const RAM_BASE: u32 = 0xE0000000;
+const RAM_SIZE: u32 = 0x10000000;
+
+const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+
+type Page = [u8; PAGE_SIZE];
+
+/// Base address and size of a region of guest memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionConfig {
+    pub base: u32,
+    pub size: u32,
+}
+
+/// Layout of guest memory, with every region starting out zeroed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryConfig {
+    pub regions: Vec<RegionConfig>,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            regions: vec![RegionConfig {
+                base: RAM_BASE,
+                size: RAM_SIZE,
+            }],
+        }
+    }
+}
+
+/// Guest memory, made up of one or more sparse regions
+pub struct Memory {
+    regions: Vec<Region>,
+}
+
+impl Memory {
+    pub fn new(config: &MemoryConfig) -> Result<Self, anyhow::Error> {
+        let mut regions: Vec<Region> = Vec::new();
+        for region in &config.regions {
+            ensure!(
+                region.base as u64 + region.size as u64 <= 1 << 32,
+                "region at {:08X} extends past the end of the address space",
+                region.base
+            );
+            ensure!(
+                !regions.iter().any(|other| other.overlaps(region)),
+                "region at {:08X} overlaps another region",
+                region.base
+            );
+            regions.push(Region::new(*region));
+        }
+        Ok(Self { regions })
+    }
+
+    fn region(&self, addr: u32) -> Option<&Region> {
+        self.regions.iter().find(|region| region.contains(addr))
+    }
+
+    fn region_mut(&mut self, addr: u32) -> Option<&mut Region> {
+        self.regions.iter_mut().find(|region| region.contains(addr))
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        self.region(addr).is_some()
+    }
+
+    /// Read `width` bytes, zero-extended
+    pub fn read(&self, addr: u32, width: u32) -> Result<u32, anyhow::Error> {
+        match self.region(addr) {
+            Some(region) => region.read(addr, width),
+            None => bail!("address invalid for read: {addr:08X}"),
+        }
+    }
+
+    /// Write the lower `width` bytes of `value`
+    pub fn write(&mut self, addr: u32, value: u32, width: u32) -> Result<(), anyhow::Error> {
+        match self.region_mut(addr) {
+            Some(region) => region.write(addr, value, width),
+            None => bail!("address invalid for write: {addr:08X}"),
+        }
+    }
+
+    /// Copy a block of data into memory, which must fit inside a single region
+    pub fn load(&mut self, addr: u32, data: &[u8]) -> Result<(), anyhow::Error> {
+        let Some(region) = self.region_mut(addr) else {
+            bail!("address invalid for load: {addr:08X}");
+        };
+        region.load(addr, data)
+    }
+
+    /// Save the layout and every allocated page
+    pub(crate) fn save(&self, out: &mut SnapshotWriter) -> Result<(), anyhow::Error> {
+        out.u32(self.regions.len() as u32)?;
+        for region in &self.regions {
+            out.u32(region.base)?;
+            out.u32(region.size)?;
+            out.u32(region.allocated_pages() as u32)?;
+            for (index, page) in region.pages.iter().enumerate() {
+                if let Some(page) = page {
+                    out.u32(index as u32)?;
+                    out.bytes(page.as_slice())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuild memory from a snapshot, including its layout
+    pub(crate) fn restore(input: &mut SnapshotReader) -> Result<Self, anyhow::Error> {
+        let mut config = MemoryConfig {
+            regions: Vec::new(),
+        };
+        let mut regions_pages = Vec::new();
+        for _ in 0..input.u32()? {
+            config.regions.push(RegionConfig {
+                base: input.u32()?,
+                size: input.u32()?,
+            });
+            let mut pages = Vec::new();
+            for _ in 0..input.u32()? {
+                let index = input.u32()? as usize;
+                let mut page = Box::new([0; PAGE_SIZE]);
+                input.bytes(page.as_mut_slice())?;
+                pages.push((index, page));
+            }
+            regions_pages.push(pages);
+        }
+
+        let mut memory = Self::new(&config)?;
+        for (region, pages) in memory.regions.iter_mut().zip(regions_pages) {
+            for (index, page) in pages {
+                let Some(slot) = region.pages.get_mut(index) else {
+                    bail!("page {index} is outside the region at {:08X}", region.base);
+                };
+                *slot = Some(page);
+            }
+        }
+        Ok(memory)
+    }
+
+    /// Number of bytes of host memory currently backing the guest
+    pub fn allocated_bytes(&self) -> usize {
+        self.regions
+            .iter()
+            .map(|region| region.allocated_pages() * PAGE_SIZE)
+            .sum()
+    }
+}
+
+/// Contiguous region of guest memory, where each page is allocated on its first write
+struct Region {
+    base: u32,
+    size: u32,
+    pages: Vec<Option<Box<Page>>>,
+}
+
+impl Region {
+    fn new(config: RegionConfig) -> Self {
+        Self {
+            base: config.base,
+            size: config.size,
+            pages: vec![None; (config.size as usize).div_ceil(PAGE_SIZE)],
+        }
+    }
+
+    fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < self.size
+    }
+
+    fn overlaps(&self, other: &RegionConfig) -> bool {
+        let end = self.base as u64 + self.size as u64;
+        let other_end = other.base as u64 + other.size as u64;
+        (self.base as u64) < other_end && (other.base as u64) < end
+    }
+
+    fn allocated_pages(&self) -> usize {
+        self.pages.iter().filter(|page| page.is_some()).count()
+    }
+
+    /// Offset into the region of an access, checking that it fits
+    fn offset(&self, addr: u32, width: u32) -> Result<usize, anyhow::Error> {
+        let offset = addr - self.base;
+        ensure!(
+            offset as u64 + width as u64 <= self.size as u64,
+            "access of {width} bytes at {addr:08X} extends past the end of memory"
+        );
+        Ok(offset as usize)
+    }
+
+    fn read_byte(&self, offset: usize) -> u8 {
+        match &self.pages[offset / PAGE_SIZE] {
+            Some(page) => page[offset % PAGE_SIZE],
+            None => 0,
+        }
+    }
+
+    fn page_mut(&mut self, offset: usize) -> &mut Page {
+        self.pages[offset / PAGE_SIZE].get_or_insert_with(|| Box::new([0; PAGE_SIZE]))
+    }
+
+    fn read(&self, addr: u32, width: u32) -> Result<u32, anyhow::Error> {
+        let offset = self.offset(addr, width)?;
+        let width = width as usize;
+        let mut bytes = [0u8; 4];
+        let page_offset = offset % PAGE_SIZE;
+        if page_offset + width <= PAGE_SIZE {
+            if let Some(page) = &self.pages[offset / PAGE_SIZE] {
+                bytes[..width].copy_from_slice(&page[page_offset..page_offset + width]);
+            }
+        } else {
+            for (index, byte) in bytes[..width].iter_mut().enumerate() {
+                *byte = self.read_byte(offset + index);
+            }
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, addr: u32, value: u32, width: u32) -> Result<(), anyhow::Error> {
+        let offset = self.offset(addr, width)?;
+        let bytes = value.to_le_bytes();
+        self.write_bytes(offset, &bytes[..width as usize]);
+        Ok(())
+    }
+
+    fn load(&mut self, addr: u32, data: &[u8]) -> Result<(), anyhow::Error> {
+        let width = u32::try_from(data.len())?;
+        let offset = self.offset(addr, width)?;
+        self.write_bytes(offset, data);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, mut offset: usize, mut data: &[u8]) {
+        while !data.is_empty() {
+            let page_offset = offset % PAGE_SIZE;
+            let length = data.len().min(PAGE_SIZE - page_offset);
+            self.page_mut(offset)[page_offset..page_offset + length]
+                .copy_from_slice(&data[..length]);
+            offset += length;
+            data = &data[length..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> Memory {
+        Memory::new(&MemoryConfig {
+            regions: vec![
+                RegionConfig {
+                    base: 0x01000000,
+                    size: 0x10000,
+                },
+                RegionConfig {
+                    base: 0x80000000,
+                    size: 0x10000000,
+                },
+            ],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn pages_are_allocated_on_first_write() {
+        let mut memory = memory();
+        assert_eq!(memory.allocated_bytes(), 0);
+
+        // reads of untouched memory are zero and do not allocate
+        assert_eq!(memory.read(0x80001234, 4).unwrap(), 0);
+        assert_eq!(memory.allocated_bytes(), 0);
+
+        memory.write(0x80001234, 0xDEADBEEF, 4).unwrap();
+        assert_eq!(memory.allocated_bytes(), PAGE_SIZE);
+        assert_eq!(memory.read(0x80001234, 4).unwrap(), 0xDEADBEEF);
+        assert_eq!(memory.read(0x80001234, 2).unwrap(), 0xBEEF);
+        assert_eq!(memory.read(0x80001237, 1).unwrap(), 0xDE);
+
+        memory.write(0x01000000, 1, 1).unwrap();
+        assert_eq!(memory.allocated_bytes(), 2 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn accesses_across_pages() {
+        let mut memory = memory();
+        memory.write(0x80000FFE, 0x11223344, 4).unwrap();
+        assert_eq!(memory.allocated_bytes(), 2 * PAGE_SIZE);
+        assert_eq!(memory.read(0x80000FFE, 4).unwrap(), 0x11223344);
+        assert_eq!(memory.read(0x80000FFC, 4).unwrap(), 0x33440000);
+        assert_eq!(memory.read(0x80001000, 4).unwrap(), 0x00001122);
+
+        let data: Vec<u8> = (0..=255).cycle().take(3 * PAGE_SIZE).collect();
+        memory.load(0x80010800, &data).unwrap();
+        assert_eq!(memory.read(0x80010800, 1).unwrap(), 0);
+        assert_eq!(memory.read(0x80011000, 1).unwrap(), 0);
+        assert_eq!(memory.read(0x80013000, 4).unwrap(), 0x03020100);
+        assert_eq!(memory.read(0x80013800, 1).unwrap(), 0);
+    }
+
+    #[test]
+    fn region_bounds() {
+        let mut memory = memory();
+        assert!(memory.contains(0x0100FFFF));
+        assert!(!memory.contains(0x01010000));
+        assert!(!memory.contains(0x00FFFFFF));
+        assert!(memory.read(0x00000000, 4).is_err());
+        assert!(memory.write(0x90000000, 0, 4).is_err());
+
+        // accesses may not run off the end of a region
+        assert!(memory.read(0x0100FFFC, 4).is_ok());
+        assert!(memory.read(0x0100FFFE, 4).is_err());
+        assert!(memory.write(0x0100FFFF, 0, 2).is_err());
+        assert!(memory.load(0x0100FFF0, &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let overlapping = MemoryConfig {
+            regions: vec![
+                RegionConfig {
+                    base: 0x1000,
+                    size: 0x2000,
+                },
+                RegionConfig {
+                    base: 0x2000,
+                    size: 0x1000,
+                },
+            ],
+        };
+        assert!(Memory::new(&overlapping).is_err());
+
+        let wrapping = MemoryConfig {
+            regions: vec![RegionConfig {
+                base: 0xFFFFF000,
+                size: 0x2000,
+            }],
+        };
+        assert!(Memory::new(&wrapping).is_err());
+    }
+
+    #[test]
+    fn instances_are_cheap() {
+        // the default map is 256 MiB, so this would need 64 GiB if it were allocated up front
+        let instances: Vec<Memory> = (0..256)
+            .map(|_| Memory::new(&MemoryConfig::default()).unwrap())
+            .collect();
+        assert!(instances.iter().all(|memory| memory.allocated_bytes() == 0));
+    }
+}
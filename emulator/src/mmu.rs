@@ -0,0 +1,282 @@
+//! Sv32 address translation, which the RTL does not have yet
+//!
+//! Page tables are walked on every translated access, so changes to them take effect without
+//! an sfence.vma. The TLB model only records which walks a real TLB would have saved. Accessed
+//! and dirty bits are never set by hardware: an access to a page without them page faults, as
+//! in the Svade extension.
+
+use crate::{
+    csr::{Csrs, Privilege, MSTATUS_MXR, MSTATUS_SUM, SATP_ASID, SATP_PPN},
+    pmp::Access,
+};
+
+pub const PAGE_SIZE: u32 = 4096;
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_G: u32 = 1 << 5;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+/// Levels of the page table, of which level 1 maps 4 MiB megapages
+const LEVELS: u32 = 2;
+
+/// Why a translation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The page tables do not allow the access
+    Page,
+    /// A page table entry could not be read
+    Access,
+}
+
+/// State that translation depends on, taken from the CSRs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Context {
+    /// Privilege level the access is checked at
+    pub privilege: Privilege,
+    pub satp: u32,
+    /// Whether supervisor mode may access user pages
+    pub sum: bool,
+    /// Whether executable pages are readable
+    pub mxr: bool,
+}
+
+impl Context {
+    pub fn new(csrs: &Csrs, access: Access) -> Self {
+        Self {
+            privilege: csrs.access_privilege(access),
+            satp: csrs.satp,
+            sum: csrs.mstatus & MSTATUS_SUM != 0,
+            mxr: csrs.mstatus & MSTATUS_MXR != 0,
+        }
+    }
+
+    pub fn asid(&self) -> u32 {
+        (self.satp & SATP_ASID) >> 22
+    }
+}
+
+/// Result of a successful walk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    /// Physical address, which may be above 4 GiB
+    pub physical: u64,
+    /// Level of the leaf entry, which is 1 for a megapage
+    pub level: u32,
+    /// Whether the mapping belongs to every address space
+    pub global: bool,
+    /// Page table entries read by the walk
+    pub reads: u32,
+}
+
+/// Walk the page tables for an access to `vaddr`, reading each entry with `read_pte`
+///
+/// `read_pte` returns `None` if the entry at a physical address could not be read.
+pub fn translate(
+    vaddr: u32,
+    access: Access,
+    context: &Context,
+    mut read_pte: impl FnMut(u64) -> Option<u32>,
+) -> Result<Translation, Fault> {
+    let vpn = [(vaddr >> 12) & 0x3FF, vaddr >> 22];
+    let mut table = (context.satp & SATP_PPN) as u64 * PAGE_SIZE as u64;
+    let mut global = false;
+
+    for level in (0..LEVELS).rev() {
+        let pte = read_pte(table + 4 * vpn[level as usize] as u64).ok_or(Fault::Access)?;
+        let reads = LEVELS - level;
+        // write without read is reserved
+        if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W {
+            return Err(Fault::Page);
+        }
+        // the global bit of a pointer applies to everything below it
+        global |= pte & PTE_G != 0;
+        let ppn = (pte >> 10) as u64;
+
+        if pte & (PTE_R | PTE_X) == 0 {
+            table = ppn * PAGE_SIZE as u64;
+            continue;
+        }
+
+        if !permits(pte, access, context) {
+            return Err(Fault::Page);
+        }
+        // megapages must be aligned to their size
+        if level == 1 && ppn & 0x3FF != 0 {
+            return Err(Fault::Page);
+        }
+        if pte & PTE_A == 0 || access == Access::Write && pte & PTE_D == 0 {
+            return Err(Fault::Page);
+        }
+
+        let page_offset = match level {
+            1 => vaddr & 0x3FFFFF,
+            _ => vaddr & (PAGE_SIZE - 1),
+        };
+        return Ok(Translation {
+            physical: (ppn * PAGE_SIZE as u64) | page_offset as u64,
+            level,
+            global,
+            reads,
+        });
+    }
+    // a pointer at the last level
+    Err(Fault::Page)
+}
+
+/// Whether a leaf entry allows an access from the context's privilege level
+fn permits(pte: u32, access: Access, context: &Context) -> bool {
+    let allowed = match access {
+        Access::Read => pte & PTE_R != 0 || context.mxr && pte & PTE_X != 0,
+        Access::Write => pte & PTE_W != 0,
+        Access::Execute => pte & PTE_X != 0,
+    };
+    let user_page = pte & PTE_U != 0;
+    let privileged = match context.privilege {
+        Privilege::User => user_page,
+        // supervisor mode never executes user pages, and only accesses them with SUM set
+        Privilege::Supervisor => !user_page || access != Access::Execute && context.sum,
+        Privilege::Machine => true,
+    };
+    allowed && privileged
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::csr::SATP_MODE;
+
+    const RWX: u32 = PTE_R | PTE_W | PTE_X;
+    const AD: u32 = PTE_A | PTE_D;
+
+    /// Page tables with the root at 0x1000 and a second level table at 0x2000
+    struct Tables(HashMap<u64, u32>);
+
+    impl Tables {
+        fn new() -> Self {
+            let mut tables = Self(HashMap::new());
+            // 0x00400000 points to the second level table
+            tables.set(0x1000, 1, 0x2000 >> 12 << 10 | PTE_V);
+            tables
+        }
+
+        fn set(&mut self, table: u64, index: u64, pte: u32) {
+            self.0.insert(table + 4 * index, pte);
+        }
+
+        fn translate(&self, vaddr: u32, access: Access, context: &Context) -> Result<u64, Fault> {
+            translate(vaddr, access, context, |addr| self.0.get(&addr).copied())
+                .map(|translation| translation.physical)
+        }
+    }
+
+    fn context(privilege: Privilege) -> Context {
+        Context {
+            privilege,
+            satp: SATP_MODE | 1 << 22 | 0x1,
+            sum: false,
+            mxr: false,
+        }
+    }
+
+    #[test]
+    fn walks() {
+        let mut tables = Tables::new();
+        // a 4 KiB page at 0x00401000, and a megapage at 0x00800000
+        tables.set(0x2000, 1, 0x80005 << 10 | RWX | AD | PTE_V);
+        tables.set(0x1000, 2, 0x80400 << 10 | PTE_R | PTE_A | PTE_V);
+        let supervisor = context(Privilege::Supervisor);
+        assert_eq!(supervisor.asid(), 1);
+
+        let translation = translate(0x00401234, Access::Read, &supervisor, |addr| {
+            tables.0.get(&addr).copied()
+        })
+        .unwrap();
+        assert_eq!(translation.physical, 0x80005234);
+        assert_eq!((translation.level, translation.reads), (0, 2));
+        assert_eq!(
+            tables.translate(0x00ABCDEF, Access::Read, &supervisor),
+            Ok(0x806BCDEF)
+        );
+
+        // entries that cannot be read, and invalid ones
+        assert_eq!(
+            tables.translate(0x00402000, Access::Read, &supervisor),
+            Err(Fault::Access)
+        );
+        tables.set(0x2000, 2, 0);
+        assert_eq!(
+            tables.translate(0x00402000, Access::Read, &supervisor),
+            Err(Fault::Page)
+        );
+        // a pointer at the last level
+        tables.set(0x2000, 2, 0x3 << 10 | PTE_V);
+        assert_eq!(
+            tables.translate(0x00402000, Access::Read, &supervisor),
+            Err(Fault::Page)
+        );
+        // a misaligned megapage
+        tables.set(0x1000, 3, 0x80401 << 10 | PTE_R | PTE_A | PTE_V);
+        assert_eq!(
+            tables.translate(0x00C00000, Access::Read, &supervisor),
+            Err(Fault::Page)
+        );
+    }
+
+    #[test]
+    fn permissions() {
+        let mut tables = Tables::new();
+        tables.set(0x2000, 0, 0x80000 << 10 | PTE_X | PTE_A | PTE_V);
+        tables.set(
+            0x2000,
+            1,
+            0x80001 << 10 | PTE_R | PTE_W | PTE_U | AD | PTE_V,
+        );
+        tables.set(0x2000, 2, 0x80002 << 10 | PTE_R | PTE_W | PTE_A | PTE_V);
+        tables.set(0x2000, 3, 0x80003 << 10 | PTE_W | AD | PTE_V);
+        let user = context(Privilege::User);
+        let mut supervisor = context(Privilege::Supervisor);
+
+        assert!(tables
+            .translate(0x00400000, Access::Execute, &supervisor)
+            .is_ok());
+        assert!(tables
+            .translate(0x00400000, Access::Read, &supervisor)
+            .is_err());
+        assert!(tables
+            .translate(0x00400000, Access::Execute, &user)
+            .is_err());
+        // make executable readable
+        supervisor.mxr = true;
+        assert!(tables
+            .translate(0x00400000, Access::Read, &supervisor)
+            .is_ok());
+
+        // user pages are only accessible to supervisor mode with sum set
+        assert!(tables.translate(0x00401000, Access::Write, &user).is_ok());
+        assert!(tables
+            .translate(0x00401000, Access::Read, &supervisor)
+            .is_err());
+        supervisor.sum = true;
+        assert!(tables
+            .translate(0x00401000, Access::Read, &supervisor)
+            .is_ok());
+
+        // pages without the dirty bit can't be written, and write-only is reserved
+        assert!(tables
+            .translate(0x00402000, Access::Read, &supervisor)
+            .is_ok());
+        assert!(tables
+            .translate(0x00402000, Access::Write, &supervisor)
+            .is_err());
+        assert!(tables
+            .translate(0x00403000, Access::Write, &supervisor)
+            .is_err());
+    }
+}
@@ -0,0 +1,433 @@
+//! Platform-level interrupt controller, for evaluating one ahead of the RTL
+//!
+//! The register layout follows the RISC-V PLIC specification, except that the per-context
+//! threshold and claim registers start at 0x8000 so the whole controller fits in one of the
+//! SoC's 64 KiB peripheral slots. Context `n` drives the machine external interrupt of hart
+//! `n`. Sources are level-triggered, and a claimed source does not become pending again until
+//! its handler completes.
+
+use anyhow::{bail, ensure};
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+/// Base address of the PLIC, in the slot after the uart
+pub const PLIC_BASE: u32 = 0x20030000;
+const PLIC_SIZE: u32 = 0x10000;
+
+const PRIORITY: u32 = 0x0000;
+const PENDING: u32 = 0x1000;
+const ENABLE: u32 = 0x2000;
+/// Distance between the enable bits of each context
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT: u32 = 0x8000;
+/// Distance between the threshold and claim registers of each context
+const CONTEXT_STRIDE: u32 = 0x1000;
+const THRESHOLD: u32 = 0x0;
+const CLAIM: u32 = 0x4;
+
+/// Most sources the register layout has room for, as source 0 is reserved
+pub const MAX_SOURCES: u32 = 1023;
+/// Most contexts whose threshold and claim registers fit in the slot
+pub const MAX_CONTEXTS: usize = ((PLIC_SIZE - CONTEXT) / CONTEXT_STRIDE) as usize;
+/// Priorities are 0 to 7, where 0 never interrupts
+const PRIORITY_MASK: u32 = 0b111;
+
+/// Number of sources and contexts, and which source the uart drives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlicConfig {
+    /// Sources are numbered from 1 to this
+    pub sources: u32,
+    pub uart_source: u32,
+}
+
+impl Default for PlicConfig {
+    fn default() -> Self {
+        Self {
+            sources: 31,
+            uart_source: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Context {
+    /// Enable bits, 32 sources to a word
+    enable: Vec<u32>,
+    threshold: u32,
+}
+
+pub struct Plic {
+    base: u32,
+    config: PlicConfig,
+    /// Priority of each source, indexed by source number
+    priority: Vec<u32>,
+    /// Level of each source's interrupt line
+    level: Vec<bool>,
+    pending: Vec<bool>,
+    /// Sources claimed by a handler that has not completed yet
+    claimed: Vec<bool>,
+    contexts: Vec<Context>,
+}
+
+impl Plic {
+    pub fn new(base: u32, config: PlicConfig, contexts: usize) -> Result<Self, anyhow::Error> {
+        ensure!(
+            (1..=MAX_SOURCES).contains(&config.sources),
+            "plic must have between 1 and {MAX_SOURCES} sources: {}",
+            config.sources
+        );
+        ensure!(
+            (1..=config.sources).contains(&config.uart_source),
+            "uart source {} is not one of the plic's {} sources",
+            config.uart_source,
+            config.sources
+        );
+        ensure!(
+            (1..=MAX_CONTEXTS).contains(&contexts),
+            "plic must have between 1 and {MAX_CONTEXTS} contexts: {contexts}"
+        );
+        let sources = config.sources as usize + 1;
+        let context = Context {
+            enable: vec![0; sources.div_ceil(32)],
+            threshold: 0,
+        };
+        Ok(Self {
+            base,
+            config,
+            priority: vec![0; sources],
+            level: vec![false; sources],
+            pending: vec![false; sources],
+            claimed: vec![false; sources],
+            contexts: vec![context; contexts],
+        })
+    }
+
+    pub fn config(&self) -> &PlicConfig {
+        &self.config
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < PLIC_SIZE
+    }
+
+    /// Address of a register, by its name in the register map, such as `claim` for context 0,
+    /// `claim1` for context 1 or `priority3` for source 3
+    pub fn register(&self, name: &str) -> Option<u32> {
+        let numbered = |prefix: &str| name.strip_prefix(prefix)?.parse::<u32>().ok();
+        let context = |prefix: &str| match name.strip_prefix(prefix)? {
+            "" => Some(0),
+            number => number
+                .parse()
+                .ok()
+                .filter(|&context| (context as usize) < self.contexts.len()),
+        };
+        let offset = if name == "pending" {
+            PENDING
+        } else if let Some(context) = context("enable") {
+            ENABLE + ENABLE_STRIDE * context
+        } else if let Some(context) = context("threshold") {
+            CONTEXT + CONTEXT_STRIDE * context + THRESHOLD
+        } else if let Some(context) = context("claim") {
+            CONTEXT + CONTEXT_STRIDE * context + CLAIM
+        } else {
+            let source = numbered("priority").filter(|&s| self.is_source(s))?;
+            PRIORITY + 4 * source
+        };
+        Some(self.base + offset)
+    }
+
+    fn is_source(&self, source: u32) -> bool {
+        (1..=self.config.sources).contains(&source)
+    }
+
+    fn enabled(&self, context: usize, source: u32) -> bool {
+        self.contexts[context].enable[source as usize / 32] & (1 << (source % 32)) != 0
+    }
+
+    /// Drive a source's interrupt line
+    pub fn set_level(&mut self, source: u32, level: bool) {
+        if !self.is_source(source) {
+            return;
+        }
+        let source = source as usize;
+        self.level[source] = level;
+        // the gateway forwards one request at a time
+        if level && !self.claimed[source] {
+            self.pending[source] = true;
+        }
+    }
+
+    /// Pending source with the highest priority above the context's threshold, preferring
+    /// the lowest numbered on a tie
+    fn best(&self, context: usize) -> Option<u32> {
+        let threshold = self.contexts[context].threshold;
+        (1..=self.config.sources)
+            .filter(|&source| self.pending[source as usize] && self.enabled(context, source))
+            .filter(|&source| self.priority[source as usize] > threshold)
+            .min_by_key(|&source| (std::cmp::Reverse(self.priority[source as usize]), source))
+    }
+
+    /// External interrupt line of a context
+    pub fn interrupt(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    /// Whether a context's interrupt line is raised, or may be in future without any more
+    /// writes given the sources whose lines `may_rise`
+    pub fn can_interrupt(&self, context: usize, may_rise: impl Fn(u32) -> bool) -> bool {
+        let threshold = self.contexts[context].threshold;
+        (1..=self.config.sources).any(|source| {
+            let index = source as usize;
+            self.enabled(context, source)
+                && self.priority[index] > threshold
+                && (self.pending[index] || !self.claimed[index] && may_rise(source))
+        })
+    }
+
+    pub fn read(&mut self, addr: u32) -> Result<u32, anyhow::Error> {
+        let offset = (addr - self.base) & !0b11;
+        Ok(match offset {
+            PRIORITY..PENDING => {
+                let source = (offset - PRIORITY) / 4;
+                if self.is_source(source) {
+                    self.priority[source as usize]
+                } else {
+                    0
+                }
+            }
+            PENDING..ENABLE => self.bits((offset - PENDING) / 4, |source| self.pending[source]),
+            ENABLE..CONTEXT => {
+                let (context, word) = self.enable_word(offset)?;
+                self.contexts[context]
+                    .enable
+                    .get(word)
+                    .copied()
+                    .unwrap_or(0)
+            }
+            _ => match self.context_register(offset)? {
+                (context, THRESHOLD) => self.contexts[context].threshold,
+                (context, _) => self.claim(context),
+            },
+        })
+    }
+
+    /// Write the bits of a register selected by `mask`, as byte enables do, where `value` is
+    /// zero outside them
+    pub fn write(&mut self, addr: u32, value: u32, mask: u32) -> Result<(), anyhow::Error> {
+        let offset = (addr - self.base) & !0b11;
+        match offset {
+            PRIORITY..PENDING => {
+                let source = (offset - PRIORITY) / 4;
+                if self.is_source(source) {
+                    let priority = &mut self.priority[source as usize];
+                    *priority = (*priority & !mask | value) & PRIORITY_MASK;
+                }
+            }
+            // pending bits are set by the sources and cleared by claims
+            PENDING..ENABLE => {}
+            ENABLE..CONTEXT => {
+                let (context, word) = self.enable_word(offset)?;
+                let sources = self.config.sources;
+                if let Some(enable) = self.contexts[context].enable.get_mut(word) {
+                    // only implemented sources can be enabled, and never source 0
+                    let implemented = (0..32)
+                        .map(|bit| 32 * word as u32 + bit)
+                        .filter(|&source| (1..=sources).contains(&source))
+                        .fold(0, |mask, source| mask | 1 << (source % 32));
+                    *enable = (*enable & !mask | value) & implemented;
+                }
+            }
+            _ => match self.context_register(offset)? {
+                (context, THRESHOLD) => {
+                    let threshold = &mut self.contexts[context].threshold;
+                    *threshold = (*threshold & !mask | value) & PRIORITY_MASK;
+                }
+                (context, _) => self.complete(context, value),
+            },
+        }
+        Ok(())
+    }
+
+    /// Pack a flag for each of 32 sources into a register
+    fn bits(&self, word: u32, flag: impl Fn(usize) -> bool) -> u32 {
+        (0..32)
+            .map(|bit| 32 * word + bit)
+            .filter(|&source| self.is_source(source) && flag(source as usize))
+            .fold(0, |bits, source| bits | 1 << (source % 32))
+    }
+
+    /// Context and word of an enable register
+    fn enable_word(&self, offset: u32) -> Result<(usize, usize), anyhow::Error> {
+        let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+        let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+        ensure!(
+            context < self.contexts.len(),
+            "invalid plic address: {:08X}",
+            self.base + offset
+        );
+        Ok((context, word))
+    }
+
+    /// Context and register of a threshold or claim register
+    fn context_register(&self, offset: u32) -> Result<(usize, u32), anyhow::Error> {
+        let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+        let register = (offset - CONTEXT) % CONTEXT_STRIDE;
+        if context >= self.contexts.len() || !matches!(register, THRESHOLD | CLAIM) {
+            bail!("invalid plic address: {:08X}", self.base + offset);
+        }
+        Ok((context, register))
+    }
+
+    /// Claim the best pending source for a context, returning 0 if there is none
+    fn claim(&mut self, context: usize) -> u32 {
+        let Some(source) = self.best(context) else {
+            return 0;
+        };
+        self.pending[source as usize] = false;
+        self.claimed[source as usize] = true;
+        source
+    }
+
+    /// Complete the handling of a source, which is ignored unless it is enabled for the
+    /// context
+    fn complete(&mut self, context: usize, source: u32) {
+        if !self.is_source(source) || !self.enabled(context, source) {
+            return;
+        }
+        let index = source as usize;
+        self.claimed[index] = false;
+        // a source still asserting its line is pending again straight away
+        if self.level[index] {
+            self.pending[index] = true;
+        }
+    }
+
+    pub(crate) fn save(&self, out: &mut SnapshotWriter) -> Result<(), anyhow::Error> {
+        out.u32(self.config.sources)?;
+        out.u32(self.config.uart_source)?;
+        out.u32(self.contexts.len() as u32)?;
+        for source in 0..self.priority.len() {
+            out.u32(self.priority[source])?;
+            out.bool(self.level[source])?;
+            out.bool(self.pending[source])?;
+            out.bool(self.claimed[source])?;
+        }
+        for context in &self.contexts {
+            for &word in &context.enable {
+                out.u32(word)?;
+            }
+            out.u32(context.threshold)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn restore(base: u32, input: &mut SnapshotReader) -> Result<Self, anyhow::Error> {
+        let config = PlicConfig {
+            sources: input.u32()?,
+            uart_source: input.u32()?,
+        };
+        let contexts = input.u32()? as usize;
+        let mut plic = Self::new(base, config, contexts)?;
+        for source in 0..plic.priority.len() {
+            plic.priority[source] = input.u32()? & PRIORITY_MASK;
+            plic.level[source] = input.bool()?;
+            plic.pending[source] = input.bool()?;
+            plic.claimed[source] = input.bool()?;
+        }
+        for context in &mut plic.contexts {
+            for word in &mut context.enable {
+                *word = input.u32()?;
+            }
+            context.threshold = input.u32()? & PRIORITY_MASK;
+        }
+        Ok(plic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_plic() -> Plic {
+        let mut plic = Plic::new(PLIC_BASE, PlicConfig::default(), 2).unwrap();
+        for (source, priority) in [(1, 1), (2, 3), (3, 3)] {
+            plic.write(PLIC_BASE + 4 * source, priority, u32::MAX)
+                .unwrap();
+        }
+        plic.write(PLIC_BASE + ENABLE, 0b1110, u32::MAX).unwrap();
+        plic
+    }
+
+    fn claim(plic: &mut Plic) -> u32 {
+        plic.read(PLIC_BASE + CONTEXT + CLAIM).unwrap()
+    }
+
+    #[test]
+    fn priorities_and_threshold() {
+        let mut plic = new_plic();
+        assert!(!plic.interrupt(0));
+        plic.set_level(1, true);
+        plic.set_level(3, true);
+        plic.set_level(2, true);
+        assert!(plic.interrupt(0));
+        // context 1 has nothing enabled
+        assert!(!plic.interrupt(1));
+        assert_eq!(plic.read(PLIC_BASE + PENDING).unwrap(), 0b1110);
+
+        // the threshold masks priorities up to it
+        plic.write(PLIC_BASE + CONTEXT + THRESHOLD, 3, u32::MAX)
+            .unwrap();
+        assert!(!plic.interrupt(0));
+        assert_eq!(claim(&mut plic), 0);
+        plic.write(PLIC_BASE + CONTEXT + THRESHOLD, 1, u32::MAX)
+            .unwrap();
+
+        // highest priority first, then the lowest numbered
+        assert_eq!(claim(&mut plic), 2);
+        assert_eq!(claim(&mut plic), 3);
+        assert!(!plic.interrupt(0));
+        assert_eq!(plic.read(PLIC_BASE + PENDING).unwrap(), 0b0010);
+
+        // priorities and enables only have bits for implemented sources
+        plic.write(PLIC_BASE + 4, 0xFF, u32::MAX).unwrap();
+        assert_eq!(plic.read(PLIC_BASE + 4).unwrap(), 7);
+        plic.write(PLIC_BASE + ENABLE, u32::MAX, u32::MAX).unwrap();
+        assert_eq!(plic.read(PLIC_BASE + ENABLE).unwrap(), u32::MAX - 1);
+        assert_eq!(plic.read(PLIC_BASE + ENABLE + 4).unwrap(), 0);
+    }
+
+    #[test]
+    fn claim_and_complete() {
+        let mut plic = new_plic();
+        plic.set_level(2, true);
+        assert_eq!(claim(&mut plic), 2);
+        // a claimed source stays quiet while its line is held, until it completes
+        plic.set_level(2, true);
+        assert!(!plic.interrupt(0));
+        assert!(!plic.can_interrupt(0, |source| source == 2));
+        plic.write(PLIC_BASE + CONTEXT + CLAIM, 2, u32::MAX)
+            .unwrap();
+        assert!(plic.interrupt(0));
+
+        // completing after the line drops leaves it idle
+        assert_eq!(claim(&mut plic), 2);
+        plic.set_level(2, false);
+        plic.write(PLIC_BASE + CONTEXT + CLAIM, 2, u32::MAX)
+            .unwrap();
+        assert!(!plic.interrupt(0));
+        assert!(plic.can_interrupt(0, |source| source == 2));
+        assert!(!plic.can_interrupt(0, |_| false));
+
+        // unknown registers and contexts are errors
+        assert!(plic.read(PLIC_BASE + CONTEXT + 2 * CONTEXT_STRIDE).is_err());
+        assert!(plic.read(PLIC_BASE + CONTEXT + 8).is_err());
+        assert_eq!(plic.register("priority3"), Some(PLIC_BASE + 12));
+        assert_eq!(plic.register("priority32"), None);
+        assert_eq!(
+            plic.register("claim1"),
+            Some(PLIC_BASE + CONTEXT + CONTEXT_STRIDE + CLAIM)
+        );
+        assert_eq!(plic.register("threshold2"), None);
+        assert!(Plic::new(PLIC_BASE, PlicConfig::default(), MAX_CONTEXTS + 1).is_err());
+    }
+}
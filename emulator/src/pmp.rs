@@ -0,0 +1,258 @@
+//! Physical memory protection, which limits the memory that code below machine mode (and
+//! machine mode, for locked entries) can access
+//!
+//! All 16 entries are implemented with a granularity of 4 bytes. The RTL has no PMP yet, so
+//! this is a model to design software against.
+
+use anyhow::bail;
+
+use crate::{
+    csr::Privilege,
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+pub const ENTRIES: usize = 16;
+
+const CFG_R: u8 = 1 << 0;
+const CFG_W: u8 = 1 << 1;
+const CFG_X: u8 = 1 << 2;
+const CFG_A: u8 = 0b11 << 3;
+const CFG_L: u8 = 1 << 7;
+/// Fields of a configuration byte that software can write
+const CFG_WRITABLE: u8 = CFG_R | CFG_W | CFG_X | CFG_A | CFG_L;
+
+/// Address-matching mode of an entry, from the A field of its configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Matching {
+    Off,
+    /// Top of range, from the previous entry's address up to this one's
+    Tor,
+    /// Naturally aligned four-byte region
+    Na4,
+    /// Naturally aligned power-of-two region, whose size is encoded in the address's low bits
+    Napot,
+}
+
+impl Matching {
+    fn from_cfg(cfg: u8) -> Self {
+        match (cfg & CFG_A) >> 3 {
+            0 => Self::Off,
+            1 => Self::Tor,
+            2 => Self::Na4,
+            _ => Self::Napot,
+        }
+    }
+}
+
+/// Kind of access being checked, each of which has its own permission bit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn permission(self) -> u8 {
+        match self {
+            Self::Read => CFG_R,
+            Self::Write => CFG_W,
+            Self::Execute => CFG_X,
+        }
+    }
+}
+
+/// The pmpcfg and pmpaddr CSRs
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Pmp {
+    /// Configuration byte of each entry, four of which make up a pmpcfg CSR
+    cfg: [u8; ENTRIES],
+    /// Bits 33:2 of each entry's address
+    addr: [u32; ENTRIES],
+}
+
+impl Pmp {
+    /// Read pmpcfg`index`
+    pub fn read_cfg(&self, index: usize) -> u32 {
+        u32::from_le_bytes(self.cfg[4 * index..4 * index + 4].try_into().unwrap())
+    }
+
+    /// Write pmpcfg`index`, leaving the bytes of locked entries alone
+    pub fn write_cfg(&mut self, index: usize, value: u32) {
+        for (offset, byte) in value.to_le_bytes().into_iter().enumerate() {
+            let entry = 4 * index + offset;
+            if self.cfg[entry] & CFG_L != 0 {
+                continue;
+            }
+            let mut cfg = byte & CFG_WRITABLE;
+            // write without read is reserved, so it reads back as neither
+            if cfg & CFG_R == 0 {
+                cfg &= !CFG_W;
+            }
+            self.cfg[entry] = cfg;
+        }
+    }
+
+    pub fn read_addr(&self, index: usize) -> u32 {
+        self.addr[index]
+    }
+
+    /// Write pmpaddr`index`, unless its entry is locked or it is the bottom of a locked TOR
+    /// entry
+    pub fn write_addr(&mut self, index: usize, value: u32) {
+        let locked = |entry: usize| self.cfg[entry] & CFG_L != 0;
+        let bottom_of_locked_tor = index + 1 < ENTRIES
+            && locked(index + 1)
+            && Matching::from_cfg(self.cfg[index + 1]) == Matching::Tor;
+        if !locked(index) && !bottom_of_locked_tor {
+            self.addr[index] = value;
+        }
+    }
+
+    /// Whether any entry is enabled
+    pub fn is_active(&self) -> bool {
+        self.cfg
+            .iter()
+            .any(|&cfg| Matching::from_cfg(cfg) != Matching::Off)
+    }
+
+    /// Bytes covered by an entry, or `None` if it is off
+    fn range(&self, entry: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[entry] as u64;
+        match Matching::from_cfg(self.cfg[entry]) {
+            Matching::Off => None,
+            Matching::Tor => {
+                let bottom = match entry {
+                    0 => 0,
+                    _ => self.addr[entry - 1] as u64,
+                };
+                Some((bottom << 2, addr << 2))
+            }
+            Matching::Na4 => Some((addr << 2, (addr << 2) + 4)),
+            Matching::Napot => {
+                let ones = self.addr[entry].trailing_ones();
+                let base = (addr & !((1 << ones) - 1)) << 2;
+                Some((base, base + (1 << (ones + 3))))
+            }
+        }
+    }
+
+    /// Whether code at `privilege` may make an access of `size` bytes at `addr`
+    ///
+    /// The lowest-numbered entry that covers any of the bytes decides, and fails the access
+    /// unless it covers all of them. Without a matching entry, only machine mode succeeds.
+    pub fn allows(&self, addr: u32, size: u32, access: Access, privilege: Privilege) -> bool {
+        let start = addr as u64;
+        let end = start + size as u64;
+        for entry in 0..ENTRIES {
+            let Some((bottom, top)) = self.range(entry) else {
+                continue;
+            };
+            if start >= top || end <= bottom {
+                continue;
+            }
+            let cfg = self.cfg[entry];
+            let enforced = privilege != Privilege::Machine || cfg & CFG_L != 0;
+            return start >= bottom && end <= top && (!enforced || cfg & access.permission() != 0);
+        }
+        privilege == Privilege::Machine
+    }
+
+    pub(crate) fn save(&self, out: &mut SnapshotWriter) -> Result<(), anyhow::Error> {
+        out.bytes(&self.cfg)?;
+        for addr in self.addr {
+            out.u32(addr)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn restore(&mut self, input: &mut SnapshotReader) -> Result<(), anyhow::Error> {
+        input.bytes(&mut self.cfg)?;
+        if self.cfg.iter().any(|&cfg| cfg & !CFG_WRITABLE != 0) {
+            bail!("invalid pmp configuration in snapshot");
+        }
+        for addr in &mut self.addr {
+            *addr = input.u32()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAPOT: u32 = 0b11 << 3;
+    const TOR: u32 = 0b01 << 3;
+    const NA4: u32 = 0b10 << 3;
+    const R: u32 = 1;
+    const W: u32 = 2;
+    const X: u32 = 4;
+    const L: u32 = 1 << 7;
+
+    #[test]
+    fn matching() {
+        let mut pmp = Pmp::default();
+        // 0x1000..0x2000 read-only, then 0x2000..0x2010 read-write, then 0x3000..0x3004 execute
+        pmp.write_addr(0, (0x1000 >> 2) | 0x1FF);
+        pmp.write_addr(1, 0x2000 >> 2);
+        pmp.write_addr(2, 0x2010 >> 2);
+        pmp.write_addr(3, 0x3000 >> 2);
+        pmp.write_cfg(0, NAPOT | R | (TOR | R | W) << 16 | (NA4 | X) << 24);
+        assert!(pmp.is_active());
+
+        let user = |addr, size, access| pmp.allows(addr, size, access, Privilege::User);
+        assert!(user(0x1000, 4, Access::Read));
+        assert!(user(0x1FFC, 4, Access::Read));
+        assert!(!user(0x1000, 4, Access::Write));
+        assert!(!user(0x1000, 4, Access::Execute));
+        assert!(user(0x2000, 4, Access::Write));
+        assert!(user(0x200C, 4, Access::Read));
+        assert!(user(0x3000, 4, Access::Execute));
+        assert!(!user(0x3004, 4, Access::Execute));
+        // the first matching entry decides, and has to cover the whole access
+        assert!(!user(0x1FFE, 4, Access::Read));
+        assert!(!user(0x200E, 4, Access::Read));
+        // without a match only machine mode succeeds
+        assert!(!user(0x4000, 4, Access::Read));
+        assert!(pmp.allows(0x4000, 4, Access::Read, Privilege::Machine));
+        // and machine mode ignores the permissions of unlocked entries
+        assert!(pmp.allows(0x1000, 4, Access::Write, Privilege::Machine));
+
+        // napot with every bit set covers all of memory
+        let mut pmp = Pmp::default();
+        pmp.write_addr(0, u32::MAX);
+        pmp.write_cfg(0, NAPOT | R | W | X);
+        assert!(pmp.allows(0, 4, Access::Execute, Privilege::User));
+        assert!(pmp.allows(0xFFFFFFFC, 4, Access::Write, Privilege::User));
+    }
+
+    #[test]
+    fn locking() {
+        let mut pmp = Pmp::default();
+        pmp.write_addr(0, 0x1000 >> 2);
+        pmp.write_addr(1, 0x2000 >> 2);
+        pmp.write_cfg(0, (TOR | R | L) << 8);
+
+        // locked entries bind machine mode too
+        assert!(pmp.allows(0x1000, 4, Access::Read, Privilege::Machine));
+        assert!(!pmp.allows(0x1000, 4, Access::Write, Privilege::Machine));
+
+        // neither the entry nor the bottom of its range can be changed, but others can
+        pmp.write_cfg(0, R | (TOR | R | W) << 8);
+        assert_eq!(pmp.read_cfg(0), R | (TOR | R | L) << 8);
+        pmp.write_addr(0, 0);
+        pmp.write_addr(1, 0);
+        assert_eq!(pmp.read_addr(0), 0x1000 >> 2);
+        assert_eq!(pmp.read_addr(1), 0x2000 >> 2);
+        pmp.write_addr(2, 0x1234);
+        assert_eq!(pmp.read_addr(2), 0x1234);
+    }
+
+    #[test]
+    fn reserved_permissions() {
+        let mut pmp = Pmp::default();
+        pmp.write_cfg(1, W | X | 0x60 | (R | W) << 8);
+        assert_eq!(pmp.read_cfg(1), X | (R | W) << 8);
+    }
+}
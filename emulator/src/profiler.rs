@@ -0,0 +1,432 @@
+//! Function-level profiler, attributing instructions and cycles to call stacks
+//!
+//! Calls and returns are recognised from the standard calling convention, using ra or t0 as
+//! the link register, and traps are treated as calls into the handler.
+
+use std::{collections::HashMap, fmt, io};
+
+use crate::{
+    instructions::{DecodedInstruction, Instruction},
+    symbols::Symbols,
+};
+
+/// Deepest call stack tracked, beyond which calls are attributed to the caller
+const MAX_DEPTH: usize = 1024;
+
+/// Cost attributed to a function or call stack
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+}
+
+/// Which count to write out for each stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    Instructions,
+    Cycles,
+}
+
+/// Profile of a single function
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// Cost of the function's own instructions
+    pub own: Counts,
+    /// Cost including everything it called
+    pub total: Counts,
+    pub calls: u64,
+}
+
+/// Unique call stack, stored as a tree of callers
+struct Node {
+    parent: usize,
+    /// Entry address of the function
+    function: u32,
+    counts: Counts,
+}
+
+struct Frame {
+    node: usize,
+    /// Address the function returns to
+    return_addr: u32,
+    /// Whether this is a trap handler, which returns with mret
+    trap: bool,
+}
+
+pub struct Profiler {
+    nodes: Vec<Node>,
+    children: HashMap<(usize, u32), usize>,
+    stack: Vec<Frame>,
+    calls: HashMap<u32, u64>,
+}
+
+impl Profiler {
+    /// Start profiling from `pc`
+    pub fn new(pc: u32, symbols: &Symbols) -> Self {
+        Self {
+            nodes: vec![Node {
+                parent: 0,
+                function: function_of(pc, symbols),
+                counts: Counts::default(),
+            }],
+            children: HashMap::new(),
+            stack: vec![Frame {
+                node: 0,
+                return_addr: 0,
+                trap: false,
+            }],
+            calls: HashMap::new(),
+        }
+    }
+
+    /// Attribute the cost of a step to the current call stack
+    pub(crate) fn charge(&mut self, instructions: u64, cycles: u64) {
+        let node = self.current();
+        self.nodes[node].counts.add(Counts {
+            instructions,
+            cycles,
+        });
+    }
+
+    /// Follow calls and returns made by a retired instruction at `pc` that went on to `next_pc`
+    pub(crate) fn retire(
+        &mut self,
+        pc: u32,
+        inst: &DecodedInstruction,
+        next_pc: u32,
+        symbols: &Symbols,
+    ) {
+        let is_link = |register: u8| register == 1 || register == 5;
+        match inst.kind {
+            Instruction::Jal | Instruction::Jalr if is_link(inst.rd) => {
+                self.call(next_pc, pc.wrapping_add(4), false, symbols);
+            }
+            Instruction::Jalr if inst.rd == 0 && is_link(inst.rs1) => {
+                // the root frame was never called, so can't be returned from
+                if let Some(index) = self.stack[1..]
+                    .iter()
+                    .rposition(|frame| frame.return_addr == next_pc)
+                {
+                    self.stack.truncate(index + 1);
+                }
+            }
+            Instruction::Jal | Instruction::Jalr if inst.rd == 0 => {
+                // a jump to the start of another function is a tail call, which takes over the
+                // current frame
+                let is_entry = symbols
+                    .lookup(next_pc)
+                    .is_some_and(|symbol| symbol.addr == next_pc);
+                let current = self.nodes[self.current()].function;
+                if is_entry && current != next_pc && self.stack.len() > 1 {
+                    let frame = self.stack.pop().unwrap();
+                    self.call(next_pc, frame.return_addr, frame.trap, symbols);
+                }
+            }
+            Instruction::Mret | Instruction::Sret => {
+                if let Some(index) = self.stack.iter().rposition(|frame| frame.trap) {
+                    self.stack.truncate(index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Follow a trap taken at `pc` into the handler at `handler`
+    pub(crate) fn trap(&mut self, pc: u32, handler: u32, symbols: &Symbols) {
+        self.call(handler, pc, true, symbols);
+    }
+
+    fn call(&mut self, target: u32, return_addr: u32, trap: bool, symbols: &Symbols) {
+        let function = function_of(target, symbols);
+        *self.calls.entry(function).or_default() += 1;
+        if self.stack.len() >= MAX_DEPTH {
+            return;
+        }
+
+        let parent = self.current();
+        let next_index = self.nodes.len();
+        let node = *self
+            .children
+            .entry((parent, function))
+            .or_insert(next_index);
+        if node == next_index {
+            self.nodes.push(Node {
+                parent,
+                function,
+                counts: Counts::default(),
+            });
+        }
+        self.stack.push(Frame {
+            node,
+            return_addr,
+            trap,
+        });
+    }
+
+    fn current(&self) -> usize {
+        self.stack.last().map_or(0, |frame| frame.node)
+    }
+
+    /// Entry addresses of the functions on the stack ending at `node`, outermost first
+    fn path(&self, mut node: usize) -> Vec<u32> {
+        let mut path = vec![self.nodes[node].function];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(self.nodes[node].function);
+        }
+        path.reverse();
+        path
+    }
+
+    /// Profile of every function, keyed by entry address
+    pub fn functions(&self) -> HashMap<u32, FunctionProfile> {
+        let mut functions: HashMap<u32, FunctionProfile> = HashMap::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            functions
+                .entry(node.function)
+                .or_default()
+                .own
+                .add(node.counts);
+
+            // recursive functions only count once towards their total
+            let mut path = self.path(index);
+            path.sort_unstable();
+            path.dedup();
+            for function in path {
+                functions
+                    .entry(function)
+                    .or_default()
+                    .total
+                    .add(node.counts);
+            }
+        }
+        for (function, calls) in &self.calls {
+            functions.entry(*function).or_default().calls = *calls;
+        }
+        functions
+    }
+
+    /// Cost of the whole run
+    pub fn total(&self) -> Counts {
+        let mut total = Counts::default();
+        for node in &self.nodes {
+            total.add(node.counts);
+        }
+        total
+    }
+
+    /// Write a table of functions, sorted by the cycles spent in their own code
+    pub fn report(&self, symbols: &Symbols, out: &mut impl fmt::Write) -> fmt::Result {
+        let total = self.total();
+        let percent = |cycles: u64| 100.0 * cycles as f64 / total.cycles.max(1) as f64;
+
+        let mut functions: Vec<_> = self.functions().into_iter().collect();
+        functions.sort_by(|(a_addr, a), (b_addr, b)| {
+            b.own.cycles.cmp(&a.own.cycles).then(a_addr.cmp(b_addr))
+        });
+
+        writeln!(
+            out,
+            "profile: {} instructions, {} cycles",
+            total.instructions, total.cycles
+        )?;
+        writeln!(
+            out,
+            "  {:>12} {:>6} {:>12} {:>6} {:>12} {:>8}  function",
+            "self cycles", "%", "total cycles", "%", "instructions", "calls"
+        )?;
+        for (function, profile) in functions {
+            writeln!(
+                out,
+                "  {:>12} {:>5.1}% {:>12} {:>5.1}% {:>12} {:>8}  {}",
+                profile.own.cycles,
+                percent(profile.own.cycles),
+                profile.total.cycles,
+                percent(profile.total.cycles),
+                profile.own.instructions,
+                profile.calls,
+                function_name(function, symbols)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write every call stack in the folded format used by inferno and flamegraph.pl
+    pub fn write_folded(
+        &self,
+        symbols: &Symbols,
+        metric: Metric,
+        out: &mut impl io::Write,
+    ) -> io::Result<()> {
+        for (index, node) in self.nodes.iter().enumerate() {
+            let count = match metric {
+                Metric::Instructions => node.counts.instructions,
+                Metric::Cycles => node.counts.cycles,
+            };
+            if count == 0 {
+                continue;
+            }
+            let stack: Vec<String> = self
+                .path(index)
+                .into_iter()
+                // semicolons separate frames, but can appear in demangled names
+                .map(|function| function_name(function, symbols).replace(';', ","))
+                .collect();
+            writeln!(out, "{} {count}", stack.join(";"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Entry address of the function containing `addr`, or the address itself if it is unknown
+fn function_of(addr: u32, symbols: &Symbols) -> u32 {
+    symbols.lookup(addr).map_or(addr, |symbol| symbol.addr)
+}
+
+fn function_name(function: u32, symbols: &Symbols) -> String {
+    match symbols.lookup(function) {
+        Some(symbol) => symbol.name.clone(),
+        None => format!("{function:08X}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::Symbol;
+
+    fn symbols() -> Symbols {
+        let symbol = |name: &str, addr, size| Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+        };
+        Symbols::new(vec![
+            symbol("main", 0x1000, 0x100),
+            symbol("foo", 0x2000, 0x100),
+            symbol("bar", 0x3000, 0x100),
+            symbol("<[u8; 4]>::len", 0x4000, 0x100),
+        ])
+    }
+
+    fn inst(raw: u32) -> DecodedInstruction {
+        DecodedInstruction::try_from(raw).unwrap()
+    }
+
+    /// jal ra, offset
+    fn call(offset: i32) -> DecodedInstruction {
+        let imm = offset as u32;
+        inst(
+            ((imm >> 20 & 0x1) << 31)
+                | ((imm >> 1 & 0x3FF) << 21)
+                | ((imm >> 11 & 0x1) << 20)
+                | (imm & 0xFF000)
+                | (1 << 7)
+                | 0b1101111,
+        )
+    }
+
+    const RET: u32 = 0x00008067;
+    const TAIL: u32 = 0x00030067; // jalr x0, 0(t1)
+    const MRET: u32 = 0x30200073;
+
+    fn step(profiler: &mut Profiler, pc: u32, inst: DecodedInstruction, next_pc: u32) {
+        profiler.charge(1, 2);
+        profiler.retire(pc, &inst, next_pc, &symbols());
+    }
+
+    fn folded(profiler: &Profiler, metric: Metric) -> Vec<String> {
+        let mut out = Vec::new();
+        profiler.write_folded(&symbols(), metric, &mut out).unwrap();
+        let mut lines: Vec<String> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        let symbols = symbols();
+        let mut profiler = Profiler::new(0x1000, &symbols);
+        // main calls foo, which calls bar twice
+        step(&mut profiler, 0x1000, call(0x1000), 0x2000);
+        step(&mut profiler, 0x2000, call(0x1000), 0x3000);
+        step(&mut profiler, 0x3000, inst(RET), 0x2004);
+        step(&mut profiler, 0x2004, call(0xFFC), 0x3000);
+        step(&mut profiler, 0x3000, inst(RET), 0x2008);
+        step(&mut profiler, 0x2008, inst(RET), 0x1004);
+        step(&mut profiler, 0x1004, inst(0x00000013), 0x1008);
+
+        assert_eq!(
+            folded(&profiler, Metric::Instructions),
+            ["main 2", "main;foo 3", "main;foo;bar 2"]
+        );
+        assert_eq!(
+            folded(&profiler, Metric::Cycles),
+            ["main 4", "main;foo 6", "main;foo;bar 4"]
+        );
+
+        let functions = profiler.functions();
+        let foo = functions[&0x2000];
+        assert_eq!(foo.own.instructions, 3);
+        assert_eq!(foo.total.instructions, 5);
+        assert_eq!(foo.calls, 1);
+        assert_eq!(functions[&0x3000].calls, 2);
+        assert_eq!(functions[&0x1000].total.instructions, 7);
+        assert_eq!(profiler.total().cycles, 14);
+    }
+
+    #[test]
+    fn tail_calls_and_recursion() {
+        let symbols = symbols();
+        let mut profiler = Profiler::new(0x1000, &symbols);
+        step(&mut profiler, 0x1000, call(0x1000), 0x2000);
+        // foo recurses, then tail calls bar, which returns straight to main
+        step(&mut profiler, 0x2000, call(0), 0x2000);
+        step(&mut profiler, 0x2000, inst(TAIL), 0x3000);
+        step(&mut profiler, 0x3000, inst(RET), 0x2004);
+        step(&mut profiler, 0x2004, inst(RET), 0x1004);
+        step(&mut profiler, 0x1004, inst(0x00000013), 0x1008);
+
+        assert_eq!(
+            folded(&profiler, Metric::Instructions),
+            ["main 2", "main;foo 2", "main;foo;bar 1", "main;foo;foo 1"]
+        );
+        // foo's own recursion only counts once
+        assert_eq!(profiler.functions()[&0x2000].total.instructions, 4);
+    }
+
+    #[test]
+    fn traps_and_unknown_functions() {
+        let symbols = symbols();
+        let mut profiler = Profiler::new(0x1000, &symbols);
+        profiler.charge(1, 1);
+        profiler.trap(0x1004, 0x8000, &symbols);
+        step(&mut profiler, 0x8000, inst(MRET), 0x1008);
+        step(&mut profiler, 0x1008, call(0x3000 - 0x1008), 0x4000);
+
+        assert_eq!(
+            folded(&profiler, Metric::Instructions),
+            ["main 2", "main;00008000 1"]
+        );
+        // returning to an address that was never called is ignored
+        step(&mut profiler, 0x4000, inst(RET), 0x5000);
+        assert_eq!(
+            folded(&profiler, Metric::Instructions)[2],
+            "main;<[u8, 4]>::len 1"
+        );
+        let mut report = String::new();
+        profiler.report(&symbols, &mut report).unwrap();
+        assert!(report.contains("00008000"), "{report}");
+    }
+}
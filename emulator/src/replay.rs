@@ -0,0 +1,224 @@
+//! Recording input from the host, so that a run can be replayed deterministically
+//!
+//! The uart is the only way input reaches the program, as mtime counts emulated cycles rather
+//! than host time. Each byte is logged with the cycle it was received at, and replaying the
+//! log delivers the bytes at the same cycles. A log is a text file with a line of
+//! `CYCLE BYTE` for each byte, in decimal and hex respectively.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{ensure, Context};
+
+use crate::uart::UartBackend;
+
+/// Byte received from the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub cycle: u64,
+    pub byte: u8,
+}
+
+/// Read a log written by a [`RecordingBackend`]
+pub fn read_input_log(path: impl AsRef<Path>) -> Result<Vec<InputEvent>, anyhow::Error> {
+    let file = File::open(path).context("could not open input log")?;
+    let mut events: Vec<InputEvent> = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let parse = || -> Option<InputEvent> {
+            let (cycle, byte) = line.split_once(' ')?;
+            Some(InputEvent {
+                cycle: cycle.parse().ok()?,
+                byte: u8::from_str_radix(byte, 16).ok()?,
+            })
+        };
+        let event = parse().with_context(|| format!("invalid input log line {}", index + 1))?;
+        ensure!(
+            events.last().is_none_or(|last| last.cycle <= event.cycle),
+            "input log is out of order at line {}",
+            index + 1
+        );
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// Bytes that have been received, with how many have been delivered since the last rewind
+#[derive(Debug, Default)]
+struct Inputs {
+    events: Vec<InputEvent>,
+    next: usize,
+}
+
+impl Inputs {
+    /// Deliver the next byte if it was received by `cycle`
+    fn next(&mut self, cycle: u64) -> Option<u8> {
+        let event = self.events.get(self.next)?;
+        if event.cycle > cycle {
+            return None;
+        }
+        self.next += 1;
+        Some(event.byte)
+    }
+
+    fn is_replaying(&self) -> bool {
+        self.next < self.events.len()
+    }
+
+    fn rewind(&mut self, cycle: u64) {
+        self.next = self.events.partition_point(|event| event.cycle < cycle);
+    }
+}
+
+/// Passes bytes through from another backend, keeping them so they can be replayed after the
+/// machine is rewound, and optionally logging them to a file
+pub struct RecordingBackend {
+    inner: Box<dyn UartBackend>,
+    inputs: Inputs,
+    log: Option<BufWriter<File>>,
+}
+
+impl RecordingBackend {
+    pub fn new(inner: Box<dyn UartBackend>) -> Self {
+        Self {
+            inner,
+            inputs: Inputs::default(),
+            log: None,
+        }
+    }
+
+    /// Also log each byte to a file, for [`ReplayBackend`]
+    pub fn with_log(mut self, path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let file = File::create(path).context("could not create input log")?;
+        self.log = Some(BufWriter::new(file));
+        Ok(self)
+    }
+}
+
+impl UartBackend for RecordingBackend {
+    fn transmit(&mut self, byte: u8) -> Result<(), anyhow::Error> {
+        self.inner.transmit(byte)
+    }
+
+    fn receive(&mut self, cycle: u64) -> Option<u8> {
+        if self.inputs.is_replaying() {
+            return self.inputs.next(cycle);
+        }
+        let byte = self.inner.receive(cycle)?;
+        self.inputs.events.push(InputEvent { cycle, byte });
+        self.inputs.next += 1;
+        if let Some(log) = &mut self.log {
+            // a partial log is still worth keeping, so failing to write it is not fatal
+            let written = writeln!(log, "{cycle} {byte:02X}").and_then(|()| log.flush());
+            if written.is_err() {
+                self.log = None;
+            }
+        }
+        Some(byte)
+    }
+
+    fn can_receive(&self) -> bool {
+        self.inputs.is_replaying() || self.inner.can_receive()
+    }
+
+    fn rewind(&mut self, cycle: u64) {
+        self.inputs.rewind(cycle);
+    }
+}
+
+/// Delivers the bytes from a log instead of receiving any, and transmits to another backend
+pub struct ReplayBackend {
+    inner: Box<dyn UartBackend>,
+    inputs: Inputs,
+}
+
+impl ReplayBackend {
+    pub fn new(events: Vec<InputEvent>, inner: Box<dyn UartBackend>) -> Self {
+        Self {
+            inner,
+            inputs: Inputs { events, next: 0 },
+        }
+    }
+}
+
+impl UartBackend for ReplayBackend {
+    fn transmit(&mut self, byte: u8) -> Result<(), anyhow::Error> {
+        self.inner.transmit(byte)
+    }
+
+    fn receive(&mut self, cycle: u64) -> Option<u8> {
+        self.inputs.next(cycle)
+    }
+
+    fn can_receive(&self) -> bool {
+        self.inputs.is_replaying()
+    }
+
+    fn rewind(&mut self, cycle: u64) {
+        self.inputs.rewind(cycle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::uart::NullBackend;
+
+    /// Receives a byte whenever it is polled, until it runs out
+    struct Source(VecDeque<u8>);
+
+    impl UartBackend for Source {
+        fn transmit(&mut self, _byte: u8) -> Result<(), anyhow::Error> {
+            Ok(())
+        }
+
+        fn receive(&mut self, _cycle: u64) -> Option<u8> {
+            self.0.pop_front()
+        }
+
+        fn can_receive(&self) -> bool {
+            !self.0.is_empty()
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input-log");
+        let mut recording = RecordingBackend::new(Box::new(Source(VecDeque::from([1, 2, 3]))))
+            .with_log(&path)
+            .unwrap();
+        assert_eq!(recording.receive(10), Some(1));
+        assert_eq!(recording.receive(25), Some(2));
+
+        // after rewinding, the recorded bytes come back at the same cycles
+        recording.rewind(11);
+        assert_eq!(recording.receive(20), None);
+        assert!(recording.can_receive());
+        assert_eq!(recording.receive(30), Some(2));
+        assert_eq!(recording.receive(31), Some(3));
+        assert_eq!(recording.receive(32), None);
+        assert!(!recording.can_receive());
+        drop(recording);
+
+        let events = read_input_log(&path).unwrap();
+        assert_eq!(
+            events,
+            [(10, 1), (25, 2), (31, 3)].map(|(cycle, byte)| InputEvent { cycle, byte })
+        );
+
+        let mut replay = ReplayBackend::new(events, Box::new(NullBackend));
+        assert_eq!(replay.receive(9), None);
+        assert_eq!(replay.receive(12), Some(1));
+        assert_eq!(replay.receive(40), Some(2));
+        assert_eq!(replay.receive(40), Some(3));
+        assert!(!replay.can_receive());
+        replay.rewind(0);
+        assert_eq!(replay.receive(10), Some(1));
+    }
+}
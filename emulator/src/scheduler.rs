@@ -0,0 +1,180 @@
+//! Order that harts take turns to step in, which depends only on the configuration so runs
+//! with several harts are as repeatable as runs with one
+
+use std::str::FromStr;
+
+use anyhow::{bail, ensure, Context};
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+/// How the steps of harts are interleaved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interleaving {
+    /// Each hart in turn runs `quantum` steps
+    RoundRobin { quantum: u64 },
+    /// A randomly chosen hart runs between 1 and `max_quantum` steps, from a generator seeded
+    /// with `seed`, to shake out races that a fixed order hides
+    Random { seed: u64, max_quantum: u64 },
+}
+
+impl Default for Interleaving {
+    fn default() -> Self {
+        Self::RoundRobin { quantum: 1 }
+    }
+}
+
+impl FromStr for Interleaving {
+    type Err = anyhow::Error;
+
+    /// Parse `round-robin[:QUANTUM]` or `random:SEED[:MAX_QUANTUM]`, where quanta default to 1
+    /// and 16 steps respectively
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut fields = text.split(':');
+        let kind = fields.next().unwrap_or_default();
+        let mut number = |name: &str, default: Option<u64>| -> Result<u64, anyhow::Error> {
+            match (fields.next(), default) {
+                (Some(field), _) => field.parse().with_context(|| format!("invalid {name}")),
+                (None, Some(default)) => Ok(default),
+                (None, None) => bail!("missing {name}"),
+            }
+        };
+        let interleaving = match kind {
+            "round-robin" => Self::RoundRobin {
+                quantum: number("quantum", Some(1))?,
+            },
+            "random" => Self::Random {
+                seed: number("seed", None)?,
+                max_quantum: number("quantum", Some(16))?,
+            },
+            _ => bail!("unknown interleaving: {text}"),
+        };
+        ensure!(
+            fields.next().is_none(),
+            "too many fields in interleaving: {text}"
+        );
+        let (Self::RoundRobin { quantum }
+        | Self::Random {
+            max_quantum: quantum,
+            ..
+        }) = interleaving;
+        ensure!(quantum > 0, "quantum must be at least one step");
+        Ok(interleaving)
+    }
+}
+
+/// Chooses which hart steps next
+pub struct Scheduler {
+    interleaving: Interleaving,
+    /// State of the random number generator
+    state: u64,
+    /// Steps the running hart has left before another is chosen
+    remaining: u64,
+}
+
+impl Scheduler {
+    /// Start with hart 0 running
+    pub fn new(interleaving: Interleaving) -> Self {
+        let mut scheduler = Self {
+            interleaving,
+            state: match interleaving {
+                Interleaving::RoundRobin { .. } => 0,
+                Interleaving::Random { seed, .. } => seed,
+            },
+            remaining: 0,
+        };
+        scheduler.remaining = scheduler.quantum();
+        scheduler
+    }
+
+    /// Count a step of hart `current`, returning the hart that runs the next step
+    pub fn next(&mut self, current: usize, harts: usize) -> usize {
+        self.remaining = self.remaining.saturating_sub(1);
+        if self.remaining > 0 {
+            return current;
+        }
+        self.remaining = self.quantum();
+        match self.interleaving {
+            Interleaving::RoundRobin { .. } => (current + 1) % harts,
+            Interleaving::Random { .. } => (self.random() % harts as u64) as usize,
+        }
+    }
+
+    /// Steps the next hart to be chosen runs for
+    fn quantum(&mut self) -> u64 {
+        match self.interleaving {
+            Interleaving::RoundRobin { quantum } => quantum,
+            Interleaving::Random { max_quantum, .. } => 1 + self.random() % max_quantum.max(1),
+        }
+    }
+
+    /// Next number from a SplitMix64 generator, which works with any seed
+    fn random(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    pub(crate) fn save(&self, out: &mut SnapshotWriter) -> Result<(), anyhow::Error> {
+        out.u64(self.state)?;
+        out.u64(self.remaining)
+    }
+
+    pub(crate) fn restore(&mut self, input: &mut SnapshotReader) -> Result<(), anyhow::Error> {
+        self.state = input.u64()?;
+        self.remaining = input.u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(interleaving: Interleaving, harts: usize, steps: usize) -> Vec<usize> {
+        let mut scheduler = Scheduler::new(interleaving);
+        let mut current = 0;
+        let mut order = vec![current];
+        for _ in 1..steps {
+            current = scheduler.next(current, harts);
+            order.push(current);
+        }
+        order
+    }
+
+    #[test]
+    fn round_robin() {
+        let interleaving = "round-robin:2".parse().unwrap();
+        assert_eq!(schedule(interleaving, 3, 8), [0, 0, 1, 1, 2, 2, 0, 0]);
+        assert_eq!(schedule(Interleaving::default(), 2, 4), [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn random_is_repeatable() {
+        let interleaving: Interleaving = "random:7:4".parse().unwrap();
+        let order = schedule(interleaving, 4, 200);
+        assert_eq!(order, schedule(interleaving, 4, 200));
+        assert!((0..4).all(|hart| order.contains(&hart)));
+        assert_ne!(order, schedule("random:8:4".parse().unwrap(), 4, 200));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "round-robin".parse::<Interleaving>().unwrap(),
+            Interleaving::RoundRobin { quantum: 1 }
+        );
+        assert_eq!(
+            "random:3".parse::<Interleaving>().unwrap(),
+            Interleaving::Random {
+                seed: 3,
+                max_quantum: 16
+            }
+        );
+        assert!("random".parse::<Interleaving>().is_err());
+        assert!("round-robin:0".parse::<Interleaving>().is_err());
+        assert!("round-robin:1:2".parse::<Interleaving>().is_err());
+        assert!("fifo".parse::<Interleaving>().is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//! RISC-V semihosting, for programs to use the host's console and files
+//!
+//! A call is an `ebreak` between `slli x0, x0, 0x1f` and `srai x0, x0, 7`, with the operation
+//! in a0 and its parameter, often the address of a block of words, in a1. The result is
+//! returned in a0. Addresses are the program's own, so they are translated and checked by the
+//! pmp, and a fault reaching them is raised in place of the call. Open files are host-side
+//! state, so they are not saved in snapshots.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+};
+
+use crate::{cpu::Cpu, pmp::Access, trap::Exception};
+
+/// `slli x0, x0, 0x1f`, which comes before the ebreak
+pub(crate) const ENTRY: u32 = 0x01F01013;
+/// `srai x0, x0, 7`, which comes after the ebreak
+pub(crate) const EXIT: u32 = 0x40705013;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// Reason given to SYS_EXIT when the program exits normally
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+/// Frequency of the core clock, which SYS_CLOCK measures emulated time with
+const CLOCK_HZ: u64 = 100_000_000;
+
+const EBADF: u32 = 9;
+const EFAULT: u32 = 14;
+const EINVAL: u32 = 22;
+const ENOSYS: u32 = 38;
+
+/// Result of a call, which is -1 for most errors
+const FAILED: u32 = u32::MAX;
+
+/// Why a call failed
+enum Error {
+    /// Error the call returns, for SYS_ERRNO
+    Errno(u32),
+    /// Fault reaching the program's memory, which the hart takes instead of returning
+    Fault(Exception),
+}
+
+impl From<u32> for Error {
+    fn from(errno: u32) -> Self {
+        Self::Errno(errno)
+    }
+}
+
+impl From<Exception> for Error {
+    fn from(exception: Exception) -> Self {
+        Self::Fault(exception)
+    }
+}
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// State of the calls a program makes
+pub struct Semihosting {
+    stdin: Box<dyn Read + Send>,
+    stdout: Box<dyn Write + Send>,
+    stderr: Box<dyn Write + Send>,
+    /// Open handles, where handle `n` is at index `n - 1`
+    handles: Vec<Option<Handle>>,
+    /// Error of the last call that failed, for SYS_ERRNO
+    errno: u32,
+}
+
+impl Default for Semihosting {
+    fn default() -> Self {
+        Self::with_console(
+            Box::new(io::stdin()),
+            Box::new(io::stdout()),
+            Box::new(io::stderr()),
+        )
+    }
+}
+
+impl Semihosting {
+    /// Use the emulator's own stdin, stdout and stderr as the console
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use other streams as the console, which the program opens as `:tt`
+    pub fn with_console(
+        stdin: Box<dyn Read + Send>,
+        stdout: Box<dyn Write + Send>,
+        stderr: Box<dyn Write + Send>,
+    ) -> Self {
+        Self {
+            stdin,
+            stdout,
+            stderr,
+            handles: Vec::new(),
+            errno: 0,
+        }
+    }
+
+    /// Make the call in the core's a0 and a1, returning the exit code if the program exited
+    pub(crate) fn call(&mut self, cpu: &mut Cpu) -> Result<Option<u32>, Exception> {
+        let op = cpu.register(10);
+        let param = cpu.register(11);
+        let result = match op {
+            SYS_EXIT => {
+                return Ok(Some(exit_code(param, 0)));
+            }
+            SYS_EXIT_EXTENDED => {
+                return match read_words(cpu, param) {
+                    Ok([reason, code]) => Ok(Some(exit_code(reason, code))),
+                    Err(Error::Errno(_)) => Ok(Some(1)),
+                    Err(Error::Fault(exception)) => Err(exception),
+                };
+            }
+            SYS_OPEN => self.open(cpu, param),
+            SYS_CLOSE => self.close(cpu, param),
+            SYS_WRITEC => self.write_console(cpu, param, 1),
+            SYS_WRITE0 => self.write0(cpu, param),
+            SYS_WRITE => self.write(cpu, param),
+            SYS_READ => self.read(cpu, param),
+            SYS_CLOCK => Ok((cpu.cycles() / (CLOCK_HZ / 100)) as u32),
+            SYS_ERRNO => Ok(self.errno),
+            _ => Err(ENOSYS.into()),
+        };
+        let result = match result {
+            Ok(result) => result,
+            Err(Error::Errno(errno)) => {
+                self.errno = errno;
+                FAILED
+            }
+            Err(Error::Fault(exception)) => return Err(exception),
+        };
+        cpu.set_register(10, result);
+        Ok(None)
+    }
+
+    fn open(&mut self, cpu: &mut Cpu, param: u32) -> Result<u32, Error> {
+        let [name, mode, len] = read_words(cpu, param)?;
+        let name = read_bytes(cpu, name, len)?;
+        let name = String::from_utf8(name).map_err(|_| EINVAL)?;
+        // modes are those of fopen: r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+ and a+b
+        let handle = if name == ":tt" {
+            match mode {
+                0..4 => Handle::Stdin,
+                4..8 => Handle::Stdout,
+                8..12 => Handle::Stderr,
+                _ => return Err(EINVAL.into()),
+            }
+        } else {
+            let mut options = OpenOptions::new();
+            let update = mode % 4 >= 2;
+            match mode {
+                0..4 => options.read(true).write(update),
+                4..8 => options.write(true).read(update).create(true).truncate(true),
+                8..12 => options.append(true).read(update).create(true),
+                _ => return Err(EINVAL.into()),
+            };
+            Handle::File(options.open(name).map_err(|error| errno(&error))?)
+        };
+
+        let index = match self.handles.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.handles.push(None);
+                self.handles.len() - 1
+            }
+        };
+        self.handles[index] = Some(handle);
+        Ok(index as u32 + 1)
+    }
+
+    fn close(&mut self, cpu: &mut Cpu, param: u32) -> Result<u32, Error> {
+        let [handle] = read_words(cpu, param)?;
+        self.handle(handle)?;
+        self.handles[handle as usize - 1] = None;
+        Ok(0)
+    }
+
+    /// Write the null-terminated string at `addr` to the console
+    fn write0(&mut self, cpu: &mut Cpu, addr: u32) -> Result<u32, Error> {
+        let mut len = 0;
+        while read_byte(cpu, addr.wrapping_add(len))? != 0 {
+            len += 1;
+        }
+        self.write_console(cpu, addr, len)
+    }
+
+    /// Write `len` bytes at `addr` to the console
+    fn write_console(&mut self, cpu: &mut Cpu, addr: u32, len: u32) -> Result<u32, Error> {
+        let bytes = read_bytes(cpu, addr, len)?;
+        self.stdout
+            .write_all(&bytes)
+            .and_then(|()| self.stdout.flush())
+            .map_err(|error| errno(&error))?;
+        Ok(0)
+    }
+
+    /// Write a buffer to a handle, returning how many bytes were not written
+    fn write(&mut self, cpu: &mut Cpu, param: u32) -> Result<u32, Error> {
+        let [handle, addr, len] = read_words(cpu, param)?;
+        let bytes = read_bytes(cpu, addr, len)?;
+        let out: &mut dyn Write = match self.handle(handle)? {
+            Handle::Stdin => return Err(EBADF.into()),
+            Handle::Stdout => &mut self.stdout,
+            Handle::Stderr => &mut self.stderr,
+            Handle::File(file) => file,
+        };
+        out.write_all(&bytes)
+            .and_then(|()| out.flush())
+            .map_err(|error| errno(&error))?;
+        Ok(0)
+    }
+
+    /// Read into a buffer from a handle, returning how many bytes were not read
+    fn read(&mut self, cpu: &mut Cpu, param: u32) -> Result<u32, Error> {
+        let [handle, addr, len] = read_words(cpu, param)?;
+        // find the whole buffer before taking any input, which a fault would lose
+        let buffer = (0..len)
+            .map(|offset| cpu.guest_address(addr.wrapping_add(offset), Access::Write))
+            .collect::<Result<Vec<_>, _>>()?;
+        let input: &mut dyn Read = match self.handle(handle)? {
+            Handle::Stdin => &mut self.stdin,
+            Handle::Stdout | Handle::Stderr => return Err(EBADF.into()),
+            Handle::File(file) => file,
+        };
+        let mut bytes = vec![0; len as usize];
+        let read = input.read(&mut bytes).map_err(|error| errno(&error))?;
+        for (&physical, &byte) in buffer.iter().zip(&bytes[..read]) {
+            cpu.write_memory(physical, byte as u32, 1)
+                .map_err(|_| EFAULT)?;
+        }
+        Ok(len - read as u32)
+    }
+
+    fn handle(&mut self, handle: u32) -> Result<&mut Handle, u32> {
+        let index = (handle as usize).checked_sub(1).ok_or(EBADF)?;
+        self.handles
+            .get_mut(index)
+            .and_then(Option::as_mut)
+            .ok_or(EBADF)
+    }
+}
+
+/// Exit code for a SYS_EXIT `reason`, where only a normal exit passes on the program's code
+fn exit_code(reason: u32, code: u32) -> u32 {
+    if reason == ADP_STOPPED_APPLICATION_EXIT {
+        code
+    } else {
+        1
+    }
+}
+
+fn read_words<const N: usize>(cpu: &mut Cpu, addr: u32) -> Result<[u32; N], Error> {
+    let mut words = [0; N];
+    for (index, word) in words.iter_mut().enumerate() {
+        let bytes = read_bytes(cpu, addr.wrapping_add(4 * index as u32), 4)?;
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    Ok(words)
+}
+
+fn read_bytes(cpu: &mut Cpu, addr: u32, len: u32) -> Result<Vec<u8>, Error> {
+    (0..len)
+        .map(|offset| read_byte(cpu, addr.wrapping_add(offset)))
+        .collect()
+}
+
+/// Byte at a program's address, where each byte is translated on its own so buffers can cross
+/// pages
+fn read_byte(cpu: &mut Cpu, addr: u32) -> Result<u8, Error> {
+    let physical = cpu.guest_address(addr, Access::Read)?;
+    let byte = cpu.read_memory(physical, 1).map_err(|_| EFAULT)?;
+    Ok(byte as u8)
+}
+
+/// errno to report for a host error
+fn errno(error: &io::Error) -> u32 {
+    error.raw_os_error().map_or(EINVAL, |errno| errno as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cpu::{tests::cpu_at_zero, Status},
+        trace::tests::Output,
+    };
+
+    const EBREAK: u32 = 0x00100073;
+
+    /// Core with a semihosting call at 0x4
+    fn semihosting_cpu() -> Cpu {
+        cpu_at_zero(&[ENTRY, EBREAK, EXIT])
+    }
+
+    fn call(cpu: &mut Cpu, op: u32, param: u32) -> u32 {
+        cpu.set_register(10, op);
+        cpu.set_register(11, param);
+        cpu.set_pc(0x4);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc(), 0x8);
+        cpu.register(10)
+    }
+
+    fn write_bytes(cpu: &mut Cpu, addr: u32, bytes: &[u8]) {
+        for (addr, byte) in (addr..).zip(bytes) {
+            cpu.write_memory(addr, *byte as u32, 1).unwrap();
+        }
+    }
+
+    fn write_words(cpu: &mut Cpu, addr: u32, words: &[u32]) {
+        for (addr, word) in (addr..).step_by(4).zip(words) {
+            cpu.write_memory(addr, *word, 4).unwrap();
+        }
+    }
+
+    #[test]
+    fn calls() {
+        let mut cpu = semihosting_cpu();
+        // without semihosting, the ebreak traps as usual
+        cpu.set_pc(0x4);
+        cpu.step().unwrap();
+        assert_eq!(cpu.csrs().mcause, 3);
+
+        let stdout = Output::default();
+        cpu.enable_semihosting(Semihosting::with_console(
+            Box::new(io::Cursor::new(b"input".to_vec())),
+            Box::new(stdout.clone()),
+            Box::new(io::sink()),
+        ));
+        write_bytes(&mut cpu, 0x100, b"hi\0");
+        assert_eq!(call(&mut cpu, SYS_WRITEC, 0x101), 0);
+        assert_eq!(call(&mut cpu, SYS_WRITE0, 0x100), 0);
+        assert_eq!(stdout.take(), "ihi");
+
+        // write a file and read it back
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let name = path.to_str().unwrap().as_bytes();
+        write_bytes(&mut cpu, 0x200, name);
+        write_bytes(&mut cpu, 0x300, b"data");
+        write_words(&mut cpu, 0x400, &[0x200, 4, name.len() as u32]);
+        let handle = call(&mut cpu, SYS_OPEN, 0x400);
+        assert_eq!(handle, 1);
+        write_words(&mut cpu, 0x410, &[handle, 0x300, 4]);
+        assert_eq!(call(&mut cpu, SYS_WRITE, 0x410), 0);
+        assert_eq!(call(&mut cpu, SYS_CLOSE, 0x410), 0);
+        assert_eq!(call(&mut cpu, SYS_CLOSE, 0x410), FAILED);
+        assert_eq!(call(&mut cpu, SYS_ERRNO, 0), EBADF);
+
+        write_words(&mut cpu, 0x400, &[0x200, 0, name.len() as u32]);
+        let handle = call(&mut cpu, SYS_OPEN, 0x400);
+        write_words(&mut cpu, 0x410, &[handle, 0x500, 8]);
+        assert_eq!(call(&mut cpu, SYS_READ, 0x410), 4);
+        assert_eq!(
+            cpu.read_memory(0x500, 4).unwrap(),
+            u32::from_le_bytes(*b"data")
+        );
+
+        // the console is opened as :tt
+        write_bytes(&mut cpu, 0x200, b":tt");
+        write_words(&mut cpu, 0x400, &[0x200, 0, 3]);
+        let handle = call(&mut cpu, SYS_OPEN, 0x400);
+        write_words(&mut cpu, 0x410, &[handle, 0x500, 3]);
+        assert_eq!(call(&mut cpu, SYS_READ, 0x410), 0);
+        assert_eq!(
+            cpu.read_memory(0x500, 4).unwrap(),
+            u32::from_le_bytes(*b"inpa")
+        );
+
+        assert_eq!(call(&mut cpu, SYS_CLOCK, 0), 0);
+        assert_eq!(call(&mut cpu, 0xFF, 0), FAILED);
+        assert_eq!(call(&mut cpu, SYS_ERRNO, 0), ENOSYS);
+        assert_eq!(cpu.status(), None);
+
+        write_words(&mut cpu, 0x410, &[ADP_STOPPED_APPLICATION_EXIT, 3]);
+        call(&mut cpu, SYS_EXIT_EXTENDED, 0x410);
+        assert_eq!(cpu.status(), Some(Status::Exited(3)));
+    }
+}
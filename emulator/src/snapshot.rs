@@ -0,0 +1,159 @@
+//! Saving and restoring the state of the machine
+//!
+//! A snapshot is a little-endian binary file: a magic number and format version, followed by
+//! the core, each peripheral and then memory. Only guest-visible state is saved, so models
+//! such as timing and the icache, compiled code, and host-side state like the uart backend
+//! carry on from wherever they were in the machine being restored into. The backend is told
+//! the cycle being restored to though, so that recorded input can be received again.
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, ensure, Context};
+
+const MAGIC: &[u8; 8] = b"ORKASNAP";
+/// Bumped whenever the layout changes, as old snapshots cannot be read
+const VERSION: u32 = 6;
+
+/// Serializes state in the snapshot format
+pub(crate) struct SnapshotWriter<'a> {
+    out: &'a mut dyn Write,
+}
+
+impl<'a> SnapshotWriter<'a> {
+    /// Start a snapshot by writing its header
+    pub fn new(out: &'a mut dyn Write) -> Result<Self, anyhow::Error> {
+        out.write_all(MAGIC)?;
+        let mut writer = Self { out };
+        writer.u32(VERSION)?;
+        Ok(writer)
+    }
+
+    pub fn u8(&mut self, value: u8) -> Result<(), anyhow::Error> {
+        self.bytes(&[value])
+    }
+
+    pub fn bool(&mut self, value: bool) -> Result<(), anyhow::Error> {
+        self.u8(value as u8)
+    }
+
+    pub fn u32(&mut self, value: u32) -> Result<(), anyhow::Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> Result<(), anyhow::Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    /// Write raw bytes, whose length the reader has to know
+    pub fn bytes(&mut self, bytes: &[u8]) -> Result<(), anyhow::Error> {
+        self.out
+            .write_all(bytes)
+            .context("could not write snapshot")
+    }
+}
+
+/// Deserializes state written by a [`SnapshotWriter`]
+pub(crate) struct SnapshotReader<'a> {
+    input: &'a mut dyn Read,
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Check the header of a snapshot, ready to read its contents
+    pub fn new(input: &'a mut dyn Read) -> Result<Self, anyhow::Error> {
+        let mut reader = Self { input };
+        let mut magic = [0; MAGIC.len()];
+        reader.bytes(&mut magic)?;
+        ensure!(&magic == MAGIC, "not a snapshot");
+        let version = reader.u32()?;
+        ensure!(
+            version == VERSION,
+            "snapshot is version {version}, but only version {VERSION} can be read"
+        );
+        Ok(reader)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, anyhow::Error> {
+        let mut bytes = [0];
+        self.bytes(&mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, anyhow::Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => bail!("invalid bool in snapshot: {value}"),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, anyhow::Error> {
+        let mut bytes = [0; 4];
+        self.bytes(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, anyhow::Error> {
+        let mut bytes = [0; 8];
+        self.bytes(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bytes(&mut self, bytes: &mut [u8]) -> Result<(), anyhow::Error> {
+        self.input
+            .read_exact(bytes)
+            .context("snapshot is truncated")
+    }
+
+    /// Check that nothing follows the state that was read
+    pub fn finish(self) -> Result<(), anyhow::Error> {
+        let mut extra = [0];
+        ensure!(
+            self.input.read(&mut extra)? == 0,
+            "snapshot has data after its end"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut out = Vec::new();
+        let mut writer = SnapshotWriter::new(&mut out).unwrap();
+        writer.u8(0xAB).unwrap();
+        writer.bool(true).unwrap();
+        writer.u32(0x12345678).unwrap();
+        writer.u64(u64::MAX - 1).unwrap();
+        writer.bytes(b"xyz").unwrap();
+
+        let mut input = out.as_slice();
+        let mut reader = SnapshotReader::new(&mut input).unwrap();
+        assert_eq!(reader.u8().unwrap(), 0xAB);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.u32().unwrap(), 0x12345678);
+        assert_eq!(reader.u64().unwrap(), u64::MAX - 1);
+        let mut bytes = [0; 3];
+        reader.bytes(&mut bytes).unwrap();
+        assert_eq!(&bytes, b"xyz");
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let mut input: &[u8] = b"ORKASNA";
+        assert!(SnapshotReader::new(&mut input).is_err());
+        let mut input: &[u8] = b"NOTASNAP\x01\0\0\0";
+        assert!(SnapshotReader::new(&mut input).is_err());
+        // snapshots from older versions lack newer state
+        let mut input: &[u8] = b"ORKASNAP\x05\0\0\0";
+        assert!(SnapshotReader::new(&mut input).is_err());
+
+        let mut input: &[u8] = b"ORKASNAP\x06\0\0\0\x02\0";
+        let mut reader = SnapshotReader::new(&mut input).unwrap();
+        assert!(reader.bool().is_err());
+        assert!(reader.u32().is_err());
+    }
+}
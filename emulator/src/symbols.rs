@@ -0,0 +1,112 @@
+//! Function symbols from the ELF, for attributing addresses to functions
+
+use elf::{endian::LittleEndian, ElfBytes};
+
+/// Function in the loaded program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    /// Demangled name, without the hash suffix
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+}
+
+impl Symbol {
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.addr) < self.size.max(1)
+    }
+}
+
+/// Function symbols sorted by address
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.addr);
+        Self { symbols }
+    }
+
+    /// Read the function symbols of an ELF, which is loaded `offset` bytes above its addresses
+    pub fn from_elf(elf: &ElfBytes<LittleEndian>, offset: u32) -> Result<Self, anyhow::Error> {
+        let Some((symbol_table, string_table)) = elf.symbol_table()? else {
+            return Ok(Self::default());
+        };
+
+        let mut symbols = Vec::new();
+        for symbol in symbol_table {
+            if symbol.st_symtype() != elf::abi::STT_FUNC || symbol.is_undefined() {
+                continue;
+            }
+            let name = string_table.get(symbol.st_name as usize)?;
+            symbols.push(Symbol {
+                name: format!("{:#}", rustc_demangle::demangle(name)),
+                addr: offset.wrapping_add(u32::try_from(symbol.st_value)?),
+                size: u32::try_from(symbol.st_size)?,
+            });
+        }
+        Ok(Self::new(symbols))
+    }
+
+    /// Function containing `addr`
+    pub fn lookup(&self, addr: u32) -> Option<&Symbol> {
+        let index = self
+            .symbols
+            .partition_point(|symbol| symbol.addr <= addr)
+            .checked_sub(1)?;
+        let symbol = &self.symbols[index];
+        symbol.contains(addr).then_some(symbol)
+    }
+
+    /// Name of the function containing `addr`, or a placeholder if it is unknown
+    pub fn name(&self, addr: u32) -> &str {
+        self.lookup(addr)
+            .map_or("<unknown>", |symbol| symbol.name.as_str())
+    }
+
+    /// Function with exactly the given demangled name
+    pub fn by_name(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, addr: u32, size: u32) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let symbols = Symbols::new(vec![
+            symbol("main", 0x2000, 0x100),
+            symbol("_start", 0x1000, 0x10),
+            symbol("helper", 0x2100, 0x20),
+        ]);
+        assert_eq!(symbols.lookup(0x1000).unwrap().name, "_start");
+        assert_eq!(symbols.lookup(0x100C).unwrap().name, "_start");
+        assert_eq!(symbols.lookup(0x1010), None);
+        assert_eq!(symbols.lookup(0x0FFF), None);
+        assert_eq!(symbols.lookup(0x20FC).unwrap().name, "main");
+        assert_eq!(symbols.lookup(0x2100).unwrap().name, "helper");
+        assert_eq!(symbols.name(0x3000), "<unknown>");
+        assert_eq!(symbols.by_name("helper").unwrap().addr, 0x2100);
+        assert_eq!(symbols.by_name("help"), None);
+    }
+}
@@ -0,0 +1,206 @@
+//! Cycle-approximate model of the multi-cycle `Cpu.vhd` state machine
+//!
+//! Each instruction passes through FETCH, DECODE and EXECUTE, then MEMORY for loads and stores
+//! and WRITEBACK if it writes a register. Exceptions end the instruction in the stage that
+//! raised them.
+
+use std::fmt;
+
+use crate::instructions::Instruction;
+
+/// Latencies of the pipeline and interconnect, in clock cycles
+///
+/// The defaults are estimates for the SoC with the passthrough instruction cache, and should be
+/// calibrated against simulation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingConfig {
+    /// Core clock frequency, used to convert cycles to time
+    pub clock_hz: u64,
+    /// Cycles for an instruction fetch that hits in the instruction cache
+    pub icache_hit_latency: u64,
+    /// Cycles for an access to RAM or ROM through the crossbar, including fetches that miss
+    pub memory_latency: u64,
+    /// Cycles for an access to a peripheral through the crossbar
+    pub mmio_latency: u64,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            clock_hz: 100_000_000,
+            icache_hit_latency: 1,
+            memory_latency: 3,
+            mmio_latency: 3,
+        }
+    }
+}
+
+/// Stage of the core's state machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Fetch,
+    Decode,
+    Execute,
+    Memory,
+    Writeback,
+}
+
+impl Stage {
+    pub const ALL: [Self; 5] = [
+        Self::Fetch,
+        Self::Decode,
+        Self::Execute,
+        Self::Memory,
+        Self::Writeback,
+    ];
+}
+
+/// Cycle counts accumulated by the timing model
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TimingStats {
+    pub cycles: u64,
+    pub instructions: u64,
+    pub traps: u64,
+    pub icache_hits: u64,
+    pub icache_misses: u64,
+    stage_cycles: [u64; Stage::ALL.len()],
+}
+
+impl TimingStats {
+    /// Cycles spent in a single stage
+    pub fn stage_cycles(&self, stage: Stage) -> u64 {
+        self.stage_cycles[stage as usize]
+    }
+}
+
+pub struct TimingModel {
+    config: TimingConfig,
+    stats: TimingStats,
+}
+
+impl TimingModel {
+    pub fn new(config: TimingConfig) -> Self {
+        Self {
+            config,
+            stats: TimingStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &TimingConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> &TimingStats {
+        &self.stats
+    }
+
+    /// Charge `cycles` to a stage, returning them for convenience
+    pub(crate) fn charge(&mut self, stage: Stage, cycles: u64) -> u64 {
+        self.stats.stage_cycles[stage as usize] += cycles;
+        self.stats.cycles += cycles;
+        cycles
+    }
+
+    /// Charge an instruction fetch, which goes to memory when it misses in the cache
+    pub(crate) fn fetch(&mut self, hit: bool, memory_latency: u64) -> u64 {
+        let latency = if hit {
+            self.stats.icache_hits += 1;
+            self.config.icache_hit_latency
+        } else {
+            self.stats.icache_misses += 1;
+            memory_latency
+        };
+        self.charge(Stage::Fetch, latency)
+    }
+
+    pub(crate) fn retire(&mut self) {
+        self.stats.instructions += 1;
+    }
+
+    pub(crate) fn trap(&mut self) {
+        self.stats.traps += 1;
+    }
+
+    /// Write a human-readable summary of the run
+    pub fn report(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        let stats = &self.stats;
+        let seconds = stats.cycles as f64 / self.config.clock_hz as f64;
+        writeln!(
+            out,
+            "cycles: {} ({seconds:.6} s at {} MHz)",
+            stats.cycles,
+            self.config.clock_hz as f64 / 1e6
+        )?;
+        writeln!(out, "instructions: {}", stats.instructions)?;
+        if stats.instructions > 0 {
+            writeln!(
+                out,
+                "cycles per instruction: {:.3}",
+                stats.cycles as f64 / stats.instructions as f64
+            )?;
+        }
+        writeln!(out, "traps: {}", stats.traps)?;
+        writeln!(
+            out,
+            "icache: {} hits, {} misses",
+            stats.icache_hits, stats.icache_misses
+        )?;
+        for stage in Stage::ALL {
+            let cycles = stats.stage_cycles(stage);
+            let share = 100.0 * cycles as f64 / stats.cycles.max(1) as f64;
+            writeln!(
+                out,
+                "  {:10} {cycles:>14} {share:5.1}%",
+                format!("{stage:?}")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether an instruction goes through the WRITEBACK stage
+///
+/// This depends only on the instruction type, so writes to x0 still take the cycle. The
+/// exception is csrrw/csrrwi, which skip the register read and writeback when rd is x0.
+pub fn writes_back(kind: Instruction, rd: u8) -> bool {
+    use Instruction::*;
+    match kind {
+        Sb | Sh | Sw | Beq | Bne | Blt | Bge | Bltu | Bgeu | Fence | FenceI | Ecall | Ebreak
+        | Mret | Sret | Wfi | SfenceVma => false,
+        Csrrw | Csrrwi => rd != 0,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fetch_latency() {
+        let mut model = TimingModel::new(TimingConfig::default());
+        assert_eq!(model.fetch(false, 5), 5);
+        assert_eq!(model.fetch(true, 5), 1);
+        assert_eq!(model.stats().icache_misses, 1);
+        assert_eq!(model.stats().icache_hits, 1);
+        assert_eq!(model.stats().stage_cycles(Stage::Fetch), 6);
+        assert_eq!(model.stats().cycles, 6);
+    }
+
+    #[test]
+    fn report() {
+        let mut model = TimingModel::new(TimingConfig::default());
+        model.fetch(false, 3);
+        model.charge(Stage::Decode, 1);
+        model.charge(Stage::Execute, 1);
+        model.retire();
+
+        let mut report = String::new();
+        model.report(&mut report).unwrap();
+        assert!(
+            report.contains("cycles: 5 (0.000000 s at 100 MHz)"),
+            "{report}"
+        );
+        assert!(report.contains("cycles per instruction: 5.000"), "{report}");
+    }
+}
@@ -0,0 +1,205 @@
+//! Fully associative TLB model, for sizing a TLB before the RTL has an MMU
+
+use std::fmt;
+
+use anyhow::ensure;
+
+use crate::{icache::AccessCounts, mmu::Translation};
+
+/// Whether a translation was for an instruction fetch or a load or store, which are counted
+/// separately as a design might split the TLB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlbAccess {
+    Fetch,
+    Data,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    /// Virtual page number, with the bits below a megapage cleared
+    vpn: u32,
+    level: u32,
+    asid: u32,
+    global: bool,
+    /// Access time, for least-recently-used replacement
+    last_used: u64,
+}
+
+impl Entry {
+    fn matches(&self, vaddr: u32, asid: u32) -> bool {
+        vpn(vaddr, self.level) == self.vpn && (self.global || self.asid == asid)
+    }
+}
+
+/// Page number of `vaddr` for a mapping at `level`
+fn vpn(vaddr: u32, level: u32) -> u32 {
+    (vaddr >> 12) & !((1 << (10 * level)) - 1)
+}
+
+pub struct Tlb {
+    capacity: usize,
+    entries: Vec<Entry>,
+    time: u64,
+    fetch: AccessCounts,
+    data: AccessCounts,
+    flushes: u64,
+}
+
+impl Tlb {
+    pub fn new(capacity: usize) -> Result<Self, anyhow::Error> {
+        ensure!(capacity > 0, "tlb must have at least one entry");
+        Ok(Self {
+            capacity,
+            entries: Vec::with_capacity(capacity),
+            time: 0,
+            fetch: AccessCounts::default(),
+            data: AccessCounts::default(),
+            flushes: 0,
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Look up a translation of `vaddr` that the page tables gave, filling an entry on a miss,
+    /// and return whether it hit
+    pub fn access(
+        &mut self,
+        vaddr: u32,
+        asid: u32,
+        translation: &Translation,
+        kind: TlbAccess,
+    ) -> bool {
+        self.time += 1;
+        let hit = match self.entries.iter_mut().find(|e| e.matches(vaddr, asid)) {
+            Some(entry) => {
+                entry.last_used = self.time;
+                true
+            }
+            None => {
+                let entry = Entry {
+                    vpn: vpn(vaddr, translation.level),
+                    level: translation.level,
+                    asid,
+                    global: translation.global,
+                    last_used: self.time,
+                };
+                if self.entries.len() < self.capacity {
+                    self.entries.push(entry);
+                } else {
+                    let victim = self.entries.iter_mut().min_by_key(|e| e.last_used);
+                    *victim.unwrap() = entry;
+                }
+                false
+            }
+        };
+
+        match kind {
+            TlbAccess::Fetch => &mut self.fetch,
+            TlbAccess::Data => &mut self.data,
+        }
+        .record(hit);
+        hit
+    }
+
+    /// Remove entries as sfence.vma does, limited to those mapping `vaddr` and to those of
+    /// address space `asid` if given
+    ///
+    /// Global entries are kept when only a single address space is flushed.
+    pub fn flush(&mut self, vaddr: Option<u32>, asid: Option<u32>) {
+        self.flushes += 1;
+        self.entries.retain(|entry| {
+            let page = vaddr.is_none_or(|vaddr| vpn(vaddr, entry.level) == entry.vpn);
+            let space = asid.is_none_or(|asid| !entry.global && entry.asid == asid);
+            !(page && space)
+        });
+    }
+
+    pub fn fetch_counts(&self) -> AccessCounts {
+        self.fetch
+    }
+
+    pub fn data_counts(&self) -> AccessCounts {
+        self.data
+    }
+
+    /// Write the hit rates of fetches and data accesses
+    pub fn report(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(
+            out,
+            "tlb ({} entries): {} flushes",
+            self.capacity, self.flushes
+        )?;
+        for (name, counts) in [("fetch", self.fetch), ("data", self.data)] {
+            writeln!(
+                out,
+                "  {name:5} {:>12} hits {:>12} misses {:>7.2}% hit rate",
+                counts.hits,
+                counts.misses,
+                100.0 * counts.hit_rate()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(level: u32, global: bool) -> Translation {
+        Translation {
+            physical: 0,
+            level,
+            global,
+            reads: 2 - level,
+        }
+    }
+
+    #[test]
+    fn hits_and_replacement() {
+        let mut tlb = Tlb::new(2).unwrap();
+        assert!(!tlb.access(0x1000, 0, &page(0, false), TlbAccess::Fetch));
+        assert!(tlb.access(0x1FFC, 0, &page(0, false), TlbAccess::Fetch));
+        // a megapage covers every page in it
+        assert!(!tlb.access(0x00400000, 0, &page(1, false), TlbAccess::Data));
+        assert!(tlb.access(0x007FF000, 0, &page(1, false), TlbAccess::Data));
+        // other address spaces miss, and evict the least recently used entry
+        assert!(!tlb.access(0x1000, 1, &page(0, false), TlbAccess::Data));
+        assert!(tlb.access(0x00400000, 0, &page(1, false), TlbAccess::Data));
+        assert!(!tlb.access(0x1000, 0, &page(0, false), TlbAccess::Fetch));
+
+        assert_eq!(tlb.fetch_counts(), AccessCounts { hits: 1, misses: 2 });
+        assert_eq!(tlb.data_counts(), AccessCounts { hits: 2, misses: 2 });
+    }
+
+    #[test]
+    fn flushes() {
+        let mut tlb = Tlb::new(4).unwrap();
+        let fill = |tlb: &mut Tlb| {
+            tlb.access(0x1000, 1, &page(0, false), TlbAccess::Data);
+            tlb.access(0x2000, 1, &page(0, false), TlbAccess::Data);
+            tlb.access(0x3000, 2, &page(0, false), TlbAccess::Data);
+            tlb.access(0x4000, 2, &page(0, true), TlbAccess::Data);
+        };
+        let cached = |tlb: &mut Tlb| {
+            [(0x1000, 1), (0x2000, 1), (0x3000, 2), (0x4000, 1)]
+                .map(|(vaddr, asid)| tlb.access(vaddr, asid, &page(0, false), TlbAccess::Data))
+        };
+
+        fill(&mut tlb);
+        tlb.flush(Some(0x1234), None);
+        assert_eq!(cached(&mut tlb), [false, true, true, true]);
+
+        let mut tlb = Tlb::new(4).unwrap();
+        fill(&mut tlb);
+        tlb.flush(None, Some(2));
+        assert_eq!(cached(&mut tlb), [true, true, false, true]);
+
+        let mut tlb = Tlb::new(4).unwrap();
+        fill(&mut tlb);
+        tlb.flush(None, None);
+        assert_eq!(cached(&mut tlb), [false; 4]);
+    }
+}
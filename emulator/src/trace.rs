@@ -0,0 +1,415 @@
+//! Execution trace, with symbolized addresses, register changes and memory accesses
+
+use std::{fmt::Write as _, io, ops::Range};
+
+use anyhow::anyhow;
+
+use crate::{instructions::DecodedInstruction, symbols::Symbols, trap::Trap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// A line of text for each instruction
+    Text,
+    /// A JSON object on each line
+    JsonLines,
+}
+
+/// Instructions to trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceFilter {
+    Range(Range<u32>),
+    /// Every instruction in the named function
+    Function(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Load,
+    Store,
+}
+
+impl AccessKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Load => "load",
+            Self::Store => "store",
+        }
+    }
+}
+
+/// Data access made by a traced instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub addr: u32,
+    /// Width in bytes
+    pub size: u32,
+    pub value: u32,
+}
+
+/// Instruction being traced, until it retires or traps
+struct Pending {
+    pc: u32,
+    cycle: u64,
+    registers: [u32; 32],
+    accesses: Vec<MemoryAccess>,
+}
+
+pub struct Tracer {
+    out: Box<dyn io::Write + Send>,
+    format: TraceFormat,
+    enabled: bool,
+    /// Addresses to trace, or everything if empty
+    ranges: Vec<Range<u32>>,
+    pending: Option<Pending>,
+}
+
+impl Tracer {
+    /// Trace instructions matching any of `filters`, or every instruction if there are none
+    pub fn new(
+        out: Box<dyn io::Write + Send>,
+        format: TraceFormat,
+        filters: &[TraceFilter],
+        symbols: &Symbols,
+    ) -> Result<Self, anyhow::Error> {
+        let ranges = filters
+            .iter()
+            .map(|filter| match filter {
+                TraceFilter::Range(range) => Ok(range.clone()),
+                TraceFilter::Function(name) => symbols
+                    .by_name(name)
+                    .map(|symbol| symbol.addr..symbol.addr.wrapping_add(symbol.size.max(1)))
+                    .ok_or_else(|| anyhow!("no function named {name} to trace")),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            out,
+            format,
+            enabled: true,
+            ranges,
+            pending: None,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Pause or resume tracing
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Whether the instruction at `pc` would be traced
+    pub fn traces(&self, pc: u32) -> bool {
+        self.enabled
+            && (self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc)))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Start tracing the instruction at `pc`, if it passes the filters
+    pub(crate) fn begin(&mut self, pc: u32, cycle: u64, registers: &[u32; 32]) {
+        self.pending = self.traces(pc).then(|| Pending {
+            pc,
+            cycle,
+            registers: *registers,
+            accesses: Vec::new(),
+        });
+    }
+
+    pub(crate) fn access(&mut self, access: MemoryAccess) {
+        if let Some(pending) = &mut self.pending {
+            pending.accesses.push(access);
+        }
+    }
+
+    /// Drop the current instruction, as it stalled without retiring
+    pub(crate) fn stall(&mut self) {
+        self.pending = None;
+    }
+
+    pub(crate) fn retire(
+        &mut self,
+        inst: &DecodedInstruction,
+        registers: &[u32; 32],
+        symbols: &Symbols,
+    ) -> io::Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+        let changes: Vec<_> = (1..32)
+            .filter(|index| pending.registers[*index] != registers[*index])
+            .map(|index| (index, pending.registers[index], registers[index]))
+            .collect();
+        let mnemonic = format!("{:?}", inst.kind).to_lowercase();
+
+        let mut line = String::new();
+        match self.format {
+            TraceFormat::Text => {
+                write!(
+                    line,
+                    "{:08X} {} {mnemonic} (imm = {:08X}, rd = {}, rs1 = {}, rs2 = {})",
+                    pending.pc,
+                    location(pending.pc, symbols),
+                    inst.immediate,
+                    inst.rd,
+                    inst.rs1,
+                    inst.rs2
+                )
+                .unwrap();
+                for (index, before, after) in changes {
+                    write!(line, "; x{index} {before:08X} -> {after:08X}").unwrap();
+                }
+                for access in pending.accesses {
+                    write!(
+                        line,
+                        "; {} {:08X} ({} bytes) = {:08X}",
+                        access.kind.name(),
+                        access.addr,
+                        access.size,
+                        access.value
+                    )
+                    .unwrap();
+                }
+            }
+            TraceFormat::JsonLines => {
+                write!(
+                    line,
+                    r#"{{"cycle":{},"pc":{},{},"raw":{},"instruction":"{mnemonic}","imm":{},"rd":{},"rs1":{},"rs2":{}"#,
+                    pending.cycle,
+                    pending.pc,
+                    json_location(pending.pc, symbols),
+                    inst.raw,
+                    inst.immediate,
+                    inst.rd,
+                    inst.rs1,
+                    inst.rs2
+                )
+                .unwrap();
+                let changes: Vec<_> = changes
+                    .into_iter()
+                    .map(|(index, before, after)| {
+                        format!(r#"{{"register":{index},"before":{before},"after":{after}}}"#)
+                    })
+                    .collect();
+                let accesses: Vec<_> = pending
+                    .accesses
+                    .into_iter()
+                    .map(|access| {
+                        format!(
+                            r#"{{"access":"{}","addr":{},"size":{},"value":{}}}"#,
+                            access.kind.name(),
+                            access.addr,
+                            access.size,
+                            access.value
+                        )
+                    })
+                    .collect();
+                write!(
+                    line,
+                    r#","registers":[{}],"memory":[{}]}}"#,
+                    changes.join(","),
+                    accesses.join(",")
+                )
+                .unwrap();
+            }
+        }
+        writeln!(self.out, "{line}")
+    }
+
+    /// Finish the current instruction with a trap into the handler at `handler`
+    pub(crate) fn trap(&mut self, trap: Trap, handler: u32, symbols: &Symbols) -> io::Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+        match self.format {
+            TraceFormat::Text => writeln!(
+                self.out,
+                "{:08X} {} trap {trap:?} -> {handler:08X} {}",
+                pending.pc,
+                location(pending.pc, symbols),
+                location(handler, symbols)
+            ),
+            TraceFormat::JsonLines => writeln!(
+                self.out,
+                r#"{{"cycle":{},"pc":{},{},"trap":"{trap:?}","cause":{},"value":{},"handler":{handler}}}"#,
+                pending.cycle,
+                pending.pc,
+                json_location(pending.pc, symbols),
+                trap.cause(),
+                trap.value()
+            ),
+        }
+    }
+}
+
+/// `<function+offset>`, or `<unknown>` outside any function
+fn location(addr: u32, symbols: &Symbols) -> String {
+    match symbols.lookup(addr) {
+        Some(symbol) => format!("<{}+{:#x}>", symbol.name, addr - symbol.addr),
+        None => "<unknown>".to_string(),
+    }
+}
+
+fn json_location(addr: u32, symbols: &Symbols) -> String {
+    match symbols.lookup(addr) {
+        Some(symbol) => format!(
+            r#""function":"{}","offset":{}"#,
+            json_escape(&symbol.name),
+            addr - symbol.addr
+        ),
+        None => r#""function":null,"offset":null"#.to_string(),
+    }
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{symbols::Symbol, trap::Exception};
+
+    /// Output that can still be read once the tracer owns it
+    #[derive(Clone, Default)]
+    pub(crate) struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        pub(crate) fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    fn symbols() -> Symbols {
+        Symbols::new(vec![Symbol {
+            name: "main".to_string(),
+            addr: 0x1000,
+            size: 0x20,
+        }])
+    }
+
+    fn tracer(format: TraceFormat, filters: &[TraceFilter]) -> (Tracer, Output) {
+        let output = Output::default();
+        let tracer = Tracer::new(Box::new(output.clone()), format, filters, &symbols()).unwrap();
+        (tracer, output)
+    }
+
+    /// Trace a load of 0x2A into x3 at `pc`
+    fn load(tracer: &mut Tracer, pc: u32) {
+        let mut registers = [0; 32];
+        tracer.begin(pc, 7, &registers);
+        tracer.access(MemoryAccess {
+            kind: AccessKind::Load,
+            addr: 0x2000,
+            size: 4,
+            value: 0x2A,
+        });
+        registers[3] = 0x2A;
+        let lw = DecodedInstruction::try_from(0x00012183).unwrap();
+        tracer.retire(&lw, &registers, &symbols()).unwrap();
+    }
+
+    #[test]
+    fn text() {
+        let (mut tracer, output) = tracer(TraceFormat::Text, &[]);
+        load(&mut tracer, 0x1008);
+        assert_eq!(
+            output.take(),
+            "00001008 <main+0x8> lw (imm = 00000000, rd = 3, rs1 = 2, rs2 = 0); \
+            x3 00000000 -> 0000002A; load 00002000 (4 bytes) = 0000002A\n"
+        );
+
+        tracer.begin(0x3000, 8, &[0; 32]);
+        tracer
+            .trap(Exception::IllegalInstruction(0).into(), 0x1010, &symbols())
+            .unwrap();
+        assert_eq!(
+            output.take(),
+            "00003000 <unknown> trap Exception(IllegalInstruction(0)) -> 00001010 <main+0x10>\n"
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        let (mut tracer, output) = tracer(TraceFormat::JsonLines, &[]);
+        load(&mut tracer, 0x1008);
+        assert_eq!(
+            output.take(),
+            r#"{"cycle":7,"pc":4104,"function":"main","offset":8,"raw":74115,"instruction":"lw","imm":0,"rd":3,"rs1":2,"rs2":0,"registers":[{"register":3,"before":0,"after":42}],"memory":[{"access":"load","addr":8192,"size":4,"value":42}]}"#
+                .to_string()
+                + "\n"
+        );
+
+        tracer.begin(0x3000, 8, &[0; 32]);
+        tracer
+            .trap(Exception::Breakpoint(0x3000).into(), 0x1010, &symbols())
+            .unwrap();
+        assert_eq!(
+            output.take(),
+            r#"{"cycle":8,"pc":12288,"function":null,"offset":null,"trap":"Exception(Breakpoint(12288))","cause":3,"value":12288,"handler":4112}"#
+                .to_string()
+                + "\n"
+        );
+        assert_eq!(json_escape("a\"b\\\n"), r#"a\"b\\\u000a"#);
+    }
+
+    #[test]
+    fn filters() {
+        let (mut tracer, output) = tracer(
+            TraceFormat::Text,
+            &[
+                TraceFilter::Range(0x4000..0x4010),
+                TraceFilter::Function("main".to_string()),
+            ],
+        );
+        assert!(tracer.traces(0x1000));
+        assert!(tracer.traces(0x101C));
+        assert!(!tracer.traces(0x1020));
+        assert!(tracer.traces(0x400C));
+        assert!(!tracer.traces(0x4010));
+
+        load(&mut tracer, 0x2000);
+        assert_eq!(output.take(), "");
+
+        tracer.set_enabled(false);
+        load(&mut tracer, 0x1000);
+        assert_eq!(output.take(), "");
+        tracer.set_enabled(true);
+        load(&mut tracer, 0x1000);
+        assert!(output.take().starts_with("00001000 <main+0x0> lw"));
+
+        let unknown = Tracer::new(
+            Box::new(io::sink()),
+            TraceFormat::Text,
+            &[TraceFilter::Function("missing".to_string())],
+            &symbols(),
+        );
+        assert!(unknown.is_err());
+    }
+}
@@ -0,0 +1,135 @@
+/// Synchronous exception, along with the value written to mtval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u32),
+    InstructionAccessFault(u32),
+    IllegalInstruction(u32),
+    Breakpoint(u32),
+    LoadAddressMisaligned(u32),
+    LoadAccessFault(u32),
+    StoreAddressMisaligned(u32),
+    StoreAccessFault(u32),
+    EnvironmentCallFromU,
+    EnvironmentCallFromS,
+    EnvironmentCallFromM,
+    InstructionPageFault(u32),
+    LoadPageFault(u32),
+    StorePageFault(u32),
+}
+
+impl Exception {
+    /// Exception code written to mcause
+    pub fn cause(&self) -> u32 {
+        match self {
+            Self::InstructionAddressMisaligned(_) => 0,
+            Self::InstructionAccessFault(_) => 1,
+            Self::IllegalInstruction(_) => 2,
+            Self::Breakpoint(_) => 3,
+            Self::LoadAddressMisaligned(_) => 4,
+            Self::LoadAccessFault(_) => 5,
+            Self::StoreAddressMisaligned(_) => 6,
+            Self::StoreAccessFault(_) => 7,
+            Self::EnvironmentCallFromU => 8,
+            Self::EnvironmentCallFromS => 9,
+            Self::EnvironmentCallFromM => 11,
+            Self::InstructionPageFault(_) => 12,
+            Self::LoadPageFault(_) => 13,
+            Self::StorePageFault(_) => 15,
+        }
+    }
+
+    /// Value written to mtval
+    pub fn value(&self) -> u32 {
+        match *self {
+            Self::InstructionAddressMisaligned(value)
+            | Self::InstructionAccessFault(value)
+            | Self::IllegalInstruction(value)
+            | Self::Breakpoint(value)
+            | Self::LoadAddressMisaligned(value)
+            | Self::LoadAccessFault(value)
+            | Self::StoreAddressMisaligned(value)
+            | Self::StoreAccessFault(value)
+            | Self::InstructionPageFault(value)
+            | Self::LoadPageFault(value)
+            | Self::StorePageFault(value) => value,
+            Self::EnvironmentCallFromU | Self::EnvironmentCallFromS | Self::EnvironmentCallFromM => 0,
+        }
+    }
+}
+
+/// Interrupt, in order of priority (lowest first)
+///
+/// Machine interrupts are in the order the RTL prioritises them, and supervisor interrupts,
+/// which only exist in the emulator, are below them in the standard order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorTimer,
+    SupervisorSoftware,
+    SupervisorExternal,
+    MachineSoftware,
+    MachineTimer,
+    MachineExternal,
+}
+
+impl Interrupt {
+    pub const ALL: [Self; 6] = [
+        Self::SupervisorTimer,
+        Self::SupervisorSoftware,
+        Self::SupervisorExternal,
+        Self::MachineSoftware,
+        Self::MachineTimer,
+        Self::MachineExternal,
+    ];
+
+    /// Interrupt code, which is also its bit in mip and mie
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::SupervisorSoftware => 1,
+            Self::MachineSoftware => 3,
+            Self::SupervisorTimer => 5,
+            Self::MachineTimer => 7,
+            Self::SupervisorExternal => 9,
+            Self::MachineExternal => 11,
+        }
+    }
+
+    /// Value written to mcause, with the interrupt bit set
+    pub fn cause(&self) -> u32 {
+        1 << 31 | self.code()
+    }
+}
+
+/// Reason for entering the trap handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    Exception(Exception),
+    Interrupt(Interrupt),
+}
+
+impl Trap {
+    pub fn cause(&self) -> u32 {
+        match self {
+            Self::Exception(exception) => exception.cause(),
+            Self::Interrupt(interrupt) => interrupt.cause(),
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        match self {
+            Self::Exception(exception) => exception.value(),
+            Self::Interrupt(_) => 0,
+        }
+    }
+}
+
+impl From<Exception> for Trap {
+    fn from(exception: Exception) -> Self {
+        Self::Exception(exception)
+    }
+}
+
+impl From<Interrupt> for Trap {
+    fn from(interrupt: Interrupt) -> Self {
+        Self::Interrupt(interrupt)
+    }
+}
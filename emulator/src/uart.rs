@@ -0,0 +1,345 @@
+//! UART, matching `shared/peripherals/uart/hdl/registers.rdl`
+//!
+//! Bytes are passed to and from the host through a [`UartBackend`]. Like the RTL, the
+//! transmitter is always ready, and there is a single byte of receive buffering.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, TryRecvError},
+};
+
+use anyhow::{bail, Context};
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+pub const UART_BASE: u32 = 0x20020000;
+const UART_SIZE: u32 = 0x10;
+
+const RX: u32 = 0x0;
+const TX: u32 = 0x4;
+const CTRL: u32 = 0x8;
+const STATUS: u32 = 0xC;
+
+const CTRL_RXIE: u32 = 1 << 0;
+const CTRL_TXIE: u32 = 1 << 1;
+const STATUS_RXR: u32 = 1 << 0;
+const STATUS_TXE: u32 = 1 << 1;
+
+/// Host side of the UART
+#[allow(unused_variables)]
+pub trait UartBackend {
+    fn transmit(&mut self, byte: u8) -> Result<(), anyhow::Error>;
+
+    /// Next byte received from the host, if one has arrived by `cycle`
+    fn receive(&mut self, cycle: u64) -> Option<u8>;
+
+    /// Whether more bytes may still be received, so waiting for them is not a hang
+    fn can_receive(&self) -> bool;
+
+    /// Called when the machine is restored to `cycle`, so the bytes received since can be
+    /// received again
+    fn rewind(&mut self, cycle: u64) {}
+}
+
+/// Discards transmitted bytes and never receives any
+pub struct NullBackend;
+
+impl UartBackend for NullBackend {
+    fn transmit(&mut self, _byte: u8) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    fn receive(&mut self, _cycle: u64) -> Option<u8> {
+        None
+    }
+
+    fn can_receive(&self) -> bool {
+        false
+    }
+}
+
+/// Transmits to stdout and receives from stdin
+pub struct StdioBackend {
+    received: Receiver<u8>,
+    open: bool,
+}
+
+impl StdioBackend {
+    /// Start reading stdin on another thread, so the program is not blocked waiting for input
+    pub fn new() -> Self {
+        let (sender, received) = mpsc::channel();
+        std::thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                let Ok(byte) = byte else {
+                    break;
+                };
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            received,
+            open: true,
+        }
+    }
+}
+
+impl Default for StdioBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UartBackend for StdioBackend {
+    fn transmit(&mut self, byte: u8) -> Result<(), anyhow::Error> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&[byte])?;
+        if byte == b'\n' {
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, _cycle: u64) -> Option<u8> {
+        match self.received.try_recv() {
+            Ok(byte) => Some(byte),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.open = false;
+                None
+            }
+        }
+    }
+
+    fn can_receive(&self) -> bool {
+        self.open
+    }
+}
+
+/// Transmits to a file and never receives
+pub struct FileBackend {
+    out: BufWriter<File>,
+}
+
+impl FileBackend {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let file = File::create(path).context("could not create uart output")?;
+        Ok(Self {
+            out: BufWriter::new(file),
+        })
+    }
+}
+
+impl UartBackend for FileBackend {
+    fn transmit(&mut self, byte: u8) -> Result<(), anyhow::Error> {
+        self.out.write_all(&[byte])?;
+        Ok(())
+    }
+
+    fn receive(&mut self, _cycle: u64) -> Option<u8> {
+        None
+    }
+
+    fn can_receive(&self) -> bool {
+        false
+    }
+}
+
+pub struct Uart {
+    base: u32,
+    ctrl: u32,
+    /// Received byte that has not been read yet
+    rx: Option<u8>,
+    backend: Box<dyn UartBackend>,
+}
+
+impl Uart {
+    pub fn new(base: u32, backend: Box<dyn UartBackend>) -> Self {
+        Self {
+            base,
+            ctrl: 0,
+            rx: None,
+            backend,
+        }
+    }
+
+    pub fn set_backend(&mut self, backend: Box<dyn UartBackend>) {
+        self.backend = backend;
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < UART_SIZE
+    }
+
+    /// Address of a register, by its name in the register map
+    pub fn register(&self, name: &str) -> Option<u32> {
+        let offset = match name {
+            "rx" => RX,
+            "tx" => TX,
+            "ctrl" => CTRL,
+            "status" => STATUS,
+            _ => return None,
+        };
+        Some(self.base + offset)
+    }
+
+    pub fn read(&mut self, addr: u32) -> Result<u32, anyhow::Error> {
+        Ok(match (addr - self.base) & !0b11 {
+            // reading the buffer frees it for the next byte
+            RX => self.rx.take().unwrap_or_default() as u32,
+            TX => 0,
+            CTRL => self.ctrl,
+            STATUS => STATUS_TXE | if self.rx.is_some() { STATUS_RXR } else { 0 },
+            _ => bail!("invalid uart read address: {addr:08X}"),
+        })
+    }
+
+    /// Write the bits of a register selected by `mask`, as byte enables do, where `value` is
+    /// zero outside them
+    pub fn write(&mut self, addr: u32, value: u32, mask: u32) -> Result<(), anyhow::Error> {
+        match (addr - self.base) & !0b11 {
+            TX => self.backend.transmit(value as u8)?,
+            CTRL => self.ctrl = (self.ctrl & !mask | value) & (CTRL_RXIE | CTRL_TXIE),
+            // rx and status are read-only
+            RX | STATUS => {}
+            _ => bail!("invalid uart write address: {addr:08X}"),
+        }
+        Ok(())
+    }
+
+    /// Save the registers, leaving out the backend as it belongs to the host
+    pub(crate) fn save(&self, out: &mut SnapshotWriter) -> Result<(), anyhow::Error> {
+        out.u32(self.ctrl)?;
+        out.bool(self.rx.is_some())?;
+        out.u8(self.rx.unwrap_or_default())
+    }
+
+    pub(crate) fn restore(&mut self, input: &mut SnapshotReader) -> Result<(), anyhow::Error> {
+        self.ctrl = input.u32()?;
+        let received = input.bool()?;
+        let rx = input.u8()?;
+        self.rx = received.then_some(rx);
+        Ok(())
+    }
+
+    /// Move the next byte from the backend into the receive buffer, if it is free
+    pub fn poll(&mut self, cycle: u64) {
+        if self.rx.is_none() {
+            self.rx = self.backend.receive(cycle);
+        }
+    }
+
+    /// Transmit a byte for another console sharing the backend, such as HTIF
+    pub(crate) fn console_write(&mut self, byte: u8) -> Result<(), anyhow::Error> {
+        self.backend.transmit(byte)
+    }
+
+    /// Receive a byte for another console sharing the backend, including one already in the
+    /// receive buffer
+    pub(crate) fn console_read(&mut self, cycle: u64) -> Option<u8> {
+        self.poll(cycle);
+        self.rx.take()
+    }
+
+    /// Let the backend know the machine was restored to `cycle`
+    pub fn rewind(&mut self, cycle: u64) {
+        self.backend.rewind(cycle);
+    }
+
+    /// Interrupt line, raised while an enabled status flag is set
+    pub fn interrupt(&self) -> bool {
+        (self.ctrl & CTRL_RXIE != 0 && self.rx.is_some()) || self.ctrl & CTRL_TXIE != 0
+    }
+
+    /// Whether the interrupt line is raised or may be in future without any more writes
+    pub fn can_interrupt(&self) -> bool {
+        self.interrupt() || (self.ctrl & CTRL_RXIE != 0 && self.backend.can_receive())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    use super::*;
+
+    /// Backend with bytes queued up to receive, recording those transmitted
+    #[derive(Default)]
+    pub(crate) struct Loopback {
+        pub(crate) received: VecDeque<u8>,
+        pub(crate) transmitted: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl UartBackend for Loopback {
+        fn transmit(&mut self, byte: u8) -> Result<(), anyhow::Error> {
+            self.transmitted.borrow_mut().push(byte);
+            Ok(())
+        }
+
+        fn receive(&mut self, _cycle: u64) -> Option<u8> {
+            self.received.pop_front()
+        }
+
+        fn can_receive(&self) -> bool {
+            !self.received.is_empty()
+        }
+    }
+
+    #[test]
+    fn transmit_and_receive() {
+        let transmitted = Rc::new(RefCell::new(Vec::new()));
+        let mut uart = Uart::new(
+            UART_BASE,
+            Box::new(Loopback {
+                received: VecDeque::from([b'a', b'b']),
+                transmitted: transmitted.clone(),
+            }),
+        );
+
+        uart.write(UART_BASE + TX, 0x121, u32::MAX).unwrap();
+        assert_eq!(*transmitted.borrow(), b"!");
+
+        // nothing is received until the backend is polled
+        assert_eq!(uart.read(UART_BASE + STATUS).unwrap(), STATUS_TXE);
+        uart.poll(0);
+        assert_eq!(
+            uart.read(UART_BASE + STATUS).unwrap(),
+            STATUS_TXE | STATUS_RXR
+        );
+        uart.poll(0);
+        assert_eq!(uart.read(UART_BASE + RX).unwrap(), b'a' as u32);
+        assert_eq!(uart.read(UART_BASE + STATUS).unwrap(), STATUS_TXE);
+        uart.poll(0);
+        assert_eq!(uart.read(UART_BASE + RX).unwrap(), b'b' as u32);
+        assert!(uart.read(UART_BASE + 0x10).is_err());
+    }
+
+    #[test]
+    fn interrupts() {
+        let mut uart = Uart::new(
+            UART_BASE,
+            Box::new(Loopback {
+                received: VecDeque::from([b'a']),
+                ..Loopback::default()
+            }),
+        );
+        assert!(!uart.interrupt());
+
+        uart.write(UART_BASE + CTRL, CTRL_RXIE, u32::MAX).unwrap();
+        assert!(!uart.interrupt());
+        assert!(uart.can_interrupt());
+        uart.poll(0);
+        assert!(uart.interrupt());
+        uart.read(UART_BASE + RX).unwrap();
+        assert!(!uart.interrupt());
+        assert!(!uart.can_interrupt());
+
+        // the transmitter is always empty
+        uart.write(UART_BASE + CTRL, CTRL_TXIE, u32::MAX).unwrap();
+        assert!(uart.interrupt());
+        assert_eq!(uart.read(UART_BASE + CTRL).unwrap(), CTRL_TXIE);
+    }
+}
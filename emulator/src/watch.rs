@@ -0,0 +1,117 @@
+//! Watchpoints, which stop a run when memory or a peripheral register is accessed
+
+use std::{ops::Range, str::FromStr};
+
+use anyhow::bail;
+
+use crate::trace::{AccessKind, MemoryAccess};
+
+/// Accesses that trigger a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Either a read or a write
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, kind: AccessKind) -> bool {
+        match self {
+            Self::Read => kind == AccessKind::Load,
+            Self::Write => kind == AccessKind::Store,
+            Self::Access => true,
+        }
+    }
+}
+
+impl FromStr for WatchKind {
+    type Err = anyhow::Error;
+
+    /// Parse `r`, `w` or `rw`
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "r" => Ok(Self::Read),
+            "w" => Ok(Self::Write),
+            "rw" => Ok(Self::Access),
+            _ => bail!("watchpoint kind must be r, w or rw: {kind}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: Range<u32>,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    /// Whether `access` touches any byte of the range in a way that is watched
+    pub fn matches(&self, access: &MemoryAccess) -> bool {
+        let start = access.addr as u64;
+        let end = start + access.size as u64;
+        self.kind.matches(access.kind)
+            && start < self.range.end as u64
+            && (self.range.start as u64) < end
+    }
+
+    /// Report an access that matches, made by the instruction at `pc`
+    pub(crate) fn hit(&self, pc: u32, access: MemoryAccess) -> WatchHit {
+        WatchHit {
+            pc,
+            access,
+            addr: access.addr.max(self.range.start),
+            kind: self.kind,
+        }
+    }
+}
+
+/// Access that triggered a watchpoint, which completes before the run stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Address of the instruction that made the access
+    pub pc: u32,
+    pub access: MemoryAccess,
+    /// First byte of the access that is watched
+    pub addr: u32,
+    /// Kind of the watchpoint that was triggered
+    pub kind: WatchKind,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches() {
+        let access = |kind, addr, size| MemoryAccess {
+            kind,
+            addr,
+            size,
+            value: 0,
+        };
+        let watchpoint = Watchpoint {
+            range: 0x100..0x104,
+            kind: WatchKind::Write,
+        };
+        assert!(watchpoint.matches(&access(AccessKind::Store, 0x100, 4)));
+        assert!(watchpoint.matches(&access(AccessKind::Store, 0x103, 1)));
+        assert!(watchpoint.matches(&access(AccessKind::Store, 0xFE, 4)));
+        assert_eq!(
+            watchpoint
+                .hit(0x40, access(AccessKind::Store, 0xFE, 4))
+                .addr,
+            0x100
+        );
+        assert!(!watchpoint.matches(&access(AccessKind::Store, 0x104, 4)));
+        assert!(!watchpoint.matches(&access(AccessKind::Store, 0xFC, 4)));
+        assert!(!watchpoint.matches(&access(AccessKind::Load, 0x100, 4)));
+
+        let watchpoint = Watchpoint {
+            range: 0xFFFFFFFC..0xFFFFFFFF,
+            kind: WatchKind::Access,
+        };
+        assert!(watchpoint.matches(&access(AccessKind::Load, 0xFFFFFFFC, 4)));
+        assert!(watchpoint.matches(&access(AccessKind::Store, 0xFFFFFFFE, 1)));
+    }
+}
@@ -1,17 +1,71 @@
+//! Debug peripheral, which only exists in RTL simulation and the emulator
+//!
+//! On hardware its address reads as 0 and ignores writes, so `platform()` returns `None` there
+//! and the other registers do nothing.
+
 const BASE_ADDR: *mut u32 = 0x1000_0000 as _;
 
 const REG_PASS: usize = 0x0;
 const REG_FAIL: usize = 0x4;
+const REG_EXIT: usize = 0x8;
+const REG_CONSOLE: usize = 0xC;
+const REG_ID: usize = 0x10;
+
+/// Upper bytes of the id register, "ORK"
+const ID_MAGIC: u32 = 0x4F52_4B00;
+
+/// Where the program is running, as reported by the id register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Simulation,
+    Emulator,
+}
 
 pub fn set_pass() -> ! {
     unsafe { BASE_ADDR.byte_add(REG_PASS).write_volatile(0) };
-    loop {
-        riscv::asm::nop();
-    }
+    halt()
 }
 
 pub fn set_fail() -> ! {
     unsafe { BASE_ADDR.byte_add(REG_FAIL).write_volatile(0) };
+    halt()
+}
+
+/// Exit with `code`, where 0 passes and anything else fails
+pub fn exit(code: u32) -> ! {
+    unsafe { BASE_ADDR.byte_add(REG_EXIT).write_volatile(code) };
+    halt()
+}
+
+/// Print a byte on the host's console, which is much faster than the uart in simulation
+pub fn write_byte(byte: u8) {
+    unsafe { BASE_ADDR.byte_add(REG_CONSOLE).write_volatile(byte as u32) };
+}
+
+/// Read the id register, returning `None` if it does not name a known platform, as on hardware
+pub fn platform() -> Option<Platform> {
+    let id = unsafe { BASE_ADDR.byte_add(REG_ID).read_volatile() };
+    match id {
+        _ if id & !0xFF != ID_MAGIC => None,
+        0x4F52_4B01 => Some(Platform::Simulation),
+        0x4F52_4B02 => Some(Platform::Emulator),
+        _ => None,
+    }
+}
+
+/// Host console, for use with `write!`
+pub struct Console;
+
+impl core::fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+fn halt() -> ! {
     loop {
         riscv::asm::nop();
     }
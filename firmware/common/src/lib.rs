@@ -6,6 +6,7 @@ use heapless::String;
 
 pub mod debug;
 pub mod gpio;
+pub mod plic;
 pub mod reg;
 pub mod uart;
 
@@ -0,0 +1,91 @@
+//! Platform-level interrupt controller, which only exists in the emulator (`--plic`) so far
+//!
+//! The layout is the standard PLIC's, except that each context's threshold and claim
+//! registers start at 0x8000 to fit in a 64 KiB peripheral slot. Context 0 drives the machine
+//! external interrupt, and the UART is source 1 by default.
+
+use crate::reg::{RW, Reg};
+use core::num::NonZeroU32;
+
+pub const PLIC_ADDR: usize = 0x2003_0000;
+
+/// Source the emulator connects the UART to by default
+pub const UART_SOURCE: u32 = 1;
+
+const REG_PRIORITY: usize = 0x0;
+const REG_PENDING: usize = 0x1000;
+const REG_ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const REG_THRESHOLD: usize = 0x8000;
+const REG_CLAIM: usize = 0x8004;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+#[derive(Clone, Copy)]
+pub struct Plic {
+    ptr: *mut (),
+}
+
+unsafe impl Send for Plic {}
+
+impl Plic {
+    /// Claim an address as a PLIC peripheral instance
+    ///
+    /// # Safety
+    /// Base address must be valid and only claimed once.
+    pub const unsafe fn from_ptr(ptr: *mut ()) -> Self {
+        Self { ptr }
+    }
+
+    /// Priority of a source, from 0 (never interrupts) to 7
+    pub fn priority(self, source: u32) -> Reg<u32, RW> {
+        let offset = REG_PRIORITY + 4 * source as usize;
+        unsafe { Reg::from_ptr(self.ptr.byte_add(offset) as *mut _) }
+    }
+
+    /// Whether a source is waiting to be claimed
+    pub fn is_pending(self, source: u32) -> bool {
+        let word = REG_PENDING + 4 * (source as usize / 32);
+        let pending = unsafe { self.ptr.byte_add(word).cast::<u32>().read_volatile() };
+        pending & (1 << (source % 32)) != 0
+    }
+
+    /// Enable bits of 32 sources for a context, starting at source `32 * word`
+    pub fn enable(self, context: usize, word: usize) -> Reg<u32, RW> {
+        let offset = REG_ENABLE + ENABLE_STRIDE * context + 4 * word;
+        unsafe { Reg::from_ptr(self.ptr.byte_add(offset) as *mut _) }
+    }
+
+    /// Priority a source must exceed to interrupt a context
+    pub fn threshold(self, context: usize) -> Reg<u32, RW> {
+        let offset = REG_THRESHOLD + CONTEXT_STRIDE * context;
+        unsafe { Reg::from_ptr(self.ptr.byte_add(offset) as *mut _) }
+    }
+
+    fn claim_reg(self, context: usize) -> *mut u32 {
+        unsafe { self.ptr.byte_add(REG_CLAIM + CONTEXT_STRIDE * context) as *mut _ }
+    }
+}
+
+impl Plic {
+    pub fn set_enabled(self, context: usize, source: u32, enabled: bool) {
+        let bit = 1 << (source % 32);
+        self.enable(context, source as usize / 32).modify(|bits| {
+            if enabled {
+                *bits |= bit;
+            } else {
+                *bits &= !bit;
+            }
+        });
+    }
+
+    /// Take the highest priority pending source for a context, which stays masked until it is
+    /// passed to `complete`
+    pub fn claim(self, context: usize) -> Option<NonZeroU32> {
+        NonZeroU32::new(unsafe { self.claim_reg(context).read_volatile() })
+    }
+
+    /// Finish handling a claimed source, after which it can interrupt again
+    pub fn complete(self, context: usize, source: NonZeroU32) {
+        unsafe { self.claim_reg(context).write_volatile(source.get()) }
+    }
+}
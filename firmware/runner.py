@@ -11,26 +11,52 @@ from cocotb_tools.runner import get_runner
 from cocotbext.uart import UartSink, UartSource
 from cocotbext.axi import AxiLiteMaster, AxiLiteSlave, AxiLiteBus
 
+# value of the id register in rtl simulation, "ORK" then 1 (the emulator reads 2)
+DEBUG_ID = 0x4F524B01
+
 class DebugPeripheral:
     def __init__(self):
         self.pass_event = Event()
         self.fail_event = Event()
+        self.exit_code = None
+        self.console_log = logging.getLogger("cocotb.console")
+        self.console_line = ""
 
     async def write(self, address: int, data: bytes):
         masked_addr = address & ((1 << 24) - 1)
-        print(f"0x{masked_addr:08X}", data)
+        value = int.from_bytes(data, "little")
         match masked_addr:
             case 0x0:
                 # pass
                 self.pass_event.set()
             case 0x4:
-                # pass
+                # fail
                 self.fail_event.set()
+            case 0x8:
+                # exit, where 0 passes
+                self.exit_code = value
+                if value == 0:
+                    self.pass_event.set()
+                else:
+                    self.fail_event.set()
+            case 0xC:
+                # console, printed a line at a time
+                char = chr(value & 0xFF)
+                if char == '\n':
+                    self.console_log.info(self.console_line)
+                    self.console_line = ""
+                else:
+                    self.console_line += char
             case _:
-                raise RuntimeError("invalid peripheral write addr")
+                raise RuntimeError(f"invalid debug peripheral write addr 0x{masked_addr:08X}")
 
     async def read(self, address: int, length: int):
-        raise RuntimeError("attempt read from debug peripheral")
+        masked_addr = address & ((1 << 24) - 1)
+        match masked_addr:
+            case 0x10:
+                return DEBUG_ID.to_bytes(4, "little")[:length]
+            case _:
+                raise RuntimeError(f"invalid debug peripheral read addr 0x{masked_addr:08X}")
 
 async def print_uart_lines(sink: UartSink):
     log = logging.getLogger("cocotb.uart")
@@ -71,7 +97,9 @@ async def run(dut):
     # detect fail event from debug peripheral
     async def fail_on_error():
         await debug_peripheral.fail_event.wait()
-        raise RuntimeError
+        if debug_peripheral.exit_code is None:
+            raise RuntimeError("program reported failure")
+        raise RuntimeError(f"program exited with code {debug_peripheral.exit_code}")
     cocotb.start_soon(fail_on_error())
     # detect fail event from traps
     async def fail_on_trap():
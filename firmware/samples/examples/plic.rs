@@ -0,0 +1,79 @@
+//! UART echo like the `uart` example, with the interrupt routed through the PLIC
+//!
+//! Only the emulator has a PLIC so far, so run it with `emulator run --plic`
+
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+
+use common::{
+    debug,
+    plic::{PLIC_ADDR, Plic, UART_SOURCE},
+    uart::{UART_ADDR, Uart},
+};
+use critical_section::Mutex;
+use heapless::Deque;
+use riscv::interrupt::Interrupt::MachineExternal;
+use riscv_rt::entry;
+
+/// Context of the machine external interrupt
+const CONTEXT: usize = 0;
+
+static UART_BUFFER: Mutex<RefCell<Deque<u8, 256>>> = Mutex::new(RefCell::new(Deque::new()));
+
+static UART: Mutex<Uart> = Mutex::new(unsafe { Uart::from_ptr(UART_ADDR as *mut _) });
+
+static PLIC: Mutex<Plic> = Mutex::new(unsafe { Plic::from_ptr(PLIC_ADDR as *mut _) });
+
+#[riscv_rt::core_interrupt(MachineExternal)]
+fn machine_external_interrupt() {
+    critical_section::with(|cs| {
+        let plic = PLIC.borrow(cs);
+        while let Some(source) = plic.claim(CONTEXT) {
+            if source.get() == UART_SOURCE {
+                let uart = UART.borrow(cs);
+                let mut buffer = UART_BUFFER.borrow(cs).borrow_mut();
+                while uart.status().read().rxr() {
+                    let _ = buffer.push_back(uart.rx());
+                }
+            }
+            plic.complete(CONTEXT, source);
+        }
+    });
+}
+
+#[entry]
+fn main() -> ! {
+    critical_section::with(|cs| {
+        let plic = PLIC.borrow(cs);
+        plic.priority(UART_SOURCE).write_value(1);
+        plic.set_enabled(CONTEXT, UART_SOURCE, true);
+        plic.threshold(CONTEXT).write_value(0);
+
+        let uart = UART.borrow(cs);
+        uart.ctrl().modify(|v| v.set_rxie(true));
+    });
+
+    unsafe { riscv::interrupt::enable_interrupt(MachineExternal) };
+    unsafe { riscv::interrupt::enable() };
+
+    loop {
+        critical_section::with(|cs| {
+            let uart = UART.borrow(cs);
+            let mut bytes = UART_BUFFER.borrow(cs).borrow_mut();
+
+            if bytes.back() == Some(&b'\n') {
+                while let Some(byte) = bytes.pop_front() {
+                    uart.write(byte);
+                }
+                // flush uart
+                while !uart.status().read().txe() {}
+
+                debug::set_pass();
+            }
+        });
+
+        riscv::asm::wfi();
+    }
+}